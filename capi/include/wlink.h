@@ -0,0 +1,49 @@
+/* C API of wlink, WCH-Link flash tool for WCH's RISC-V MCUs.
+ *
+ * Functions return 0 on success, -1 on error, WLINK_ERR_PANIC on an internal panic.
+ * Use wlink_last_error() for the message.
+ */
+#ifndef WLINK_H
+#define WLINK_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* An internal panic was caught, the session should be closed */
+#define WLINK_ERR_PANIC (-2)
+
+typedef struct WlinkSession wlink_session_t;
+
+/* Message of the last error in the current thread, NULL if none */
+const char *wlink_last_error(void);
+
+/* Open the nth WCH-Link probe, NULL on error */
+wlink_session_t *wlink_open(size_t index);
+
+/* Attach to the chip, chip family name like "CH32V30X", or NULL to detect */
+int wlink_attach(wlink_session_t *session, const char *chip);
+
+/* Flash a firmware file, in ELF, ihex or binary format */
+int wlink_flash_file(wlink_session_t *session, const char *path);
+
+/* Flash raw data to the address */
+int wlink_flash(wlink_session_t *session, uint32_t address, const uint8_t *data, size_t len);
+
+/* Read memory, the MCU should be halted */
+int wlink_read_memory(wlink_session_t *session, uint32_t address, uint8_t *buf, size_t len);
+
+/* Reset the MCU and run */
+int wlink_reset(wlink_session_t *session);
+
+/* Detach the chip, resume the MCU, and free the session */
+void wlink_close(wlink_session_t *session);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WLINK_H */
@@ -0,0 +1,237 @@
+//! C API of wlink, see `include/wlink.h`.
+//!
+//! All functions return 0 on success, -1 on error and -2 on a caught panic, the
+//! error message is kept per thread and can be read by `wlink_last_error()`.
+//! Panics never unwind into the caller.
+//! A session handle must not be used by multiple threads at the same time.
+#![allow(clippy::missing_safety_doc)]
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use wlink::{
+    commands::Speed,
+    firmware::{read_firmware_from_file, Firmware},
+    operations::{FlashBackend, ProbeSession},
+    probe::WchLink,
+    RiscvChip,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Opaque session handle, a probe that may be attached to a chip
+pub struct WlinkSession {
+    probe: Option<WchLink>,
+    sess: Option<ProbeSession>,
+}
+
+fn set_last_error(msg: String) {
+    let msg = CString::new(msg.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Return code of a panic caught at the API boundary
+const ERR_PANIC: c_int = -2;
+
+fn to_ret(result: Result<(), String>) -> c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            log::error!("{}", e);
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    let msg = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    format!("panic: {msg}")
+}
+
+/// Run an API function body, a panic is caught and reported as `ERR_PANIC`
+fn guard(f: impl FnOnce() -> Result<(), String>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => to_ret(result),
+        Err(payload) => {
+            let msg = panic_message(payload);
+            log::error!("{}", msg);
+            set_last_error(msg);
+            ERR_PANIC
+        }
+    }
+}
+
+unsafe fn session<'a>(handle: *mut WlinkSession) -> Result<&'a mut WlinkSession, String> {
+    handle
+        .as_mut()
+        .ok_or_else(|| "null session handle".to_string())
+}
+
+unsafe fn attached<'a>(handle: *mut WlinkSession) -> Result<&'a mut ProbeSession, String> {
+    session(handle)?
+        .sess
+        .as_mut()
+        .ok_or_else(|| "not attached, call wlink_attach() first".to_string())
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{what} is null"));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| format!("{what} is not valid UTF-8"))
+}
+
+/// Message of the last error in the current thread, NULL if none.
+/// Valid until the next failed call in the same thread.
+#[no_mangle]
+pub extern "C" fn wlink_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+/// Open the nth WCH-Link probe, returns NULL on error
+#[no_mangle]
+pub extern "C" fn wlink_open(index: usize) -> *mut WlinkSession {
+    let mut handle = ptr::null_mut();
+    guard(|| {
+        let probe = WchLink::open_nth(index).map_err(|e| e.to_string())?;
+        handle = Box::into_raw(Box::new(WlinkSession {
+            probe: Some(probe),
+            sess: None,
+        }));
+        Ok(())
+    });
+    handle
+}
+
+/// Attach to the chip, `chip` is the chip family name like "CH32V30X", or NULL to detect
+#[no_mangle]
+pub unsafe extern "C" fn wlink_attach(handle: *mut WlinkSession, chip: *const c_char) -> c_int {
+    guard(|| {
+        let handle = session(handle)?;
+        let chip = if chip.is_null() {
+            None
+        } else {
+            Some(
+                str_arg(chip, "chip")?
+                    .parse::<RiscvChip>()
+                    .map_err(|e| e.to_string())?,
+            )
+        };
+        let probe = handle
+            .probe
+            .take()
+            .ok_or_else(|| "already attached".to_string())?;
+        let sess =
+            ProbeSession::attach(probe, chip, Speed::default()).map_err(|e| e.to_string())?;
+        handle.sess = Some(sess);
+        Ok(())
+    })
+}
+
+/// Flash a firmware file, in ELF, ihex or binary format. Binary files are flashed to the code flash start
+#[no_mangle]
+pub unsafe extern "C" fn wlink_flash_file(handle: *mut WlinkSession, path: *const c_char) -> c_int {
+    guard(|| {
+        let sess = attached(handle)?;
+        let firmware =
+            read_firmware_from_file(str_arg(path, "path")?).map_err(|e| e.to_string())?;
+        sess.write_firmware(&firmware, None, FlashBackend::default())
+            .map_err(|e| e.to_string())
+    })
+}
+
+/// Flash raw data to the address
+#[no_mangle]
+pub unsafe extern "C" fn wlink_flash(
+    handle: *mut WlinkSession,
+    address: u32,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    guard(|| {
+        let sess = attached(handle)?;
+        if data.is_null() {
+            return Err("data is null".to_string());
+        }
+        let firmware = Firmware::Binary(slice::from_raw_parts(data, len).to_vec());
+        sess.write_firmware(&firmware, Some(address), FlashBackend::default())
+            .map_err(|e| e.to_string())
+    })
+}
+
+/// Read memory into `buf`, the MCU should be halted
+#[no_mangle]
+pub unsafe extern "C" fn wlink_read_memory(
+    handle: *mut WlinkSession,
+    address: u32,
+    buf: *mut u8,
+    len: usize,
+) -> c_int {
+    guard(|| {
+        let sess = attached(handle)?;
+        if buf.is_null() {
+            return Err("buf is null".to_string());
+        }
+        let len32 = u32::try_from(len).map_err(|_| "len is too large".to_string())?;
+        let mem = sess
+            .read_memory(address, len32)
+            .map_err(|e| e.to_string())?;
+        slice::from_raw_parts_mut(buf, len).copy_from_slice(&mem[..len]);
+        Ok(())
+    })
+}
+
+/// Reset the MCU and run
+#[no_mangle]
+pub unsafe extern "C" fn wlink_reset(handle: *mut WlinkSession) -> c_int {
+    guard(|| attached(handle)?.soft_reset().map_err(|e| e.to_string()))
+}
+
+/// Detach the chip, resume the MCU, and free the handle. NULL is ignored
+#[no_mangle]
+pub unsafe extern "C" fn wlink_close(handle: *mut WlinkSession) {
+    if handle.is_null() {
+        return;
+    }
+    guard(|| {
+        let mut handle = Box::from_raw(handle);
+        if let Some(mut sess) = handle.sess.take() {
+            if let Err(e) = sess.detach_chip() {
+                log::warn!("detach failed: {}", e);
+            }
+        }
+        Ok(())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(wlink_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn panic_is_caught() {
+        assert_eq!(guard(|| panic!("boom")), ERR_PANIC);
+        assert_eq!(last_error(), "panic: boom");
+        assert_eq!(guard(|| Err("failed".to_string())), -1);
+        assert_eq!(last_error(), "failed");
+    }
+}
@@ -1,6 +1,8 @@
 //! The chip DB.
 //! This numbers are from `GetCHIPID` fn in EVT code.
 
+use crate::RiscvChip;
+
 pub fn chip_id_to_chip_name(chip_id: u32) -> Option<&'static str> {
     match chip_id & 0xFFF00000 {
         0x650_00000 => Some("CH565"),
@@ -135,3 +137,74 @@ pub fn chip_id_to_chip_name(chip_id: u32) -> Option<&'static str> {
         _ => None,
     }
 }
+
+/// Chip family of a chip ID, by the series in the upper bits, None if unknown
+pub fn chip_id_to_family(chip_id: u32) -> Option<RiscvChip> {
+    let family = match chip_id >> 20 {
+        0x650 | 0x690 => RiscvChip::CH56X,
+        0x710 | 0x730 => RiscvChip::CH57X,
+        0x810..=0x830 => RiscvChip::CH582,
+        0x840 | 0x930 => RiscvChip::CH585,
+        0x920 => RiscvChip::CH59X,
+        0x003 => RiscvChip::CH32V003,
+        0x002 | 0x004..=0x007 => RiscvChip::CH32V007,
+        0x035 => RiscvChip::CH32X035,
+        0x103 => RiscvChip::CH32L103,
+        0x203 | 0x208 => RiscvChip::CH32V20X,
+        0x303 | 0x305 | 0x307 => RiscvChip::CH32V30X,
+        0x317 => RiscvChip::CH32V317,
+        0x641 => RiscvChip::CH641,
+        0x643 => RiscvChip::CH643,
+        _ => return None,
+    };
+    Some(family)
+}
+
+/// Check if a chip reported as `family` may have the chip ID of `id_family`,
+/// probes report some families as the family they fall back to
+pub fn is_family_compatible(family: RiscvChip, id_family: RiscvChip) -> bool {
+    use RiscvChip::*;
+
+    family == id_family
+        || matches!(
+            (id_family, family),
+            (CH32X035, CH643)
+                | (CH641, CH32V003)
+                | (CH59X, CH582)
+                | (CH585, CH582 | CH59X)
+                | (CH32V317, CH32V30X)
+        )
+}
+
+/// Code flash and SRAM size of a part, in the default SRAM/flash split
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChipMemory {
+    pub flash_kb: u32,
+    pub sram_kb: u32,
+}
+
+/// Memory size of a part, from the datasheet.
+/// Parts of a family like CH32V303/305/307 differ in flash and SRAM size.
+pub fn chip_id_to_memory(chip_id: u32) -> Option<ChipMemory> {
+    let name = chip_id_to_chip_name(chip_id)?;
+    let (flash_kb, sram_kb) = match name {
+        n if n.starts_with("CH32V003") => (16, 2),
+        n if n.starts_with("CH32V002") => (16, 4),
+        n if n.starts_with("CH32V004") || n.starts_with("CH32V005") => (32, 6),
+        n if n.starts_with("CH32V006") || n.starts_with("CH32V007") => (62, 8),
+        "CH32X035F7P6" | "CH32L103F7P6" => (48, 20),
+        n if n.starts_with("CH32X03") || n.starts_with("CH32L103") => (62, 20),
+        "CH32V203C6T6" | "CH32V203K6T6" | "CH32V203G6U6" | "CH32V203F6P6" => (32, 10),
+        "CH32V203RBT6" => (128, 64),
+        n if n.starts_with("CH32V203") => (64, 20),
+        n if n.starts_with("CH32V208") => (128, 64),
+        "CH32V303CBT6" => (128, 32),
+        "CH32V303RBT6" => (128, 64),
+        "CH32V303RCT6" | "CH32V303VCT6" => (256, 64),
+        n if n.starts_with("CH32V305") => (128, 32),
+        "CH32V307RCT6" | "CH32V307VCT6" | "CH32V307WCU6" => (256, 64),
+        _ => return None,
+    };
+    Some(ChipMemory { flash_kb, sram_kb })
+}
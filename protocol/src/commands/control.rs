@@ -1,6 +1,11 @@
 //! Probe control commands. COMMAND_ID = 0x0d
 
-use crate::{probe::WchLinkVariant, RiscvChip};
+use alloc::string::String;
+
+use crate::{
+    probe::{FirmwareFeature, WchLinkVariant},
+    RiscvChip,
+};
 
 use super::*;
 
@@ -14,16 +19,24 @@ impl Command for GetProbeInfo {
         vec![0x01]
     }
 }
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ProbeInfo {
     pub major_version: u8,
     pub minor_version: u8,
     pub variant: WchLinkVariant,
+    /// USB serial number, read by the host on open, None if not available
+    pub serial: Option<String>,
 }
 impl ProbeInfo {
     pub fn version(&self) -> (u8, u8) {
         (self.major_version, self.minor_version)
     }
+
+    /// Whether the probe firmware is new enough for a feature
+    pub fn supports(&self, feature: FirmwareFeature) -> bool {
+        self.version() >= feature.min_version()
+    }
 }
 impl Response for ProbeInfo {
     fn from_payload(bytes: &[u8]) -> Result<Self> {
@@ -39,6 +52,7 @@ impl Response for ProbeInfo {
             } else {
                 WchLinkVariant::Ch549
             },
+            serial: None,
         })
     }
 }
@@ -51,7 +65,11 @@ impl fmt::Display for ProbeInfo {
             self.minor_version,
             self.major_version * 10 + self.minor_version,
             self.variant
-        )
+        )?;
+        if let Some(serial) = &self.serial {
+            write!(f, " SN {}", serial)?;
+        }
+        Ok(())
     }
 }
 
@@ -137,7 +155,7 @@ impl Command for GetChipRomRamSplit {
 
 /// 0, 1, 2, 3
 #[derive(Debug)]
-pub struct SetChipRomRamSplit(u8);
+pub struct SetChipRomRamSplit(pub u8);
 impl Command for SetChipRomRamSplit {
     type Response = ();
     const COMMAND_ID: u8 = 0x0d;
@@ -146,6 +164,28 @@ impl Command for SetChipRomRamSplit {
     }
 }
 
+/// Check the QE bit of the external QSPI flash, CH56X only (0x0d, 0x06)
+#[derive(Debug)]
+pub struct CheckQE;
+impl Command for CheckQE {
+    type Response = u8;
+    const COMMAND_ID: u8 = 0x0d;
+    fn payload(&self) -> Vec<u8> {
+        vec![0x06]
+    }
+}
+
+/// Set the QE bit of the external QSPI flash, CH56X only (0x0d, 0x07)
+#[derive(Debug)]
+pub struct EnableQE;
+impl Command for EnableQE {
+    type Response = ();
+    const COMMAND_ID: u8 = 0x0d;
+    fn payload(&self) -> Vec<u8> {
+        vec![0x07]
+    }
+}
+
 // ?? close out
 /// Detach Chip, (0x0d, 0xff)
 #[derive(Debug)]
@@ -159,7 +199,8 @@ impl Command for OptEnd {
 }
 
 /// Set Power, from pow3v3, pow5v fn
-#[derive(clap::Subcommand, PartialEq, Clone, Copy, Debug)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "clap", derive(clap::Subcommand))]
 pub enum SetPower {
     /// Enable 3.3V output
     Enable3v3,
@@ -202,10 +243,15 @@ impl Command for SetSdiPrintEnabled {
 }
 
 /// Set RST pin
-#[derive(Debug)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
 pub enum SetRSTPin {
+    /// Drive nRST low, hold the target in reset
     Low,
+    /// Drive nRST high
     High,
+    /// Release nRST
+    #[cfg_attr(feature = "clap", value(name = "float"))]
     Floating,
 }
 impl Command for SetRSTPin {
@@ -220,3 +266,17 @@ impl Command for SetRSTPin {
         vec![0x0e, subcmd]
     }
 }
+
+/// Experimental, probe-side diagnostic log (0x0d, subcmd).
+///
+/// Newer WCH-LinkE firmware seems to buffer diagnostic info, the subcommand is undocumented
+/// and varies between firmware versions.
+#[derive(Debug)]
+pub struct GetProbeLog(pub u8);
+impl Command for GetProbeLog {
+    type Response = Vec<u8>;
+    const COMMAND_ID: u8 = 0x0d;
+    fn payload(&self) -> Vec<u8> {
+        vec![self.0]
+    }
+}
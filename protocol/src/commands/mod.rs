@@ -1,7 +1,8 @@
 //! WCH-Link commands and response types.
 
-use std::fmt;
-use std::fmt::Debug;
+use alloc::{format, vec, vec::Vec};
+use core::fmt;
+use core::fmt::Debug;
 
 use crate::error::{Error, Result};
 
@@ -32,6 +33,9 @@ pub trait Response {
     where
         Self: Sized,
     {
+        if resp.len() < 3 {
+            return Err(Error::InvalidPayloadLength);
+        }
         if resp[0] == 0x81 {
             let reason = resp[1];
             let len = resp[2] as usize;
@@ -133,7 +137,7 @@ pub enum Program {
     EraseFlash = 0x01,
     // Before write firmware bytes, choice between 0x02 and 0x04
     WriteFlash = 0x02,
-    // Write flash
+    /// Like WriteFlash, the flash OP also reads each pack back, a mismatch fails the pack status
     WriteFlashAndVerify = 0x04,
     /// Write Flash OP
     WriteFlashOP = 0x05,
@@ -156,6 +160,45 @@ impl Command for Program {
     }
 }
 
+/// Status frame of fastprogram, `41 01 01 STATUS`.
+///
+/// Read from the data endpoint after each write pack.
+/// Statuses other than 0x04 are from captures of failed writes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FastprogramStatus {
+    /// 0x04, the pack is written
+    Done,
+    /// 0x05, the pack is not written, flash is busy. Can be resent
+    WriteFailed,
+    /// 0x18, flash_op reports program error, the flash is protected or not erased
+    ProgramError,
+    /// 0xff, flash_op is not running, or the target is lost
+    NoResponse,
+    Unknown(u8),
+}
+impl FastprogramStatus {
+    pub fn from_raw(resp: &[u8]) -> Result<Self> {
+        if resp.len() != 4 {
+            return Err(Error::InvalidPayloadLength);
+        }
+        if resp[..3] != [0x41, 0x01, 0x01] {
+            return Err(Error::InvalidPayload);
+        }
+        Ok(match resp[3] {
+            0x04 => FastprogramStatus::Done,
+            0x05 => FastprogramStatus::WriteFailed,
+            0x18 => FastprogramStatus::ProgramError,
+            0xff => FastprogramStatus::NoResponse,
+            other => FastprogramStatus::Unknown(other),
+        })
+    }
+
+    /// Only a busy flash can be retried, others are fatal
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, FastprogramStatus::WriteFailed)
+    }
+}
+
 /// 0x06 subset
 // query -> check -> set
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
@@ -229,6 +272,7 @@ impl Command for GetChipInfo {
 // 20360510 => chip id
 /// Flash size and Chip UID, also reported by wchisp
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct ESignature {
     /// Non-zero-wait flash size in KB
     pub flash_size_kb: u16,
@@ -259,7 +303,7 @@ impl Response for ESignature {
 impl fmt::Display for ESignature {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // write aa-bb-cc-.. style UID
-        let bytes: [u8; 8] = unsafe { std::mem::transmute(self.uid) };
+        let bytes: [u8; 8] = unsafe { core::mem::transmute(self.uid) };
         write!(
             f,
             "FlashSize({}KB) UID({})",
@@ -273,6 +317,58 @@ impl fmt::Display for ESignature {
     }
 }
 
+/// Chip info query (0x11, 0x0d), sent by WCH-LinkUtility before GetChipRomRamSplit.
+///
+/// It refreshes the chip state cached by the probe, without it, GetChipRomRamSplit
+/// may return a stale value on CH32V30x.
+#[derive(Debug)]
+pub struct QueryPreRomRamSplit;
+impl Command for QueryPreRomRamSplit {
+    type Response = PreRomRamSplitInfo;
+    const COMMAND_ID: u8 = 0x11;
+    fn payload(&self) -> Vec<u8> {
+        vec![0x0d]
+    }
+}
+
+/// Response of `QueryPreRomRamSplit`, the meaning is not documented.
+///
+/// Like GetChipInfo, some firmware versions reply without the `82 11 LEN` header,
+/// so the raw response is kept.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PreRomRamSplitInfo {
+    pub raw: Vec<u8>,
+}
+
+impl PreRomRamSplitInfo {
+    /// Payload part of the response, the whole response if not in the standard format
+    pub fn payload(&self) -> &[u8] {
+        match self.raw.as_slice() {
+            [0x82, 0x11, len, payload @ ..] if *len as usize == payload.len() => payload,
+            raw => raw,
+        }
+    }
+}
+
+impl Response for PreRomRamSplitInfo {
+    fn from_payload(_bytes: &[u8]) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        unreachable!("PreRomRamSplitInfo is not parsed from payload; qed")
+    }
+
+    fn from_raw(resp: &[u8]) -> Result<Self> {
+        if resp.len() < 3 {
+            return Err(Error::InvalidPayloadLength);
+        }
+        if resp[0] == 0x81 {
+            return Err(Error::Protocol(resp[1], resp.to_vec()));
+        }
+        Ok(Self { raw: resp.to_vec() })
+    }
+}
+
 /// Device reset (0x0b, _)
 #[derive(Debug)]
 pub enum Reset {
@@ -296,7 +392,8 @@ impl Command for Reset {
 }
 
 /// Speed settings
-#[derive(Debug, Copy, Clone, clap::ValueEnum, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
 pub enum Speed {
     /// 400kHz
     Low = 0x03,
@@ -307,6 +404,37 @@ pub enum Speed {
     High = 0x01,
 }
 
+impl Speed {
+    /// Clock frequency in kHz
+    pub fn khz(self) -> u32 {
+        match self {
+            Speed::Low => 400,
+            Speed::Medium => 4000,
+            Speed::High => 6000,
+        }
+    }
+
+    /// The supported speed closest to `khz`, the slower one on a tie
+    ///
+    /// The firmware only takes these 3 divisor codes, other values of the
+    /// SetSpeed command are rejected.
+    pub fn nearest_khz(khz: u32) -> Speed {
+        [Speed::Low, Speed::Medium, Speed::High]
+            .into_iter()
+            .min_by_key(|s| s.khz().abs_diff(khz))
+            .unwrap()
+    }
+
+    /// The next slower speed, None for the slowest
+    pub fn slower(self) -> Option<Speed> {
+        match self {
+            Speed::High => Some(Speed::Medium),
+            Speed::Medium => Some(Speed::Low),
+            Speed::Low => None,
+        }
+    }
+}
+
 /// Set CLK Speed, 0x0C
 #[derive(Debug)]
 pub struct SetSpeed {
@@ -419,11 +547,69 @@ impl Command for DisableDebug {
 
 // 81 0D 05 11 SetAccessAddress
 // 81 0F 01 02 GetDeviceMode
-// 81 0D 01 07 EnableQE
-// 81 0D 01 06 CheckQE
 // 81 FE 01 00 DisEncrypt
 // 81 0D 01 0F ClearCodeFlashB
 // 81 0D 02 08 xx ClearCodeFlash
-// 81 11 01 0D unknown in query info, before GetChipRomRamSplit
 // 81 0D 02 EE 00/02/03 SetSDLineMode
 // 81 0F 01 01 SetIAPMode
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fastprogram_status_done() {
+        assert_eq!(
+            FastprogramStatus::from_raw(&[0x41, 0x01, 0x01, 0x04]).unwrap(),
+            FastprogramStatus::Done
+        );
+    }
+
+    #[test]
+    fn fastprogram_status_failures() {
+        let cases = [
+            (
+                [0x41, 0x01, 0x01, 0x05],
+                FastprogramStatus::WriteFailed,
+                true,
+            ),
+            (
+                [0x41, 0x01, 0x01, 0x18],
+                FastprogramStatus::ProgramError,
+                false,
+            ),
+            (
+                [0x41, 0x01, 0x01, 0xff],
+                FastprogramStatus::NoResponse,
+                false,
+            ),
+            (
+                [0x41, 0x01, 0x01, 0x07],
+                FastprogramStatus::Unknown(0x07),
+                false,
+            ),
+        ];
+        for (frame, status, retryable) in cases {
+            let decoded = FastprogramStatus::from_raw(&frame).unwrap();
+            assert_eq!(decoded, status);
+            assert_eq!(decoded.is_retryable(), retryable, "{:?}", status);
+        }
+        assert!(!FastprogramStatus::Done.is_retryable());
+    }
+
+    #[test]
+    fn fastprogram_status_invalid_frames() {
+        assert!(matches!(
+            FastprogramStatus::from_raw(&[0x82, 0x02, 0x01, 0x04]),
+            Err(Error::InvalidPayload)
+        ));
+        assert!(matches!(
+            FastprogramStatus::from_raw(&[0x41, 0x01, 0x01]),
+            Err(Error::InvalidPayloadLength)
+        ));
+        assert!(matches!(
+            FastprogramStatus::from_raw(&[]),
+            Err(Error::InvalidPayloadLength)
+        ));
+    }
+}
@@ -0,0 +1,80 @@
+//! Protocol errors
+use alloc::{string::String, vec::Vec};
+
+use thiserror::Error;
+
+/// Alias for a `Result` with the error type `wlink_protocol::Error`.
+pub type Result<T> = core::result::Result<T, Error>;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Unknown WCH-Link variant: {0}")]
+    UnknownLinkVariant(u8),
+    #[error("Unknown RISC-V Chip: 0x{0:02x}")]
+    UnknownChip(u8),
+    #[error("Unknown chip: {0}")]
+    UnknownChipName(String),
+    #[error("WCH-Link underlying protocol error: {0:#04x} {1:#04x?}")]
+    Protocol(u8, Vec<u8>),
+    #[error("Invalid payload length")]
+    InvalidPayloadLength,
+    #[error("Invalid payload")]
+    InvalidPayload,
+}
+
+/// A failure reply of the probe, `81 <reason> ...`, with the command that failed.
+///
+/// The probe replies the same reason for most failures, so only pairs of command and
+/// reason confirmed from captures get a hint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeFailure {
+    pub command: u8,
+    pub subcommand: Option<u8>,
+    pub reason: u8,
+    pub hint: Option<FailureHint>,
+}
+
+/// Likely cause of a probe failure
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureHint {
+    #[error("target does not respond, check wiring and power, or debug is disabled (hint: use wchisp to enable debug)")]
+    AttachFailed,
+}
+
+/// Reason of failure replies of the WCH-Link
+pub const REASON_FAILED: u8 = 0x55;
+
+impl ProbeFailure {
+    /// Decode a failure reply of a raw command `request`, None if it is not a command frame
+    pub fn decode(request: &[u8], reason: u8) -> Option<Self> {
+        let command = *request.get(1)?;
+        let subcommand = request.get(3).copied();
+        let hint = match (command, subcommand, reason) {
+            // AttachChip
+            (0x0d, Some(0x02), REASON_FAILED) => Some(FailureHint::AttachFailed),
+            _ => None,
+        };
+        Some(Self {
+            command,
+            subcommand,
+            reason,
+            hint,
+        })
+    }
+}
+
+impl core::fmt::Display for ProbeFailure {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "command 0x{:02x}", self.command)?;
+        if let Some(subcommand) = self.subcommand {
+            write!(f, " 0x{:02x}", subcommand)?;
+        }
+        write!(f, " failed with reason 0x{:02x}", self.reason)?;
+        if let Some(hint) = self.hint {
+            write!(f, ", {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl core::error::Error for ProbeFailure {}
@@ -0,0 +1,468 @@
+//! WCH-Link protocol, command encoding, response decoding and RISC-V debug registers.
+//!
+//! No std or USB dependencies, only `alloc`, so the protocol can be reused in
+//! firmware-side tools, like a bridge speaking the WCH-Link protocol.
+//! Enable the `clap` feature for command line value parsing.
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "clap")]
+extern crate std;
+
+pub mod chips;
+pub mod commands;
+pub mod error;
+pub mod probe;
+pub mod regs;
+
+#[cfg(feature = "clap")]
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+#[cfg(feature = "clap")]
+use clap::{builder::PossibleValue, ValueEnum};
+
+pub use crate::error::{Error, Result};
+
+/// Currently supported RISC-V chip series/family
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[repr(u8)]
+pub enum RiscvChip {
+    /// CH32V103 RISC-V3A series
+    CH32V103 = 0x01,
+    /// CH571/CH573 RISC-V3A BLE 4.2 series
+    CH57X = 0x02,
+    /// CH565/CH569 RISC-V3A series
+    CH56X = 0x03,
+    /// CH32V20X RISC-V4B/V4C series
+    CH32V20X = 0x05,
+    /// CH32V30X RISC-V4C/V4F series, The same as type 5
+    CH32V30X = 0x06,
+    /// CH583/CH582/CH581 RISC-V4A BLE 5.3 series
+    // Use CH582 here as it's the most common one
+    CH582 = 0x07,
+    /// CH32V003 RISC-V2A series
+    CH32V003 = 0x09,
+    /// RISC-V EC controller, undocumented.
+    /// The only reference I can find is <https://www.wch.cn/news/606.html>.
+    CH8571 = 0x0A, // 10,
+    /// CH59x RISC-V4C BLE 5.4 series, fallback as CH58X
+    /// FIXME: CH585 also reported as this
+    CH59X = 0x0B, // 11
+    /// CH643 RISC-V4C series, RGB Display Driver MCU
+    CH643 = 0x0C, // 12
+    /// CH32X035 RISC-V4C USB-PD series, fallback as CH643
+    CH32X035 = 0x0D, // 13
+    /// CH32L103 RISC-V4C low power series, USB-PD
+    CH32L103 = 0x0E, // 14
+    /// CH641 RISC-V2A series, USB-PD, fallback as CH32V003
+    CH641 = 0x49,
+    // Added at 0.1.0
+    /// CH585/CH584 RISC-V3C series, BLE 5.4, NFC, USB HS, fallback as CH582
+    CH585 = 0x4B,
+    /// CH564 RISC-V4J series
+    CH564 = 0x0F,
+    /// CH32V002/4/5/6/7, CH32M007
+    CH32V007 = 0x4E,
+    /// CH645, CH653, RISC-V4C
+    CH645 = 0x46,
+    /// CH32V317 RISC-V4 series
+    CH32V317 = 0x86,
+    // Cortex-M chips
+    CH32F10X = 0x04,
+    CH32F20X = 0x08,
+}
+
+#[cfg(feature = "clap")]
+impl ValueEnum for RiscvChip {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            RiscvChip::CH32V103,
+            RiscvChip::CH57X,
+            RiscvChip::CH56X,
+            RiscvChip::CH32V20X,
+            RiscvChip::CH32V30X,
+            RiscvChip::CH582,
+            RiscvChip::CH32V003,
+            RiscvChip::CH8571,
+            RiscvChip::CH59X,
+            RiscvChip::CH643,
+            RiscvChip::CH32X035,
+            RiscvChip::CH32L103,
+            RiscvChip::CH641,
+            RiscvChip::CH585,
+            RiscvChip::CH564,
+            RiscvChip::CH32V007,
+            RiscvChip::CH645,
+            RiscvChip::CH32V317,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            RiscvChip::CH32V103 => Some(PossibleValue::new("CH32V103")),
+            RiscvChip::CH57X => Some(PossibleValue::new("CH57X")),
+            RiscvChip::CH56X => Some(PossibleValue::new("CH56X")),
+            RiscvChip::CH32V20X => Some(PossibleValue::new("CH32V20X")),
+            RiscvChip::CH32V30X => Some(PossibleValue::new("CH32V30X")),
+            RiscvChip::CH582 => Some(PossibleValue::new("CH582")),
+            RiscvChip::CH585 => Some(PossibleValue::new("CH585")),
+            RiscvChip::CH32V003 => Some(PossibleValue::new("CH32V003")),
+            RiscvChip::CH8571 => Some(PossibleValue::new("CH8571")),
+            RiscvChip::CH59X => Some(PossibleValue::new("CH59X")),
+            RiscvChip::CH643 => Some(PossibleValue::new("CH643")),
+            RiscvChip::CH32X035 => Some(PossibleValue::new("CH32X035")),
+            RiscvChip::CH32L103 => Some(PossibleValue::new("CH32L103")),
+            RiscvChip::CH641 => Some(PossibleValue::new("CH641")),
+            RiscvChip::CH564 => Some(PossibleValue::new("CH564")),
+            RiscvChip::CH32V007 => Some(PossibleValue::new("CH32V007")),
+            RiscvChip::CH645 => Some(PossibleValue::new("CH645")),
+            RiscvChip::CH32V317 => Some(PossibleValue::new("CH32V317")),
+            _ => None,
+        }
+    }
+
+    fn from_str(input: &str, ignore_case: bool) -> core::result::Result<Self, String> {
+        if ignore_case {
+            input.parse().map_err(|e: Error| e.to_string())
+        } else {
+            RiscvChip::from_name(input).ok_or_else(|| format!("Unknown chip: {}", input))
+        }
+    }
+}
+
+/// Parse a chip family or chip name like `CH32V307`, case insensitive
+impl core::str::FromStr for RiscvChip {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        RiscvChip::from_name(&s.to_ascii_uppercase())
+            .ok_or_else(|| Error::UnknownChipName(s.into()))
+    }
+}
+
+impl RiscvChip {
+    /// Chip family of an upper case family or chip name
+    fn from_name(name: &str) -> Option<Self> {
+        let chip = match name {
+            "CH32V103" => RiscvChip::CH32V103,
+            "CH32V20X" | "CH32V203" | "CH32V208" => RiscvChip::CH32V20X,
+            "CH32V30X" | "CH32V303" | "CH32V305" | "CH32V307" => RiscvChip::CH32V30X,
+            "CH32V317" => RiscvChip::CH32V317,
+            "CH32V003" => RiscvChip::CH32V003,
+            "CH32L103" => RiscvChip::CH32L103,
+            // Note that CH32X034 seems never released
+            "CH32X0" | "CH32X03X" | "CH32X033" | "CH32X034" | "CH32X035" => RiscvChip::CH32X035,
+            "CH32V002" | "CH32V004" | "CH32V005" | "CH32V006" | "CH32V007" | "CH32M007" => {
+                RiscvChip::CH32V007
+            }
+            "CH565" | "CH569" => RiscvChip::CH56X,
+            "CH57X" | "CH571" | "CH573" => RiscvChip::CH57X,
+            "CH581" | "CH582" | "CH583" => RiscvChip::CH582,
+            "CH584" | "CH585" => RiscvChip::CH585,
+            "CH564" => RiscvChip::CH564,
+            "CH59X" | "CH591" | "CH592" => RiscvChip::CH59X,
+            "CH641" => RiscvChip::CH641,
+            "CH643" => RiscvChip::CH643,
+            "CH645" | "CH653" => RiscvChip::CH645,
+            "CH8571" => RiscvChip::CH8571,
+            "CH56X" => {
+                log::warn!(
+                    "Ambiguous chip family, assume CH569. use either CH564, CH565 or CH569 instead"
+                );
+                RiscvChip::CH56X
+            }
+            "CH58X" => {
+                log::warn!(
+                    "Ambiguous chip family, assume CH582. use either CH582 or CH585 instead"
+                );
+                RiscvChip::CH582
+            }
+            _ => return None,
+        };
+        Some(chip)
+    }
+
+    /// Support flash protect commands, and info query commands
+    pub fn support_flash_protect(&self) -> bool {
+        // 1, 6, 5, 9, 0x49, 0x46, 0x86
+        matches!(
+            self,
+            RiscvChip::CH32V103
+                | RiscvChip::CH32V20X
+                | RiscvChip::CH32V30X
+                | RiscvChip::CH32V003
+                | RiscvChip::CH32V007
+                | RiscvChip::CH32L103
+                | RiscvChip::CH32X035
+                | RiscvChip::CH641
+                | RiscvChip::CH645
+                | RiscvChip::CH32V317
+        )
+    }
+
+    // CH32V208xB, CH32V307, CH32V303RCT6/VCT6
+    pub fn support_ram_rom_mode(&self) -> bool {
+        matches!(
+            self,
+            RiscvChip::CH32V20X | RiscvChip::CH32V30X | RiscvChip::CH32V317
+        )
+    }
+
+    /// Code flash and SRAM size in KB of a ROM/RAM split mode, from the reference manuals
+    pub fn rom_ram_split_sizes(&self, mode: u8) -> Option<(u32, u32)> {
+        match (self, mode) {
+            // CH32V208xB
+            (RiscvChip::CH32V20X, 0) => Some((128, 64)),
+            (RiscvChip::CH32V20X, 1) => Some((144, 48)),
+            (RiscvChip::CH32V20X, 2 | 3) => Some((160, 32)),
+            // CH32V307, CH32V303RCT6/VCT6
+            (RiscvChip::CH32V30X, 0) => Some((192, 128)),
+            (RiscvChip::CH32V30X, 1) => Some((224, 96)),
+            (RiscvChip::CH32V30X, 2) => Some((256, 64)),
+            (RiscvChip::CH32V30X, 3) => Some((288, 32)),
+            _ => None,
+        }
+    }
+
+    /// Support config registers, query info(UID, etc.)
+    pub fn support_query_info(&self) -> bool {
+        !matches!(
+            self,
+            RiscvChip::CH57X
+                | RiscvChip::CH56X
+                | RiscvChip::CH582
+                | RiscvChip::CH585
+                | RiscvChip::CH59X
+        )
+    }
+
+    /// Very unsafe.
+    /// This disables the debug interface of the chip.
+    /// Command sequence is 810e0101
+    pub fn support_disable_debug(&self) -> bool {
+        matches!(
+            self,
+            RiscvChip::CH57X
+                | RiscvChip::CH56X
+                | RiscvChip::CH582
+                | RiscvChip::CH585
+                | RiscvChip::CH59X
+        )
+    }
+
+    /// QE bit of the external QSPI flash, by CheckQE and EnableQE
+    pub fn support_qe_bit(&self) -> bool {
+        matches!(self, RiscvChip::CH56X)
+    }
+
+    /// Erase code flash by RST pin or power-off
+    pub fn support_special_erase(&self) -> bool {
+        !matches!(
+            self,
+            RiscvChip::CH57X
+                | RiscvChip::CH56X
+                | RiscvChip::CH582
+                | RiscvChip::CH585
+                | RiscvChip::CH59X
+        )
+    }
+
+    pub fn support_sdi_print(&self) -> bool {
+        // CH641, CH643, CH32V00x, CH32V103, CH32V20x, CH32V30x, CH32X035, CH32L103
+        matches!(
+            self,
+            RiscvChip::CH32V003
+                | RiscvChip::CH645
+                | RiscvChip::CH32V007
+                | RiscvChip::CH32V103
+                | RiscvChip::CH32V20X
+                | RiscvChip::CH32V30X
+                | RiscvChip::CH32X035
+                | RiscvChip::CH32L103
+                | RiscvChip::CH643
+                | RiscvChip::CH641
+                | RiscvChip::CH32V317
+        )
+    }
+
+    pub fn is_rv32ec(&self) -> bool {
+        matches!(
+            self,
+            RiscvChip::CH32V003 | RiscvChip::CH641 | RiscvChip::CH32V007
+        )
+    }
+
+    pub fn reset_command(&self) -> commands::Reset {
+        match self {
+            RiscvChip::CH57X | RiscvChip::CH582 | RiscvChip::CH59X => commands::Reset::Chip,
+            _ => commands::Reset::Normal,
+        }
+    }
+
+    fn try_from_u8(value: u8) -> Result<Self> {
+        match value {
+            0x01 => Ok(RiscvChip::CH32V103),
+            0x02 => Ok(RiscvChip::CH57X),
+            0x03 => Ok(RiscvChip::CH56X),
+            0x05 => Ok(RiscvChip::CH32V20X),
+            0x06 => Ok(RiscvChip::CH32V30X),
+            0x07 => Ok(RiscvChip::CH582),
+            0x09 => Ok(RiscvChip::CH32V003),
+            0x0A => Ok(RiscvChip::CH8571),
+            0x0B => Ok(RiscvChip::CH59X),
+            0x0C => Ok(RiscvChip::CH643),
+            0x0D => Ok(RiscvChip::CH32X035),
+            0x0E => Ok(RiscvChip::CH32L103),
+            0x49 => Ok(RiscvChip::CH641),
+            0x4B => Ok(RiscvChip::CH585),
+            0x0F => Ok(RiscvChip::CH564),
+            0x4E => Ok(RiscvChip::CH32V007),
+            0x46 => Ok(RiscvChip::CH645),
+            0x86 => Ok(RiscvChip::CH32V317),
+            0x04 => Ok(RiscvChip::CH32F10X),
+            0x08 => Ok(RiscvChip::CH32F20X),
+            _ => Err(Error::UnknownChip(value)),
+        }
+    }
+
+    /// Packet data length of data endpoint
+    pub fn data_packet_size(&self) -> usize {
+        match self {
+            RiscvChip::CH32V103 => 128,
+            RiscvChip::CH32V003 | RiscvChip::CH641 => 64,
+            _ => 256,
+        }
+    }
+
+    pub fn code_flash_start(&self) -> u32 {
+        match self {
+            RiscvChip::CH56X
+            | RiscvChip::CH57X
+            | RiscvChip::CH582
+            | RiscvChip::CH585
+            | RiscvChip::CH59X
+            | RiscvChip::CH8571 => 0x0000_0000,
+            _ => 0x0800_0000,
+        }
+    }
+
+    /// System flash with the factory bootloader, start address and size in bytes,
+    /// None if not documented for the family
+    pub fn system_flash_region(&self) -> Option<(u32, u32)> {
+        match self {
+            RiscvChip::CH32V003 | RiscvChip::CH641 => Some((0x1FFF_F000, 1920)),
+            RiscvChip::CH32V103 => Some((0x1FFF_F000, 2048)),
+            RiscvChip::CH32V20X | RiscvChip::CH32V30X | RiscvChip::CH32V317 => {
+                Some((0x1FFF_8000, 28 * 1024))
+            }
+            _ => None,
+        }
+    }
+
+    /// How firmware section addresses are translated to flash addresses
+    pub fn address_map(&self) -> AddressMap {
+        AddressMap::CodeFlash {
+            flash_start: self.code_flash_start(),
+        }
+    }
+
+    /// pack size for fastprogram
+    pub fn write_pack_size(&self) -> u32 {
+        match self {
+            RiscvChip::CH32V003 | RiscvChip::CH641 | RiscvChip::CH32V007 => 1024,
+            _ => 4096,
+        }
+    }
+
+    /// PFIC_SCTLR and PWR_CTLR at the CH32 addresses, for sleep state detection
+    pub fn support_low_power_detection(&self) -> bool {
+        matches!(
+            self,
+            RiscvChip::CH32V003
+                | RiscvChip::CH32V007
+                | RiscvChip::CH641
+                | RiscvChip::CH32V103
+                | RiscvChip::CH32V20X
+                | RiscvChip::CH32V30X
+                | RiscvChip::CH32V317
+                | RiscvChip::CH32X035
+                | RiscvChip::CH32L103
+                | RiscvChip::CH643
+        )
+    }
+
+    /// Chips with the FPEC at 0x40022000, can be programmed by DMI directly
+    pub fn support_dmi_flash(&self) -> bool {
+        matches!(
+            self,
+            RiscvChip::CH32V003
+                | RiscvChip::CH32V007
+                | RiscvChip::CH641
+                | RiscvChip::CH32V103
+                | RiscvChip::CH32V20X
+                | RiscvChip::CH32V30X
+                | RiscvChip::CH32V317
+                | RiscvChip::CH32X035
+                | RiscvChip::CH32L103
+                | RiscvChip::CH643
+                | RiscvChip::CH645
+        )
+    }
+
+    /// Flash size covered by one bit of the WRP(write protect) option bytes
+    pub fn write_protect_sector_size(&self) -> u32 {
+        match self {
+            RiscvChip::CH32V003 | RiscvChip::CH641 | RiscvChip::CH32V007 => 1024,
+            _ => 4096,
+        }
+    }
+
+    /// Page size of fast page erase/program
+    pub fn fast_page_size(&self) -> u32 {
+        match self {
+            RiscvChip::CH32V003 | RiscvChip::CH641 | RiscvChip::CH32V007 => 64,
+            RiscvChip::CH32V103 => 128,
+            _ => 256,
+        }
+    }
+}
+
+/// Translation of firmware section addresses to flash addresses.
+///
+/// Linker scripts of CH32 chips may place code at 0x00000000, where code flash is
+/// aliased when booting from flash, so ELF and ihex addresses are not always flash
+/// addresses. The same as wch-openocd-riscv, addresses are remapped before flashing:
+///
+/// | Chip family                | Section address | Flash address |
+/// |----------------------------|-----------------|---------------|
+/// | CH32V/CH32X/CH32L/CH64x    | 0x0000_8000     | 0x0800_8000   |
+/// | CH32V/CH32X/CH32L/CH64x    | 0x0800_8000     | 0x0800_8000   |
+/// | CH56x/CH57x/CH58x/CH59x    | 0x0000_8000     | 0x0000_8000   |
+///
+/// Use `AddressMap::Identity`(`--keep-addresses`) to flash sections as-is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressMap {
+    /// Section addresses are flash addresses
+    Identity,
+    /// Section addresses are offsets from `flash_start`, or in the flash region itself
+    CodeFlash { flash_start: u32 },
+}
+
+impl AddressMap {
+    pub fn translate(&self, address: u32) -> u32 {
+        match *self {
+            AddressMap::Identity => address,
+            AddressMap::CodeFlash { flash_start } => {
+                let addr = flash_start.wrapping_add(address);
+                if addr >= 0x1000_0000 {
+                    addr - 0x0800_0000
+                } else {
+                    addr
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! WCH-Link probe variants
+use core::fmt;
+
+use crate::{Error, Result, RiscvChip};
+
+/// All WCH-Link probe variants, see-also: <http://www.wch-ic.com/products/WCH-Link.html>
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[repr(u8)]
+pub enum WchLinkVariant {
+    /// WCH-Link-CH549, does not support CH32V00X
+    Ch549 = 1,
+    /// WCH-LinkE-CH32V305
+    #[default]
+    ECh32v305 = 2,
+    /// WCH-LinkS-CH32V203
+    SCh32v203 = 3,
+    /// WCH-LinkW-CH32V208
+    WCh32v208 = 5,
+}
+
+impl WchLinkVariant {
+    pub fn try_from_u8(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(Self::Ch549),
+            2 | 0x12 => Ok(Self::ECh32v305),
+            3 => Ok(Self::SCh32v203),
+            5 | 0x85 => Ok(Self::WCh32v208),
+            _ => Err(Error::UnknownLinkVariant(value)),
+        }
+    }
+
+    /// CH549 variant does not support mode switch. re-program is needed.
+    pub fn support_switch_mode(&self) -> bool {
+        !matches!(self, WchLinkVariant::Ch549)
+    }
+
+    /// Only W, E mode support this, power functions
+    pub fn support_power_funcs(&self) -> bool {
+        matches!(self, WchLinkVariant::WCh32v208 | WchLinkVariant::ECh32v305)
+    }
+
+    /// Only E mode support SDR print functionality
+    pub fn support_sdi_print(&self) -> bool {
+        matches!(self, WchLinkVariant::ECh32v305)
+    }
+
+    /// Better use E variant, the Old CH549-based variant does not support all chips
+    pub fn support_chip(&self, chip: RiscvChip) -> bool {
+        match self {
+            WchLinkVariant::Ch549 => !matches!(
+                chip,
+                RiscvChip::CH32V003 | RiscvChip::CH32X035 | RiscvChip::CH643
+            ),
+            WchLinkVariant::WCh32v208 => !matches!(
+                chip,
+                RiscvChip::CH56X | RiscvChip::CH57X | RiscvChip::CH582 | RiscvChip::CH59X
+            ),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for WchLinkVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WchLinkVariant::Ch549 => write!(f, "WCH-Link-CH549"),
+            WchLinkVariant::ECh32v305 => write!(f, "WCH-LinkE-CH32V305"),
+            WchLinkVariant::SCh32v203 => write!(f, "WCH-LinkS-CH32V203"),
+            WchLinkVariant::WCh32v208 => write!(f, "WCH-LinkW-CH32V208"),
+        }
+    }
+}
+
+/// Probe firmware features, by the first firmware version that supports them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FirmwareFeature {
+    /// GetChipInfo V2, ESIG with chip ID
+    EsigV2,
+    /// SDI print, debug output over the SWDIO line
+    SdiPrint,
+}
+
+impl FirmwareFeature {
+    pub const ALL: &'static [FirmwareFeature] =
+        &[FirmwareFeature::EsigV2, FirmwareFeature::SdiPrint];
+
+    /// Minimum firmware version, as (major, minor)
+    pub fn min_version(&self) -> (u8, u8) {
+        match self {
+            FirmwareFeature::EsigV2 => (2, 9),
+            FirmwareFeature::SdiPrint => (2, 10),
+        }
+    }
+}
+
+impl fmt::Display for FirmwareFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FirmwareFeature::EsigV2 => write!(f, "ESIG v2"),
+            FirmwareFeature::SdiPrint => write!(f, "SDI print"),
+        }
+    }
+}
@@ -1,4 +1,6 @@
 //! Register definitions
+use alloc::{format, string::String};
+
 use bitfield::bitfield;
 
 // Register fields for command.regno (16-bit)
@@ -12,6 +14,7 @@ pub const MSCRATCH: u16 = 0x340;
 pub const MEPC: u16 = 0x341;
 pub const MCAUSE: u16 = 0x342;
 pub const MTVAL: u16 = 0x343;
+pub const DCSR: u16 = 0x7b0;
 pub const DPC: u16 = 0x7b1;
 
 // Debug interface, DMI registers
@@ -32,6 +35,10 @@ pub const DMPROGBUF4: u8 = 0x24;
 pub const DMPROGBUF5: u8 = 0x25;
 pub const DMPROGBUF6: u8 = 0x26;
 pub const DMPROGBUF7: u8 = 0x27;
+// System bus access, optional, check sbcs.sbversion
+pub const DMSBCS: u8 = 0x38;
+pub const DMSBADDRESS0: u8 = 0x39;
+pub const DMSBDATA0: u8 = 0x3c;
 pub const DMHALTSUM0: u8 = 0x40;
 
 // GPR: 0x1000 - 0x101f
@@ -111,6 +118,91 @@ pub const CSRS: &[(&str, u16)] = &[
     ("corecfgr", 0xbc0),
 ];
 
+/// Registers that may wedge the debug session if written while the core is running:
+/// mstatus, trigger CSRs(0x7a0-0x7af) and debug mode CSRs(0x7b0-0x7bf)
+pub fn is_debug_sensitive_reg(regno: u16) -> bool {
+    regno == MSTATUS || (0x7a0..=0x7bf).contains(&regno)
+}
+
+/// Name of a CSR or GPR, for messages
+pub fn reg_name(regno: u16) -> Option<&'static str> {
+    CSRS.iter()
+        .find(|(_, r)| *r == regno)
+        .map(|(name, _)| *name)
+        .or_else(|| {
+            GPRS_RVI
+                .iter()
+                .find(|(_, _, r)| *r == regno)
+                .map(|(_, name, _)| *name)
+        })
+}
+
+/// Known DMI registers, name and address
+pub const DMI_REGS: &[(&str, u8)] = &[
+    ("data0", DMDATA0),
+    ("data1", DMDATA1),
+    ("dmcontrol", DMCONTROL),
+    ("dmstatus", DMSTATUS),
+    ("hartinfo", DMHARTINFO),
+    ("abstractcs", DMABSTRACTCS),
+    ("command", DMCOMMAND),
+    ("abstractauto", DMABSTRACTAUTO),
+    ("progbuf0", DMPROGBUF0),
+    ("progbuf1", DMPROGBUF1),
+    ("progbuf2", DMPROGBUF2),
+    ("progbuf3", DMPROGBUF3),
+    ("progbuf4", DMPROGBUF4),
+    ("progbuf5", DMPROGBUF5),
+    ("progbuf6", DMPROGBUF6),
+    ("progbuf7", DMPROGBUF7),
+    ("sbcs", DMSBCS),
+    ("sbaddress0", DMSBADDRESS0),
+    ("sbdata0", DMSBDATA0),
+    ("haltsum0", DMHALTSUM0),
+];
+
+/// Name of a known DMI register
+pub fn dmi_reg_name(addr: u8) -> Option<&'static str> {
+    DMI_REGS
+        .iter()
+        .find(|(_, reg)| *reg == addr)
+        .map(|(name, _)| *name)
+}
+
+/// Decode DMI register value into fields, for registers with known layout
+pub fn decode_dmi_reg(addr: u8, value: u32) -> Option<String> {
+    let decoded = match addr {
+        Dmcontrol::ADDR => format!("{:#x?}", Dmcontrol::from(value)),
+        Dmstatus::ADDR => format!("{:#x?}", Dmstatus::from(value)),
+        Hartinfo::ADDR => format!("{:#x?}", Hartinfo::from(value)),
+        Abstractcs::ADDR => format!("{:#x?}", Abstractcs::from(value)),
+        Command::ADDR => format!("{:#x?}", Command::from(value)),
+        Sbcs::ADDR => format!("{:#x?}", Sbcs::from(value)),
+        _ => return None,
+    };
+    Some(decoded)
+}
+
+/// Describe the trap cause in mcause
+pub fn mcause_description(mcause: u32) -> &'static str {
+    if mcause & 0x8000_0000 != 0 {
+        return "Interrupt";
+    }
+    match mcause & 0x7fff_ffff {
+        0 => "Instruction address misaligned",
+        1 => "Instruction access fault",
+        2 => "Illegal instruction",
+        3 => "Breakpoint",
+        4 => "Load address misaligned",
+        5 => "Load access fault",
+        6 => "Store/AMO address misaligned",
+        7 => "Store/AMO access fault",
+        8 => "Environment call from U-mode",
+        11 => "Environment call from M-mode",
+        _ => "Reserved",
+    }
+}
+
 /// Physical Memory Protection CSRs, only available for QingkeV4
 pub const PMP_CSRS: &[(&str, u16)] = &[
     ("pmpcfg0", 0x3A0),
@@ -120,7 +212,71 @@ pub const PMP_CSRS: &[(&str, u16)] = &[
     ("pmpaddr3", 0x3B3),
 ];
 
+pub const PMPCFG0: u16 = 0x3A0;
+pub const PMPADDR0: u16 = 0x3B0;
+/// Number of PMP regions, configured by pmpcfg0 and pmpaddr0-3
+pub const PMP_REGIONS: u8 = 4;
+/// Locked bit of a PMP region config, cleared only by reset
+pub const PMP_CFG_L: u8 = 1 << 7;
+
+/// Decode a PMP region config, like `L NAPOT RWX`
+pub fn decode_pmp_cfg(cfg: u8) -> String {
+    let mode = match (cfg >> 3) & 0b11 {
+        0 => "OFF",
+        1 => "TOR",
+        2 => "NA4",
+        _ => "NAPOT",
+    };
+    let perm: String = [(0, 'R'), (1, 'W'), (2, 'X')]
+        .iter()
+        .map(|&(bit, c)| if cfg & (1 << bit) != 0 { c } else { '-' })
+        .collect();
+    let lock = if cfg & PMP_CFG_L != 0 { "L " } else { "" };
+    format!("{lock}{mode} {perm}")
+}
+
 // FPR: 0x1020-0x103f
+pub const FPRS: &[(&str, &str, u16)] = &[
+    ("f0", "ft0", 0x1020),
+    ("f1", "ft1", 0x1021),
+    ("f2", "ft2", 0x1022),
+    ("f3", "ft3", 0x1023),
+    ("f4", "ft4", 0x1024),
+    ("f5", "ft5", 0x1025),
+    ("f6", "ft6", 0x1026),
+    ("f7", "ft7", 0x1027),
+    ("f8", "fs0", 0x1028),
+    ("f9", "fs1", 0x1029),
+    ("f10", "fa0", 0x102a),
+    ("f11", "fa1", 0x102b),
+    ("f12", "fa2", 0x102c),
+    ("f13", "fa3", 0x102d),
+    ("f14", "fa4", 0x102e),
+    ("f15", "fa5", 0x102f),
+    ("f16", "fa6", 0x1030),
+    ("f17", "fa7", 0x1031),
+    ("f18", "fs2", 0x1032),
+    ("f19", "fs3", 0x1033),
+    ("f20", "fs4", 0x1034),
+    ("f21", "fs5", 0x1035),
+    ("f22", "fs6", 0x1036),
+    ("f23", "fs7", 0x1037),
+    ("f24", "fs8", 0x1038),
+    ("f25", "fs9", 0x1039),
+    ("f26", "fs10", 0x103a),
+    ("f27", "fs11", 0x103b),
+    ("f28", "ft8", 0x103c),
+    ("f29", "ft9", 0x103d),
+    ("f30", "ft10", 0x103e),
+    ("f31", "ft11", 0x103f),
+];
+
+/// Floating-point control and status register
+pub const FCSR: u16 = 0x003;
+/// misa bit of the F extension
+pub const MISA_F: u32 = 1 << 5;
+/// mstatus.FS, FPU state, 0 for off
+pub const MSTATUS_FS_MASK: u32 = 0b11 << 13;
 
 /// Debug Module Register
 pub trait DMReg: From<u32> + Into<u32> {
@@ -153,6 +309,7 @@ impl DMReg for Dmcontrol {
 
 bitfield! {
     /// Debug Module Status, 0x11
+    #[derive(Clone, Copy)]
     pub struct Dmstatus(u32);
     impl Debug;
     pub allhavereset, _: 19;
@@ -254,3 +411,33 @@ impl From<Command> for u32 {
 impl DMReg for Command {
     const ADDR: u8 = 0x17;
 }
+
+bitfield! {
+    /// System bus access control and status, 0x38
+    #[derive(Clone, Copy)]
+    pub struct Sbcs(u32);
+    impl Debug;
+    pub sbversion, _: 31, 29;
+    pub sbbusyerror, set_sbbusyerror: 22;
+    pub sbbusy, _: 21;
+    pub sbreadonaddr, set_sbreadonaddr: 20;
+    pub sbaccess, set_sbaccess: 19, 17;
+    pub sbautoincrement, set_sbautoincrement: 16;
+    pub sbreadondata, set_sbreadondata: 15;
+    pub sberror, set_sberror: 14, 12;
+    pub sbasize, _: 11, 5;
+    pub sbaccess32, _: 2;
+}
+impl From<u32> for Sbcs {
+    fn from(value: u32) -> Self {
+        Self(value)
+    }
+}
+impl From<Sbcs> for u32 {
+    fn from(val: Sbcs) -> Self {
+        val.0
+    }
+}
+impl DMReg for Sbcs {
+    const ADDR: u8 = 0x38;
+}
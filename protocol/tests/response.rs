@@ -0,0 +1,70 @@
+//! Response decoding must not panic on arbitrary bytes from the probe
+use proptest::prelude::*;
+use wlink_protocol::commands::{
+    control::{AttachChipResponse, ProbeInfo},
+    DmiOpResponse, ESignature, FastprogramStatus, PreRomRamSplitInfo, Response,
+};
+
+/// Decode with all response types
+fn decode_all(resp: &[u8]) {
+    let _ = <()>::from_raw(resp);
+    let _ = <Vec<u8>>::from_raw(resp);
+    let _ = u8::from_raw(resp);
+    let _ = bool::from_raw(resp);
+    let _ = DmiOpResponse::from_raw(resp);
+    let _ = ESignature::from_raw(resp);
+    let _ = ProbeInfo::from_raw(resp);
+    let _ = AttachChipResponse::from_raw(resp);
+    let _ = FastprogramStatus::from_raw(resp);
+    let _ = PreRomRamSplitInfo::from_raw(resp);
+}
+
+/// A frame with the response or error header, any command and a valid length byte
+fn frame() -> impl Strategy<Value = Vec<u8>> {
+    (
+        prop_oneof![Just(0x81u8), Just(0x82u8)],
+        any::<u8>(),
+        prop::collection::vec(any::<u8>(), 0..64),
+    )
+        .prop_map(|(header, command, payload)| {
+            let mut frame = vec![header, command, payload.len() as u8];
+            frame.extend(payload);
+            frame
+        })
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes(resp in prop::collection::vec(any::<u8>(), 0..80)) {
+        decode_all(&resp);
+    }
+
+    #[test]
+    fn truncated_frames(frame in frame(), cut in any::<prop::sample::Index>()) {
+        let len = cut.index(frame.len() + 1);
+        decode_all(&frame[..len]);
+    }
+
+    #[test]
+    fn wrong_length_byte(mut frame in frame(), len in any::<u8>()) {
+        frame[2] = len;
+        decode_all(&frame);
+    }
+
+    #[test]
+    fn length_mismatch_is_an_error(mut frame in frame(), extra in 1u8..16) {
+        frame[0] = 0x82;
+        frame[2] = frame[2].wrapping_add(extra);
+        prop_assert!(<Vec<u8>>::from_raw(&frame).is_err());
+    }
+}
+
+#[test]
+fn unknown_command_is_decoded() {
+    // the command byte is not checked, the payload is
+    assert_eq!(u8::from_raw(&[0x82, 0xee, 0x01, 0x42]).unwrap(), 0x42);
+    assert!(matches!(
+        <()>::from_raw(&[0x81, 0xee, 0x01, 0x00]),
+        Err(wlink_protocol::Error::Protocol(0xee, _))
+    ));
+}
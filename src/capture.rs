@@ -0,0 +1,383 @@
+//! USB capture and replay, for debugging the probe protocol.
+//!
+//! A capture file is in JSON Lines format. The first line is a header with the
+//! command line arguments, followed by one line per endpoint transaction:
+//!
+//! ```json
+//! {"wlink":"0.1.1","args":["wlink","status"]}
+//! {"t":0.001234,"dir":"out","ep":1,"data":"810d0101"}
+//! {"t":0.002345,"dir":"in","ep":129,"data":"820d0401020301"}
+//! ```
+//!
+//! Replay feeds recorded IN transactions back to the command layer, and checks
+//! OUT transactions against the recording.
+//!
+//! Transactions can also be written as pcapng, in the USBPcap link type of
+//! Windows captures, to compare with captures of the vendor tools in Wireshark.
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{usb_device::USBDeviceBackend, Error, Result};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub wlink: String,
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Out,
+    In,
+}
+
+/// An endpoint transaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    /// Seconds since capture start
+    pub t: f64,
+    pub dir: Direction,
+    pub ep: u8,
+    #[serde(with = "hex_data")]
+    pub data: Vec<u8>,
+}
+
+pub(crate) mod hex_data {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&hex::encode(data))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        hex::decode(s).map_err(serde::de::Error::custom)
+    }
+}
+
+enum Hook {
+    Capture(Arc<Mutex<CaptureWriter>>),
+    Replay(Arc<Mutex<VecDeque<Transaction>>>),
+}
+
+static HOOK: Mutex<Option<Hook>> = Mutex::new(None);
+
+/// pcapng link type of USBPcap
+const LINKTYPE_USBPCAP: u16 = 249;
+/// URB_FUNCTION_BULK_OR_INTERRUPT_TRANSFER
+const URB_FUNCTION_BULK: u16 = 0x0009;
+const USBPCAP_TRANSFER_BULK: u8 = 3;
+/// Length of the USBPcap packet header of bulk transfers
+const USBPCAP_HEADER_LEN: u16 = 27;
+
+enum CaptureFormat {
+    JsonLines,
+    /// Capture start, in microseconds since the Unix epoch
+    Pcapng {
+        epoch_us: u64,
+    },
+}
+
+struct CaptureWriter {
+    out: BufWriter<File>,
+    start: Instant,
+    format: CaptureFormat,
+}
+
+impl CaptureWriter {
+    fn record(&mut self, dir: Direction, ep: u8, data: &[u8]) {
+        let ret = match self.format {
+            CaptureFormat::JsonLines => {
+                let tx = Transaction {
+                    t: self.start.elapsed().as_secs_f64(),
+                    dir,
+                    ep,
+                    data: data.to_vec(),
+                };
+                serde_json::to_string(&tx)
+                    .map_err(std::io::Error::other)
+                    .and_then(|line| writeln!(self.out, "{line}"))
+            }
+            CaptureFormat::Pcapng { epoch_us } => {
+                let ts = epoch_us + self.start.elapsed().as_micros() as u64;
+                write_packet_block(&mut self.out, ts, dir, ep, data)
+            }
+        };
+        if let Err(e) = ret.and_then(|_| self.out.flush()) {
+            log::warn!("USB capture write failed: {}", e);
+        }
+    }
+}
+
+/// Write a pcapng block, the body is padded to 32 bits
+fn write_block(out: &mut impl Write, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+    let padding = (4 - body.len() % 4) % 4;
+    let total_len = (12 + body.len() + padding) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&total_len.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&[0; 3][..padding])?;
+    out.write_all(&total_len.to_le_bytes())
+}
+
+/// Section header and interface description blocks
+fn write_pcapng_header(out: &mut impl Write) -> std::io::Result<()> {
+    let mut shb = vec![];
+    shb.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes()); // byte-order magic
+    shb.extend_from_slice(&1u16.to_le_bytes()); // major version
+    shb.extend_from_slice(&0u16.to_le_bytes()); // minor version
+    shb.extend_from_slice(&(-1i64).to_le_bytes()); // section length, unspecified
+    write_block(out, 0x0A0D_0D0A, &shb)?;
+
+    let mut idb = vec![];
+    idb.extend_from_slice(&LINKTYPE_USBPCAP.to_le_bytes());
+    idb.extend_from_slice(&0u16.to_le_bytes()); // reserved
+    idb.extend_from_slice(&0u32.to_le_bytes()); // snap length, no limit
+    write_block(out, 0x0000_0001, &idb)
+}
+
+/// Enhanced packet block of a bulk transfer, timestamp in microseconds
+fn write_packet_block(
+    out: &mut impl Write,
+    ts: u64,
+    dir: Direction,
+    ep: u8,
+    data: &[u8],
+) -> std::io::Result<()> {
+    let mut packet = vec![];
+    packet.extend_from_slice(&USBPCAP_HEADER_LEN.to_le_bytes());
+    packet.extend_from_slice(&0u64.to_le_bytes()); // IRP ID
+    packet.extend_from_slice(&0u32.to_le_bytes()); // USBD status, success
+    packet.extend_from_slice(&URB_FUNCTION_BULK.to_le_bytes());
+    // IN data is seen on URB completion, from the device to the host
+    packet.push(if dir == Direction::In { 1 } else { 0 });
+    packet.extend_from_slice(&0u16.to_le_bytes()); // bus
+    packet.extend_from_slice(&1u16.to_le_bytes()); // device address
+    packet.push(ep);
+    packet.push(USBPCAP_TRANSFER_BULK);
+    packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    packet.extend_from_slice(data);
+
+    let mut epb = vec![];
+    epb.extend_from_slice(&0u32.to_le_bytes()); // interface ID
+    epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+    epb.extend_from_slice(&(ts as u32).to_le_bytes());
+    epb.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // captured length
+    epb.extend_from_slice(&(packet.len() as u32).to_le_bytes()); // original length
+    epb.extend_from_slice(&packet);
+    write_block(out, 0x0000_0006, &epb)
+}
+
+/// Record all USB transactions of devices opened after this call
+pub fn capture_to<P: AsRef<Path>>(path: P, args: Vec<String>) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let header = Header {
+        wlink: env!("CARGO_PKG_VERSION").to_string(),
+        args,
+    };
+    let line = serde_json::to_string(&header).map_err(|e| Error::Custom(e.to_string()))?;
+    writeln!(out, "{line}")?;
+
+    let writer = CaptureWriter {
+        out,
+        start: Instant::now(),
+        format: CaptureFormat::JsonLines,
+    };
+    *HOOK.lock().unwrap() = Some(Hook::Capture(Arc::new(Mutex::new(writer))));
+    Ok(())
+}
+
+/// Record all USB transactions of devices opened after this call, in pcapng for Wireshark
+pub fn capture_to_pcapng<P: AsRef<Path>>(path: P) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    write_pcapng_header(&mut out)?;
+    out.flush()?;
+
+    let epoch_us = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let writer = CaptureWriter {
+        out,
+        start: Instant::now(),
+        format: CaptureFormat::Pcapng { epoch_us },
+    };
+    *HOOK.lock().unwrap() = Some(Hook::Capture(Arc::new(Mutex::new(writer))));
+    Ok(())
+}
+
+/// Load a capture file, devices opened after this call replay it instead of real USB.
+///
+/// Returns the header, with the captured command line.
+pub fn replay_from<P: AsRef<Path>>(path: P) -> Result<Header> {
+    let (header, transactions) = load(path)?;
+    log::info!(
+        "Replay {} USB transactions, captured by wlink {}",
+        transactions.len(),
+        header.wlink
+    );
+
+    *HOOK.lock().unwrap() = Some(Hook::Replay(Arc::new(Mutex::new(transactions))));
+    Ok(header)
+}
+
+/// Read a capture file in JSON Lines format, the header and the transactions
+pub fn load<P: AsRef<Path>>(path: P) -> Result<(Header, VecDeque<Transaction>)> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let parse_err = |e: serde_json::Error| Error::Custom(format!("invalid capture file: {e}"));
+
+    let header = lines
+        .next()
+        .ok_or_else(|| Error::Custom("empty capture file".to_string()))??;
+    let header: Header = serde_json::from_str(&header).map_err(parse_err)?;
+    let mut transactions = VecDeque::new();
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        transactions.push_back(serde_json::from_str(&line).map_err(parse_err)?);
+    }
+    Ok((header, transactions))
+}
+
+/// Wrap the opened device for capture, or replace it for replay
+pub(crate) fn hook_open(
+    open: impl FnOnce() -> Result<Box<dyn USBDeviceBackend>>,
+) -> Result<Box<dyn USBDeviceBackend>> {
+    match &*HOOK.lock().unwrap() {
+        None => open(),
+        Some(Hook::Capture(writer)) => Ok(Box::new(CaptureDevice {
+            inner: open()?,
+            writer: writer.clone(),
+        })),
+        Some(Hook::Replay(transactions)) => Ok(Box::new(ReplayDevice {
+            transactions: transactions.clone(),
+            strict: false,
+        })),
+    }
+}
+
+struct CaptureDevice {
+    inner: Box<dyn USBDeviceBackend>,
+    writer: Arc<Mutex<CaptureWriter>>,
+}
+
+impl std::fmt::Debug for CaptureDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptureDevice")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl USBDeviceBackend for CaptureDevice {
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.set_timeout(timeout);
+    }
+
+    fn read_endpoint(&mut self, ep: u8, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read_endpoint(ep, buf)?;
+        self.writer
+            .lock()
+            .unwrap()
+            .record(Direction::In, ep, &buf[..n]);
+        Ok(n)
+    }
+
+    fn open_nth(_vid: u16, _pid: u16, _nth: usize) -> Result<Box<dyn USBDeviceBackend>> {
+        unreachable!("created by hook_open")
+    }
+
+    fn write_endpoint(&mut self, ep: u8, buf: &[u8]) -> Result<()> {
+        self.writer.lock().unwrap().record(Direction::Out, ep, buf);
+        self.inner.write_endpoint(ep, buf)
+    }
+}
+
+/// A device replaying captured transactions, for a `WchLink` without hardware
+#[derive(Debug)]
+pub struct ReplayDevice {
+    transactions: Arc<Mutex<VecDeque<Transaction>>>,
+    /// Fail on OUT data differing from the capture, instead of a warning
+    strict: bool,
+}
+
+impl ReplayDevice {
+    pub fn new(transactions: impl IntoIterator<Item = Transaction>) -> Self {
+        Self {
+            transactions: Arc::new(Mutex::new(transactions.into_iter().collect())),
+            strict: false,
+        }
+    }
+
+    /// Fail on commands differing from the capture
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Transactions not replayed yet, shared with the device after it is boxed
+    pub fn remaining(&self) -> Arc<Mutex<VecDeque<Transaction>>> {
+        self.transactions.clone()
+    }
+
+    fn next(&mut self, dir: Direction, ep: u8) -> Result<Transaction> {
+        let mut transactions = self.transactions.lock().unwrap();
+        match transactions.pop_front() {
+            Some(tx) if tx.dir == dir && tx.ep == ep => Ok(tx),
+            Some(tx) => Err(Error::Custom(format!(
+                "replay diverged at t={:.6}: expected {:?} ep 0x{:02x}, got {:?} ep 0x{:02x}",
+                tx.t, tx.dir, tx.ep, dir, ep
+            ))),
+            None => Err(Error::Custom(
+                "replay reached the end of the capture".to_string(),
+            )),
+        }
+    }
+}
+
+impl USBDeviceBackend for ReplayDevice {
+    fn read_endpoint(&mut self, ep: u8, buf: &mut [u8]) -> Result<usize> {
+        let tx = self.next(Direction::In, ep)?;
+        let n = tx.data.len().min(buf.len());
+        buf[..n].copy_from_slice(&tx.data[..n]);
+        Ok(n)
+    }
+
+    fn open_nth(_vid: u16, _pid: u16, _nth: usize) -> Result<Box<dyn USBDeviceBackend>> {
+        unreachable!("created by hook_open")
+    }
+
+    fn write_endpoint(&mut self, ep: u8, buf: &[u8]) -> Result<()> {
+        let tx = self.next(Direction::Out, ep)?;
+        if tx.data != buf && self.strict {
+            return Err(Error::Custom(format!(
+                "replay diverged at t={:.6} ep 0x{:02x}: captured {}, sent {}",
+                tx.t,
+                ep,
+                hex::encode(&tx.data),
+                hex::encode(buf)
+            )));
+        }
+        if tx.data != buf {
+            log::warn!(
+                "replay mismatch at t={:.6} ep 0x{:02x}: captured {}, sent {}",
+                tx.t,
+                ep,
+                hex::encode(&tx.data),
+                hex::encode(buf)
+            );
+        }
+        Ok(())
+    }
+}
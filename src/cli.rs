@@ -0,0 +1,1853 @@
+//! Command handlers of the `wlink` command line tool
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::Duration,
+};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    commands::Speed,
+    dmi::{DebugModuleInterface, PowerState},
+    dwarf,
+    firmware::{self, read_firmware_from_files, DumpFormat, Firmware, ImageLayout, MetaField},
+    gang::GangProgrammer,
+    operations::{rom_ram_split_label, trim_blank_tail, ChipStatus, FlashBackend, ProbeSession},
+    probe::{FirmwareFeature, OutputWatch, UsbId, WchLink},
+    regs, svd,
+    target::Target,
+    AddressMap, RiscvChip,
+};
+
+pub use crate::firmware::parse_number;
+
+/// SRAM of all supported chips is mapped here, below the peripherals
+const SRAM_REGION: std::ops::Range<u32> = 0x2000_0000..0x4000_0000;
+
+/// Bytes per DMI write chunk of `fill` and `ramtest`, for progress
+const DMI_WRITE_CHUNK_SIZE: usize = 0x400;
+
+/// Wait after a firmware file change before flashing, for the writer to finish
+const FILE_CHANGE_SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// Rust panic messages of the firmware contain this
+const PANIC_MARKER: &str = "panicked at";
+
+/// Output format of listing commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable
+    #[default]
+    Text,
+    /// JSON, for scripts and GUIs
+    Json,
+}
+
+/// Options of the `flash` subcommand
+#[derive(Debug, Clone, Default)]
+pub struct FlashOptions {
+    pub address: Option<u32>,
+    /// Erase flash before flashing
+    pub erase: bool,
+    /// Reset and run after flashing
+    pub run: bool,
+    /// Enable SDI print after reset
+    pub enable_sdi_print: bool,
+    /// Open serial port(print only) after reset
+    pub watch_serial: bool,
+    /// Continue without serial port if it cannot be opened
+    pub serial_optional: bool,
+    /// Stop conditions of serial watching
+    pub output_watch: OutputWatch,
+    /// Decode serial output as defmt frames, with the table of this ELF file
+    pub defmt: Option<PathBuf>,
+    /// Run from this entry point after reset, instead of the reset vector
+    pub entry: Option<u32>,
+    /// Save the current flash before flashing, for `wlink rollback`
+    pub backup: bool,
+    /// Flash ELF and ihex sections at their addresses, without remapping
+    pub keep_addresses: bool,
+    /// Flash even if the image does not fit the code flash
+    pub force: bool,
+    pub backend: FlashBackend,
+    /// Offset and padding of the image
+    pub layout: ImageLayout,
+    /// Write metadata fields into the image at an address, before the CRC
+    pub insert_meta: Option<(u32, Vec<MetaField>)>,
+    /// Write the CRC-32 of the image at this address
+    pub insert_crc: Option<u32>,
+}
+
+/// Print memory in hexdump format
+pub fn print_hexdump(data: &[u8], address: u32) {
+    println!(
+        "{}",
+        nu_pretty_hex::config_hex(
+            &data,
+            nu_pretty_hex::HexConfig {
+                title: true,
+                ascii: true,
+                address_offset: address as _,
+                ..Default::default()
+            },
+        )
+    );
+}
+
+/// Dump memory region to stdout, or to a file
+pub fn dump(
+    sess: &mut dyn Target,
+    address: u32,
+    length: Option<u32>,
+    filename: Option<&str>,
+    format: Option<DumpFormat>,
+    record_len: usize,
+    resume_from: Option<u32>,
+) -> Result<()> {
+    let length = match length {
+        Some(length) => length,
+        None => {
+            // to the end of code flash
+            let flash_start = sess.chip_family().code_flash_start();
+            let flash_end = range_end(flash_start, sess.flash_size_kb()? as u32 * 1024)?;
+            if !(flash_start..flash_end).contains(&address) {
+                anyhow::bail!("length is required outside of code flash");
+            }
+            flash_end - address
+        }
+    };
+    let end = range_end(address, length)?;
+    log::info!("Read memory from 0x{:08x} to 0x{:08x}", address, end);
+
+    let format = format.unwrap_or_else(|| filename.map(DumpFormat::from_path).unwrap_or_default());
+    match (filename, format) {
+        (Some(fname), DumpFormat::Bin) => {
+            return dump_to_file(sess, address, length, fname, resume_from);
+        }
+        _ if resume_from.is_some() => anyhow::bail!("--resume-from requires a binary output file"),
+        _ => (),
+    }
+
+    let out = sess.read_memory(address, length)?;
+    let encoded = match format {
+        DumpFormat::Bin => None,
+        format => Some(encode_image(&out, address, format, record_len)?),
+    };
+
+    match (filename, encoded) {
+        (Some(fname), Some(encoded)) => {
+            std::fs::write(fname, encoded)?;
+            log::info!("{} bytes written to file {} as {:?}", length, fname, format);
+        }
+        (Some(_), None) => unreachable!("binary files are streamed"),
+        (None, Some(encoded)) => {
+            use std::io::Write as _;
+            std::io::stdout().write_all(&encoded)?;
+        }
+        (None, None) => print_hexdump(&out, address),
+    }
+    Ok(())
+}
+
+/// Encode an image at `address` in an output format
+fn encode_image(
+    data: &[u8],
+    address: u32,
+    format: DumpFormat,
+    record_len: usize,
+) -> Result<Vec<u8>> {
+    Ok(match format {
+        DumpFormat::Bin => data.to_vec(),
+        DumpFormat::Ihex => firmware::write_ihex(data, address)?.into_bytes(),
+        DumpFormat::Srec => firmware::write_srec(data, address, record_len)?.into_bytes(),
+        DumpFormat::Elf => firmware::write_elf(data, address),
+    })
+}
+
+/// Convert a firmware file to another format, like `objcopy`.
+///
+/// `base_address` places a binary input, or moves an image with addresses. Gaps
+/// between sections are filled with 0xff.
+pub fn convert(
+    input: &str,
+    output: &str,
+    format: Option<DumpFormat>,
+    base_address: Option<u32>,
+    record_len: usize,
+) -> Result<()> {
+    let format = format.unwrap_or_else(|| DumpFormat::from_path(output));
+    let (address, data) = match read_firmware_from_files(&[input.to_string()])?.merge_sections()? {
+        Firmware::Binary(data) => match (base_address, format) {
+            (Some(address), _) => (address, data),
+            (None, DumpFormat::Bin) => (0, data),
+            (None, _) => anyhow::bail!(
+                "{:?} output needs the address of the binary input, use --base-address or {}@0x08000000",
+                format,
+                input
+            ),
+        },
+        Firmware::Sections(mut sections) => {
+            let sect = sections.pop().expect("merged into one section");
+            match base_address {
+                Some(address) if address != sect.address => {
+                    log::info!("Move image from 0x{:08x} to 0x{:08x}", sect.address, address);
+                    (address, sect.data)
+                }
+                _ => (sect.address, sect.data),
+            }
+        }
+    };
+    if format == DumpFormat::Bin {
+        log::info!("Binary output starts at 0x{:08x}", address);
+    }
+    std::fs::write(output, encode_image(&data, address, format, record_len)?)?;
+    log::info!(
+        "{} bytes at 0x{:08x} written to {} as {:?}",
+        data.len(),
+        address,
+        output,
+        format
+    );
+    Ok(())
+}
+
+/// Patch metadata, then the CRC into the image, by `--insert-meta` and `--insert-crc`
+fn stamp_image(sess: &mut dyn Target, firmware: &mut Firmware, opts: &FlashOptions) -> Result<()> {
+    let base = opts
+        .address
+        .unwrap_or_else(|| sess.chip_family().code_flash_start());
+    if let Some((address, fields)) = &opts.insert_meta {
+        let mut meta = vec![];
+        for field in fields {
+            match field {
+                MetaField::Uid => {
+                    let uid = sess.esig()?.uid;
+                    meta.extend_from_slice(&uid[0].to_le_bytes());
+                    meta.extend_from_slice(&uid[1].to_le_bytes());
+                }
+                MetaField::Timestamp => {
+                    meta.extend_from_slice(&(chrono::Utc::now().timestamp() as u32).to_le_bytes())
+                }
+                MetaField::Version(version) => meta.extend_from_slice(&version.to_le_bytes()),
+            }
+        }
+        firmware.patch(base, *address, &meta)?;
+        log::info!("Metadata {:?} written at 0x{:08x}", fields, address);
+    }
+    if let Some(address) = opts.insert_crc {
+        let crc = firmware.insert_crc32(base, address)?;
+        log::info!("CRC-32 0x{:08x} written at 0x{:08x}", crc, address);
+    }
+    Ok(())
+}
+
+/// Stream a memory region to a binary file chunk by chunk, optionally continuing
+/// a partial file from `resume_from` bytes into the region
+fn dump_to_file(
+    sess: &mut dyn Target,
+    address: u32,
+    length: u32,
+    fname: &str,
+    resume_from: Option<u32>,
+) -> Result<()> {
+    use std::io::{Seek, SeekFrom, Write as _};
+
+    let start = resume_from.unwrap_or(0);
+    if start > length {
+        anyhow::bail!(
+            "--resume-from 0x{:x} must be within the {} bytes region",
+            start,
+            length
+        );
+    }
+    let mut file = match resume_from {
+        Some(_) => {
+            let mut file = std::fs::OpenOptions::new().write(true).open(fname)?;
+            let file_len = file.metadata()?.len();
+            if start as u64 > file_len {
+                anyhow::bail!(
+                    "{} has only {} bytes, can't resume from 0x{:x}",
+                    fname,
+                    file_len,
+                    start
+                );
+            }
+            file.set_len(start as u64)?;
+            file.seek(SeekFrom::End(0))?;
+            log::info!("Resume from 0x{:08x}", address + start);
+            file
+        }
+        None => std::fs::File::create(fname)?,
+    };
+
+    let bar = indicatif::ProgressBar::new(length as _);
+    bar.set_position(start as _);
+    let mut written = start;
+    let ret = sess.read_memory_chunked(address + start, length - start, &mut |_, chunk| {
+        file.write_all(chunk)?;
+        written += chunk.len() as u32;
+        bar.set_position(written as _);
+        Ok(())
+    });
+    bar.finish_and_clear();
+    if let Err(e) = ret {
+        log::error!(
+            "Dump stopped, {} of {} bytes written (hint: continue with --resume-from 0x{:x})",
+            written,
+            length,
+            written
+        );
+        return Err(e.into());
+    }
+    log::info!("{} bytes written to file {}", length, fname);
+    Ok(())
+}
+
+/// Blank check, default to the whole code flash
+pub fn blank_check(
+    sess: &mut dyn Target,
+    address: Option<u32>,
+    length: Option<u32>,
+) -> Result<Option<u32>> {
+    let address = address.unwrap_or_else(|| sess.chip_family().code_flash_start());
+    let length = match length {
+        Some(length) => length,
+        None => sess.flash_size_kb()? as u32 * 1024,
+    };
+    let end = range_end(address, length)?;
+    log::info!("Blank check 0x{:08x} to 0x{:08x}", address, end);
+    Ok(sess.blank_check(address, length)?)
+}
+
+/// Log the chip status, warns if debug access is blocked by read protection
+pub fn log_chip_status(status: &ChipStatus) {
+    for line in status.to_string().lines() {
+        log::info!("{}", line);
+    }
+    if let (Some(esig), Some(memory)) = (&status.esig, &status.memory) {
+        if memory.flash_kb != esig.flash_size_kb as u32 {
+            log::debug!("ESIG flash size differs, SRAM/flash split configured?");
+        }
+    }
+    if status.read_protected == Some(true) {
+        log::warn!("Flash is protected, debug access is not available");
+    }
+}
+
+/// Chip status, core info and debug module state
+pub fn status(sess: &mut ProbeSession, output: OutputFormat) -> Result<()> {
+    let chip = sess.chip_status()?;
+    let core = sess.core_info()?;
+    let dm = sess.dm_state()?;
+    // option bytes can't be read through read protection
+    let option_bytes = if sess.chip_family.support_dmi_flash() && chip.read_protected != Some(true)
+    {
+        Some(sess.option_bytes()?)
+    } else {
+        None
+    };
+    match output {
+        OutputFormat::Text => {
+            log_chip_status(&chip);
+            let mut text = format!("{}\n{}", core, dm);
+            if let Some(obs) = &option_bytes {
+                write!(text, "\n{}", obs)?;
+            }
+            for line in text.lines() {
+                log::info!("{}", line);
+            }
+            log::warn!("The halt status may be incorrect because detaching might resume the MCU");
+        }
+        OutputFormat::Json => {
+            let status = serde_json::json!({
+                "probe": sess.probe.info,
+                "chip": chip,
+                "core": core,
+                "dm": dm,
+                "option_bytes": option_bytes,
+            });
+            println!("{}", serde_json::to_string_pretty(&status)?);
+        }
+    }
+    Ok(())
+}
+
+/// Flash firmware files, then reset and run
+pub fn flash(sess: &mut dyn Target, paths: &[String], opts: &FlashOptions) -> Result<()> {
+    log_chip_status(&sess.chip_status()?);
+
+    let defmt_table = match &opts.defmt {
+        Some(elf) => Some(crate::defmt::Table::from_elf(&std::fs::read(elf)?)?),
+        None => None,
+    };
+
+    let mut firmware = opts.layout.apply(read_firmware_from_files(paths)?)?;
+    stamp_image(sess, &mut firmware, opts)?;
+    check_ram_usage(sess, paths)?;
+    if opts.keep_addresses {
+        sess.set_address_map(AddressMap::Identity);
+    }
+    let problems = sess.check_firmware(&firmware, opts.address)?;
+    if !problems.is_empty() {
+        for problem in &problems {
+            log::warn!("Image {}", problem);
+        }
+        if !opts.force {
+            anyhow::bail!("Image does not fit the chip, wrong board or linker script? (hint: use --force to flash anyway)");
+        }
+    }
+
+    if opts.backup {
+        backup_flash(sess)?;
+    }
+
+    if opts.erase {
+        let flash_start = sess.chip_family().code_flash_start();
+        let flash_size = sess.flash_size_kb()? as u32 * 1024;
+        if sess.is_region_blank(flash_start, flash_size)? {
+            log::info!("Flash is blank, skip erase");
+        } else {
+            log::info!("Erase Flash");
+            sess.erase_flash()?;
+        }
+    }
+
+    sess.write_firmware(&firmware, opts.address, opts.backend)?;
+
+    log::info!("Flash done");
+
+    sleep(Duration::from_millis(500));
+
+    if opts.run {
+        log::info!("Now reset...");
+        match opts.entry {
+            Some(entry) => sess.reset_and_run_at(entry)?,
+            None => sess.soft_reset()?,
+        }
+        if opts.enable_sdi_print {
+            sess.set_sdi_print_enabled(true)?;
+
+            log::info!("Now connect to the WCH-Link serial port to read SDI print");
+        }
+        if opts.watch_serial {
+            let ret = sess.open_serial_port().and_then(|port| match &defmt_table {
+                Some(table) => crate::probe::watch_serial_defmt(port, table, &opts.output_watch),
+                None => crate::probe::watch_serial(port, &opts.output_watch),
+            });
+            match ret {
+                Err(e @ (crate::Error::OutputMatched(_) | crate::Error::OutputTimeout(_))) => {
+                    return Err(e.into())
+                }
+                Err(e) if opts.serial_optional => {
+                    log::warn!("{}", e);
+                    log::warn!("Continue without serial port");
+                }
+                ret => ret?,
+            }
+        } else {
+            sleep(Duration::from_millis(500));
+        }
+    }
+    Ok(())
+}
+
+/// Reflash and reset whenever a firmware file changes, never returns unless error
+pub fn flash_on_change(
+    sess: &mut ProbeSession,
+    paths: &[String],
+    opts: &FlashOptions,
+) -> Result<()> {
+    use ::notify::{RecursiveMode, Watcher};
+
+    // the previous image is already saved
+    let opts = FlashOptions {
+        backup: false,
+        ..opts.clone()
+    };
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = ::notify::recommended_watcher(tx)?;
+    let mut targets = vec![];
+    for spec in paths {
+        let path = std::fs::canonicalize(firmware::split_address(spec)?.0)?;
+        // watch the directory, linkers and editors often replace the file
+        let dir = path.parent().unwrap_or(&path);
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        targets.push(path);
+    }
+
+    log::info!("Watching firmware for changes, press Ctrl-C to stop");
+    loop {
+        let event = rx.recv()??;
+        if !(event.kind.is_modify() || event.kind.is_create())
+            || !event.paths.iter().any(|p| targets.contains(p))
+        {
+            continue;
+        }
+        // let the writer finish, then drop the rest of the burst
+        sleep(FILE_CHANGE_SETTLE_TIME);
+        while rx.try_recv().is_ok() {}
+
+        log::info!("Firmware changed, flashing");
+        match flash(sess, paths, &opts) {
+            Ok(()) => log::info!("Watching firmware for changes"),
+            Err(e) => log::error!("Flash failed: {:#}, waiting for the next change", e),
+        }
+    }
+}
+
+/// Flash and run an ELF file, streaming its output to stdout, as a Cargo runner.
+///
+/// Stops at Ctrl-C or when `opts.output_watch` stops it, fails if the firmware panics.
+pub fn run_elf(sess: &mut ProbeSession, elf: &str, opts: &FlashOptions) -> Result<()> {
+    use std::io::{Read, Write as _};
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    let sdi_print = sess.chip_family.support_sdi_print()
+        && sess.probe.info.variant.support_sdi_print()
+        && sess.probe.info.supports(FirmwareFeature::SdiPrint);
+    if !sdi_print {
+        log::info!("SDI print is not available, streaming UART output of the serial port");
+    }
+    let mut opts = FlashOptions {
+        run: true,
+        enable_sdi_print: sdi_print,
+        watch_serial: false,
+        ..opts.clone()
+    };
+    opts.output_watch.fail_on.push(PANIC_MARKER.to_string());
+
+    // open before reset, not to miss the first lines
+    let mut port = sess.probe.open_serial_port()?;
+    flash(sess, &[elf.to_string()], &opts)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = stop.clone();
+        ctrlc::set_handler(move || stop.store(true, Ordering::SeqCst))?;
+    }
+    log::info!("Streaming firmware output, press Ctrl-C to stop");
+
+    let watch = &opts.output_watch;
+    let deadline = watch.timeout.map(|t| std::time::Instant::now() + t);
+    let mut stdout = std::io::stdout();
+    let mut line = vec![];
+    let mut buf = [0u8; 1024];
+    while !stop.load(Ordering::SeqCst) {
+        if deadline.is_some_and(|t| std::time::Instant::now() >= t) {
+            log::info!("Run time is up");
+            return Ok(watch.timed_out()?);
+        }
+        let n = match port.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        stdout.write_all(&buf[..n])?;
+        stdout.flush()?;
+
+        for &b in &buf[..n] {
+            if b != b'\n' {
+                line.push(b);
+                continue;
+            }
+            if let Some(ret) = watch.check_line(&String::from_utf8_lossy(&line)) {
+                return Ok(ret?);
+            }
+            line.clear();
+        }
+    }
+    Ok(())
+}
+
+/// Flash an `embedded-test` binary, then run its tests one by one, fails if any test fails
+pub fn test(
+    sess: &mut ProbeSession,
+    elf: &str,
+    filters: &[String],
+    opts: &FlashOptions,
+    timeout: Duration,
+) -> Result<()> {
+    use crate::embedded_test::{self, Outcome};
+
+    if !embedded_test::is_test_binary(&std::fs::read(elf)?)? {
+        anyhow::bail!("{} is not a test binary of the embedded-test crate", elf);
+    }
+    let opts = FlashOptions {
+        run: false,
+        ..opts.clone()
+    };
+    flash(sess, &[elf.to_string()], &opts)?;
+
+    let tests = embedded_test::list_tests(sess)?;
+    let selected: Vec<_> = tests
+        .iter()
+        .filter(|t| filters.is_empty() || filters.iter().any(|f| t.name.contains(f.as_str())))
+        .collect();
+    println!();
+    println!("running {} tests", selected.len());
+
+    let mut failed = vec![];
+    let mut ignored = 0;
+    for test in &selected {
+        let outcome = embedded_test::run_test(sess, test, timeout)?;
+        println!("test {} ... {}", test.name, outcome);
+        match outcome {
+            Outcome::Passed => (),
+            Outcome::Failed => failed.push(&test.name),
+            Outcome::Ignored => ignored += 1,
+        }
+    }
+
+    println!();
+    if !failed.is_empty() {
+        println!("failures:");
+        for name in &failed {
+            println!("    {}", name);
+        }
+        println!();
+    }
+    println!(
+        "test result: {}. {} passed; {} failed; {} ignored; {} filtered out",
+        if failed.is_empty() { "ok" } else { "FAILED" },
+        selected.len() - failed.len() - ignored,
+        failed.len(),
+        ignored,
+        tests.len() - selected.len()
+    );
+    if !failed.is_empty() {
+        anyhow::bail!("{} of {} tests failed", failed.len(), selected.len());
+    }
+    Ok(())
+}
+
+/// Warn if an ELF file uses more RAM than the attached chip has
+fn check_ram_usage(sess: &mut dyn Target, paths: &[String]) -> Result<()> {
+    let Some(sram_kb) = sess.sram_size_kb()? else {
+        return Ok(());
+    };
+    let sram_end = SRAM_REGION.start + sram_kb * 1024;
+    for path in paths {
+        let Ok(raw) = std::fs::read(path) else {
+            continue; // binary with an address suffix
+        };
+        if !raw.starts_with(b"\x7fELF") {
+            continue;
+        }
+        if let Some((ram_end, name)) = firmware::read_elf_ram_end(&raw, SRAM_REGION)? {
+            log::debug!("{} uses RAM up to 0x{:08x} ({})", path, ram_end, name);
+            if ram_end > sram_end {
+                log::warn!(
+                    "{} is linked for RAM up to 0x{:08x} ({}), but the chip has {}KB SRAM, ending at 0x{:08x}",
+                    path,
+                    ram_end,
+                    name,
+                    sram_kb,
+                    sram_end
+                );
+                log::warn!("The firmware may crash on start, check the linker script");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Backup directory of `flash --backup`, `$WLINK_BACKUP_DIR` or `~/.wlink/backup`
+pub fn backup_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("WLINK_BACKUP_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .ok_or_else(|| anyhow::format_err!("home directory not found, set WLINK_BACKUP_DIR"))?;
+    Ok(PathBuf::from(home).join(".wlink").join("backup"))
+}
+
+/// Show or set the ROM/RAM split, the new split takes effect after a power cycle
+pub fn rom_ram_split(sess: &mut ProbeSession, mode: Option<u8>) -> Result<()> {
+    let chip = sess.chip_family;
+    if !chip.support_ram_rom_mode() {
+        anyhow::bail!("ROM/RAM split is not supported by {:?}", chip);
+    }
+    let current = sess.rom_ram_split()?;
+    println!("ROM/RAM split: {}", rom_ram_split_label(chip, current));
+    let Some(mode) = mode else {
+        return Ok(());
+    };
+    if mode == current {
+        log::info!("ROM/RAM split is already mode {}, nothing to do", mode);
+        return Ok(());
+    }
+    if let (Some((old_flash, _)), Some((new_flash, _))) = (
+        chip.rom_ram_split_sizes(current),
+        chip.rom_ram_split_sizes(mode),
+    ) {
+        if new_flash < old_flash {
+            log::warn!(
+                "Code flash shrinks from {}KB to {}KB, code above is no longer executable",
+                old_flash,
+                new_flash
+            );
+        }
+    }
+    sess.set_rom_ram_split(mode)?;
+    println!("ROM/RAM split set: {}", rom_ram_split_label(chip, mode));
+    log::info!("Power cycle the chip to take effect");
+    Ok(())
+}
+
+/// Session state of `wlink attach`, kept until `wlink detach`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttachState {
+    pub chip: String,
+    pub chip_id: u32,
+    pub speed: String,
+}
+
+impl AttachState {
+    pub fn new(sess: &ProbeSession) -> Self {
+        Self {
+            chip: value_name(&sess.chip_family),
+            chip_id: sess.chip_id,
+            speed: value_name(&sess.speed),
+        }
+    }
+
+    pub fn chip(&self) -> Result<RiscvChip> {
+        RiscvChip::from_str(&self.chip, true).map_err(|e| anyhow::format_err!(e))
+    }
+
+    pub fn speed(&self) -> Result<Speed> {
+        Speed::from_str(&self.speed, true).map_err(|e| anyhow::format_err!(e))
+    }
+}
+
+fn value_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default()
+}
+
+/// Known alternate ids of WCH-Link clones, in `probes.toml`
+#[derive(Debug, Default, Deserialize)]
+struct ProbesConfig {
+    /// `VID:PID` of each alternate, e.g. "1a86:8012"
+    #[serde(default)]
+    alternate_ids: Vec<String>,
+}
+
+/// Alternate VID:PIDs of WCH-Link in RV mode, from `~/.wlink/probes.toml`, or the
+/// file set by `WLINK_PROBES_CONFIG`. Empty if there is no such file.
+pub fn load_alternate_ids() -> Result<Vec<UsbId>> {
+    let path = match std::env::var_os("WLINK_PROBES_CONFIG") {
+        Some(path) => PathBuf::from(path),
+        None => match std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+            Some(home) => PathBuf::from(home).join(".wlink").join("probes.toml"),
+            None => return Ok(vec![]),
+        },
+    };
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let config: ProbesConfig = toml::from_str(&std::fs::read_to_string(&path)?)
+        .map_err(|e| anyhow::format_err!("{}: {}", path.display(), e))?;
+    let ids = config
+        .alternate_ids
+        .iter()
+        .map(|id| id.parse())
+        .collect::<std::result::Result<Vec<UsbId>, _>>()
+        .map_err(|e| anyhow::format_err!("{}: {}", path.display(), e))?;
+    log::debug!("Alternate probe ids: {:?}", ids);
+    Ok(ids)
+}
+
+/// State file of `wlink attach`, per probe index, `$WLINK_STATE_DIR` or `~/.wlink`
+fn attach_state_path(device_index: usize) -> Result<PathBuf> {
+    let dir = match std::env::var_os("WLINK_STATE_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".wlink"))
+            .ok_or_else(|| anyhow::format_err!("home directory not found, set WLINK_STATE_DIR"))?,
+    };
+    Ok(dir.join(format!("attached-{}.json", device_index)))
+}
+
+/// Session state left by `wlink attach` on the probe, if any
+pub fn load_attach_state(device_index: usize) -> Result<Option<AttachState>> {
+    let path = attach_state_path(device_index)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let state = serde_json::from_slice(&std::fs::read(&path)?)?;
+    Ok(Some(state))
+}
+
+pub fn save_attach_state(device_index: usize, state: &AttachState) -> Result<()> {
+    let path = attach_state_path(device_index)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(&path, serde_json::to_vec_pretty(state)?)?;
+    log::debug!("Attach state saved to {}", path.display());
+    Ok(())
+}
+
+pub fn clear_attach_state(device_index: usize) -> Result<()> {
+    let path = attach_state_path(device_index)?;
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Backup file of a chip, keyed by chip UID
+fn backup_path(sess: &mut dyn Target) -> Result<PathBuf> {
+    let uid = sess.esig()?.uid;
+    Ok(backup_dir()?.join(format!(
+        "{:?}-{:08x}{:08x}.bin",
+        sess.chip_family(),
+        uid[0],
+        uid[1]
+    )))
+}
+
+/// Save the current code flash, for `wlink rollback`
+pub fn backup_flash(sess: &mut dyn Target) -> Result<PathBuf> {
+    let path = backup_path(sess)?;
+    let address = sess.chip_family().code_flash_start();
+    let length = sess.flash_size_kb()? as u32 * 1024;
+    let end = range_end(address, length)?;
+    log::info!("Backup flash 0x{:08x} to 0x{:08x}", address, end);
+
+    sess.halt()?;
+    let mut image = sess.read_memory(address, length)?;
+    trim_blank_tail(&mut image);
+
+    std::fs::create_dir_all(backup_dir()?)?;
+    std::fs::write(&path, &image)?;
+    log::info!("{} bytes saved to {}", image.len(), path.display());
+    Ok(path)
+}
+
+/// Restore the code flash saved by `flash --backup`
+pub fn rollback(sess: &mut dyn Target) -> Result<()> {
+    let path = backup_path(sess)?;
+    let image = std::fs::read(&path)
+        .map_err(|e| anyhow::format_err!("no backup of this chip, {}: {}", path.display(), e))?;
+    let address = sess.chip_family().code_flash_start();
+    log::info!("Restore {} bytes from {}", image.len(), path.display());
+
+    sess.erase_flash()?;
+    if !image.is_empty() {
+        sess.write_flash(&image, address)?;
+    }
+    log::info!("Rollback done");
+    Ok(())
+}
+
+/// End of a memory range, fails if it wraps past the end of the address space
+fn range_end(address: u32, length: u32) -> Result<u32> {
+    address.checked_add(length).ok_or_else(|| {
+        anyhow::format_err!(
+            "Invalid range, 0x{:08x} bytes at 0x{:08x} wrap past the end of memory",
+            length,
+            address
+        )
+    })
+}
+
+/// Write memory over DMI in chunks, with a progress bar
+fn write_memory_chunked(sess: &mut ProbeSession, address: u32, data: &[u8]) -> Result<()> {
+    let bar = indicatif::ProgressBar::new(data.len() as _);
+    for (i, chunk) in data.chunks(DMI_WRITE_CHUNK_SIZE).enumerate() {
+        let offset = (i * DMI_WRITE_CHUNK_SIZE) as u32;
+        sess.write_memory_by_dmi(address + offset, chunk)?;
+        bar.inc(chunk.len() as _);
+    }
+    bar.finish_and_clear();
+    Ok(())
+}
+
+/// Fill memory with a 32-bit pattern, in little endian
+pub fn fill(sess: &mut ProbeSession, address: u32, length: u32, pattern: u32) -> Result<()> {
+    sess.ensure_writable("Memory fill")?;
+    let end = range_end(address, length)?;
+    let data: Vec<u8> = pattern
+        .to_le_bytes()
+        .into_iter()
+        .cycle()
+        .take(length as usize)
+        .collect();
+    sess.ensure_mcu_halt()?;
+    log::info!(
+        "Fill 0x{:08x} to 0x{:08x} with 0x{:08x}",
+        address,
+        end,
+        pattern
+    );
+    write_memory_chunked(sess, address, &data)?;
+    Ok(())
+}
+
+/// Test RAM: walking ones on the data bus, then address-in-address and its inverse.
+///
+/// Fails on the first pattern with mismatches, the content of the region is lost.
+pub fn ramtest(sess: &mut ProbeSession, address: u32, length: u32) -> Result<()> {
+    sess.ensure_writable("RAM test")?;
+    if !address.is_multiple_of(4) || !length.is_multiple_of(4) || length == 0 {
+        anyhow::bail!("Address and length must be non-zero multiples of 4");
+    }
+    let end = range_end(address, length)?;
+    sess.ensure_mcu_halt()?;
+
+    log::info!("Walking ones at 0x{:08x}", address);
+    for bit in 0..32 {
+        let value = 1u32 << bit;
+        sess.write_mem32(address, value)?;
+        let readback = sess.read_mem32(address)?;
+        if readback != value {
+            anyhow::bail!(
+                "Walking ones failed at bit {}: wrote 0x{:08x}, read 0x{:08x}",
+                bit,
+                value,
+                readback
+            );
+        }
+    }
+
+    for (name, invert) in [
+        ("Address-in-address", false),
+        ("Inverse address-in-address", true),
+    ] {
+        log::info!("{} test 0x{:08x} to 0x{:08x}", name, address, end);
+        let data: Vec<u8> = (address..end)
+            .step_by(4)
+            .flat_map(|addr| if invert { !addr } else { addr }.to_le_bytes())
+            .collect();
+        write_memory_chunked(sess, address, &data)?;
+        let readback = sess.read_memory(address, length)?;
+
+        let errors: Vec<u32> = data
+            .chunks(4)
+            .zip(readback.chunks(4))
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, _)| address + i as u32 * 4)
+            .collect();
+        if let Some(&first) = errors.first() {
+            let offset = (first - address) as usize;
+            anyhow::bail!(
+                "{} test failed, {} bad words, first at 0x{:08x}: wrote {:02x?}, read {:02x?}",
+                name,
+                errors.len(),
+                first,
+                &data[offset..offset + 4],
+                &readback[offset..offset + 4]
+            );
+        }
+    }
+    log::info!("RAM test passed, {} bytes at 0x{:08x}", length, address);
+    Ok(())
+}
+
+/// Load the loadable segments of an ELF file into SRAM, then run from its entry point
+pub fn run_ram(sess: &mut ProbeSession, path: &str) -> Result<()> {
+    let raw = std::fs::read(path)?;
+    let entry = firmware::read_elf_entry(&raw)?;
+    let Firmware::Sections(sections) = firmware::read_elf(&raw)? else {
+        unreachable!("ELF is read as sections");
+    };
+    for section in &sections {
+        if !SRAM_REGION.contains(&section.address) {
+            anyhow::bail!(
+                "segment at 0x{:08x} is not in SRAM, use `wlink flash` instead",
+                section.address
+            );
+        }
+    }
+
+    sess.reset_and_halt()?;
+    for section in &sections {
+        log::info!(
+            "Load {} bytes to 0x{:08x}",
+            section.data.len(),
+            section.address
+        );
+        sess.write_memory_by_dmi(section.address, &section.data)?;
+
+        let aligned = section.data.len() as u32 & !0b11;
+        let readback = sess.read_memory_by_dmi(section.address, aligned)?;
+        if readback[..] != section.data[..aligned as usize] {
+            anyhow::bail!("SRAM verify failed at 0x{:08x}", section.address);
+        }
+    }
+    sess.set_entry(entry)?;
+    sess.ensure_mcu_resume()?;
+    log::info!("Running from 0x{:08x}", entry);
+    Ok(())
+}
+
+/// Ask the user to choose one of multiple probes, fails if stdin is not a terminal
+pub fn choose_probe(probes: &[String]) -> Result<usize> {
+    use std::io::{BufRead, IsTerminal};
+
+    let mut list = String::new();
+    for (i, probe) in probes.iter().enumerate() {
+        let _ = writeln!(list, "  [{}] {}", i, probe);
+    }
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!(
+            "{} probes connected, choose one with --device INDEX:\n{}",
+            probes.len(),
+            list.trim_end()
+        );
+    }
+
+    println!("{} probes connected:", probes.len());
+    print!("{}", list);
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        print!("Select probe [0-{}]: ", probes.len() - 1);
+        std::io::Write::flush(&mut std::io::stdout())?;
+        let Some(line) = lines.next() else {
+            anyhow::bail!("no probe selected");
+        };
+        match line?.trim().parse::<usize>() {
+            Ok(index) if index < probes.len() => return Ok(index),
+            _ => println!("Invalid selection"),
+        }
+    }
+}
+
+/// Close the debug interface, asks to type the chip family to confirm when on a terminal
+pub fn disable_debug(sess: &mut ProbeSession) -> Result<()> {
+    use std::io::IsTerminal;
+
+    let chip = format!("{:?}", sess.chip_family);
+    if !sess.chip_family.support_disable_debug() {
+        anyhow::bail!("Disabling debug is not supported by {}", chip);
+    }
+    match sess.esig() {
+        Ok(esig) => println!("Chip: {}, UID {:08x}{:08x}", chip, esig.uid[0], esig.uid[1]),
+        Err(_) => println!("Chip: {}", chip),
+    }
+
+    if std::io::stdin().is_terminal() {
+        println!("The debug interface will be closed, the probe can no longer attach this chip.");
+        print!("Type {} to confirm: ", chip);
+        std::io::Write::flush(&mut std::io::stdout())?;
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        if line.trim() != chip {
+            anyhow::bail!("not confirmed, debug interface left open");
+        }
+    } else {
+        log::warn!("Not a terminal, disabling debug without typed confirmation");
+    }
+
+    sess.disable_debug()?;
+    log::info!("Debug interface disabled, takes effect after reset");
+    Ok(())
+}
+
+/// List connected probes
+pub fn list_probes(usb_id: UsbId, output: OutputFormat) -> Result<()> {
+    let probes = WchLink::enumerate(usb_id)?;
+    match output {
+        OutputFormat::Text => {
+            for probe in &probes {
+                println!("{}", probe);
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&probes)?),
+    }
+    Ok(())
+}
+
+/// Print firmware features of the probe, and how to update if any is missing
+pub fn probe_update(probe: &WchLink) -> Result<()> {
+    println!("Probe: {}", probe.info);
+    let mut outdated = false;
+    for &feature in FirmwareFeature::ALL {
+        let (major, minor) = feature.min_version();
+        let status =
+            if feature == FirmwareFeature::SdiPrint && !probe.info.variant.support_sdi_print() {
+                format!("not supported by {}", probe.info.variant)
+            } else if probe.info.supports(feature) {
+                "ok".to_string()
+            } else {
+                outdated = true;
+                "firmware update required".to_string()
+            };
+        println!(
+            "  {:<12} v{}.{}+  {}",
+            feature.to_string(),
+            major,
+            minor,
+            status
+        );
+    }
+    if outdated {
+        println!("Update the probe firmware with WCH-LinkUtility or MounRiver Studio, both offer the update on connecting the probe");
+    } else {
+        println!("Probe firmware supports all known features");
+    }
+    Ok(())
+}
+
+/// Checks of `wlink doctor`, in order
+const DOCTOR_CHECKS: [&str; 7] = [
+    "Probe",
+    "Probe mode",
+    "Probe firmware",
+    "Target (SWDIO)",
+    "Debug module",
+    "Core",
+    "Flash protection",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CheckStatus {
+    Pass,
+    Warn,
+    Fail,
+    Skip,
+}
+
+/// Pass/fail checklist of `wlink doctor`, printed as it goes
+#[derive(Debug, Default)]
+struct Checklist {
+    done: usize,
+    failed: usize,
+}
+
+impl Checklist {
+    fn report(&mut self, status: CheckStatus, detail: impl std::fmt::Display) {
+        let tag = match status {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Warn => "WARN",
+            CheckStatus::Fail => {
+                self.failed += 1;
+                "FAIL"
+            }
+            CheckStatus::Skip => "SKIP",
+        };
+        println!("[{}] {:<16} {}", tag, DOCTOR_CHECKS[self.done], detail);
+        self.done += 1;
+    }
+
+    fn hint(&self, hint: &str) {
+        println!("       hint: {}", hint);
+    }
+
+    /// Remaining checks depend on a failed one
+    fn skip_rest(&mut self) {
+        while self.done < DOCTOR_CHECKS.len() {
+            self.report(CheckStatus::Skip, "");
+        }
+    }
+}
+
+/// Guided connection diagnostics, prints a pass/fail checklist, fails if any check fails
+pub fn doctor(
+    device_index: usize,
+    usb_id: UsbId,
+    chip: Option<RiscvChip>,
+    speed: Speed,
+) -> Result<()> {
+    let mut list = Checklist::default();
+    match WchLink::open_nth_with_usb_id(device_index, usb_id) {
+        Ok(probe) => {
+            list.report(CheckStatus::Pass, &probe.info);
+            list.report(CheckStatus::Pass, "RV");
+            doctor_probe(&mut list, probe, chip, speed)?;
+        }
+        Err(crate::Error::ProbeModeNotSupported) => {
+            list.report(CheckStatus::Pass, "found");
+            list.report(CheckStatus::Fail, "DAP");
+            list.hint("switch to RV mode by `wlink mode-switch --rv`");
+        }
+        Err(e) => {
+            list.report(CheckStatus::Fail, e);
+            list.hint("check the USB cable and udev rules, `wlink list` shows connected probes");
+        }
+    }
+    list.skip_rest();
+
+    if list.failed > 0 {
+        anyhow::bail!("{} of {} checks failed", list.failed, DOCTOR_CHECKS.len());
+    }
+    println!("All checks passed");
+    Ok(())
+}
+
+fn doctor_probe(
+    list: &mut Checklist,
+    mut probe: WchLink,
+    chip: Option<RiscvChip>,
+    speed: Speed,
+) -> Result<()> {
+    let missing: Vec<_> = FirmwareFeature::ALL
+        .iter()
+        .filter(|&&feature| {
+            !probe.info.supports(feature)
+                && (feature != FirmwareFeature::SdiPrint || probe.info.variant.support_sdi_print())
+        })
+        .map(|feature| feature.to_string())
+        .collect();
+    let (major, minor) = probe.info.version();
+    if missing.is_empty() {
+        list.report(CheckStatus::Pass, format!("v{}.{}", major, minor));
+    } else {
+        list.report(
+            CheckStatus::Warn,
+            format!("v{}.{}, lacks {}", major, minor, missing.join(", ")),
+        );
+        list.hint("see `wlink probe-update`");
+    }
+
+    if probe.detect_target(chip)?.is_none() {
+        list.report(CheckStatus::Fail, "no answer to the attach request");
+        list.hint("check target power, the SWDIO and GND wiring, or use `wlink erase --method power-off` if debug is disabled");
+        if probe.info.variant.support_power_funcs() {
+            list.hint("an unpowered target can be powered by `wlink set-power enable3v3`");
+        }
+        return Ok(());
+    }
+    let mut sess = match ProbeSession::attach(probe, chip, speed) {
+        Ok(sess) => sess,
+        Err(e) => {
+            list.report(CheckStatus::Fail, e);
+            list.hint("try a lower speed, e.g. `--speed low`, and shorter wires");
+            return Ok(());
+        }
+    };
+    list.report(
+        CheckStatus::Pass,
+        format!("{:?} attached at {}kHz", sess.chip_family, sess.speed.khz()),
+    );
+
+    let ret = doctor_session(list, &mut sess);
+    sess.detach_chip()?;
+    ret
+}
+
+fn doctor_session(list: &mut Checklist, sess: &mut ProbeSession) -> Result<()> {
+    match sess.check_debug_module() {
+        Ok(dmstatus) if dmstatus.anyhavereset() => {
+            list.report(
+                CheckStatus::Warn,
+                format!(
+                    "v{}, the core was reset since the last check",
+                    dmstatus.version()
+                ),
+            );
+            list.hint("a watchdog or brown-out may be resetting the chip");
+        }
+        Ok(dmstatus) => list.report(
+            CheckStatus::Pass,
+            format!("v{}, authenticated, core available", dmstatus.version()),
+        ),
+        Err(e) => {
+            list.report(CheckStatus::Fail, e);
+            return Ok(());
+        }
+    }
+
+    match sess.power_state()? {
+        PowerState::Active => list.report(CheckStatus::Pass, "active"),
+        PowerState::Sleeping(_) => list.report(CheckStatus::Pass, "in WFI"),
+        PowerState::Unresponsive => {
+            list.report(CheckStatus::Fail, "halt request not acknowledged");
+            list.hint("the chip is likely in Stop or Standby mode, wake it up or reset it");
+        }
+    }
+
+    if sess.is_read_protected()? {
+        list.report(CheckStatus::Warn, "flash is read protected");
+        list.hint("`wlink unprotect` removes the protection, this erases the flash");
+    } else {
+        list.report(CheckStatus::Pass, "not read protected");
+    }
+    Ok(())
+}
+
+/// Flash firmware files to all connected probes
+pub fn gang_flash(gang: &GangProgrammer, paths: &[String], address: Option<u32>) -> Result<()> {
+    let firmware = read_firmware_from_files(paths)?;
+
+    let results = gang.program(&firmware, address)?;
+    let mut failed = 0;
+    for r in &results {
+        match &r.result {
+            Ok(()) => log::info!("Probe #{}: OK", r.index),
+            Err(e) => {
+                log::error!("Probe #{}: {}", r.index, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        anyhow::bail!("{} of {} probes failed", failed, results.len());
+    }
+    log::info!("All {} probes flashed", results.len());
+    Ok(())
+}
+
+/// Copy the code flash, and optionally the option bytes, from one target to another
+pub fn clone(src: &mut ProbeSession, dst: &mut ProbeSession, option_bytes: bool) -> Result<()> {
+    if src.chip_family != dst.chip_family {
+        anyhow::bail!(
+            "Chip mismatch: source is {:?}, destination is {:?}",
+            src.chip_family,
+            dst.chip_family
+        );
+    }
+    if option_bytes && !src.chip_family.support_dmi_flash() {
+        anyhow::bail!(
+            "Option bytes copy is not supported for {:?}",
+            src.chip_family
+        );
+    }
+    if src.is_read_protected()? {
+        anyhow::bail!("Source flash is read protected, it can not be copied");
+    }
+    if dst.is_read_protected()? {
+        anyhow::bail!("Destination flash is read protected (hint: use `wlink unprotect` first)");
+    }
+
+    let address = src.chip_family.code_flash_start();
+    let length = src.flash_size_kb()? as u32 * 1024;
+    let end = range_end(address, length)?;
+    log::info!("Read source flash 0x{:08x} to 0x{:08x}", address, end);
+    src.ensure_mcu_halt()?;
+    let mut image = src.read_memory(address, length)?;
+    let obs = if option_bytes {
+        let obs = src.read_option_bytes()?;
+        log::info!("Source option bytes: {:02x?}", obs);
+        Some(obs)
+    } else {
+        None
+    };
+
+    // the destination is fully erased, no need to write the blank tail
+    trim_blank_tail(&mut image);
+
+    log::info!("Erase destination flash");
+    dst.erase_flash()?;
+    if image.is_empty() {
+        log::warn!("Source flash is blank");
+    } else {
+        log::info!("Flashing {} bytes to 0x{:08x}", image.len(), address);
+        dst.write_flash(&image, address)?;
+
+        dst.ensure_mcu_halt()?;
+        let readback = dst.timed("verify", Some(image.len() as u64), |dst| {
+            dst.read_memory(address, image.len() as u32)
+        })?;
+        if let Some(pos) = image.iter().zip(&readback).position(|(a, b)| a != b) {
+            anyhow::bail!("Verify failed at 0x{:08x}", address + pos as u32);
+        }
+        log::info!("Verify OK");
+    }
+
+    if let Some(obs) = obs {
+        log::info!("Write option bytes");
+        dst.write_option_bytes(&obs)?;
+        log::info!("Option bytes take effect after a power cycle");
+    }
+    Ok(())
+}
+
+/// Run for a limited time, then halt and dump registers and memory regions
+pub fn run(
+    sess: &mut ProbeSession,
+    duration: Duration,
+    reset: bool,
+    entry: Option<u32>,
+    regions: &[(u32, u32)],
+) -> Result<()> {
+    sess.run_for(duration, reset, entry)?;
+
+    println!("{}", sess.register_file()?);
+    for &(address, length) in regions {
+        let out = sess.read_memory(address, length)?;
+        print_hexdump(&out, address);
+    }
+    Ok(())
+}
+
+/// Watch global variables, never returns unless error
+pub fn watch_var(
+    sess: &mut ProbeSession,
+    elf: &str,
+    names: &[String],
+    interval: Duration,
+) -> Result<()> {
+    let elf_data = std::fs::read(elf)?;
+    let vars = crate::dwarf::find_variables(&elf_data, names)?;
+    for var in &vars {
+        log::info!(
+            "{} @ 0x{:08x}, {} bytes",
+            var.name,
+            var.address,
+            var.ty.size()
+        );
+    }
+    sess.ensure_mcu_resume()?;
+    log::info!("Watching variables, press Ctrl-C to exit");
+    loop {
+        let mut values = vec![];
+        for var in &vars {
+            let data = sess.peek_memory(var.address, var.ty.size().max(1))?;
+            values.push(format!("{} = {}", var.name, var.ty.decode(&data)));
+        }
+        println!("{}", values.join(", "));
+        sleep(interval);
+    }
+}
+
+/// Read a DMI register, print the value and decoded fields, or only the value if `raw`
+pub fn dmi_read(sess: &mut ProbeSession, reg: u8, raw: bool) -> Result<()> {
+    let value = sess.probe.dmi_read(reg)?;
+    if raw {
+        println!("0x{value:08x}");
+        return Ok(());
+    }
+    let name = regs::dmi_reg_name(reg).unwrap_or("unknown");
+    println!("dmi 0x{reg:02x}({name}): 0x{value:08x}");
+    if let Some(decoded) = regs::decode_dmi_reg(reg, value) {
+        println!("{decoded}");
+    }
+    Ok(())
+}
+
+/// Write a DMI register
+pub fn dmi_write(sess: &mut ProbeSession, reg: u8, value: u32) -> Result<()> {
+    sess.ensure_writable("DMI write")?;
+    let name = regs::dmi_reg_name(reg).unwrap_or("unknown");
+    log::info!("Write dmi 0x{reg:02x}({name}) with 0x{value:08x}");
+    sess.probe.dmi_write(reg, value)?;
+    Ok(())
+}
+
+/// Register definitions of `--svd`, or the bundled ones of the attached chip
+pub fn load_svd(path: Option<&Path>, chip: RiscvChip) -> Result<svd::Device> {
+    if let Some(path) = path {
+        return svd::Device::load(path);
+    }
+    match svd::bundled(chip)? {
+        Some(device) => {
+            log::debug!("Using bundled register definitions of {:?}", chip);
+            Ok(device)
+        }
+        None => anyhow::bail!(
+            "No bundled register definitions for {:?}, give a SVD file with --svd",
+            chip
+        ),
+    }
+}
+
+/// Read peripheral registers by SVD name, `RCC.CFGR0`, or all registers of `RCC`
+pub fn peri_read(sess: &mut ProbeSession, device: &svd::Device, path: &str) -> Result<()> {
+    let (peripheral, register) = device.find(path)?;
+    let registers = match register {
+        Some(register) => std::slice::from_ref(register),
+        None => &peripheral.registers[..],
+    };
+    for register in registers {
+        let value = read_peripheral_reg(sess, peripheral, register)?;
+        print_peripheral_reg(peripheral, register, value);
+    }
+    Ok(())
+}
+
+/// Write a peripheral register by SVD name, a whole value and/or `FIELD=VALUE` updates.
+/// Without a whole value, fields are updated by read-modify-write.
+pub fn peri_write(
+    sess: &mut ProbeSession,
+    device: &svd::Device,
+    path: &str,
+    value: Option<u32>,
+    updates: &[(String, u32)],
+) -> Result<()> {
+    sess.ensure_writable("Peripheral register write")?;
+    let (peripheral, register) = device.find(path)?;
+    let register = register.ok_or_else(|| {
+        anyhow::format_err!("{} is not a register, use PERIPHERAL.REGISTER", path)
+    })?;
+    if value.is_none() && updates.is_empty() {
+        anyhow::bail!("nothing to write, give a value or FIELD=VALUE updates");
+    }
+    let value = match value {
+        Some(value) => value,
+        None => read_peripheral_reg(sess, peripheral, register)?,
+    };
+    let value = register.update(value, updates)?;
+    if register.size < 32 && value >> register.size != 0 {
+        anyhow::bail!(
+            "value 0x{:x} does not fit {}.{}, {} bits",
+            value,
+            peripheral.name,
+            register.name,
+            register.size
+        );
+    }
+
+    let address = peripheral.base_address + register.offset;
+    log::info!(
+        "Write {}.{} at 0x{:08x} with 0x{:08x}",
+        peripheral.name,
+        register.name,
+        address,
+        value
+    );
+    match register.size {
+        8 => sess.write_mem8(address, value as u8)?,
+        16 => sess.write_mem16(address, value as u16)?,
+        _ => sess.write_mem32(address, value)?,
+    }
+    let value = read_peripheral_reg(sess, peripheral, register)?;
+    print_peripheral_reg(peripheral, register, value);
+    Ok(())
+}
+
+fn read_peripheral_reg(
+    sess: &mut ProbeSession,
+    peripheral: &svd::Peripheral,
+    register: &svd::Register,
+) -> Result<u32> {
+    let address = peripheral.base_address + register.offset;
+    let value = match register.size {
+        8 => sess.read_mem8(address)? as u32,
+        16 => sess.read_mem16(address)? as u32,
+        _ => sess.read_mem32(address)?,
+    };
+    Ok(value)
+}
+
+fn print_peripheral_reg(peripheral: &svd::Peripheral, register: &svd::Register, value: u32) {
+    println!(
+        "{}.{} @ 0x{:08x} = 0x{:0w$x}  {}",
+        peripheral.name,
+        register.name,
+        peripheral.base_address + register.offset,
+        value,
+        register.description,
+        w = register.size as usize / 4
+    );
+    for field in &register.fields {
+        println!(
+            "  {:<8} {:<12} 0x{:<6x} {}",
+            field.bit_range(),
+            field.name,
+            field.extract(value),
+            field.description
+        );
+    }
+}
+
+/// Report if the core is sleeping, a common reason of "can't halt my chip"
+pub fn report_power_state(sess: &mut ProbeSession) -> Result<()> {
+    match sess.power_state()? {
+        PowerState::Active => log::info!("Core power state: active"),
+        PowerState::Sleeping(Some(mode)) => {
+            log::warn!("Core power state: in WFI, {:?} mode", mode)
+        }
+        PowerState::Sleeping(None) => log::warn!("Core power state: in WFI"),
+        PowerState::Unresponsive => {
+            log::warn!("Core power state: halt request not acknowledged");
+            log::warn!(
+                "The chip is likely in Stop or Standby mode, wake it up or reset it to attach"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Print all PMP regions
+pub fn pmp_show(sess: &mut ProbeSession) -> Result<()> {
+    for region in 0..regs::PMP_REGIONS {
+        let (addr, cfg) = sess.read_pmp(region)?;
+        println!(
+            "pmp{region}: pmpaddr=0x{addr:08x} cfg=0x{cfg:02x} {}",
+            regs::decode_pmp_cfg(cfg)
+        );
+    }
+    Ok(())
+}
+
+/// Generate a crash report of a halted or faulted core: trap CSRs, GPRs and backtrace.
+///
+/// The backtrace walks the frame pointer chain(`s0`), requires firmware built with
+/// frame pointers. Addresses are resolved to function names when an ELF is given.
+pub fn crash_report(sess: &mut ProbeSession, elf: Option<&str>) -> Result<String> {
+    let symbols = match elf {
+        Some(path) => dwarf::load_symbols(&std::fs::read(path)?)?,
+        None => vec![],
+    };
+    let symbolize = |addr: u32| match dwarf::lookup_symbol(&symbols, addr) {
+        Some((sym, offset)) => format!("0x{addr:08x} <{}+0x{offset:x}>", sym.name),
+        None => format!("0x{addr:08x}"),
+    };
+
+    sess.ensure_mcu_halt()?;
+
+    let mut report = String::new();
+    writeln!(report, "wlink crash report")?;
+    writeln!(report, "chip: {:?}", sess.chip_family)?;
+    writeln!(report)?;
+
+    let dpc = sess.read_reg(regs::DPC)?;
+    let mcause = sess.read_reg(regs::MCAUSE)?;
+    let mepc = sess.read_reg(regs::MEPC)?;
+    let mtval = sess.read_reg(regs::MTVAL)?;
+    let mstatus = sess.read_reg(regs::MSTATUS)?;
+    writeln!(report, "pc(dpc):  {}", symbolize(dpc))?;
+    writeln!(
+        report,
+        "mcause:   0x{mcause:08x} ({})",
+        regs::mcause_description(mcause)
+    )?;
+    writeln!(report, "mepc:     {}", symbolize(mepc))?;
+    writeln!(report, "mtval:    0x{mtval:08x}")?;
+    writeln!(report, "mstatus:  0x{mstatus:08x}")?;
+    writeln!(report)?;
+
+    let gprs = if sess.chip_family.is_rv32ec() {
+        regs::GPRS_RVE
+    } else {
+        regs::GPRS_RVI
+    };
+    let mut fp = 0;
+    for (reg, name, regno) in gprs {
+        let val = sess.read_reg(*regno)?;
+        if *name == "s0" {
+            fp = val;
+        }
+        writeln!(report, "{reg:<4}{name:>5}: 0x{val:08x}")?;
+    }
+    writeln!(report)?;
+
+    writeln!(report, "backtrace:")?;
+    for (i, addr) in sess.backtrace(dpc, fp)?.iter().enumerate() {
+        writeln!(report, "  #{i:<2} {}", symbolize(*addr))?;
+    }
+
+    Ok(report)
+}
+
+const REPL_HELP: &str = "\
+Commands:
+  81 0d 01 02            send a raw probe command frame, print the response
+  dmi r <reg>            read a DMI register, by address or name
+  dmi w <reg> <value>    write a DMI register
+  peek <addr> [len]      read memory, default to 4 bytes
+  poke <addr> <value>    write a memory word
+  reg <regno> [value]    read or write a register, GPR: 0x1000 - 0x101f
+  monitor <command>      run a monitor command, `monitor help` to list them
+  help                   show this help
+  quit                   exit";
+
+/// Interactive REPL for protocol exploration, reads commands from stdin
+pub fn repl(sess: &mut ProbeSession) -> Result<()> {
+    use std::io::BufRead;
+
+    println!("wlink REPL, type `help` for commands");
+    let stdin = std::io::stdin();
+    loop {
+        print!("wlink> ");
+        std::io::Write::flush(&mut std::io::stdout())?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            println!();
+            return Ok(());
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.first() {
+            None => continue,
+            Some(&("quit" | "exit" | "q")) => return Ok(()),
+            Some(&"help") => println!("{REPL_HELP}"),
+            Some(_) => {
+                if let Err(e) = repl_command(sess, &args) {
+                    println!("error: {e:#}");
+                }
+            }
+        }
+    }
+}
+
+fn repl_command(sess: &mut ProbeSession, args: &[&str]) -> Result<()> {
+    let number = |s: &str| parse_number(s).map_err(anyhow::Error::msg);
+    match args {
+        ["dmi", "r" | "read", reg] => {
+            dmi_read(sess, parse_dmi_reg(reg).map_err(anyhow::Error::msg)?, false)?;
+        }
+        ["dmi", "w" | "write", reg, value] => {
+            let reg = parse_dmi_reg(reg).map_err(anyhow::Error::msg)?;
+            dmi_write(sess, reg, number(value)?)?;
+        }
+        ["peek", addr, rest @ ..] if rest.len() <= 1 => {
+            let addr = number(addr)?;
+            let len = rest.first().map(|s| number(s)).transpose()?.unwrap_or(4);
+            let mem = sess.peek_memory(addr, len)?;
+            print_hexdump(&mem, addr);
+        }
+        ["poke", addr, value] => {
+            sess.write_mem32(number(addr)?, number(value)?)?;
+        }
+        ["reg", regno] => {
+            let regno = number(regno)? as u16;
+            println!("0x{:08x}", sess.read_reg(regno)?);
+        }
+        ["reg", regno, value] => {
+            sess.write_reg(number(regno)? as u16, number(value)?)?;
+        }
+        ["monitor" | "mon", command @ ..] => {
+            print!("{}", crate::monitor::execute(sess, &command.join(" "))?);
+        }
+        _ => {
+            let frame = args
+                .iter()
+                .map(|s| u8::from_str_radix(s.trim_start_matches("0x"), 16))
+                .collect::<std::result::Result<Vec<u8>, _>>()
+                .map_err(|_| anyhow::format_err!("unknown command, type `help` for commands"))?;
+            sess.ensure_writable("Raw command")?;
+            let resp = sess.probe.send_raw_command(&frame)?;
+            let hex: Vec<_> = resp.iter().map(|b| format!("{b:02x}")).collect();
+            println!("{}", hex.join(" "));
+            match resp.as_slice() {
+                [0x82, cmd, len, payload @ ..] => println!(
+                    "ok: cmd 0x{cmd:02x}, {len} bytes, payload {}",
+                    hex::encode(payload)
+                ),
+                [0x81, reason, ..] => println!("error: reason 0x{reason:02x}"),
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Notify the operator that a command finished.
+///
+/// Shows a desktop notification with the `notify` feature, or else rings the terminal bell.
+pub fn notify(result: &Result<()>) {
+    let message = match result {
+        Ok(()) => "Done".to_string(),
+        Err(e) => format!("Failed: {e}"),
+    };
+
+    #[cfg(feature = "notify")]
+    {
+        match notify_rust::Notification::new()
+            .summary("wlink")
+            .body(&message)
+            .show()
+        {
+            Ok(_) => return,
+            Err(e) => log::warn!("Desktop notification failed: {}", e),
+        }
+    }
+
+    log::debug!("Notify: {}", message);
+    eprint!("\x07");
+}
+
+/// Parse DMI register address, or a known register name like `dmstatus`
+pub fn parse_dmi_reg(s: &str) -> std::result::Result<u8, String> {
+    if let Some((_, addr)) = regs::DMI_REGS
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(s))
+    {
+        return Ok(*addr);
+    }
+    let addr = match s.strip_prefix("0x") {
+        Some(hex_str) => u8::from_str_radix(hex_str, 16).ok(),
+        None => s.parse().ok(),
+    };
+    addr.filter(|addr| *addr < 0x80)
+        .ok_or_else(|| format!("invalid DMI register: {s:?}"))
+}
+
+/// Parse a byte value, like `0xff`
+pub fn parse_byte(s: &str) -> std::result::Result<u8, String> {
+    parse_number(s)
+        .ok()
+        .and_then(|n| u8::try_from(n).ok())
+        .ok_or_else(|| format!("invalid byte: {s:?}"))
+}
+
+/// Parse a `FIELD=VALUE` register field update
+pub fn parse_field_update(s: &str) -> std::result::Result<(String, u32), String> {
+    let (field, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expect FIELD=VALUE, got {s:?}"))?;
+    Ok((field.trim().to_string(), parse_number(value.trim())?))
+}
+
+/// Parse a USB VID or PID, in hex with an optional `0x` prefix
+pub fn parse_usb_id_part(s: &str) -> std::result::Result<u16, String> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid USB VID/PID {s:?}"))
+}
+
+/// Parse `ADDRESS=FIELD,...` of `--insert-meta`, fields are `uid`, `timestamp`
+/// and `version`, the wlink version, or `version:1.2.3`
+pub fn parse_meta_spec(s: &str) -> std::result::Result<(u32, Vec<MetaField>), String> {
+    let (address, fields) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected ADDRESS=FIELD,..., got {s:?}"))?;
+    let address = parse_number(address)?;
+    let fields = fields
+        .split(',')
+        .map(|field| match field.trim().split_once(':') {
+            None if field.trim() == "uid" => Ok(MetaField::Uid),
+            None if field.trim() == "timestamp" => Ok(MetaField::Timestamp),
+            None if field.trim() == "version" => Ok(MetaField::Version(
+                firmware::pack_version(env!("CARGO_PKG_VERSION")).unwrap_or_default(),
+            )),
+            Some(("version", version)) => firmware::pack_version(version)
+                .map(MetaField::Version)
+                .ok_or_else(|| format!("invalid version: {version:?}")),
+            _ => Err(format!("unknown metadata field: {field:?}")),
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok((address, fields))
+}
+
+/// Parse durations like `10s`, `500ms`, `2m`. Plain numbers are seconds.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let s = s.trim().to_lowercase();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (&*s, "s"),
+    };
+    let n: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid duration: {s:?}"))?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(n)),
+        "s" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        _ => Err(format!("invalid duration unit: {unit:?}, use ms, s or m")),
+    }
+}
+
+/// Parse memory region in `ADDR:LEN` form
+pub fn parse_region(s: &str) -> std::result::Result<(u32, u32), String> {
+    let (addr, len) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid region {s:?}, expected ADDR:LEN"))?;
+    Ok((parse_number(addr)?, parse_number(len)?))
+}
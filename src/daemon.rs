@@ -0,0 +1,254 @@
+//! Persistent probe session, shared by later `wlink --via daemon` invocations.
+//!
+//! `wlink daemon` attaches the chip once, keeps the USB device open, and forwards
+//! endpoint transactions of clients over a local TCP socket. Clients resume the
+//! attached session instead of attaching again, and never detach.
+//!
+//! The protocol is JSON Lines, one request and one reply per line:
+//!
+//! ```json
+//! {"op":"hello"}
+//! {"status":"session","chip":"CH32V30X","chip_id":807469328,"speed":"high"}
+//! {"op":"write","ep":1,"data":"810d0101"}
+//! {"status":"ok"}
+//! {"op":"read","ep":129,"len":64}
+//! {"status":"data","data":"820d0401020301"}
+//! ```
+//!
+//! There is no authentication, keep the daemon on a loopback address.
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    capture::hex_data,
+    cli::AttachState,
+    operations::ProbeSession,
+    probe::UsbId,
+    usb_device::{USBDeviceBackend, DEFAULT_TIMEOUT},
+    Error,
+};
+
+/// Default listen address of `wlink daemon`
+pub const DEFAULT_DAEMON_ADDR: &str = "127.0.0.1:4141";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    /// Session info of the attached chip
+    Hello,
+    Write {
+        ep: u8,
+        #[serde(with = "hex_data")]
+        data: Vec<u8>,
+    },
+    Read {
+        ep: u8,
+        len: usize,
+    },
+    SetTimeout {
+        ms: u64,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Reply {
+    Session(AttachState),
+    Ok,
+    Data {
+        #[serde(with = "hex_data")]
+        data: Vec<u8>,
+    },
+    /// USB transfer timeout, kept apart for stall detection of the client
+    Timeout,
+    Error {
+        message: String,
+    },
+}
+
+/// Serve the attached session until Ctrl-C, one client at a time
+pub fn serve(sess: &mut ProbeSession, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = stop.clone();
+        ctrlc::set_handler(move || stop.store(true, Ordering::SeqCst))?;
+    }
+
+    let state = AttachState::new(sess);
+    log::info!(
+        "Serving {:?} on {}, press Ctrl-C to stop",
+        sess.chip_family,
+        listener.local_addr()?
+    );
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                log::debug!("Client {} connected", peer);
+                stream.set_nonblocking(false)?;
+                if let Err(e) = handle_client(sess, &state, stream) {
+                    log::warn!("Client {}: {}", peer, e);
+                }
+                // undo timeout overrides of the client
+                let timeout = sess.probe.usb_timeout();
+                sess.probe.set_usb_timeout(timeout);
+                log::debug!("Client {} disconnected", peer);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    log::info!("Daemon stopped");
+    Ok(())
+}
+
+fn handle_client(sess: &mut ProbeSession, state: &AttachState, stream: TcpStream) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let request: Request = serde_json::from_str(&line?)?;
+        let device = &mut sess.probe.device;
+        let reply = match request {
+            Request::Hello => Reply::Session(state.clone()),
+            Request::Write { ep, data } => match device.write_endpoint(ep, &data) {
+                Ok(()) => Reply::Ok,
+                Err(e) => error_reply(e),
+            },
+            Request::Read { ep, len } => {
+                let mut buf = vec![0u8; len];
+                match device.read_endpoint(ep, &mut buf) {
+                    Ok(n) => Reply::Data {
+                        data: buf[..n].to_vec(),
+                    },
+                    Err(e) => error_reply(e),
+                }
+            }
+            Request::SetTimeout { ms } => {
+                device.set_timeout(Duration::from_millis(ms));
+                Reply::Ok
+            }
+        };
+        writeln!(writer, "{}", serde_json::to_string(&reply)?)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn error_reply(e: Error) -> Reply {
+    match e {
+        Error::Rusb(rusb::Error::Timeout) => Reply::Timeout,
+        e => Reply::Error {
+            message: e.to_string(),
+        },
+    }
+}
+
+/// Daemon address and the probe it serves, set by `connect`
+static REMOTE: Mutex<Option<(String, UsbId)>> = Mutex::new(None);
+
+/// Use the daemon for probes opened after this call, returns the attached session
+pub fn connect(addr: &str, usb_id: UsbId) -> Result<AttachState> {
+    let mut device = RemoteDevice::connect(addr)
+        .map_err(|e| anyhow::format_err!("daemon at {} is not reachable: {}", addr, e))?;
+    let state = match device.call(&Request::Hello)? {
+        Reply::Session(state) => state,
+        reply => anyhow::bail!("unexpected daemon reply: {:?}", reply),
+    };
+    *REMOTE.lock().unwrap() = Some((addr.to_string(), usb_id));
+    Ok(state)
+}
+
+/// The daemon device, if connected and serving the requested probe
+pub(crate) fn open_remote(vid: u16, pid: u16) -> crate::Result<Option<Box<dyn USBDeviceBackend>>> {
+    match &*REMOTE.lock().unwrap() {
+        Some((addr, usb_id)) if usb_id.vid == vid && usb_id.pid == pid => {
+            let mut device = RemoteDevice::connect(addr)?;
+            device.set_timeout(DEFAULT_TIMEOUT);
+            Ok(Some(Box::new(device)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// A probe served by the daemon
+#[derive(Debug)]
+struct RemoteDevice {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RemoteDevice {
+    fn connect(addr: &str) -> crate::Result<Self> {
+        let writer = TcpStream::connect(addr)?;
+        writer.set_nodelay(true)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(Self { reader, writer })
+    }
+
+    fn call(&mut self, request: &Request) -> crate::Result<Reply> {
+        let line = serde_json::to_string(request).map_err(|e| Error::Custom(e.to_string()))?;
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()?;
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(Error::Custom("daemon closed the connection".to_string()));
+        }
+        match serde_json::from_str(&line).map_err(|e| Error::Custom(e.to_string()))? {
+            Reply::Timeout => Err(Error::Rusb(rusb::Error::Timeout)),
+            Reply::Error { message } => Err(Error::Custom(format!("daemon: {}", message))),
+            reply => Ok(reply),
+        }
+    }
+}
+
+impl USBDeviceBackend for RemoteDevice {
+    fn set_timeout(&mut self, timeout: Duration) {
+        let request = Request::SetTimeout {
+            ms: timeout.as_millis() as u64,
+        };
+        if let Err(e) = self.call(&request) {
+            log::warn!("Failed to set daemon USB timeout: {}", e);
+        }
+    }
+
+    fn read_endpoint(&mut self, ep: u8, buf: &mut [u8]) -> crate::Result<usize> {
+        match self.call(&Request::Read { ep, len: buf.len() })? {
+            Reply::Data { data } => {
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok(n)
+            }
+            _ => Err(Error::InvalidPayload),
+        }
+    }
+
+    fn open_nth(_vid: u16, _pid: u16, _nth: usize) -> crate::Result<Box<dyn USBDeviceBackend>> {
+        unreachable!("created by open_remote")
+    }
+
+    fn write_endpoint(&mut self, ep: u8, buf: &[u8]) -> crate::Result<()> {
+        match self.call(&Request::Write {
+            ep,
+            data: buf.to_vec(),
+        })? {
+            Reply::Ok => Ok(()),
+            _ => Err(Error::InvalidPayload),
+        }
+    }
+}
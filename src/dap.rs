@@ -0,0 +1,285 @@
+//! Minimal CMSIS-DAP access, for probes in DAP mode.
+//!
+//! DAP_Info tells which probe is connected. A minimal SWD transport allows IDCODE
+//! reads and 32-bit memory access over MEM-AP, for the ARM core of WCH parts.
+//! Use `wlink mode-switch --rv` to switch back for debugging RISC-V chips.
+use std::fmt;
+
+use crate::{
+    probe::{ENDPOINT_OUT_DAP, PRODUCT_ID_DAP, VENDOR_ID_DAP},
+    usb_device::{self, USBDeviceBackend},
+    Error, Result,
+};
+
+/// Bulk IN endpoint of the CMSIS-DAP v2 interface
+pub const ENDPOINT_IN_DAP: u8 = 0x83;
+
+const DAP_INFO: u8 = 0x00;
+const DAP_CONNECT: u8 = 0x02;
+const DAP_DISCONNECT: u8 = 0x03;
+const DAP_TRANSFER_CONFIGURE: u8 = 0x04;
+const DAP_TRANSFER: u8 = 0x05;
+const DAP_SWJ_CLOCK: u8 = 0x11;
+const DAP_SWJ_SEQUENCE: u8 = 0x12;
+
+/// DAP_Connect port
+const DAP_PORT_SWD: u8 = 0x01;
+/// DAP_Transfer ACK
+const DAP_TRANSFER_OK: u8 = 0x01;
+
+/// Transfer request bits
+const APNDP: u8 = 1 << 0;
+const RNW: u8 = 1 << 1;
+
+/// DP registers
+pub const DP_IDCODE: u8 = 0x00;
+pub const DP_ABORT: u8 = 0x00;
+pub const DP_CTRL_STAT: u8 = 0x04;
+pub const DP_SELECT: u8 = 0x08;
+pub const DP_RDBUFF: u8 = 0x0c;
+
+const CTRL_STAT_CDBGPWRUPREQ: u32 = 1 << 28;
+const CTRL_STAT_CDBGPWRUPACK: u32 = 1 << 29;
+const CTRL_STAT_CSYSPWRUPREQ: u32 = 1 << 30;
+const CTRL_STAT_CSYSPWRUPACK: u32 = 1 << 31;
+
+/// MEM-AP registers, in bank 0
+pub const AP_CSW: u8 = 0x00;
+pub const AP_TAR: u8 = 0x04;
+pub const AP_DRW: u8 = 0x0c;
+pub const AP_IDR: u8 = 0xfc;
+
+/// 32-bit access, no auto-increment, with debug SW access enabled
+const CSW_WORD: u32 = 0x2300_0002;
+
+/// Default SWD clock, in Hz
+pub const DEFAULT_SWD_CLOCK: u32 = 1_000_000;
+
+/// DAP_Info IDs
+pub const DAP_INFO_VENDOR: u8 = 0x01;
+pub const DAP_INFO_PRODUCT: u8 = 0x02;
+pub const DAP_INFO_SERIAL: u8 = 0x03;
+pub const DAP_INFO_PROTOCOL_VERSION: u8 = 0x04;
+pub const DAP_INFO_FIRMWARE_VERSION: u8 = 0x09;
+
+/// Probe info reported by DAP_Info, all fields are optional by spec
+#[derive(Debug, Clone, Default)]
+pub struct DapProbeInfo {
+    pub vendor: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub protocol_version: Option<String>,
+    pub firmware_version: Option<String>,
+}
+
+impl fmt::Display for DapProbeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = "unknown".to_string();
+        write!(
+            f,
+            "{} {} CMSIS-DAP v{}, firmware {}, serial {}",
+            self.vendor.as_ref().unwrap_or(&unknown),
+            self.product.as_ref().unwrap_or(&unknown),
+            self.protocol_version.as_ref().unwrap_or(&unknown),
+            self.firmware_version.as_ref().unwrap_or(&unknown),
+            self.serial.as_ref().unwrap_or(&unknown),
+        )
+    }
+}
+
+/// A WCH-Link in DAP mode
+#[derive(Debug)]
+pub struct DapProbe {
+    device: Box<dyn USBDeviceBackend>,
+}
+
+impl DapProbe {
+    pub fn open_nth(nth: usize) -> Result<Self> {
+        let device = usb_device::open_nth(VENDOR_ID_DAP, PRODUCT_ID_DAP, nth)?;
+        Ok(Self { device })
+    }
+
+    /// Send a DAP_Info request, returns None if the info is not available
+    pub fn info_string(&mut self, id: u8) -> Result<Option<String>> {
+        self.device
+            .write_endpoint(ENDPOINT_OUT_DAP, &[DAP_INFO, id])?;
+
+        let mut buf = [0u8; 64];
+        let len = self.device.read_endpoint(ENDPOINT_IN_DAP, &mut buf)?;
+        log::trace!("recv {}", hex::encode(&buf[..len]));
+
+        if len < 2 || buf[0] != DAP_INFO {
+            return Err(Error::InvalidPayload);
+        }
+        let info_len = buf[1] as usize;
+        if info_len == 0 {
+            return Ok(None);
+        }
+        if 2 + info_len > len {
+            return Err(Error::InvalidPayloadLength);
+        }
+        let s = String::from_utf8_lossy(&buf[2..2 + info_len]);
+        Ok(Some(s.trim_end_matches('\0').to_string()))
+    }
+
+    /// Send a DAP command, returns the response, with the command id checked
+    fn command(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        log::trace!("send {}", hex::encode(request));
+        self.device.write_endpoint(ENDPOINT_OUT_DAP, request)?;
+
+        let mut buf = [0u8; 64];
+        let len = self.device.read_endpoint(ENDPOINT_IN_DAP, &mut buf)?;
+        log::trace!("recv {}", hex::encode(&buf[..len]));
+        if len < 2 || buf[0] != request[0] {
+            return Err(Error::InvalidPayload);
+        }
+        Ok(buf[..len].to_vec())
+    }
+
+    /// Connect in SWD mode, switch the line from JTAG, returns the DP IDCODE
+    pub fn connect_swd(&mut self, clock: u32) -> Result<u32> {
+        let resp = self.command(&[DAP_CONNECT, DAP_PORT_SWD])?;
+        if resp[1] != DAP_PORT_SWD {
+            return Err(Error::Custom("probe does not support SWD".to_string()));
+        }
+        let mut req = vec![DAP_SWJ_CLOCK];
+        req.extend_from_slice(&clock.to_le_bytes());
+        self.check_status(&req)?;
+        // idle cycles 0, wait retry 64, match retry 0
+        self.check_status(&[DAP_TRANSFER_CONFIGURE, 0, 64, 0, 0, 0])?;
+
+        // line reset, JTAG-to-SWD sequence 0xE79E, line reset, then idle
+        self.swj_sequence(51, &[0xff; 7])?;
+        self.swj_sequence(16, &[0x9e, 0xe7])?;
+        self.swj_sequence(51, &[0xff; 7])?;
+        self.swj_sequence(8, &[0x00])?;
+
+        let idcode = self.read_dp(DP_IDCODE)?;
+        log::debug!("SWD DP IDCODE: 0x{:08x}", idcode);
+        Ok(idcode)
+    }
+
+    pub fn disconnect(&mut self) -> Result<()> {
+        self.check_status(&[DAP_DISCONNECT])
+    }
+
+    /// Send a command whose response is a single status byte
+    fn check_status(&mut self, request: &[u8]) -> Result<()> {
+        let resp = self.command(request)?;
+        if resp[1] != 0x00 {
+            return Err(Error::Protocol(request[0], resp));
+        }
+        Ok(())
+    }
+
+    fn swj_sequence(&mut self, bits: u8, data: &[u8]) -> Result<()> {
+        let mut req = vec![DAP_SWJ_SEQUENCE, bits];
+        req.extend_from_slice(data);
+        self.check_status(&req)
+    }
+
+    /// Single DAP_Transfer, returns the read value, or 0 for writes
+    fn transfer(&mut self, request: u8, value: Option<u32>) -> Result<u32> {
+        let mut req = vec![DAP_TRANSFER, 0, 1, request];
+        if let Some(value) = value {
+            req.extend_from_slice(&value.to_le_bytes());
+        }
+        let resp = self.command(&req)?;
+        if resp.len() < 3 {
+            return Err(Error::InvalidPayloadLength);
+        }
+        let ack = resp[2] & 0x07;
+        if resp[1] != 1 || ack != DAP_TRANSFER_OK {
+            return Err(Error::SwdTransfer(ack));
+        }
+        if request & RNW == 0 {
+            return Ok(0);
+        }
+        let data = resp.get(3..7).ok_or(Error::InvalidPayloadLength)?;
+        Ok(u32::from_le_bytes(data.try_into().unwrap()))
+    }
+
+    pub fn read_dp(&mut self, reg: u8) -> Result<u32> {
+        self.transfer(RNW | (reg & 0x0c), None)
+    }
+
+    pub fn write_dp(&mut self, reg: u8, value: u32) -> Result<()> {
+        self.transfer(reg & 0x0c, Some(value))?;
+        Ok(())
+    }
+
+    /// Read an AP register, selects the AP and register bank first
+    pub fn read_ap(&mut self, ap: u8, reg: u8) -> Result<u32> {
+        self.select(ap, reg)?;
+        self.transfer(APNDP | RNW | (reg & 0x0c), None)
+    }
+
+    pub fn write_ap(&mut self, ap: u8, reg: u8, value: u32) -> Result<()> {
+        self.select(ap, reg)?;
+        self.transfer(APNDP | (reg & 0x0c), Some(value))?;
+        Ok(())
+    }
+
+    fn select(&mut self, ap: u8, reg: u8) -> Result<()> {
+        self.write_dp(DP_SELECT, ((ap as u32) << 24) | (reg as u32 & 0xf0))
+    }
+
+    /// Power up the debug and system domains, then set up MEM-AP `ap` for word access
+    pub fn init_mem_ap(&mut self, ap: u8) -> Result<()> {
+        // clear sticky errors
+        self.write_dp(DP_ABORT, 0x1e)?;
+        self.write_dp(
+            DP_CTRL_STAT,
+            CTRL_STAT_CDBGPWRUPREQ | CTRL_STAT_CSYSPWRUPREQ,
+        )?;
+        let acks = CTRL_STAT_CDBGPWRUPACK | CTRL_STAT_CSYSPWRUPACK;
+        let mut powered = false;
+        for _ in 0..100 {
+            if self.read_dp(DP_CTRL_STAT)? & acks == acks {
+                powered = true;
+                break;
+            }
+        }
+        if !powered {
+            return Err(Error::Timeout);
+        }
+        let idr = self.read_ap(ap, AP_IDR)?;
+        log::debug!("AP #{} IDR: 0x{:08x}", ap, idr);
+        if idr == 0 {
+            return Err(Error::Custom(format!("AP #{} not present", ap)));
+        }
+        self.write_ap(ap, AP_CSW, CSW_WORD)
+    }
+
+    /// Read a word over MEM-AP `ap`, which should be set up by `init_mem_ap`
+    pub fn read_mem32(&mut self, ap: u8, address: u32) -> Result<u32> {
+        self.write_ap(ap, AP_TAR, address)?;
+        self.read_ap(ap, AP_DRW)
+    }
+
+    pub fn write_mem32(&mut self, ap: u8, address: u32, value: u32) -> Result<()> {
+        self.write_ap(ap, AP_TAR, address)?;
+        self.write_ap(ap, AP_DRW, value)
+    }
+
+    /// Read memory word by word, `length` is rounded up to 4 bytes
+    pub fn read_memory(&mut self, ap: u8, address: u32, length: u32) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(length as usize + 3);
+        for offset in (0..length).step_by(4) {
+            let word = self.read_mem32(ap, address + offset)?;
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        data.truncate(length as usize);
+        Ok(data)
+    }
+
+    pub fn probe_info(&mut self) -> Result<DapProbeInfo> {
+        Ok(DapProbeInfo {
+            vendor: self.info_string(DAP_INFO_VENDOR)?,
+            product: self.info_string(DAP_INFO_PRODUCT)?,
+            serial: self.info_string(DAP_INFO_SERIAL)?,
+            protocol_version: self.info_string(DAP_INFO_PROTOCOL_VERSION)?,
+            firmware_version: self.info_string(DAP_INFO_FIRMWARE_VERSION)?,
+        })
+    }
+}
@@ -0,0 +1,677 @@
+//! Debug Adapter Protocol server, for debugging from VS Code without OpenOCD and gdb.
+//!
+//! `wlink dap-server` attaches the chip, then serves one client over TCP. Point a
+//! VS Code launch configuration at it with `"debugServer": 4711`, the `program`
+//! argument is the ELF file, flashed on `launch` unless `noFlash` is set, and only
+//! read for debug info on `attach`.
+//!
+//! Breakpoints use the hardware triggers of the core, the flash is never patched.
+//! The stack trace walks the frame pointer chain, so firmware should be built with
+//! frame pointers. Locals of the current frame are read by their DWARF location,
+//! optimized out variables are not shown. Stepping runs single instructions until
+//! the source line changes.
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    dmi::DebugModuleInterface,
+    dwarf::{self, FrameBase, Function, LineRow, LocalLocation, Symbol, Variable},
+    firmware::read_firmware_from_file,
+    operations::{FlashBackend, ProbeSession},
+    regs::{self, Dmstatus},
+};
+
+/// Default listen address of `wlink dap-server`
+pub const DEFAULT_DAP_ADDR: &str = "127.0.0.1:4711";
+
+const TSELECT: u16 = 0x7a0;
+const TDATA1: u16 = 0x7a1;
+const TDATA2: u16 = 0x7a2;
+/// mcontrol of an execute trigger: type 2, dmode, enter debug mode, M and U mode
+const MCONTROL_EXECUTE: u32 = (2 << 28) | (1 << 27) | (1 << 12) | (1 << 6) | (1 << 3) | (1 << 2);
+/// Upper bound of the triggers probed
+const MAX_TRIGGERS: u32 = 8;
+
+const DCSR_STEP: u32 = 1 << 2;
+/// Frame pointer, the CFA of RISC-V GCC frames
+const GPR_S0: u16 = 0x1008;
+const GPR_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+const THREAD_ID: u32 = 1;
+const REGISTERS_REF: u32 = 1;
+const GLOBALS_REF: u32 = 2;
+const LOCALS_REF: u32 = 3;
+
+/// Halt check interval of a running target
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Max instructions of a source line step
+const MAX_LINE_STEPS: usize = 1000;
+const STEP_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    seq: i64,
+    command: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+struct LaunchArguments {
+    program: Option<PathBuf>,
+    no_flash: bool,
+    stop_on_entry: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetBreakpointsArguments {
+    source: Source,
+    #[serde(default)]
+    breakpoints: Vec<SourceBreakpoint>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Source {
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SourceBreakpoint {
+    line: u32,
+}
+
+/// Debug info of the program
+#[derive(Debug, Default)]
+struct Program {
+    symbols: Vec<Symbol>,
+    lines: Vec<LineRow>,
+    globals: Vec<Variable>,
+    functions: Vec<Function>,
+}
+
+impl Program {
+    fn load(path: &Path) -> Result<Self> {
+        let elf_data = std::fs::read(path)?;
+        let program = Self {
+            symbols: dwarf::load_symbols(&elf_data)?,
+            lines: dwarf::load_line_rows(&elf_data)?,
+            globals: dwarf::global_variables(&elf_data)?,
+            functions: dwarf::load_functions(&elf_data)?,
+        };
+        log::info!(
+            "Loaded {}: {} functions, {} line rows, {} globals, {} functions with debug info",
+            path.display(),
+            program.symbols.len(),
+            program.lines.len(),
+            program.globals.len(),
+            program.functions.len()
+        );
+        Ok(program)
+    }
+}
+
+/// Serve one debug session over TCP, until the client disconnects
+pub fn serve(sess: &mut ProbeSession, addr: &str) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    log::info!(
+        "Debug adapter for {:?} listening on {}",
+        sess.chip_family,
+        listener.local_addr()?
+    );
+    let (stream, peer) = listener.accept()?;
+    log::info!("Client {} connected", peer);
+    stream.set_nodelay(true)?;
+
+    // requests are read on a thread, so the target can be polled while running
+    let (tx, rx) = mpsc::channel();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    thread::spawn(move || loop {
+        match read_message(&mut reader) {
+            Ok(Some(request)) => {
+                if tx.send(request).is_err() {
+                    break;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                log::warn!("Invalid DAP message: {}", e);
+                break;
+            }
+        }
+    });
+
+    let mut adapter = Adapter::new(stream);
+    loop {
+        let request = if adapter.running {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(request) => Some(request),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        } else {
+            match rx.recv() {
+                Ok(request) => Some(request),
+                Err(_) => break,
+            }
+        };
+        if let Some(request) = request {
+            if !adapter.handle(sess, request)? {
+                return Ok(());
+            }
+        }
+        if adapter.running {
+            adapter.poll_halt(sess)?;
+        }
+    }
+    log::info!("Client {} disconnected", peer);
+    adapter.end_session(sess)
+}
+
+fn read_message(reader: &mut impl BufRead) -> Result<Option<Request>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(len) = line.strip_prefix("Content-Length:") {
+            length = Some(len.trim().parse::<usize>()?);
+        }
+    }
+    let length = length.ok_or_else(|| anyhow::format_err!("message without Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+struct Adapter<W: Write> {
+    writer: W,
+    seq: i64,
+    program: Program,
+    /// Breakpoint addresses by source path
+    breakpoints: BTreeMap<PathBuf, Vec<u32>>,
+    /// Number of execute triggers, probed on first use
+    triggers: Option<u32>,
+    stop_on_entry: bool,
+    running: bool,
+}
+
+impl<W: Write> Adapter<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            seq: 1,
+            program: Program::default(),
+            breakpoints: BTreeMap::new(),
+            triggers: None,
+            stop_on_entry: false,
+            running: false,
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = serde_json::to_vec(&message)?;
+        write!(self.writer, "Content-Length: {}\r\n\r\n", body.len())?;
+        self.writer.write_all(&body)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn stopped(&mut self, reason: &str) -> Result<()> {
+        self.running = false;
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+        )
+    }
+
+    /// Handle a request, returns false when the session ends
+    fn handle(&mut self, sess: &mut ProbeSession, request: Request) -> Result<bool> {
+        log::debug!("DAP request {}: {}", request.command, request.arguments);
+        let result = self.dispatch(sess, &request.command, request.arguments);
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request.seq,
+            "command": request.command,
+            "success": result.is_ok(),
+        });
+        match &result {
+            Ok(body) => response["body"] = body.clone(),
+            Err(e) => {
+                log::warn!("DAP request {} failed: {:#}", request.command, e);
+                response["message"] = json!(format!("{:#}", e));
+            }
+        }
+        self.send(response)?;
+
+        match request.command.as_str() {
+            "initialize" => self.event("initialized", json!({}))?,
+            "configurationDone" if self.stop_on_entry => self.stopped("entry")?,
+            "next" | "stepIn" if result.is_ok() => self.stopped("step")?,
+            "pause" if result.is_ok() => self.stopped("pause")?,
+            "disconnect" => {
+                self.end_session(sess)?;
+                return Ok(false);
+            }
+            _ => (),
+        }
+        Ok(true)
+    }
+
+    fn dispatch(&mut self, sess: &mut ProbeSession, command: &str, args: Value) -> Result<Value> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsTerminateRequest": false,
+            })),
+            "launch" => {
+                let args: LaunchArguments = serde_json::from_value(args)?;
+                if let Some(path) = &args.program {
+                    if !args.no_flash {
+                        let firmware = read_firmware_from_file(path)?;
+                        sess.write_firmware(&firmware, None, FlashBackend::default())?;
+                    }
+                    self.program = Program::load(path)?;
+                }
+                sess.reset_and_halt()?;
+                self.stop_on_entry = args.stop_on_entry;
+                Ok(json!({}))
+            }
+            "attach" => {
+                let args: LaunchArguments = serde_json::from_value(args)?;
+                if let Some(path) = &args.program {
+                    self.program = Program::load(path)?;
+                }
+                sess.ensure_mcu_halt()?;
+                self.stop_on_entry = true;
+                Ok(json!({}))
+            }
+            "setBreakpoints" => {
+                let args: SetBreakpointsArguments = serde_json::from_value(args)?;
+                self.set_breakpoints(sess, args)
+            }
+            "configurationDone" => {
+                if !self.stop_on_entry {
+                    self.resume(sess)?;
+                }
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({
+                "threads": [{ "id": THREAD_ID, "name": format!("{:?}", sess.chip_family) }]
+            })),
+            "stackTrace" => self.stack_trace(sess),
+            "scopes" => {
+                let mut scopes = vec![json!({
+                    "name": "Registers",
+                    "variablesReference": REGISTERS_REF,
+                    "expensive": false,
+                })];
+                // registers of caller frames are not unwound, locals of the current frame only
+                if args["frameId"].as_u64() == Some(0) && !self.program.functions.is_empty() {
+                    scopes.insert(
+                        0,
+                        json!({
+                            "name": "Locals",
+                            "variablesReference": LOCALS_REF,
+                            "expensive": false,
+                        }),
+                    );
+                }
+                if !self.program.globals.is_empty() {
+                    scopes.push(json!({
+                        "name": "Globals",
+                        "variablesReference": GLOBALS_REF,
+                        "expensive": true,
+                    }));
+                }
+                Ok(json!({ "scopes": scopes }))
+            }
+            "variables" => {
+                let reference = args["variablesReference"].as_u64().unwrap_or(0) as u32;
+                let variables = match reference {
+                    REGISTERS_REF => self.registers(sess)?,
+                    GLOBALS_REF => self.globals(sess)?,
+                    LOCALS_REF => self.locals(sess)?,
+                    _ => vec![],
+                };
+                Ok(json!({ "variables": variables }))
+            }
+            "continue" => {
+                self.resume(sess)?;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "pause" => {
+                sess.ensure_mcu_halt()?;
+                self.running = false;
+                Ok(json!({}))
+            }
+            "next" | "stepIn" => {
+                self.step_line(sess)?;
+                Ok(json!({}))
+            }
+            "disconnect" => Ok(json!({})),
+            _ => anyhow::bail!("unsupported request {}", command),
+        }
+    }
+
+    fn set_breakpoints(
+        &mut self,
+        sess: &mut ProbeSession,
+        args: SetBreakpointsArguments,
+    ) -> Result<Value> {
+        let path = args
+            .source
+            .path
+            .ok_or_else(|| anyhow::format_err!("breakpoint source without path"))?;
+        let mut addresses = vec![];
+        let mut breakpoints = vec![];
+        for bp in &args.breakpoints {
+            match dwarf::find_line_address(&self.program.lines, &path, bp.line) {
+                Some((address, line)) => {
+                    addresses.push(address);
+                    breakpoints.push(json!({ "verified": true, "line": line }));
+                }
+                None => breakpoints.push(json!({
+                    "verified": false,
+                    "line": bp.line,
+                    "message": "no code at this line",
+                })),
+            }
+        }
+        self.breakpoints.insert(path.clone(), addresses);
+
+        let used = self.program_triggers(sess)?;
+        // triggers are assigned in path order, mark the breakpoints left without one
+        let before: usize = self
+            .breakpoints
+            .range(..path)
+            .map(|(_, addresses)| addresses.len())
+            .sum();
+        let verified = breakpoints.iter_mut().filter(|bp| bp["verified"] == true);
+        for (index, bp) in verified.enumerate() {
+            if before + index >= used {
+                bp["verified"] = json!(false);
+                bp["message"] = json!("no free hardware breakpoint");
+            }
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Load all breakpoint addresses to the triggers, returns the number loaded
+    fn program_triggers(&mut self, sess: &mut ProbeSession) -> Result<usize> {
+        let was_running = !sess.probe.read_dmi_reg::<Dmstatus>()?.allhalted();
+        sess.ensure_mcu_halt()?;
+        let count = match self.triggers {
+            Some(count) => count,
+            None => {
+                let count = count_triggers(sess)?;
+                log::info!("{} hardware breakpoints", count);
+                self.triggers = Some(count);
+                count
+            }
+        };
+        let addresses: Vec<u32> = self.breakpoints.values().flatten().copied().collect();
+        if addresses.len() > count as usize {
+            log::warn!(
+                "{} breakpoints, only {} hardware breakpoints",
+                addresses.len(),
+                count
+            );
+        }
+        for index in 0..count {
+            sess.write_reg(TSELECT, index)?;
+            sess.write_reg(TDATA1, 0)?;
+            if let Some(&address) = addresses.get(index as usize) {
+                sess.write_reg(TDATA2, address)?;
+                sess.write_reg(TDATA1, MCONTROL_EXECUTE)?;
+            }
+        }
+        if was_running {
+            sess.ensure_mcu_resume()?;
+        }
+        Ok(addresses.len().min(count as usize))
+    }
+
+    fn resume(&mut self, sess: &mut ProbeSession) -> Result<()> {
+        sess.ensure_mcu_resume()?;
+        self.running = true;
+        Ok(())
+    }
+
+    fn poll_halt(&mut self, sess: &mut ProbeSession) -> Result<()> {
+        if !sess.probe.read_dmi_reg::<Dmstatus>()?.allhalted() {
+            return Ok(());
+        }
+        let dcsr = sess.read_reg(regs::DCSR)?;
+        let reason = match (dcsr >> 6) & 0b111 {
+            1 | 2 => "breakpoint",
+            3 => "pause",
+            4 => "step",
+            _ => "exception",
+        };
+        self.stopped(reason)
+    }
+
+    /// Step instructions until the source line changes
+    fn step_line(&mut self, sess: &mut ProbeSession) -> Result<()> {
+        let pc = sess.read_reg(regs::DPC)?;
+        let start = dwarf::lookup_line(&self.program.lines, pc).map(|row| (row.line, &row.path));
+        for _ in 0..MAX_LINE_STEPS {
+            step_instruction(sess)?;
+            if start.is_none() {
+                break;
+            }
+            let pc = sess.read_reg(regs::DPC)?;
+            let line = dwarf::lookup_line(&self.program.lines, pc).map(|row| (row.line, &row.path));
+            if line.is_some() && line != start {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn stack_trace(&mut self, sess: &mut ProbeSession) -> Result<Value> {
+        let pc = sess.read_reg(regs::DPC)?;
+        let fp = sess.read_reg(GPR_S0)?;
+        let frames: Vec<Value> = sess
+            .backtrace(pc, fp)?
+            .into_iter()
+            .enumerate()
+            .map(|(i, addr)| {
+                let name = match dwarf::lookup_symbol(&self.program.symbols, addr) {
+                    Some((sym, 0)) => sym.name.clone(),
+                    Some((sym, offset)) => format!("{}+0x{:x}", sym.name, offset),
+                    None => format!("0x{:08x}", addr),
+                };
+                let mut frame = json!({
+                    "id": i,
+                    "name": name,
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": format!("0x{:08x}", addr),
+                });
+                // return addresses are after the call, the line of the call is before
+                let line_addr = if i == 0 { addr } else { addr - 1 };
+                if let Some(row) = dwarf::lookup_line(&self.program.lines, line_addr) {
+                    frame["line"] = json!(row.line);
+                    frame["source"] = json!({ "path": row.path });
+                }
+                frame
+            })
+            .collect();
+        Ok(json!({ "totalFrames": frames.len(), "stackFrames": frames }))
+    }
+
+    fn registers(&mut self, sess: &mut ProbeSession) -> Result<Vec<Value>> {
+        let mut variables = vec![json!({
+            "name": "pc",
+            "value": format!("0x{:08x}", sess.read_reg(regs::DPC)?),
+            "variablesReference": 0,
+        })];
+        for (i, name) in GPR_NAMES.iter().enumerate().skip(1) {
+            let value = sess.read_reg(0x1000 + i as u16)?;
+            variables.push(json!({
+                "name": name,
+                "value": format!("0x{:08x}", value),
+                "variablesReference": 0,
+            }));
+        }
+        Ok(variables)
+    }
+
+    fn globals(&mut self, sess: &mut ProbeSession) -> Result<Vec<Value>> {
+        let globals = &self.program.globals;
+        sess.preserving_scratch_gprs(|sess| {
+            Ok(globals
+                .iter()
+                .map(|var| {
+                    json!({
+                        "name": var.name,
+                        "value": read_variable(sess, var.address, &var.ty),
+                        "memoryReference": format!("0x{:08x}", var.address),
+                        "variablesReference": 0,
+                    })
+                })
+                .collect())
+        })
+    }
+
+    /// Parameters and locals of the function at pc
+    fn locals(&mut self, sess: &mut ProbeSession) -> Result<Vec<Value>> {
+        let pc = sess.read_reg(regs::DPC)?;
+        let Some(function) = dwarf::lookup_function(&self.program.functions, pc) else {
+            return Ok(vec![]);
+        };
+
+        // registers first, memory access clobbers the scratch GPRs
+        let frame_base = match function.frame_base {
+            Some(FrameBase::Cfa) => Some(sess.read_reg(GPR_S0)?),
+            Some(FrameBase::Register(reg)) => Some(sess.read_reg(0x1000 + reg)?),
+            None => None,
+        };
+        let mut gprs = BTreeMap::new();
+        for var in &function.locals {
+            if let LocalLocation::Register(reg) | LocalLocation::RegisterOffset(reg, _) =
+                var.location
+            {
+                gprs.insert(reg, 0);
+            }
+        }
+        for (&reg, value) in gprs.iter_mut() {
+            *value = sess.read_reg(0x1000 + reg)?;
+        }
+
+        sess.preserving_scratch_gprs(|sess| {
+            Ok(function
+                .locals
+                .iter()
+                .map(|var| {
+                    let address = match var.location {
+                        LocalLocation::Register(reg) => {
+                            let value = var.ty.decode(&gprs[&reg].to_le_bytes());
+                            return json!({
+                                "name": var.name,
+                                "value": value,
+                                "variablesReference": 0,
+                            });
+                        }
+                        LocalLocation::RegisterOffset(reg, offset) => {
+                            Some(gprs[&reg].wrapping_add(offset as u32))
+                        }
+                        LocalLocation::FrameOffset(offset) => {
+                            frame_base.map(|base| base.wrapping_add(offset as u32))
+                        }
+                    };
+                    match address {
+                        Some(address) => json!({
+                            "name": var.name,
+                            "value": read_variable(sess, address, &var.ty),
+                            "memoryReference": format!("0x{:08x}", address),
+                            "variablesReference": 0,
+                        }),
+                        None => json!({
+                            "name": var.name,
+                            "value": "<no frame base>",
+                            "variablesReference": 0,
+                        }),
+                    }
+                })
+                .collect())
+        })
+    }
+
+    /// Clear the breakpoints and let the target run
+    fn end_session(&mut self, sess: &mut ProbeSession) -> Result<()> {
+        if self.triggers.is_some() {
+            self.breakpoints.clear();
+            self.program_triggers(sess)?;
+        }
+        sess.ensure_mcu_resume()?;
+        Ok(())
+    }
+}
+
+/// Read and decode a variable in memory, the error as value if the read fails
+fn read_variable(sess: &mut ProbeSession, address: u32, ty: &dwarf::VarType) -> String {
+    match ty.size() {
+        0 => "?".to_string(),
+        size => match sess.peek_memory(address, size) {
+            Ok(data) => ty.decode(&data),
+            Err(e) => format!("<{}>", e),
+        },
+    }
+}
+
+/// Count the triggers supporting execute address match
+fn count_triggers(sess: &mut ProbeSession) -> Result<u32> {
+    let mut count = 0;
+    while count < MAX_TRIGGERS {
+        sess.write_reg(TSELECT, count)?;
+        if sess.read_reg(TSELECT)? != count || sess.read_reg(TDATA1)? >> 28 != 2 {
+            break;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn step_instruction(sess: &mut ProbeSession) -> Result<()> {
+    let dcsr = sess.read_reg(regs::DCSR)?;
+    sess.write_reg(regs::DCSR, dcsr | DCSR_STEP)?;
+    sess.ensure_mcu_resume()?;
+    let deadline = Instant::now() + STEP_TIMEOUT;
+    while !sess.probe.read_dmi_reg::<Dmstatus>()?.allhalted() {
+        if Instant::now() >= deadline {
+            sess.ensure_mcu_halt()?;
+            break;
+        }
+    }
+    sess.write_reg(regs::DCSR, dcsr & !DCSR_STEP)?;
+    Ok(())
+}
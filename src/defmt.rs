@@ -0,0 +1,635 @@
+//! defmt log decoding, for firmware using a defmt logger over SDI print.
+//!
+//! Format strings are interned in the `.defmt` section of the ELF file, a symbol
+//! per string, named in JSON, with the string index as its address. The target
+//! sends rzCOBS encoded frames, separated by 0x00: the index of the format string,
+//! the timestamp, then the arguments.
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use defmt_parser::{DisplayHint, Fragment, Level, Parameter, ParserMode, TimePrecision, Type};
+use object::{Object, ObjectSection, ObjectSymbol};
+use serde::Deserialize;
+
+/// Max nesting of `Format` values to decode
+const MAX_FORMAT_DEPTH: usize = 16;
+
+/// Name of a symbol in the `.defmt` section
+#[derive(Debug, Deserialize)]
+struct SymbolName {
+    tag: String,
+    data: String,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    tag: String,
+    format: String,
+}
+
+/// A decoded log message
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// None for `println!`
+    pub level: Option<Level>,
+    pub timestamp: Option<String>,
+    pub text: String,
+}
+
+/// Interned strings of an ELF file
+#[derive(Debug, Clone)]
+pub struct Table {
+    entries: HashMap<u16, Entry>,
+    timestamp: Option<Entry>,
+}
+
+impl Table {
+    /// Load the defmt table, fails if the ELF file does not use defmt
+    pub fn from_elf(elf_data: &[u8]) -> Result<Self> {
+        let file = object::File::parse(elf_data)?;
+        let section = file
+            .section_by_name(".defmt")
+            .context("no .defmt section, the firmware does not use defmt")?;
+
+        let mut entries = HashMap::new();
+        let mut timestamp = None;
+        for sym in file.symbols() {
+            if sym.section_index() != Some(section.index()) {
+                continue;
+            }
+            let Ok(name) = sym.name() else { continue };
+            let Ok(name) = serde_json::from_str::<SymbolName>(name) else {
+                continue;
+            };
+            let entry = Entry {
+                tag: name.tag,
+                format: name.data,
+            };
+            if entry.tag == "defmt_timestamp" {
+                timestamp = Some(entry);
+            } else {
+                entries.insert(sym.address() as u16, entry);
+            }
+        }
+        log::debug!("Loaded {} defmt strings", entries.len());
+        Ok(Self { entries, timestamp })
+    }
+
+    fn entry(&self, index: u16) -> Result<&Entry> {
+        self.entries
+            .get(&index)
+            .with_context(|| format!("unknown defmt string index {}", index))
+    }
+
+    /// Decode an rzCOBS decoded frame
+    pub fn decode_frame(&self, frame: &[u8]) -> Result<Message> {
+        let mut r = Reader { data: frame };
+        let entry = self.entry(r.u16()?)?;
+        let level = match entry.tag.as_str() {
+            "defmt_trace" => Some(Level::Trace),
+            "defmt_debug" => Some(Level::Debug),
+            "defmt_info" => Some(Level::Info),
+            "defmt_warn" => Some(Level::Warn),
+            "defmt_error" => Some(Level::Error),
+            "defmt_println" => None,
+            tag => anyhow::bail!("unexpected defmt frame of tag {}", tag),
+        };
+        let timestamp = match &self.timestamp {
+            Some(ts) => Some(self.format(&ts.format, &mut r, 0)?),
+            None => None,
+        };
+        let text = self.format(&entry.format, &mut r, 0)?;
+        Ok(Message {
+            level,
+            timestamp,
+            text,
+        })
+    }
+
+    /// Decode the data of a `Format` value, by its interned format string
+    fn format_entry(&self, entry: &Entry, r: &mut Reader, depth: usize) -> Result<String> {
+        if entry.tag == "defmt_derived" {
+            let variants = split_variants(&entry.format);
+            if variants.len() > 1 {
+                let discriminant = match variants.len() {
+                    2..=0xff => r.u8()? as usize,
+                    0x100..=0xffff => r.u16()? as usize,
+                    _ => r.u32()? as usize,
+                };
+                let variant = variants
+                    .get(discriminant)
+                    .with_context(|| format!("invalid enum discriminant {}", discriminant))?;
+                return self.format(variant, r, depth);
+            }
+        }
+        self.format(&entry.format, r, depth)
+    }
+
+    fn format(&self, format: &str, r: &mut Reader, depth: usize) -> Result<String> {
+        if depth > MAX_FORMAT_DEPTH {
+            anyhow::bail!("defmt values nested too deep");
+        }
+        let fragments = defmt_parser::parse(format, ParserMode::ForwardsCompatible)
+            .map_err(|e| anyhow::format_err!("invalid defmt format {:?}: {}", format, e))?;
+
+        // arguments are encoded in index order, bitfields of the same index share one
+        let mut params: BTreeMap<usize, Vec<&Parameter>> = BTreeMap::new();
+        for frag in &fragments {
+            if let Fragment::Parameter(param) = frag {
+                params.entry(param.index).or_default().push(param);
+            }
+        }
+        let mut args = BTreeMap::new();
+        for (index, params) in params {
+            let arg = match &params[0].ty {
+                Type::BitField(_) => {
+                    let start = params.iter().map(|p| bit_range(p).0).min().unwrap();
+                    let end = params.iter().map(|p| bit_range(p).1).max().unwrap();
+                    let lowest_byte = start / 8;
+                    let highest_byte = (end - 1) / 8;
+                    let bytes = r.bytes((highest_byte - lowest_byte + 1) as usize)?;
+                    let mut buf = [0u8; 16];
+                    buf[..bytes.len()].copy_from_slice(bytes);
+                    Arg::Uint(u128::from_le_bytes(buf) << (lowest_byte * 8))
+                }
+                ty => self.decode_arg(ty, r, depth)?,
+            };
+            args.insert(index, arg);
+        }
+
+        let mut out = String::new();
+        for frag in &fragments {
+            match frag {
+                Fragment::Literal(s) => out.push_str(s),
+                Fragment::Parameter(param) => {
+                    let arg = &args[&param.index];
+                    match &param.ty {
+                        Type::BitField(range) => {
+                            let Arg::Uint(v) = arg else { unreachable!() };
+                            let width = (range.end - range.start) as u32;
+                            let v = (v >> range.start) & (u128::MAX >> (128 - width));
+                            out.push_str(&format_uint(v, param.hint.as_ref()));
+                        }
+                        _ => out.push_str(&arg.render(param.hint.as_ref())),
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    fn decode_arg(&self, ty: &Type, r: &mut Reader, depth: usize) -> Result<Arg> {
+        let arg = match ty {
+            Type::U8 => Arg::Uint(r.u8()? as u128),
+            Type::U16 => Arg::Uint(r.u16()? as u128),
+            Type::U32 | Type::Usize => Arg::Uint(r.u32()? as u128),
+            Type::U64 => Arg::Uint(r.u64()? as u128),
+            Type::U128 => Arg::Uint(r.u128()?),
+            Type::I8 => Arg::Int(r.u8()? as i8 as i128, 1),
+            Type::I16 => Arg::Int(r.u16()? as i16 as i128, 2),
+            Type::I32 | Type::Isize => Arg::Int(r.u32()? as i32 as i128, 4),
+            Type::I64 => Arg::Int(r.u64()? as i64 as i128, 8),
+            Type::I128 => Arg::Int(r.u128()? as i128, 16),
+            Type::F32 => Arg::Float(f32::from_bits(r.u32()?) as f64),
+            Type::F64 => Arg::Float(f64::from_bits(r.u64()?)),
+            Type::Bool => Arg::Bool(r.u8()? != 0),
+            Type::Char => Arg::Char(char::from_u32(r.u32()?).unwrap_or('\u{fffd}')),
+            Type::Str => {
+                let len = r.u32()? as usize;
+                Arg::Str(String::from_utf8_lossy(r.bytes(len)?).into_owned())
+            }
+            Type::IStr => Arg::Str(self.entry(r.u16()?)?.format.clone()),
+            Type::U8Slice => {
+                let len = r.u32()? as usize;
+                Arg::Bytes(r.bytes(len)?.to_vec())
+            }
+            Type::U8Array(len) => Arg::Bytes(r.bytes(*len)?.to_vec()),
+            Type::Debug | Type::Display => {
+                let len = r
+                    .data
+                    .iter()
+                    .position(|&b| b == 0xff)
+                    .context("unterminated defmt string")?;
+                let s = String::from_utf8_lossy(r.bytes(len)?).into_owned();
+                r.bytes(1)?;
+                Arg::Formatted(s)
+            }
+            Type::Format => {
+                let entry = self.entry(r.u16()?)?;
+                Arg::Formatted(self.format_entry(entry, r, depth + 1)?)
+            }
+            Type::FormatSlice | Type::FormatArray(_) => {
+                let len = match ty {
+                    Type::FormatArray(len) => *len,
+                    _ => r.u32()? as usize,
+                };
+                let entry = self.entry(r.u16()?)?;
+                let items = (0..len)
+                    .map(|_| self.format_entry(entry, r, depth + 1))
+                    .collect::<Result<Vec<_>>>()?;
+                Arg::Formatted(format!("[{}]", items.join(", ")))
+            }
+            Type::FormatSequence => {
+                let mut s = String::new();
+                loop {
+                    let index = r.u16()?;
+                    if index == 0 {
+                        break;
+                    }
+                    s.push_str(&self.format_entry(self.entry(index)?, r, depth + 1)?);
+                }
+                Arg::Formatted(s)
+            }
+            Type::BitField(_) => unreachable!("decoded by the caller"),
+        };
+        Ok(arg)
+    }
+}
+
+/// Split variants of a derived enum format, like `None|Some({=?})`
+fn split_variants(format: &str) -> Vec<&str> {
+    let mut variants = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in format.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '|' if depth == 0 => {
+                variants.push(&format[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    variants.push(&format[start..]);
+    variants
+}
+
+fn bit_range(param: &Parameter) -> (u8, u8) {
+    match &param.ty {
+        Type::BitField(range) => (range.start, range.end),
+        _ => unreachable!(),
+    }
+}
+
+/// A decoded argument
+#[derive(Debug)]
+enum Arg {
+    Uint(u128),
+    /// Value and size in bytes
+    Int(i128, u32),
+    Float(f64),
+    Bool(bool),
+    Char(char),
+    Str(String),
+    Bytes(Vec<u8>),
+    /// Already formatted by the target, or a nested `Format` value
+    Formatted(String),
+}
+
+impl Arg {
+    fn render(&self, hint: Option<&DisplayHint>) -> String {
+        match self {
+            Arg::Uint(v) => format_uint(*v, hint),
+            Arg::Int(v, size) => match hint {
+                Some(DisplayHint::Hexadecimal { .. })
+                | Some(DisplayHint::Binary { .. })
+                | Some(DisplayHint::Octal { .. }) => {
+                    // two's complement of the original size
+                    let mask = u128::MAX >> (128 - size * 8);
+                    format_uint(*v as u128 & mask, hint)
+                }
+                Some(DisplayHint::NoHint { zero_pad }) => format!("{:01$}", v, zero_pad),
+                _ => v.to_string(),
+            },
+            Arg::Float(v) => v.to_string(),
+            Arg::Bool(v) => v.to_string(),
+            Arg::Char(c) => match hint {
+                Some(DisplayHint::Debug) => format!("{:?}", c),
+                _ => c.to_string(),
+            },
+            Arg::Str(s) => match hint {
+                Some(DisplayHint::Debug) => format!("{:?}", s),
+                _ => s.clone(),
+            },
+            Arg::Bytes(bytes) => match hint {
+                Some(DisplayHint::Ascii) => {
+                    let s: String = bytes
+                        .iter()
+                        .flat_map(|&b| std::ascii::escape_default(b))
+                        .map(char::from)
+                        .collect();
+                    format!("b\"{}\"", s)
+                }
+                _ => {
+                    let items: Vec<_> = bytes
+                        .iter()
+                        .map(|&b| format_uint(b as u128, hint))
+                        .collect();
+                    format!("[{}]", items.join(", "))
+                }
+            },
+            Arg::Formatted(s) => s.clone(),
+        }
+    }
+}
+
+fn format_uint(v: u128, hint: Option<&DisplayHint>) -> String {
+    match hint {
+        Some(DisplayHint::Hexadecimal {
+            alternate,
+            uppercase,
+            zero_pad,
+        }) => match (alternate, uppercase) {
+            (false, false) => format!("{:01$x}", v, zero_pad),
+            (false, true) => format!("{:01$X}", v, zero_pad),
+            (true, false) => format!("{:#01$x}", v, zero_pad),
+            (true, true) => format!("{:#01$X}", v, zero_pad),
+        },
+        Some(DisplayHint::Binary {
+            alternate,
+            zero_pad,
+        }) => match alternate {
+            false => format!("{:01$b}", v, zero_pad),
+            true => format!("{:#01$b}", v, zero_pad),
+        },
+        Some(DisplayHint::Octal {
+            alternate,
+            zero_pad,
+        }) => match alternate {
+            false => format!("{:01$o}", v, zero_pad),
+            true => format!("{:#01$o}", v, zero_pad),
+        },
+        Some(DisplayHint::NoHint { zero_pad }) => format!("{:01$}", v, zero_pad),
+        Some(DisplayHint::Seconds(precision))
+        | Some(DisplayHint::Time(precision))
+        | Some(DisplayHint::ISO8601(precision)) => match precision {
+            TimePrecision::Micros => format!("{}.{:06}", v / 1_000_000, v % 1_000_000),
+            TimePrecision::Millis => format!("{}.{:03}", v / 1_000, v % 1_000),
+            TimePrecision::Seconds => v.to_string(),
+        },
+        _ => v.to_string(),
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            anyhow::bail!("truncated defmt frame");
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn u128(&mut self) -> Result<u128> {
+        Ok(u128::from_le_bytes(self.bytes(16)?.try_into().unwrap()))
+    }
+}
+
+/// Decode an rzCOBS frame, without the 0x00 separator.
+///
+/// The encoding runs backwards from the end of the frame, trailing zeros of the
+/// result are padding, ignored by the frame decoder.
+pub fn rzcobs_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let mut res = vec![];
+    let mut data = data.iter().rev().copied();
+    while let Some(x) = data.next() {
+        match x {
+            0x00 => anyhow::bail!("corrupt rzCOBS frame"),
+            0x01..=0x7f => {
+                for i in 0..7 {
+                    if x & (1 << (6 - i)) == 0 {
+                        res.push(data.next().context("corrupt rzCOBS frame")?);
+                    } else {
+                        res.push(0);
+                    }
+                }
+            }
+            0x80..=0xfe => {
+                res.push(0);
+                for _ in 0..(x & 0x7f) + 7 {
+                    res.push(data.next().context("corrupt rzCOBS frame")?);
+                }
+            }
+            0xff => {
+                for _ in 0..134 {
+                    res.push(data.next().context("corrupt rzCOBS frame")?);
+                }
+            }
+        }
+    }
+    res.reverse();
+    Ok(res)
+}
+
+/// Stream decoder, splits frames from the serial byte stream
+#[derive(Debug)]
+pub struct Decoder<'a> {
+    table: &'a Table,
+    buf: Vec<u8>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(table: &'a Table) -> Self {
+        Self { table, buf: vec![] }
+    }
+
+    /// Feed received bytes, returns the messages of all completed frames
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Result<Message>> {
+        let mut messages = vec![];
+        for &b in data {
+            if b != 0x00 {
+                self.buf.push(b);
+                continue;
+            }
+            if self.buf.is_empty() {
+                continue;
+            }
+            let frame = std::mem::take(&mut self.buf);
+            messages.push(rzcobs_decode(&frame).and_then(|f| self.table.decode_frame(&f)));
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// rzCOBS encoder of the defmt crate's `encoding-rzcobs`, frames as sent by a target
+    struct Encoder {
+        run: u8,
+        zeros: u8,
+    }
+
+    impl Encoder {
+        fn encode(frame: &[u8]) -> Vec<u8> {
+            let mut out = vec![];
+            let mut e = Encoder { run: 0, zeros: 0 };
+            for &byte in frame {
+                if e.run < 7 {
+                    if byte == 0 {
+                        e.zeros |= 1 << e.run;
+                    } else {
+                        out.push(byte);
+                    }
+                    e.run += 1;
+                    if e.run == 7 && e.zeros != 0x00 {
+                        out.push(e.zeros);
+                        e.run = 0;
+                        e.zeros = 0;
+                    }
+                } else if byte == 0 {
+                    out.push((e.run - 7) | 0x80);
+                    e.run = 0;
+                    e.zeros = 0;
+                } else {
+                    out.push(byte);
+                    e.run += 1;
+                    if e.run == 134 {
+                        out.push(0xff);
+                        e.run = 0;
+                        e.zeros = 0;
+                    }
+                }
+            }
+            match e.run {
+                0 => {}
+                1..=6 => out.push((e.zeros | (0xff << e.run)) & 0x7f),
+                _ => out.push((e.run - 7) | 0x80),
+            }
+            out.push(0x00);
+            out
+        }
+    }
+
+    fn table(entries: &[(u16, &str, &str)]) -> Table {
+        Table {
+            entries: entries
+                .iter()
+                .map(|&(index, tag, format)| {
+                    let entry = Entry {
+                        tag: tag.to_string(),
+                        format: format.to_string(),
+                    };
+                    (index, entry)
+                })
+                .collect(),
+            timestamp: None,
+        }
+    }
+
+    fn assert_decodes(encoded: &[u8], frame: &[u8]) {
+        let decoded = rzcobs_decode(&encoded[..encoded.len() - 1]).unwrap();
+        // zeros at the end are padding of the last group
+        assert_eq!(&decoded[..frame.len()], frame, "{:02x?}", encoded);
+        assert!(decoded[frame.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn rzcobs_reference_vectors() {
+        // test vectors of the defmt encoder
+        let vectors: &[(&[u8], &[u8])] = &[
+            (&[0x00], &[0x7f, 0x00]),
+            (&[0x00; 8], &[0x7f, 0x7f, 0x00]),
+            (&[0x01], &[0x01, 0x7e, 0x00]),
+            (&[0x00, 0x01], &[0x01, 0x7d, 0x00]),
+            (&[0x01, 0x02], &[0x01, 0x02, 0x7c, 0x00]),
+            (
+                &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x00],
+                &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x40, 0x00],
+            ),
+            (
+                &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88],
+                &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x81, 0x00],
+            ),
+            (
+                &[0, 0, 0, 0, 0, 0x44, 0, 0, 0, 0, 0, 0, 0, 0xff],
+                &[0x44, 0x5f, 0xff, 0x3f, 0x00],
+            ),
+        ];
+        for (frame, encoded) in vectors {
+            assert_eq!(&Encoder::encode(frame), encoded);
+            assert_decodes(encoded, frame);
+        }
+    }
+
+    #[test]
+    fn rzcobs_long_runs() {
+        for len in [133, 134, 135, 300] {
+            let frame: Vec<u8> = (0..len).map(|i| (i % 255) as u8 + 1).collect();
+            assert_decodes(&Encoder::encode(&frame), &frame);
+            let mut frame = frame;
+            frame.push(0);
+            frame.push(0x42);
+            assert_decodes(&Encoder::encode(&frame), &frame);
+        }
+    }
+
+    #[test]
+    fn decode_encoded_frames() {
+        let table = table(&[
+            (1, "defmt_info", "x={=u8} y={=u32:#x}"),
+            (2, "defmt_println", "{=str}!"),
+            (3, "defmt_warn", "v={=i16} ok={=bool}"),
+        ]);
+        let mut stream = vec![0x00];
+        // info!("x={=u8} y={=u32:#x}", 5, 0x1000)
+        stream.extend(Encoder::encode(&[0x01, 0x00, 0x05, 0x00, 0x10, 0x00, 0x00]));
+        // println!("{=str}!", "hi")
+        stream.extend(Encoder::encode(&[0x02, 0x00, 2, 0, 0, 0, b'h', b'i']));
+        // warn!("v={=i16} ok={=bool}", -2, false)
+        stream.extend(Encoder::encode(&[0x03, 0x00, 0xfe, 0xff, 0x00]));
+
+        let mut decoder = Decoder::new(&table);
+        // split the stream like serial reads do
+        let (a, b) = stream.split_at(5);
+        let messages: Vec<Message> = decoder
+            .feed(a)
+            .into_iter()
+            .chain(decoder.feed(b))
+            .collect::<Result<_>>()
+            .unwrap();
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].level, Some(Level::Info));
+        assert_eq!(messages[0].text, "x=5 y=0x1000");
+        assert_eq!(messages[1].level, None);
+        assert_eq!(messages[1].text, "hi!");
+        assert_eq!(messages[2].level, Some(Level::Warn));
+        assert_eq!(messages[2].text, "v=-2 ok=false");
+    }
+
+    #[test]
+    fn corrupt_frames() {
+        assert!(rzcobs_decode(&[0x01, 0x02, 0x7d]).is_err());
+        assert!(rzcobs_decode(&[0x81]).is_err());
+        let table = table(&[]);
+        let frame = Encoder::encode(&[0x09, 0x00]);
+        assert!(table
+            .decode_frame(&rzcobs_decode(&frame[..frame.len() - 1]).unwrap())
+            .is_err());
+    }
+}
@@ -6,11 +6,39 @@
 use crate::{
     commands::DmiOp,
     error::{AbstractcsCmdErr, Error, Result},
+    observer::Event,
     operations::ProbeSession,
     probe::WchLink,
-    regs::{self, Abstractcs, DMReg, Dmcontrol, Dmstatus},
+    progress::ProgressBar,
+    regs::{self, Abstractcs, DMReg, Dmcontrol, Dmstatus, Sbcs},
+    RiscvChip,
 };
-use std::{thread, time::Duration};
+use serde::Serialize;
+use std::{
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+/// Max times to recover from target resets in one memory read
+pub const MAX_RESET_RECOVERIES: usize = 3;
+
+/// Target routines are loaded at the start of SRAM
+pub const ROUTINE_LOAD_ADDRESS: u32 = 0x2000_0000;
+/// Stack of a target routine, right after the code
+const ROUTINE_STACK_SIZE: u32 = 0x200;
+/// Max run time of a target routine
+pub const ROUTINE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Max time an abstract command may stay busy
+const ABSTRACT_COMMAND_TIMEOUT: Duration = Duration::from_millis(100);
+/// Max stack frames to walk in a backtrace
+const MAX_BACKTRACE_DEPTH: usize = 32;
+
+/// Enter debug mode on `ebreak` in M mode
+pub(crate) const DCSR_EBREAKM: u32 = 1 << 15;
+/// x5, x6 and x7, clobbered by memory access over DMI
+pub(crate) const SCRATCH_GPRS: [u16; 3] = [0x1005, 0x1006, 0x1007];
+const MSTATUS_MIE: u32 = 1 << 3;
+const EBREAK: u32 = 0x00100073;
 
 // FPEC, OPTWRE to unlock,
 pub const KEY1: u32 = 0x45670123;
@@ -55,14 +83,15 @@ impl DebugModuleInterface for WchLink {
             }
             if resp.is_success() {
                 return Ok(resp.data);
-            } else if n > 100 {
+            } else if n > self.dmi_retries {
                 return Err(Error::Timeout);
             } else if resp.is_busy() {
                 log::warn!("dmi_read: busy, retrying");
+                self.emit(Event::Retry { op: "dmi_read" });
                 thread::sleep(Duration::from_millis(10));
                 n += 1;
             } else {
-                return Err(Error::DmiFailed);
+                return Err(self.dmi_failure());
             }
         }
     }
@@ -73,6 +102,32 @@ impl DebugModuleInterface for WchLink {
     }
 }
 
+impl WchLink {
+    /// Explain a failed DMI operation by DMSTATUS, if it is readable
+    fn dmi_failure(&mut self) -> Error {
+        match self.send_command(DmiOp::read(Dmstatus::ADDR)) {
+            Ok(resp) if resp.is_success() => {
+                dm_unavailable(Dmstatus::from(resp.data)).unwrap_or(Error::DmiFailed)
+            }
+            _ => Error::DmiFailed,
+        }
+    }
+}
+
+/// Why the DM can't be used, by its DMSTATUS, None if it can
+fn dm_unavailable(dmstatus: Dmstatus) -> Option<Error> {
+    // a DM that is not attached reads as all 0s or all 1s
+    if dmstatus.version() == 0 || u32::from(dmstatus) == 0xffff_ffff {
+        None
+    } else if !dmstatus.authenticated() {
+        Some(Error::NotAuthenticated)
+    } else if dmstatus.allunavail() {
+        Some(Error::HartUnavailable)
+    } else {
+        None
+    }
+}
+
 impl ProbeSession {
     fn clear_abstractcs_cmderr(&mut self) -> Result<()> {
         let mut abstractcs = Abstractcs::from(0);
@@ -87,6 +142,29 @@ impl ProbeSession {
         Ok(())
     }
 
+    /// Check if the target was reset(e.g. by watchdog) since the last check, and acknowledge it
+    pub fn check_target_reset(&mut self) -> Result<bool> {
+        let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
+        if !dmstatus.anyhavereset() {
+            return Ok(false);
+        }
+        log::warn!("Target reset detected");
+        self.clear_dmstatus_havereset()?;
+        Ok(true)
+    }
+
+    /// Check that the DM is authenticated and the core is available
+    pub fn check_debug_module(&mut self) -> Result<Dmstatus> {
+        let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
+        if dmstatus.version() == 0 || u32::from(dmstatus) == 0xffff_ffff {
+            return Err(Error::NotAttached);
+        }
+        match dm_unavailable(dmstatus) {
+            Some(e) => Err(e),
+            None => Ok(dmstatus),
+        }
+    }
+
     pub fn ensure_mcu_halt(&mut self) -> Result<()> {
         let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
         if dmstatus.allhalted() && dmstatus.anyhalted() {
@@ -98,6 +176,8 @@ impl ProbeSession {
                 let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
                 if dmstatus.anyhalted() && dmstatus.allhalted() {
                     break;
+                } else if let Some(e) = dm_unavailable(dmstatus) {
+                    return Err(e);
                 } else {
                     log::warn!("Not halt, try send");
                     thread::sleep(Duration::from_millis(10));
@@ -135,6 +215,46 @@ impl ProbeSession {
         }
     }
 
+    /// Reset the core by ndmreset, and halt it immediately after reset
+    pub fn reset_and_halt(&mut self) -> Result<()> {
+        self.ensure_writable("Reset")?;
+        self.with_recovery(Self::reset_and_halt_once)
+    }
+
+    fn reset_and_halt_once(&mut self) -> Result<()> {
+        self.ensure_mcu_halt()?;
+
+        // Initiate a core reset request and hold the halt request.
+        self.probe.dmi_write(0x10, 0x80000003)?;
+        let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
+        if !dmstatus.anyhavereset() {
+            log::debug!("Reset not observed");
+        }
+        // Clear the reset status signal and hold the halt request
+        self.probe.dmi_write(0x10, 0x90000001)?;
+        let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
+        if dmstatus.anyhavereset() {
+            log::warn!("Reset status clear failed");
+        }
+        // Clear the halt request, the core stays halted
+        self.probe.dmi_write(0x10, 0x00000001)?;
+        Ok(())
+    }
+
+    /// Set the entry point, the core is halted, and runs from `entry` on resume
+    pub fn set_entry(&mut self, entry: u32) -> Result<()> {
+        self.ensure_mcu_halt()?;
+        log::info!("Set entry point to 0x{:08x}", entry);
+        self.write_reg(regs::DPC, entry)
+    }
+
+    /// Reset, then run from an alternate entry point instead of the reset vector
+    pub fn reset_and_run_at(&mut self, entry: u32) -> Result<()> {
+        self.reset_and_halt()?;
+        self.set_entry(entry)?;
+        self.ensure_mcu_resume()
+    }
+
     pub fn reset_debug_module(&mut self) -> Result<()> {
         self.probe.dmi_write(0x10, 0x00000000)?;
         self.probe.dmi_write(0x10, 0x00000001)?;
@@ -144,50 +264,164 @@ impl ProbeSession {
         if dmcontrol.dmactive() {
             Ok(())
         } else {
+            self.check_debug_module()?;
             Err(Error::DmiFailed)
         }
     }
 
+    /// Execute an abstract command, the transaction wrapper of the DM.
+    ///
+    /// Stale cmderr is cleared first. `abstractcs.busy` is polled until the command
+    /// completes, then `cmderr` is read and cleared (W1C). Only Parity errors and failed DMI transfers reissue the command,
+    /// with bounded backoff, other errors are returned with a register snapshot.
+    fn execute_abstract_command(&mut self, command: u32) -> Result<()> {
+        const MAX_RETRIES: u32 = 5;
+
+        // a stale cmderr, e.g. of an aborted session, blocks the command
+        self.clear_abstractcs_cmderr()?;
+        let mut backoff = Duration::from_millis(1);
+        let mut retries = 0;
+        loop {
+            let abstractcs = match self.run_abstract_command(command) {
+                Ok(abstractcs) => abstractcs,
+                Err(e @ (Error::DmiFailed | Error::Rusb(_))) if retries < MAX_RETRIES => {
+                    log::debug!(
+                        "Abstract command 0x{:08x} transfer failed: {}, retrying...",
+                        command,
+                        e
+                    );
+                    self.probe.emit(Event::Retry {
+                        op: "abstract command",
+                    });
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    retries += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            log::trace!("{:?}", abstractcs);
+            let err = if abstractcs.busy() {
+                // still running after the poll timeout, reissuing would only set cmderr
+                AbstractcsCmdErr::Busy
+            } else {
+                match AbstractcsCmdErr::from_cmderr(abstractcs.cmderr() as _) {
+                    None => return Ok(()),
+                    Some(err) => err,
+                }
+            };
+            // cmderr is sticky, no command runs until it is cleared
+            self.clear_abstractcs_cmderr()?;
+
+            if matches!(err, AbstractcsCmdErr::Parity) && retries < MAX_RETRIES {
+                self.probe.emit(Event::Retry {
+                    op: "abstract command",
+                });
+                log::debug!(
+                    "Abstract command 0x{:08x} failed: {:?}, retrying...",
+                    command,
+                    err
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+                retries += 1;
+                continue;
+            }
+
+            let dmstatus = self.probe.dmi_read(regs::DMSTATUS)?;
+            if Dmstatus::from(dmstatus).anyhavereset() {
+                // The core is running after reset, halt it again for the following commands
+                log::warn!("Target reset during abstract command 0x{:08x}", command);
+                self.clear_dmstatus_havereset()?;
+                self.ensure_mcu_halt()?;
+                return Err(Error::TargetReset);
+            }
+            return Err(Error::AbstractCommandFailed {
+                command,
+                err,
+                abstractcs: abstractcs.into(),
+                dmstatus,
+            });
+        }
+    }
+
+    /// Write the command, and poll `abstractcs` until it is not busy, or the timeout
+    fn run_abstract_command(&mut self, command: u32) -> Result<Abstractcs> {
+        self.probe.dmi_write(regs::DMCOMMAND, command)?;
+        let start = Instant::now();
+        loop {
+            let abstractcs: Abstractcs = self.probe.read_dmi_reg()?;
+            if !abstractcs.busy() || start.elapsed() > ABSTRACT_COMMAND_TIMEOUT {
+                return Ok(abstractcs);
+            }
+        }
+    }
+
     /// Read register value
     /// CSR: 0x0000 - 0x0fff
     /// GPR: 0x1000 - 0x101f
     /// FPR: 0x1020 - 0x103f
     // ref: QingKeV2 Microprocessor Debug Manual
     pub fn read_reg(&mut self, regno: u16) -> Result<u32> {
-        self.clear_abstractcs_cmderr()?;
-
         let reg = regno as u32;
-        self.probe.dmi_write(0x04, 0x00000000)?; // Clear the Data0 register
-        self.probe.dmi_write(0x17, 0x00220000 | (reg & 0xFFFF))?;
+        self.with_recovery(|sess| {
+            sess.probe.dmi_write(0x04, 0x00000000)?; // Clear the Data0 register
+            sess.execute_abstract_command(0x00220000 | (reg & 0xFFFF))?;
 
-        let abstractcs = self.probe.read_dmi_reg::<Abstractcs>()?;
-        if abstractcs.busy() {
-            return Err(Error::AbstractCommandError(AbstractcsCmdErr::Busy)); // resue busy
-        }
-        if abstractcs.cmderr() != 0 {
-            AbstractcsCmdErr::try_from_cmderr(abstractcs.cmderr() as _)?;
-        }
-
-        let resp = self.probe.dmi_read(0x04)?;
+            let resp = sess.probe.dmi_read(0x04)?;
 
-        Ok(resp)
+            Ok(resp)
+        })
     }
 
     pub fn write_reg(&mut self, regno: u16, value: u32) -> Result<()> {
-        // self.ensure_mcu_halt()?;
+        self.ensure_writable("Register write")?;
 
-        let reg = regno as u32;
-        self.probe.send_command(DmiOp::write(0x04, value))?;
-        self.probe
-            .send_command(DmiOp::write(0x17, 0x00230000 | (reg & 0xFFFF)))?;
+        self.with_recovery(|sess| sess.write_reg_unchecked(regno, value))
+    }
 
-        let abstractcs = self.probe.read_dmi_reg::<Abstractcs>()?;
-        if abstractcs.busy() {
-            return Err(Error::AbstractCommandError(AbstractcsCmdErr::Busy)); //resue busy
+    /// Check a register write against the run state, debug sensitive registers must be
+    /// written with the core halted, unless forced
+    pub fn check_reg_write(&mut self, regno: u16, force: bool) -> Result<()> {
+        if !regs::is_debug_sensitive_reg(regno) {
+            return Ok(());
+        }
+        let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
+        if dmstatus.allhalted() {
+            return Ok(());
         }
-        if abstractcs.cmderr() != 0 {
-            AbstractcsCmdErr::try_from_cmderr(abstractcs.cmderr() as _)?;
+        let name = regs::reg_name(regno)
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| format!("0x{regno:04x}"));
+        if force {
+            log::warn!("Force writing register {} while the core is running", name);
+            Ok(())
+        } else {
+            Err(Error::UnsafeRegisterWrite(name))
         }
+    }
+
+    /// Run `f` with the core halted, then restore the GPRs clobbered by memory access
+    pub(crate) fn preserving_scratch_gprs<T, E: From<Error>>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> std::result::Result<T, E>,
+    ) -> std::result::Result<T, E> {
+        let scratch = SCRATCH_GPRS
+            .iter()
+            .map(|&reg| self.read_reg(reg))
+            .collect::<Result<Vec<_>>>()?;
+        let ret = f(self);
+        for (&reg, &value) in SCRATCH_GPRS.iter().zip(&scratch) {
+            self.write_reg_unchecked(reg, value)?;
+        }
+        ret
+    }
+
+    /// Write register even in read-only mode, only for restoring scratch registers
+    fn write_reg_unchecked(&mut self, regno: u16, value: u32) -> Result<()> {
+        let reg = regno as u32;
+        self.probe.dmi_write(0x04, value)?;
+        self.execute_abstract_command(0x00230000 | (reg & 0xFFFF))?;
 
         Ok(())
     }
@@ -197,97 +431,86 @@ impl ProbeSession {
         self.probe.dmi_write(0x21, 0x00100073)?; // ebreak
 
         self.probe.dmi_write(0x04, addr)?; // data0 <- address
-        self.clear_abstractcs_cmderr()?;
+        self.execute_abstract_command(0x00271005)?; // x5 <- data0, then exec progbuf
 
-        self.probe.dmi_write(0x17, 0x00271005)?;
+        self.execute_abstract_command(0x00221006)?; // data0 <- x6
 
-        let abstractcs: Abstractcs = self.probe.read_dmi_reg()?;
-        if abstractcs.busy() {
-            return Err(Error::AbstractCommandError(AbstractcsCmdErr::Busy)); //resue busy
-        }
-        if abstractcs.cmderr() != 0 {
-            AbstractcsCmdErr::try_from_cmderr(abstractcs.cmderr() as _)?;
-        }
+        let data0 = self.probe.dmi_read(0x04)?;
+        Ok(data0)
+    }
 
-        self.probe.dmi_write(0x17, 0x00221006)?; // data0 <- x6
-        if abstractcs.busy() {
-            return Err(Error::AbstractCommandError(AbstractcsCmdErr::Busy)); //resue busy
-        }
-        if abstractcs.cmderr() != 0 {
-            AbstractcsCmdErr::try_from_cmderr(abstractcs.cmderr() as _)?;
-        }
+    /// Byte read, for registers with byte-access semantics
+    pub fn read_mem8(&mut self, addr: u32) -> Result<u8> {
+        self.probe.dmi_write(0x20, 0x0002c303)?; // lbu x6,0(x5)
+        self.probe.dmi_write(0x21, 0x00100073)?; // ebreak
+
+        self.probe.dmi_write(0x04, addr)?; // data0 <- address
+        self.execute_abstract_command(0x00271005)?; // x5 <- data0, then exec progbuf
+
+        self.execute_abstract_command(0x00221006)?; // data0 <- x6
 
         let data0 = self.probe.dmi_read(0x04)?;
-        Ok(data0)
+        Ok(data0 as u8)
+    }
+
+    /// Halfword read, `addr` must be 2-byte aligned
+    pub fn read_mem16(&mut self, addr: u32) -> Result<u16> {
+        self.probe.dmi_write(0x20, 0x0002d303)?; // lhu x6,0(x5)
+        self.probe.dmi_write(0x21, 0x00100073)?; // ebreak
+
+        self.probe.dmi_write(0x04, addr)?; // data0 <- address
+        self.execute_abstract_command(0x00271005)?; // x5 <- data0, then exec progbuf
+
+        self.execute_abstract_command(0x00221006)?; // data0 <- x6
+
+        let data0 = self.probe.dmi_read(0x04)?;
+        Ok(data0 as u16)
     }
 
     pub fn write_mem32(&mut self, addr: u32, data: u32) -> Result<()> {
+        self.ensure_writable("Memory write")?;
+
         // rasm2 -a riscv -d 23a07200
         // sw t2, 0(t0)
         self.probe.dmi_write(0x20, 0x0072a023)?; // sw x7,0(x5)
         self.probe.dmi_write(0x21, 0x00100073)?; // ebreak
 
         self.probe.dmi_write(0x04, addr)?; // data0 <- address
-
-        self.clear_abstractcs_cmderr()?;
-        self.probe.dmi_write(0x17, 0x00231005)?; // x5 <- data0
-
-        let abstractcs: Abstractcs = self.probe.read_dmi_reg()?;
-        log::trace!("{:?}", abstractcs);
-        if abstractcs.busy() {
-            return Err(Error::AbstractCommandError(AbstractcsCmdErr::Busy)); //resue busy
-        }
-        if abstractcs.cmderr() != 0 {
-            AbstractcsCmdErr::try_from_cmderr(abstractcs.cmderr() as _)?;
-        }
+        self.execute_abstract_command(0x00231005)?; // x5 <- data0
 
         self.probe.dmi_write(0x04, data)?; // data0 <- data
-        self.clear_abstractcs_cmderr()?;
+        self.execute_abstract_command(0x00271007)?; // x7 <- data0, then exec progbuf
 
-        self.probe.dmi_write(0x17, 0x00271007)?; // x7 <- data0
-
-        let abstractcs: Abstractcs = self.probe.read_dmi_reg()?;
-        log::trace!("{:?}", abstractcs);
-        if abstractcs.busy() {
-            return Err(Error::AbstractCommandError(AbstractcsCmdErr::Busy)); //resue busy
-        }
-        if abstractcs.cmderr() != 0 {
-            AbstractcsCmdErr::try_from_cmderr(abstractcs.cmderr() as _)?;
-        }
         Ok(())
     }
 
     pub fn write_mem8(&mut self, addr: u32, data: u8) -> Result<()> {
+        self.ensure_writable("Memory write")?;
+
         self.probe.dmi_write(0x20, 0x00728023)?; // sb x7,0(x5)
         self.probe.dmi_write(0x21, 0x00100073)?; // ebreak
 
         self.probe.dmi_write(0x04, addr)?; // data0 <- address
+        self.execute_abstract_command(0x00231005)?; // x5 <- data0
 
-        self.clear_abstractcs_cmderr()?;
-        self.probe.dmi_write(0x17, 0x00231005)?; // x5 <- data0
+        self.probe.dmi_write(0x04, data as u32)?; // data0 <- data
+        self.execute_abstract_command(0x00271007)?; // x7 <- data0, then exec progbuf
 
-        let abstractcs: Abstractcs = self.probe.read_dmi_reg()?;
-        log::trace!("{:?}", abstractcs);
-        if abstractcs.busy() {
-            return Err(Error::AbstractCommandError(AbstractcsCmdErr::Busy)); //resue busy
-        }
-        if abstractcs.cmderr() != 0 {
-            AbstractcsCmdErr::try_from_cmderr(abstractcs.cmderr() as _)?;
-        }
+        Ok(())
+    }
 
-        self.probe.dmi_write(0x04, data as u32)?; // data0 <- data
-        self.clear_abstractcs_cmderr()?;
+    pub fn write_mem16(&mut self, addr: u32, data: u16) -> Result<()> {
+        self.ensure_writable("Memory write")?;
+
+        self.probe.dmi_write(0x20, 0x00729023)?; // sh x7,0(x5)
+        self.probe.dmi_write(0x21, 0x00100073)?; // ebreak
 
-        self.probe.dmi_write(0x17, 0x00271007)?; // x7 <- data0
+        self.probe.dmi_write(0x04, addr)?; // data0 <- address
+        self.execute_abstract_command(0x00231005)?; // x5 <- data0
+
+        self.probe.dmi_write(0x04, data as u32)?; // data0 <- data
+        self.execute_abstract_command(0x00271007)?; // x7 <- data0, then exec progbuf
 
-        let abstractcs: Abstractcs = self.probe.read_dmi_reg()?;
-        log::trace!("{:?}", abstractcs);
-        if abstractcs.busy() {
-            return Err(Error::AbstractCommandError(AbstractcsCmdErr::Busy)); //resue busy
-        }
-        if abstractcs.cmderr() != 0 {
-            AbstractcsCmdErr::try_from_cmderr(abstractcs.cmderr() as _)?;
-        }
         Ok(())
     }
 
@@ -316,57 +539,383 @@ impl ProbeSession {
 
     // The same as read_memory, but use DMI
     pub fn read_memory_by_dmi(&mut self, addr: u32, len: u32) -> Result<Vec<u8>> {
-        if len % 4 != 0 {
+        if !len.is_multiple_of(4) {
             return Err(Error::Custom("len must be 4 bytes aligned".to_string()));
         }
 
         let mut ret = Vec::with_capacity(len as usize);
-        for i in 0..len / 4 {
-            let data = self.read_mem32(addr + i * 4)?;
-            ret.extend_from_slice(&data.to_le_bytes());
+        let mut recoveries = 0;
+        let mut i = 0;
+        while i < len / 4 {
+            match self.read_mem32(addr + i * 4) {
+                Ok(data) => {
+                    ret.extend_from_slice(&data.to_le_bytes());
+                    i += 1;
+                }
+                Err(Error::TargetReset) if recoveries < MAX_RESET_RECOVERIES => {
+                    recoveries += 1;
+                    log::warn!("Resume reading from 0x{:08x}", addr + i * 4);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Write memory word by word, the unaligned tail byte by byte
+    pub fn write_memory_by_dmi(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        if !addr.is_multiple_of(4) {
+            return Err(Error::Custom("address must be 4 bytes aligned".to_string()));
+        }
+        let mut chunks = data.chunks_exact(4);
+        let mut offset = addr;
+        for word in chunks.by_ref() {
+            self.write_mem32(offset, u32::from_le_bytes(word.try_into().unwrap()))?;
+            offset += 4;
+        }
+        for &b in chunks.remainder() {
+            self.write_mem8(offset, b)?;
+            offset += 1;
+        }
+        Ok(())
+    }
+
+    /// Read memory while the core keeps running.
+    ///
+    /// With system bus access in the DM, the core is not disturbed. Otherwise the core
+    /// is halted for a short moment, and the scratch registers used by `read_mem32` are
+    /// restored before resuming.
+    pub fn peek_memory(&mut self, addr: u32, len: u32) -> Result<Vec<u8>> {
+        let start = addr & !0b11;
+        let end = addr
+            .checked_add(len)
+            .and_then(|end| end.checked_next_multiple_of(4))
+            .ok_or_else(|| {
+                Error::Custom(format!(
+                    "memory range 0x{:08x}+0x{:x} overflows the address space",
+                    addr, len
+                ))
+            })?;
+
+        let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
+        let running = !dmstatus.allhalted();
+        if !running {
+            let mem = self.read_memory_by_dmi(start, end - start)?;
+            return Ok(mem[(addr - start) as usize..][..len as usize].to_vec());
+        }
+
+        let sbcs = self.probe.read_dmi_reg::<Sbcs>()?;
+        if sbcs.sbversion() == 1 && sbcs.sbaccess32() {
+            let mem = self.read_memory_by_sba(start, end - start)?;
+            return Ok(mem[(addr - start) as usize..][..len as usize].to_vec());
+        }
+
+        self.ensure_mcu_halt()?;
+        let x5 = self.read_reg(0x1005)?;
+        let x6 = self.read_reg(0x1006)?;
+        let mem = self.read_memory_by_dmi(start, end - start);
+        self.write_reg_unchecked(0x1005, x5)?;
+        self.write_reg_unchecked(0x1006, x6)?;
+        self.ensure_mcu_resume()?;
+
+        Ok(mem?[(addr - start) as usize..][..len as usize].to_vec())
+    }
+
+    /// Read memory words by system bus access, the core keeps running
+    fn read_memory_by_sba(&mut self, addr: u32, len: u32) -> Result<Vec<u8>> {
+        let mut sbcs = Sbcs::from(0);
+        sbcs.set_sbaccess(2); // 32-bit
+        sbcs.set_sbreadonaddr(true);
+        sbcs.set_sbreadondata(true);
+        sbcs.set_sbautoincrement(true);
+        // W1C, stale errors block the access
+        sbcs.set_sbbusyerror(true);
+        sbcs.set_sberror(0b111);
+        self.probe.write_dmi_reg(sbcs)?;
+        self.probe.dmi_write(regs::DMSBADDRESS0, addr)?;
+
+        let mut ret = Vec::with_capacity(len as usize);
+        for _ in 0..len / 4 {
+            let word = self.probe.dmi_read(regs::DMSBDATA0)?;
+            ret.extend_from_slice(&word.to_le_bytes());
+        }
+
+        let sbcs = self.probe.read_dmi_reg::<Sbcs>()?;
+        if sbcs.sbbusyerror() || sbcs.sberror() != 0 {
+            let mut clear = Sbcs::from(0);
+            clear.set_sbbusyerror(true);
+            clear.set_sberror(0b111);
+            self.probe.write_dmi_reg(clear)?;
+            return Err(Error::Custom(format!(
+                "system bus read at 0x{:08x} failed, sbcs=0x{:08x}",
+                addr,
+                u32::from(sbcs)
+            )));
         }
         Ok(ret)
     }
+
+    /// Upload a routine to SRAM and run it until ebreak, returns the argument registers.
+    ///
+    /// `params` are passed in a0..a7 (a0..a5 on RV32E), and the same registers are
+    /// returned. `ra` points to an ebreak appended to the code, so the routine may
+    /// end with either `ebreak` or `ret`. Interrupts are disabled while running,
+    /// dpc, dcsr and mstatus are restored afterwards, other registers are not.
+    pub fn run_routine(&mut self, code: &[u8], params: &[u32]) -> Result<Vec<u32>> {
+        let arg_regs: u16 = if self.chip_family.is_rv32ec() { 6 } else { 8 };
+        if params.len() > arg_regs as usize {
+            return Err(Error::Custom(format!(
+                "too many routine params: {}, max {}",
+                params.len(),
+                arg_regs
+            )));
+        }
+        if !code.len().is_multiple_of(2) {
+            return Err(Error::Custom(
+                "routine code must be 2 bytes aligned".to_string(),
+            ));
+        }
+
+        let mut image = code.to_vec();
+        if !image.len().is_multiple_of(4) {
+            image.extend_from_slice(&[0x01, 0x00]); // c.nop
+        }
+        let ebreak_addr = ROUTINE_LOAD_ADDRESS + image.len() as u32;
+        image.extend_from_slice(&EBREAK.to_le_bytes());
+        let sp = (ROUTINE_LOAD_ADDRESS + image.len() as u32 + ROUTINE_STACK_SIZE + 15) & !15;
+
+        self.ensure_mcu_halt()?;
+        let dpc = self.read_reg(regs::DPC)?;
+        let dcsr = self.read_reg(regs::DCSR)?;
+        let mstatus = self.read_reg(regs::MSTATUS)?;
+
+        // write_memory_by_dmi clobbers x5 and x7, load the code before setting registers
+        log::debug!(
+            "Load routine, {} bytes to 0x{:08x}",
+            image.len(),
+            ROUTINE_LOAD_ADDRESS
+        );
+        self.write_memory_by_dmi(ROUTINE_LOAD_ADDRESS, &image)?;
+
+        self.write_reg(0x1001, ebreak_addr)?; // ra
+        self.write_reg(0x1002, sp)?; // sp
+        for i in 0..arg_regs {
+            let value = params.get(i as usize).copied().unwrap_or(0);
+            self.write_reg(0x100a + i, value)?; // a0..
+        }
+        self.write_reg(regs::MSTATUS, mstatus & !MSTATUS_MIE)?;
+        self.write_reg(regs::DCSR, dcsr | DCSR_EBREAKM)?;
+        self.write_reg(regs::DPC, ROUTINE_LOAD_ADDRESS)?;
+
+        self.ensure_mcu_resume()?;
+        let start = Instant::now();
+        let finished = loop {
+            let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
+            if dmstatus.allhalted() {
+                break true;
+            }
+            if start.elapsed() >= ROUTINE_TIMEOUT {
+                break false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        if !finished {
+            self.ensure_mcu_halt()?;
+        }
+
+        let result = (0..arg_regs)
+            .map(|i| self.read_reg(0x100a + i))
+            .collect::<Result<Vec<_>>>();
+        let pc = self.read_reg(regs::DPC)?;
+
+        self.write_reg(regs::DCSR, dcsr)?;
+        self.write_reg(regs::MSTATUS, mstatus)?;
+        self.write_reg(regs::DPC, dpc)?;
+
+        if !finished {
+            log::error!("Routine timeout, halted at 0x{:08x}", pc);
+            return Err(Error::Timeout);
+        }
+        if pc < ROUTINE_LOAD_ADDRESS || pc > ebreak_addr {
+            log::warn!("Routine stopped at 0x{:08x}, outside of the routine", pc);
+        }
+        result
+    }
+}
+
+/// ISA and core version, from misa and marchid
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CoreInfo {
+    pub misa: u32,
+    pub marchid: u32,
+    /// Decoded misa, like "RV32IMAC"
+    pub isa: Option<String>,
+    /// Decoded marchid of QingKe cores, like "WCH-V4F"
+    pub arch: Option<String>,
+}
+
+impl fmt::Display for CoreInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RISC-V ISA(misa): {:?}", self.isa)?;
+        write!(f, "RISC-V arch(marchid): {:?}", self.arch)
+    }
+}
+
+/// A register value, with the ABI name of GPRs and FPRs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RegisterValue {
+    pub name: &'static str,
+    pub abi_name: Option<&'static str>,
+    pub value: u32,
+}
+
+/// State of the FPU, for `RegisterFile`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FpuState {
+    /// No F extension
+    Absent,
+    /// mstatus.FS=0, FPRs are not accessible
+    Off,
+    On {
+        fprs: Vec<RegisterValue>,
+        fcsr: u32,
+    },
+}
+
+/// Core registers: dpc, GPRs, common CSRs and FPRs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RegisterFile {
+    pub dpc: u32,
+    pub gprs: Vec<RegisterValue>,
+    pub csrs: Vec<RegisterValue>,
+    pub fpu: FpuState,
+}
+
+impl fmt::Display for RegisterFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dpc(pc):   0x{:08x}", self.dpc)?;
+        for reg in &self.gprs {
+            let abi_name = reg.abi_name.unwrap_or_default();
+            write!(f, "\n{:<4}{:>5}: 0x{:08x}", reg.name, abi_name, reg.value)?;
+        }
+        for reg in &self.csrs {
+            write!(f, "\n{:<9}: 0x{:08x}", reg.name, reg.value)?;
+        }
+        match &self.fpu {
+            FpuState::Absent => (),
+            FpuState::Off => write!(f, "\nFPU is off (mstatus.FS=0), FPRs are not accessible")?,
+            FpuState::On { fprs, fcsr } => {
+                for reg in fprs {
+                    let abi_name = reg.abi_name.unwrap_or_default();
+                    write!(
+                        f,
+                        "\n{:<4}{:>5}: 0x{:08x} ({})",
+                        reg.name,
+                        abi_name,
+                        reg.value,
+                        f32::from_bits(reg.value)
+                    )?;
+                }
+                write!(f, "\nfcsr     : 0x{:08x}", fcsr)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Debug module registers, raw values
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DmState {
+    pub dmstatus: u32,
+    pub dmcontrol: u32,
+    pub hartinfo: u32,
+    pub abstractcs: u32,
+    pub haltsum0: u32,
+}
+
+impl fmt::Display for DmState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:#x?}", Dmstatus::from(self.dmstatus))?;
+        writeln!(f, "{:#x?}", Dmcontrol::from(self.dmcontrol))?;
+        writeln!(f, "{:#x?}", regs::Hartinfo::from(self.hartinfo))?;
+        writeln!(f, "{:#x?}", Abstractcs::from(self.abstractcs))?;
+        write!(f, "haltsum0: {:#x?}", self.haltsum0)
+    }
 }
 
 impl ProbeSession {
-    pub fn dump_core_csrs(&mut self) -> Result<()> {
+    pub fn core_info(&mut self) -> Result<CoreInfo> {
         let misa = self.read_reg(regs::MISA)?;
         log::trace!("Read csr misa: {misa:08x}");
-        let misa = parse_misa(misa);
-        log::info!("RISC-V ISA(misa): {misa:?}");
-
         // detect chip's RISC-V core version, QingKe cores
         let marchid = self.read_reg(regs::MARCHID)?;
         log::trace!("Read csr marchid: {marchid:08x}");
-        let core_type = parse_marchid(marchid);
-        log::info!("RISC-V arch(marchid): {core_type:?}");
 
         // mimpid is always "WCH", skip
-        Ok(())
+        Ok(CoreInfo {
+            misa,
+            marchid,
+            isa: parse_misa(misa),
+            arch: parse_marchid(marchid),
+        })
     }
 
-    pub fn dump_regs(&mut self) -> Result<()> {
+    pub fn register_file(&mut self) -> Result<RegisterFile> {
         let dpc = self.read_reg(regs::DPC)?;
-        println!("dpc(pc):   0x{dpc:08x}");
 
-        let gprs = if self.chip_family.is_rv32ec() {
+        let gpr_defs = if self.chip_family.is_rv32ec() {
             regs::GPRS_RVE
         } else {
             regs::GPRS_RVI
         };
-
-        for (reg, name, regno) in gprs {
-            let val = self.read_reg(*regno)?;
-            println!("{reg:<4}{name:>5}: 0x{val:08x}");
+        let mut gprs = vec![];
+        for (reg, name, regno) in gpr_defs {
+            gprs.push(RegisterValue {
+                name: reg,
+                abi_name: Some(name),
+                value: self.read_reg(*regno)?,
+            });
         }
 
+        let mut csrs = vec![];
         for (reg, regno) in regs::CSRS {
-            let val = self.read_reg(*regno)?;
-            println!("{reg:<9}: 0x{val:08x}");
+            csrs.push(RegisterValue {
+                name: reg,
+                abi_name: None,
+                value: self.read_reg(*regno)?,
+            });
         }
 
-        Ok(())
+        Ok(RegisterFile {
+            dpc,
+            gprs,
+            csrs,
+            fpu: self.fpu_state()?,
+        })
+    }
+
+    /// FPRs and fcsr, only for cores with F extension, like Qingke V4F
+    pub fn fpu_state(&mut self) -> Result<FpuState> {
+        let misa = self.read_reg(regs::MISA)?;
+        if misa & regs::MISA_F == 0 {
+            return Ok(FpuState::Absent);
+        }
+        let mstatus = self.read_reg(regs::MSTATUS)?;
+        if mstatus & regs::MSTATUS_FS_MASK == 0 {
+            return Ok(FpuState::Off);
+        }
+
+        let mut fprs = vec![];
+        for (reg, name, regno) in regs::FPRS {
+            fprs.push(RegisterValue {
+                name: reg,
+                abi_name: Some(name),
+                value: self.read_reg(*regno)?,
+            });
+        }
+        let fcsr = self.read_reg(regs::FCSR)?;
+        Ok(FpuState::On { fprs, fcsr })
     }
 
     /// Only for Qingke V4
@@ -379,40 +928,109 @@ impl ProbeSession {
         Ok(())
     }
 
-    pub fn dump_dmi(&mut self) -> Result<()> {
-        log::warn!("The halt status may be incorrect because detaching might resume the MCU");
-
-        let dmstatus: regs::Dmstatus = self.probe.read_dmi_reg()?;
-        log::info!("{dmstatus:#x?}");
-        let dmcontrol: regs::Dmcontrol = self.probe.read_dmi_reg()?;
-        log::info!("{dmcontrol:#x?}");
-        let hartinfo: regs::Hartinfo = self.probe.read_dmi_reg()?;
-        log::info!("{hartinfo:#x?}");
-        let abstractcs: regs::Abstractcs = self.probe.read_dmi_reg()?;
-        log::info!("{abstractcs:#x?}");
-        let haltsum0 = self.probe.dmi_read(0x40)?;
-        log::info!("haltsum0: {:#x?}", haltsum0);
+    /// Read a PMP region, returns pmpaddr and config. Only for Qingke V4
+    pub fn read_pmp(&mut self, region: u8) -> Result<(u32, u8)> {
+        check_pmp_region(region)?;
+        let cfg = self.read_reg(regs::PMPCFG0)?.to_le_bytes()[region as usize];
+        let addr = self.read_reg(regs::PMPADDR0 + region as u16)?;
+        Ok((addr, cfg))
+    }
 
+    /// Configure a PMP region, `addr` is the raw pmpaddr value. Only for Qingke V4
+    pub fn write_pmp(&mut self, region: u8, addr: u32, cfg: u8) -> Result<()> {
+        self.ensure_writable("PMP write")?;
+        check_pmp_region(region)?;
+        self.ensure_mcu_halt()?;
+
+        let mut cfgs = self.read_reg(regs::PMPCFG0)?.to_le_bytes();
+        if cfgs[region as usize] & regs::PMP_CFG_L != 0 {
+            return Err(Error::Custom(format!(
+                "PMP region {} is locked until reset",
+                region
+            )));
+        }
+        // address first, the region takes effect when the config is written
+        self.write_reg(regs::PMPADDR0 + region as u16, addr)?;
+        cfgs[region as usize] = cfg;
+        self.write_reg(regs::PMPCFG0, u32::from_le_bytes(cfgs))?;
+
+        let (readback_addr, readback_cfg) = self.read_pmp(region)?;
+        if readback_addr != addr || readback_cfg != cfg {
+            log::warn!(
+                "PMP region {} reads back as pmpaddr=0x{:08x} cfg=0x{:02x}, some bits are not writable",
+                region,
+                readback_addr,
+                readback_cfg
+            );
+        }
         Ok(())
     }
+
+    pub fn dm_state(&mut self) -> Result<DmState> {
+        Ok(DmState {
+            dmstatus: self.probe.read_dmi_reg::<Dmstatus>()?.into(),
+            dmcontrol: self.probe.read_dmi_reg::<Dmcontrol>()?.into(),
+            hartinfo: self.probe.read_dmi_reg::<regs::Hartinfo>()?.into(),
+            abstractcs: self.probe.read_dmi_reg::<Abstractcs>()?.into(),
+            haltsum0: self.probe.dmi_read(0x40)?,
+        })
+    }
+
+    /// Walk the frame pointer chain, in RISC-V GCC frame layout: `ra` at `fp-4`, previous `fp` at `fp-8`.
+    /// Returns `pc`, then the return addresses of the callers.
+    pub fn backtrace(&mut self, pc: u32, fp: u32) -> Result<Vec<u32>> {
+        self.preserving_scratch_gprs(|sess| {
+            let mut frames = vec![pc];
+
+            let mut fp = fp;
+            while frames.len() < MAX_BACKTRACE_DEPTH {
+                if fp == 0 || !fp.is_multiple_of(4) || fp < 8 {
+                    break;
+                }
+                let (Ok(ra), Ok(prev_fp)) = (sess.read_mem32(fp - 4), sess.read_mem32(fp - 8))
+                else {
+                    break;
+                };
+                if ra == 0 || ra == 0xffffffff {
+                    break;
+                }
+                frames.push(ra);
+                // stack grows down, caller frames must be at higher addresses
+                if prev_fp <= fp {
+                    break;
+                }
+                fp = prev_fp;
+            }
+            Ok(frames)
+        })
+    }
 }
 
-/*
-    fn lock_flash(&mut self) -> Result<()> {
-        const FLASH_CTLR: u32 = 0x40022010;
+// FPEC registers, CH32V/CH32X/CH32L/CH64X series
+const FLASH_KEYR: u32 = 0x40022004;
+const FLASH_OBKEYR: u32 = 0x40022008;
+const FLASH_STATR: u32 = 0x4002200C;
+const FLASH_CTLR: u32 = 0x40022010;
+const FLASH_ADDR: u32 = 0x40022014;
+const FLASH_WPR: u32 = 0x40022020;
+const FLASH_MODEKEYR: u32 = 0x40022024;
+
+/// Option bytes, each byte is followed by its complement
+pub(crate) const OPTION_BYTES_ADDR: u32 = 0x1FFFF800;
+pub(crate) const OPTION_BYTES_LEN: u32 = 8;
 
+/// Direct flash programming, driving the FPEC registers by DMI.
+///
+/// This is slower than the flash_op blob, but does not depend on it.
+/// Requires the MCU to be halted.
+impl ProbeSession {
+    fn lock_flash(&mut self) -> Result<()> {
         self.modify_mem32(FLASH_CTLR, |r| r | 0x00008080)?;
         Ok(())
     }
 
     /// unlock FLASH LOCK and FLOCK
     fn unlock_flash(&mut self) -> Result<()> {
-        const FLASH_CTLR: u32 = 0x40022010;
-        const FLASH_KEYR: u32 = 0x40022004;
-        const FLASH_MODEKEYR: u32 = 0x40022024;
-        const KEY1: u32 = 0x45670123;
-        const KEY2: u32 = 0xCDEF89AB;
-
         let flash_ctlr = self.read_mem32(FLASH_CTLR)?;
         log::debug!("flash_ctlr: 0x{:08x}", flash_ctlr);
         // Test LOCK, FLOCK bits
@@ -430,33 +1048,78 @@ impl ProbeSession {
 
         let flash_ctlr = self.read_mem32(FLASH_CTLR)?;
         log::debug!("flash_ctlr: 0x{:08x}", flash_ctlr);
+        if flash_ctlr & 0x00008080 != 0 {
+            return Err(Error::Custom("flash unlock failed".to_string()));
+        }
+
+        Ok(())
+    }
 
+    /// Write protected sectors in the region, returns the start addresses of the sectors.
+    ///
+    /// Each cleared bit of FLASH_WPR protects one sector.
+    pub fn write_protected_sectors(&mut self, address: u32, length: u32) -> Result<Vec<u32>> {
+        let flash_start = self.chip_family.code_flash_start();
+        let sector_size = self.chip_family.write_protect_sector_size();
+
+        self.ensure_mcu_halt()?;
+        let wpr = self.read_mem32(FLASH_WPR)?;
+        log::debug!("flash_wpr: 0x{:08x}", wpr);
+
+        let offset = self
+            .address_map()
+            .translate(address)
+            .saturating_sub(flash_start);
+        let first = offset / sector_size;
+        let last = (offset + length.max(1) - 1) / sector_size;
+        let sectors = (first..=last.min(31))
+            .filter(|i| wpr & (1 << i) == 0)
+            .map(|i| flash_start + i * sector_size)
+            .collect();
+        Ok(sectors)
+    }
+
+    /// Fail early if any sector in the region is write protected
+    pub fn check_write_protect(&mut self, address: u32, length: u32) -> Result<()> {
+        if !self.chip_family.support_dmi_flash() {
+            return Ok(());
+        }
+        let sectors = match self.write_protected_sectors(address, length) {
+            Ok(sectors) => sectors,
+            Err(e) => {
+                log::warn!("Failed to read write protect status: {}", e);
+                return Ok(());
+            }
+        };
+        if !sectors.is_empty() {
+            return Err(Error::WriteProtected(sectors));
+        }
         Ok(())
     }
 
-    /// Erase by 256 bytes page
-    /// address must be 256 bytes aligned
+    /// Fast page erase, page size is chip-specific.
+    /// address must be page aligned
     pub fn fast_erase(&mut self, address: u32) -> Result<()> {
-        // require unlock
-        self.unlock_flash()?;
+        self.ensure_writable("Flash erase")?;
 
-        const FLASH_STATR: u32 = 0x4002200C;
         const BUSY_MASK: u32 = 0x00000001;
         const START_MASK: u32 = 1 << 6;
         // const EOP_MASK: u32 = 1 << 5;
         const WPROTECT_ERR_MASK: u32 = 1 << 4;
 
-        const FLASH_ADDR: u32 = 0x40022014;
-        const FLASH_CTLR: u32 = 0x40022010;
-
         const PAGE_ERASE_MASK: u32 = 1 << 17;
 
-        if address & 0xff != 0 {
-            return Err(Error::Custom(
-                "address must be 256 bytes aligned".to_string(),
-            ));
+        let page_size = self.chip_family.fast_page_size();
+        if !address.is_multiple_of(page_size) {
+            return Err(Error::Custom(format!(
+                "address must be {} bytes aligned",
+                page_size
+            )));
         }
 
+        // require unlock
+        self.unlock_flash()?;
+
         let statr = self.read_mem32(FLASH_STATR)?;
         // check if busy
         if statr & BUSY_MASK != 0 {
@@ -469,96 +1132,26 @@ impl ProbeSession {
 
         self.modify_mem32(FLASH_CTLR, |r| r | START_MASK)?;
 
-        loop {
-            let statr = self.read_mem32(FLASH_STATR)?;
-            // check if busy
-            if statr & BUSY_MASK != 0 {
-                thread::sleep(Duration::from_millis(1));
-            } else {
-                if statr & WPROTECT_ERR_MASK != 0 {
-                    return Err(Error::Custom("flash write protect error".to_string()));
-                }
-                self.write_mem32(FLASH_STATR, statr)?; // write 1 to clear EOP
-
-                break;
-            }
-        }
-        // read 1 word to verify
-        let word = self.read_mem32(address)?;
-        println!("=> {:08x}", word);
+        let statr = self.wait_mem32(FLASH_STATR, |r| r & BUSY_MASK == 0)?;
+        self.write_mem32(FLASH_STATR, statr)?; // write 1 to clear EOP
 
         // end erase, disable page erase
         self.modify_mem32(FLASH_CTLR, |r| r & (!PAGE_ERASE_MASK))?;
 
         self.lock_flash()?;
 
-        Ok(())
-    }
-
-    pub fn fast_erase_32k(&mut self, address: u32) -> Result<()> {
-        // require unlock
-        self.unlock_flash()?;
-
-        const FLASH_STATR: u32 = 0x4002200C;
-        const BUSY_MASK: u32 = 0x00000001;
-        const START_MASK: u32 = 1 << 6;
-        const WPROTECT_ERR_MASK: u32 = 1 << 4;
-
-        const FLASH_ADDR: u32 = 0x40022014;
-        const FLASH_CTLR: u32 = 0x40022010;
-
-        const BLOCK_ERASE_32K_MASK: u32 = 1 << 18;
-
-        if address & 0x7fff != 0 {
-            return Err(Error::Custom(
-                "address must be 32k bytes aligned".to_string(),
-            ));
-        }
-
-        let statr = self.read_mem32(FLASH_STATR)?;
-        // check if busy
-        if statr & BUSY_MASK != 0 {
-            return Err(Error::Custom("flash busy".to_string()));
-        }
-
-        self.modify_mem32(FLASH_CTLR, |r| r | BLOCK_ERASE_32K_MASK)?;
-
-        self.write_mem32(FLASH_ADDR, address)?;
-
-        self.modify_mem32(FLASH_CTLR, |r| r | START_MASK)?;
-
-        loop {
-            let statr = self.read_mem32(FLASH_STATR)?;
-            // check if busy
-            if statr & BUSY_MASK != 0 {
-                thread::sleep(Duration::from_millis(1));
-            } else {
-                if statr & WPROTECT_ERR_MASK != 0 {
-                    return Err(Error::Custom("flash write protect error".to_string()));
-                }
-                self.write_mem32(FLASH_STATR, statr)?; // write 1 to clear EOP
-
-                break;
-            }
+        if statr & WPROTECT_ERR_MASK != 0 {
+            return Err(Error::Custom("flash write protect error".to_string()));
         }
-        // read 1 word to verify
-        let word = self.read_mem32(address)?;
-        println!("=> {:08x}", word);
-
-        // end erase
-        // disable page erase
-        self.modify_mem32(FLASH_CTLR, |r| r & (!BLOCK_ERASE_32K_MASK))?;
-
-        self.lock_flash()?;
 
         Ok(())
     }
 
     pub fn erase_all(&mut self) -> Result<()> {
-        const FLASH_STATR: u32 = 0x4002200C;
+        self.ensure_writable("Flash erase")?;
+
         const BUSY_MASK: u32 = 0x00000001;
 
-        const FLASH_CTLR: u32 = 0x40022010;
         const MASS_ERASE_MASK: u32 = 1 << 2; // MER
         const START_MASK: u32 = 1 << 6;
 
@@ -574,6 +1167,8 @@ impl ProbeSession {
         // clear MER
         self.modify_mem32(FLASH_CTLR, |r| r & (!MASS_ERASE_MASK))?;
 
+        self.lock_flash()?;
+
         Ok(())
     }
 
@@ -582,40 +1177,47 @@ impl ProbeSession {
     /// # Arguments
     ///
     /// * `address` - The start address of the flash page to program.
-    /// * `data` - The data to be written to the page.
+    /// * `data` - The data to be written to the page, at most one page.
     ///
     /// The page must be erased first
     pub fn program_page(&mut self, address: u32, data: &[u8]) -> Result<()> {
-        // require unlock
-        self.unlock_flash()?;
+        self.ensure_writable("Flash write")?;
 
-        const FLASH_STATR: u32 = 0x4002200C;
         const BUSY_MASK: u32 = 0x00000001;
         const WRITE_BUSY_MASK: u32 = 1 << 1;
         const WPROTECT_ERR_MASK: u32 = 1 << 4;
 
-        const FLASH_CTLR: u32 = 0x40022010;
         const PAGE_START_MASK: u32 = 1 << 21; // start page program
         const PAGE_PROG_MASK: u32 = 1 << 16; //
 
-        if address & 0xff != 0 {
-            return Err(Error::Custom(
-                "address must be 256 bytes aligned".to_string(),
-            ));
+        let page_size = self.chip_family.fast_page_size();
+        if !address.is_multiple_of(page_size) {
+            return Err(Error::Custom(format!(
+                "address must be {} bytes aligned",
+                page_size
+            )));
+        }
+        if data.len() > page_size as usize {
+            return Err(Error::Custom(format!(
+                "data must be at most {} bytes",
+                page_size
+            )));
         }
 
+        // require unlock
+        self.unlock_flash()?;
+
         // check if busy
         let statr = self.read_mem32(FLASH_STATR)?;
         if statr & BUSY_MASK != 0 {
             return Err(Error::Custom("flash busy".to_string()));
         }
 
-        //let ctlr = self.read_mem32(FLASH_CTLR)?;
-        //let ctlr = ctlr | PAGE_PROG_MASK;
-        //self.write_mem32(FLASH_CTLR, ctlr)?;
         self.modify_mem32(FLASH_CTLR, |r| r | PAGE_PROG_MASK)?;
 
-        for (i, word) in data.chunks(4).enumerate() {
+        let mut page = data.to_vec();
+        page.resize(page_size as usize, 0xff);
+        for (i, word) in page.chunks(4).enumerate() {
             let word = u32::from_le_bytes(word.try_into().unwrap());
             self.write_mem32(address + (i as u32 * 4), word)?;
 
@@ -630,28 +1232,365 @@ impl ProbeSession {
         let statr = self.wait_mem32(FLASH_STATR, |r| r & BUSY_MASK == 0)?;
 
         self.write_mem32(FLASH_STATR, statr)?; // write 1 to clear EOP
+
+        // end program, clear PAGE_PROG
+        self.modify_mem32(FLASH_CTLR, |r| r & (!PAGE_PROG_MASK))?;
+
+        self.lock_flash()?;
+
         if statr & WPROTECT_ERR_MASK != 0 {
             return Err(Error::Custom("flash write protect error".to_string()));
         }
 
-        // verify
-        // read 1 word to verify
-        //let word = self.read_mem32(address)?;
-        //println!("=> {:08x}", word);
+        Ok(())
+    }
 
-        // end program, clear PAGE_PROG
-        //let ctlr = self.read_mem32(FLASH_CTLR)?;
-        //let ctlr = ctlr & (!PAGE_PROG_MASK); // disable page erase
-        //self.write_mem32(FLASH_CTLR, ctlr)?;
-        self.modify_mem32(FLASH_CTLR, |r| r & (!PAGE_PROG_MASK))?;
+    /// Erase, program and verify page by page, without the flash_op blob.
+    ///
+    /// `erase` is false for a region found blank, the pages are programmed directly.
+    pub fn write_flash_by_dmi(&mut self, data: &[u8], address: u32, erase: bool) -> Result<()> {
+        self.ensure_writable("Flash write")?;
+
+        if !self.chip_family.support_dmi_flash() {
+            return Err(Error::Custom(format!(
+                "DMI flash backend is not supported for {:?}",
+                self.chip_family
+            )));
+        }
+        let page_size = self.chip_family.fast_page_size();
+        let merged = self.merge_with_flash_pages(data, address)?;
+        let (address, data) = match &merged {
+            Some((address, data)) => (*address, data.as_slice()),
+            None => (address, data),
+        };
+
+        self.ensure_mcu_halt()?;
+
+        let bar = ProgressBar::new(data.len() as _);
+        for (i, chunk) in data.chunks(page_size as usize).enumerate() {
+            let page_address = address + i as u32 * page_size;
+            log::trace!("Program page 0x{:08x}", page_address);
+
+            if erase {
+                self.fast_erase(page_address)?;
+            }
+            self.program_page(page_address, chunk)?;
+
+            let readback = self.read_memory_by_dmi(page_address, page_size)?;
+            if readback[..chunk.len()] != *chunk {
+                return Err(Error::Custom(format!(
+                    "verify failed at page 0x{:08x}",
+                    page_address
+                )));
+            }
+            bar.inc(chunk.len() as _);
+        }
+        bar.finish();
+
+        Ok(())
+    }
+}
+
+const PFIC_SCTLR: u32 = 0xE000ED10;
+const PFIC_SCTLR_SLEEPDEEP: u32 = 1 << 2;
+const PWR_CTLR: u32 = 0x40007000;
+const PWR_CTLR_PDDS: u32 = 1 << 1;
+const WFI: u32 = 0x10500073;
+
+/// Low power mode entered by WFI, configured by PFIC_SCTLR.SLEEPDEEP and PWR_CTLR.PDDS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LowPowerMode {
+    Sleep,
+    Stop,
+    Standby,
+}
+
+/// Power state of the core, as seen by the debugger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    /// Halted, not in a low power mode
+    Active,
+    /// Halted in WFI, with the configured low power mode if known
+    Sleeping(Option<LowPowerMode>),
+    /// Halt request is not acknowledged, likely in Stop or Standby mode
+    Unresponsive,
+}
+
+/// Low power state detection
+impl ProbeSession {
+    /// Try to halt the core within the timeout, returns false if it does not respond
+    pub fn try_halt(&mut self, timeout: Duration) -> Result<bool> {
+        let start = Instant::now();
+        loop {
+            self.probe.dmi_write(0x10, 0x80000001)?;
+            let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
+            if dmstatus.allhalted() {
+                self.probe.dmi_write(0x10, 0x00000001)?;
+                return Ok(true);
+            }
+            if start.elapsed() >= timeout {
+                self.probe.dmi_write(0x10, 0x00000001)?;
+                return Ok(false);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Detect if the core is sleeping. A running core is halted for a moment, then resumed.
+    pub fn power_state(&mut self) -> Result<PowerState> {
+        let dmstatus = self.probe.read_dmi_reg::<Dmstatus>()?;
+        let running = !dmstatus.allhalted();
+        if running && !self.try_halt(Duration::from_millis(100))? {
+            return Ok(PowerState::Unresponsive);
+        }
+
+        // scratch registers used by read_mem32
+        let x5 = self.read_reg(0x1005)?;
+        let x6 = self.read_reg(0x1006)?;
+        let dpc = self.read_reg(regs::DPC)?;
+        // dpc is the WFI itself, or the next instruction, by implementation
+        let mut in_wfi = false;
+        for addr in [dpc, dpc.wrapping_sub(4)] {
+            let word = addr & !0b11;
+            let Ok(lo) = self.read_mem32(word) else {
+                continue;
+            };
+            let insn = if addr.is_multiple_of(4) {
+                lo
+            } else {
+                // compressed code, a 32-bit instruction may be halfword aligned
+                let Ok(hi) = self.read_mem32(word + 4) else {
+                    continue;
+                };
+                (lo >> 16) | (hi << 16)
+            };
+            in_wfi |= insn == WFI;
+        }
+        let mode = if in_wfi && self.chip_family.support_low_power_detection() {
+            let sctlr = self.read_mem32(PFIC_SCTLR)?;
+            let pwr_ctlr = self.read_mem32(PWR_CTLR)?;
+            Some(if sctlr & PFIC_SCTLR_SLEEPDEEP == 0 {
+                LowPowerMode::Sleep
+            } else if pwr_ctlr & PWR_CTLR_PDDS == 0 {
+                LowPowerMode::Stop
+            } else {
+                LowPowerMode::Standby
+            })
+        } else {
+            None
+        };
+        self.write_reg_unchecked(0x1005, x5)?;
+        self.write_reg_unchecked(0x1006, x6)?;
+
+        if running {
+            self.ensure_mcu_resume()?;
+        }
+        Ok(if in_wfi {
+            PowerState::Sleeping(mode)
+        } else {
+            PowerState::Active
+        })
+    }
+}
 
+/// RDPR value of unprotected flash
+const RDPR_UNPROTECTED: u8 = 0xa5;
+/// USER option byte bits
+const USER_IWDG_SW: u8 = 1 << 0;
+const USER_STOP_RST: u8 = 1 << 1;
+const USER_STANDBY_RST: u8 = 1 << 2;
+
+/// Option bytes decoded as named fields
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OptionBytes {
+    /// RDPR, USER, DATA0, DATA1, WRPR0-3
+    pub raw: [u8; OPTION_BYTES_LEN as usize],
+    pub read_protected: bool,
+    /// IWDG is started by hardware after reset, can't be stopped
+    pub hardware_iwdg: bool,
+    /// Reset instead of entering stop mode, None for chips without stop mode
+    pub stop_reset: Option<bool>,
+    /// Reset instead of entering standby mode
+    pub standby_reset: bool,
+    /// PD7/nRST pin mode, CH32V003 only
+    pub rst_mode: Option<RstMode>,
+    pub data: [u8; 2],
+    /// Write protected flash sectors, by start address
+    pub write_protected: Vec<u32>,
+}
+
+impl fmt::Display for OptionBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes_no = |b: bool| if b { "yes" } else { "no" };
+        writeln!(f, "Option bytes: {}", hex::encode(self.raw))?;
+        writeln!(f, "Read protection: {}", yes_no(self.read_protected))?;
+        writeln!(
+            f,
+            "IWDG: {}",
+            if self.hardware_iwdg {
+                "hardware, started on reset"
+            } else {
+                "software"
+            }
+        )?;
+        if let Some(stop_reset) = self.stop_reset {
+            writeln!(f, "Reset on stop: {}", yes_no(stop_reset))?;
+        }
+        writeln!(f, "Reset on standby: {}", yes_no(self.standby_reset))?;
+        if let Some(mode) = self.rst_mode {
+            writeln!(f, "RST pin mode: {:?}", mode)?;
+        }
+        writeln!(
+            f,
+            "User data: 0x{:02x} 0x{:02x}",
+            self.data[0], self.data[1]
+        )?;
+        if self.write_protected.is_empty() {
+            write!(f, "Write protected sectors: none")
+        } else {
+            let sectors = self
+                .write_protected
+                .iter()
+                .map(|addr| format!("0x{addr:08x}"))
+                .collect::<Vec<_>>();
+            write!(f, "Write protected sectors: {}", sectors.join(", "))
+        }
+    }
+}
+
+/// Option bytes, by FPEC registers
+impl ProbeSession {
+    /// Read and decode option bytes, halts the MCU
+    pub fn option_bytes(&mut self) -> Result<OptionBytes> {
+        self.ensure_mcu_halt()?;
+        let raw = self.read_option_bytes()?;
+        let user = raw[1];
+        let wrpr = u32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]);
+        let flash_start = self.chip_family.code_flash_start();
+        let sector_size = self.chip_family.write_protect_sector_size();
+        let is_v003 = matches!(
+            self.chip_family,
+            RiscvChip::CH32V003 | RiscvChip::CH641 | RiscvChip::CH32V007
+        );
+        Ok(OptionBytes {
+            raw,
+            read_protected: raw[0] != RDPR_UNPROTECTED,
+            hardware_iwdg: user & USER_IWDG_SW == 0,
+            stop_reset: (!is_v003).then_some(user & USER_STOP_RST == 0),
+            standby_reset: user & USER_STANDBY_RST == 0,
+            rst_mode: (self.chip_family == RiscvChip::CH32V003).then(|| RstMode::from_user(user)),
+            data: [raw[2], raw[3]],
+            write_protected: (0..32)
+                .filter(|i| wrpr & (1 << i) == 0)
+                .map(|i| flash_start + i * sector_size)
+                .collect(),
+        })
+    }
+
+    /// Read option bytes, without complements: RDPR, USER, DATA0, DATA1, WRPR0-3
+    pub fn read_option_bytes(&mut self) -> Result<[u8; OPTION_BYTES_LEN as usize]> {
+        let raw = self.read_memory_by_dmi(OPTION_BYTES_ADDR, OPTION_BYTES_LEN * 2)?;
+        let mut values = [0u8; OPTION_BYTES_LEN as usize];
+        for (v, pair) in values.iter_mut().zip(raw.chunks(2)) {
+            *v = pair[0];
+        }
+        Ok(values)
+    }
+
+    /// Erase and re-program all option bytes, complements are generated by hardware.
+    ///
+    /// Takes effect after a power-on reset.
+    pub fn write_option_bytes(&mut self, values: &[u8; OPTION_BYTES_LEN as usize]) -> Result<()> {
+        self.ensure_writable("Option bytes write")?;
+
+        const BUSY_MASK: u32 = 0x00000001;
+        const WPROTECT_ERR_MASK: u32 = 1 << 4;
+        const OBPG_MASK: u32 = 1 << 4;
+        const OBER_MASK: u32 = 1 << 5;
+        const START_MASK: u32 = 1 << 6;
+        const OBWRE_MASK: u32 = 1 << 9;
+
+        self.ensure_mcu_halt()?;
+        self.unlock_flash()?;
+        if self.read_mem32(FLASH_CTLR)? & OBWRE_MASK == 0 {
+            self.write_mem32(FLASH_OBKEYR, KEY1)?;
+            self.write_mem32(FLASH_OBKEYR, KEY2)?;
+            if self.read_mem32(FLASH_CTLR)? & OBWRE_MASK == 0 {
+                return Err(Error::Custom("option bytes unlock failed".to_string()));
+            }
+        }
+
+        // erase
+        self.wait_mem32(FLASH_STATR, |r| r & BUSY_MASK == 0)?;
+        self.modify_mem32(FLASH_CTLR, |r| r | OBER_MASK)?;
+        self.modify_mem32(FLASH_CTLR, |r| r | START_MASK)?;
+        self.wait_mem32(FLASH_STATR, |r| r & BUSY_MASK == 0)?;
+        self.modify_mem32(FLASH_CTLR, |r| r & !OBER_MASK)?;
+
+        // program, halfword by halfword
+        self.modify_mem32(FLASH_CTLR, |r| r | OBPG_MASK)?;
+        for (i, v) in values.iter().enumerate() {
+            self.write_mem16(OPTION_BYTES_ADDR + i as u32 * 2, *v as u16)?;
+            self.wait_mem32(FLASH_STATR, |r| r & BUSY_MASK == 0)?;
+        }
+        self.modify_mem32(FLASH_CTLR, |r| r & !OBPG_MASK)?;
+
+        let statr = self.read_mem32(FLASH_STATR)?;
+        self.modify_mem32(FLASH_CTLR, |r| r & !OBWRE_MASK)?;
         self.lock_flash()?;
 
+        if statr & WPROTECT_ERR_MASK != 0 {
+            return Err(Error::Custom(
+                "option bytes write protect error".to_string(),
+            ));
+        }
+
+        let readback = self.read_option_bytes()?;
+        if &readback != values {
+            return Err(Error::Custom(format!(
+                "option bytes verify failed, expected {:02x?}, got {:02x?}",
+                values, readback
+            )));
+        }
         Ok(())
     }
+
+    fn ensure_ch32v003(&self) -> Result<()> {
+        if self.chip_family != RiscvChip::CH32V003 {
+            return Err(Error::Custom(format!(
+                "RST pin mode is only available for CH32V003, got {:?}",
+                self.chip_family
+            )));
+        }
+        Ok(())
+    }
+
+    /// CH32V003 only, PD7/nRST pin mode
+    pub fn rst_mode(&mut self) -> Result<RstMode> {
+        self.ensure_ch32v003()?;
+        let values = self.read_option_bytes()?;
+        Ok(RstMode::from_user(values[1]))
+    }
+
+    /// CH32V003 only, set PD7/nRST pin mode, keeps other option bytes
+    pub fn set_rst_mode(&mut self, mode: RstMode) -> Result<()> {
+        self.ensure_ch32v003()?;
+        let mut values = self.read_option_bytes()?;
+        log::debug!("Option bytes: {:02x?}", values);
+        values[1] = mode.to_user(values[1]);
+        self.write_option_bytes(&values)
+    }
 }
 
-*/
+fn check_pmp_region(region: u8) -> Result<()> {
+    if region >= regs::PMP_REGIONS {
+        return Err(Error::Custom(format!(
+            "PMP region must be 0 to {}",
+            regs::PMP_REGIONS - 1
+        )));
+    }
+    Ok(())
+}
 
 // marchid => dc68d882
 // Parsed marchid: WCH-V4B
@@ -672,6 +1611,47 @@ fn parse_marchid(marchid: u32) -> Option<String> {
     }
 }
 
+/// CH32V003 PD7/nRST pin mode, `RST_MODE` in the USER option byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum RstMode {
+    /// PD7 as reset input, ignore pulses shorter than 128us
+    Reset,
+    /// PD7 as reset input, ignore pulses shorter than 1ms
+    #[cfg_attr(feature = "cli", value(name = "reset-1ms"))]
+    Reset1ms,
+    /// PD7 as reset input, ignore pulses shorter than 12ms
+    #[cfg_attr(feature = "cli", value(name = "reset-12ms"))]
+    Reset12ms,
+    /// Reset function disabled, PD7 as GPIO
+    Gpio,
+}
+
+impl RstMode {
+    const SHIFT: u8 = 3;
+    const MASK: u8 = 0b11 << Self::SHIFT;
+
+    pub fn from_user(user: u8) -> Self {
+        match (user & Self::MASK) >> Self::SHIFT {
+            0b00 => RstMode::Reset,
+            0b01 => RstMode::Reset1ms,
+            0b10 => RstMode::Reset12ms,
+            _ => RstMode::Gpio,
+        }
+    }
+
+    pub fn to_user(self, user: u8) -> u8 {
+        let bits = match self {
+            RstMode::Reset => 0b00,
+            RstMode::Reset1ms => 0b01,
+            RstMode::Reset12ms => 0b10,
+            RstMode::Gpio => 0b11,
+        };
+        (user & !Self::MASK) | (bits << Self::SHIFT)
+    }
+}
+
 fn parse_misa(misa: u32) -> Option<String> {
     let mut s = String::new();
     let mxl = (misa >> 30) & 0x3;
@@ -0,0 +1,505 @@
+//! Debug info of ELF files, variables and line tables from DWARF, and function symbols
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use gimli::{AttributeValue, EndianSlice, Operation, RunTimeEndian, UnitOffset};
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+
+type R<'a> = EndianSlice<'a, RunTimeEndian>;
+
+/// Max nesting of struct members to decode
+const MAX_TYPE_DEPTH: usize = 4;
+
+/// Type of a variable, decoded from DWARF
+#[derive(Debug, Clone)]
+pub enum VarType {
+    Unsigned(u32),
+    Signed(u32),
+    Float(u32),
+    Bool,
+    Pointer(u32),
+    Struct {
+        name: String,
+        size: u32,
+        members: Vec<Member>,
+    },
+    /// Unsupported type, with byte size
+    Unknown(u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub offset: u32,
+    pub ty: VarType,
+}
+
+/// A global variable with static address
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub name: String,
+    pub address: u32,
+    pub ty: VarType,
+}
+
+impl VarType {
+    /// Byte size of the type
+    pub fn size(&self) -> u32 {
+        match self {
+            VarType::Unsigned(size)
+            | VarType::Signed(size)
+            | VarType::Float(size)
+            | VarType::Pointer(size)
+            | VarType::Unknown(size)
+            | VarType::Struct { size, .. } => *size,
+            VarType::Bool => 1,
+        }
+    }
+
+    /// Decode little endian raw data as a human readable value
+    pub fn decode(&self, data: &[u8]) -> String {
+        let size = (self.size() as usize).min(data.len());
+        let data = &data[..size];
+        let mut raw = [0u8; 8];
+        raw[..size.min(8)].copy_from_slice(&data[..size.min(8)]);
+        let val = u64::from_le_bytes(raw);
+
+        match self {
+            VarType::Unsigned(_) => format!("{}", val),
+            VarType::Signed(_) if size > 0 && size < 8 => {
+                let shift = 64 - size * 8;
+                format!("{}", ((val << shift) as i64) >> shift)
+            }
+            VarType::Signed(_) => format!("{}", val as i64),
+            VarType::Float(4) => format!("{}", f32::from_bits(val as u32)),
+            VarType::Float(8) => format!("{}", f64::from_bits(val)),
+            VarType::Bool => format!("{}", val != 0),
+            VarType::Pointer(_) => format!("0x{:08x}", val),
+            VarType::Struct { name, members, .. } => {
+                let fields = members
+                    .iter()
+                    .filter(|m| (m.offset as usize) < data.len())
+                    .map(|m| format!("{}: {}", m.name, m.ty.decode(&data[m.offset as usize..])))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("{} {{ {} }}", name, fields)
+            }
+            VarType::Float(_) | VarType::Unknown(_) => format!("{:02x?}", data),
+        }
+    }
+}
+
+/// Where a local variable lives, in RISC-V DWARF register numbers, 0 - 31 are `x0` - `x31`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalLocation {
+    /// In memory, at the frame base plus offset
+    FrameOffset(i64),
+    /// In memory, at a register plus offset
+    RegisterOffset(u16, i64),
+    /// In a register
+    Register(u16),
+}
+
+/// Frame base of a function, for [`LocalLocation::FrameOffset`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameBase {
+    /// The CFA, the frame pointer `s0` after the prologue of RISC-V GCC frames
+    Cfa,
+    Register(u16),
+}
+
+/// A parameter or local variable of a function
+#[derive(Debug, Clone)]
+pub struct LocalVariable {
+    pub name: String,
+    pub location: LocalLocation,
+    pub ty: VarType,
+}
+
+/// A function with debug info, for looking up locals by address
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub low_pc: u32,
+    pub high_pc: u32,
+    pub frame_base: Option<FrameBase>,
+    pub locals: Vec<LocalVariable>,
+}
+
+/// A row of the line table, the source line of the code starting at `address`
+#[derive(Debug, Clone)]
+pub struct LineRow {
+    pub address: u32,
+    pub path: PathBuf,
+    pub line: u32,
+}
+
+/// A function symbol, for address to name lookup
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+/// Load function symbols from an ELF file, sorted by address
+pub fn load_symbols(elf_data: &[u8]) -> Result<Vec<Symbol>> {
+    let file = object::File::parse(elf_data)?;
+    let mut symbols: Vec<_> = file
+        .symbols()
+        .filter(|sym| sym.kind() == SymbolKind::Text)
+        .filter_map(|sym| {
+            Some(Symbol {
+                name: sym.name().ok()?.to_string(),
+                address: sym.address() as u32,
+                size: sym.size() as u32,
+            })
+        })
+        .collect();
+    symbols.sort_by_key(|sym| sym.address);
+    Ok(symbols)
+}
+
+/// Find the function containing the address, returns the symbol and the offset
+pub fn lookup_symbol(symbols: &[Symbol], address: u32) -> Option<(&Symbol, u32)> {
+    let idx = symbols.partition_point(|sym| sym.address <= address);
+    let sym = symbols[..idx].last()?;
+    let offset = address - sym.address;
+    if sym.size != 0 && offset >= sym.size {
+        return None;
+    }
+    Some((sym, offset))
+}
+
+fn load_dwarf(elf_data: &[u8]) -> Result<gimli::Dwarf<R<'_>>> {
+    let file = object::File::parse(elf_data)?;
+    let endian = if file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let dwarf = gimli::Dwarf::load(|id| -> Result<R> {
+        let data = file
+            .section_by_name(id.name())
+            .and_then(|section| section.data().ok())
+            .unwrap_or(&[]);
+        Ok(EndianSlice::new(data, endian))
+    })?;
+    Ok(dwarf)
+}
+
+/// Load the line table of an ELF file, statement rows sorted by address
+pub fn load_line_rows(elf_data: &[u8]) -> Result<Vec<LineRow>> {
+    let dwarf = load_dwarf(elf_data)?;
+    let mut rows = vec![];
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let Some(program) = unit.line_program.clone() else {
+            continue;
+        };
+        let comp_dir = unit
+            .comp_dir
+            .map(|dir| PathBuf::from(dir.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        let mut program_rows = program.rows();
+        while let Some((header, row)) = program_rows.next_row()? {
+            if row.end_sequence() || !row.is_stmt() {
+                continue;
+            }
+            let (Some(line), Some(file)) = (row.line(), row.file(header)) else {
+                continue;
+            };
+            let mut path = comp_dir.clone();
+            if let Some(dir) = file.directory(header) {
+                path.push(dwarf.attr_string(&unit, dir)?.to_string_lossy().as_ref());
+            }
+            path.push(
+                dwarf
+                    .attr_string(&unit, file.path_name())?
+                    .to_string_lossy()
+                    .as_ref(),
+            );
+            rows.push(LineRow {
+                address: row.address() as u32,
+                path,
+                line: line.get() as u32,
+            });
+        }
+    }
+    rows.sort_by_key(|row| row.address);
+    Ok(rows)
+}
+
+/// Find the source line of the code at the address
+pub fn lookup_line(rows: &[LineRow], address: u32) -> Option<&LineRow> {
+    let idx = rows.partition_point(|row| row.address <= address);
+    rows[..idx].last()
+}
+
+/// Find the first address of a source line, or of the next line with code.
+/// Returns the address and the line found.
+pub fn find_line_address(rows: &[LineRow], path: &Path, line: u32) -> Option<(u32, u32)> {
+    rows.iter()
+        .filter(|row| row.line >= line && (row.path.ends_with(path) || path.ends_with(&row.path)))
+        .min_by_key(|row| (row.line, row.address))
+        .map(|row| (row.address, row.line))
+}
+
+/// Find global variables by name in the DWARF info of an ELF file
+pub fn find_variables(elf_data: &[u8], names: &[String]) -> Result<Vec<Variable>> {
+    let mut found = global_variables(elf_data)?;
+    // keep the order given by user
+    let mut vars = vec![];
+    for name in names {
+        let Some(pos) = found.iter().position(|v| &v.name == name) else {
+            anyhow::bail!("variable {:?} not found in DWARF info", name);
+        };
+        vars.push(found.swap_remove(pos));
+    }
+    Ok(vars)
+}
+
+/// All global variables with a static address in the DWARF info of an ELF file
+pub fn global_variables(elf_data: &[u8]) -> Result<Vec<Variable>> {
+    let dwarf = load_dwarf(elf_data)?;
+    let mut found: Vec<Variable> = vec![];
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_variable {
+                continue;
+            }
+            let Some(name) = entry.attr_value(gimli::DW_AT_name)? else {
+                continue;
+            };
+            let name = dwarf
+                .attr_string(&unit, name)?
+                .to_string_lossy()
+                .into_owned();
+            if found.iter().any(|v| v.name == name) {
+                continue;
+            }
+            // Only variables with a static address, declarations have no location
+            let Some(AttributeValue::Exprloc(expr)) = entry.attr_value(gimli::DW_AT_location)?
+            else {
+                continue;
+            };
+            let mut ops = expr.operations(unit.encoding());
+            let Some(Operation::Address { address }) = ops.next()? else {
+                continue;
+            };
+            let ty = match entry.attr_value(gimli::DW_AT_type)? {
+                Some(AttributeValue::UnitRef(offset)) => resolve_type(&dwarf, &unit, offset, 0)?,
+                _ => VarType::Unknown(0),
+            };
+            log::trace!("Found variable {} at 0x{:08x}: {:?}", name, address, ty);
+            found.push(Variable {
+                name,
+                address: address as u32,
+                ty,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Functions with their parameters and locals, sorted by address.
+///
+/// Only single location expressions are supported, variables in location lists
+/// (optimized code) are skipped, so are locals of inlined functions.
+pub fn load_functions(elf_data: &[u8]) -> Result<Vec<Function>> {
+    let dwarf = load_dwarf(elf_data)?;
+    let mut functions = vec![];
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut tree = unit.entries_tree(None)?;
+        collect_functions(&dwarf, &unit, tree.root()?, &mut functions)?;
+    }
+    functions.sort_by_key(|f| f.low_pc);
+    Ok(functions)
+}
+
+/// Find the function containing the address
+pub fn lookup_function(functions: &[Function], address: u32) -> Option<&Function> {
+    let idx = functions.partition_point(|f| f.low_pc <= address);
+    functions[..idx].iter().rev().find(|f| address < f.high_pc)
+}
+
+fn collect_functions(
+    dwarf: &gimli::Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    node: gimli::EntriesTreeNode<R>,
+    functions: &mut Vec<Function>,
+) -> Result<()> {
+    let entry = node.entry();
+    if entry.tag() == gimli::DW_TAG_subprogram {
+        let low_pc = match entry.attr_value(gimli::DW_AT_low_pc)? {
+            Some(AttributeValue::Addr(addr)) => Some(addr as u32),
+            _ => None,
+        };
+        let high_pc = match (low_pc, entry.attr_value(gimli::DW_AT_high_pc)?) {
+            (Some(_), Some(AttributeValue::Addr(addr))) => Some(addr as u32),
+            (Some(low), Some(attr)) => attr.udata_value().map(|len| low + len as u32),
+            _ => None,
+        };
+        let frame_base = match entry.attr_value(gimli::DW_AT_frame_base)? {
+            Some(AttributeValue::Exprloc(expr)) => {
+                match expr.operations(unit.encoding()).next()? {
+                    Some(Operation::CallFrameCFA) => Some(FrameBase::Cfa),
+                    Some(Operation::Register { register }) if register.0 < 32 => {
+                        Some(FrameBase::Register(register.0))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        if let (Some(low_pc), Some(high_pc)) = (low_pc, high_pc) {
+            let mut locals = vec![];
+            collect_locals(dwarf, unit, node, &mut locals)?;
+            functions.push(Function {
+                low_pc,
+                high_pc,
+                frame_base,
+                locals,
+            });
+        }
+        return Ok(());
+    }
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        collect_functions(dwarf, unit, child, functions)?;
+    }
+    Ok(())
+}
+
+/// Parameters and variables of a function, including nested lexical blocks
+fn collect_locals(
+    dwarf: &gimli::Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    node: gimli::EntriesTreeNode<R>,
+    locals: &mut Vec<LocalVariable>,
+) -> Result<()> {
+    let mut children = node.children();
+    while let Some(child) = children.next()? {
+        let entry = child.entry();
+        match entry.tag() {
+            gimli::DW_TAG_lexical_block => collect_locals(dwarf, unit, child, locals)?,
+            gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable => {
+                let Some(name) = entry.attr_value(gimli::DW_AT_name)? else {
+                    continue;
+                };
+                let name = dwarf
+                    .attr_string(unit, name)?
+                    .to_string_lossy()
+                    .into_owned();
+                // statics have an address, they are in the globals
+                let Some(AttributeValue::Exprloc(expr)) =
+                    entry.attr_value(gimli::DW_AT_location)?
+                else {
+                    continue;
+                };
+                let location = match expr.operations(unit.encoding()).next()? {
+                    Some(Operation::FrameOffset { offset }) => LocalLocation::FrameOffset(offset),
+                    Some(Operation::RegisterOffset {
+                        register, offset, ..
+                    }) if register.0 < 32 => LocalLocation::RegisterOffset(register.0, offset),
+                    Some(Operation::Register { register }) if register.0 < 32 => {
+                        LocalLocation::Register(register.0)
+                    }
+                    _ => continue,
+                };
+                let ty = match entry.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(offset)) => resolve_type(dwarf, unit, offset, 0)?,
+                    _ => VarType::Unknown(0),
+                };
+                locals.push(LocalVariable { name, location, ty });
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn resolve_type(
+    dwarf: &gimli::Dwarf<R>,
+    unit: &gimli::Unit<R>,
+    offset: UnitOffset,
+    depth: usize,
+) -> Result<VarType> {
+    let entry = unit.entry(offset)?;
+    let size = entry
+        .attr_value(gimli::DW_AT_byte_size)?
+        .and_then(|v| v.udata_value())
+        .unwrap_or(0) as u32;
+    let inner = match entry.attr_value(gimli::DW_AT_type)? {
+        Some(AttributeValue::UnitRef(offset)) => Some(offset),
+        _ => None,
+    };
+
+    let ty = match entry.tag() {
+        gimli::DW_TAG_typedef | gimli::DW_TAG_volatile_type | gimli::DW_TAG_const_type => {
+            match inner {
+                Some(offset) => resolve_type(dwarf, unit, offset, depth)?,
+                None => VarType::Unknown(size),
+            }
+        }
+        gimli::DW_TAG_enumeration_type => VarType::Unsigned(size),
+        gimli::DW_TAG_pointer_type => VarType::Pointer(if size == 0 { 4 } else { size }),
+        gimli::DW_TAG_base_type => match entry.attr_value(gimli::DW_AT_encoding)? {
+            Some(AttributeValue::Encoding(gimli::DW_ATE_boolean)) => VarType::Bool,
+            Some(AttributeValue::Encoding(gimli::DW_ATE_float)) => VarType::Float(size),
+            Some(AttributeValue::Encoding(gimli::DW_ATE_signed | gimli::DW_ATE_signed_char)) => {
+                VarType::Signed(size)
+            }
+            Some(AttributeValue::Encoding(
+                gimli::DW_ATE_unsigned | gimli::DW_ATE_unsigned_char | gimli::DW_ATE_UTF,
+            )) => VarType::Unsigned(size),
+            _ => VarType::Unknown(size),
+        },
+        gimli::DW_TAG_structure_type if depth < MAX_TYPE_DEPTH => {
+            let name = match entry.attr_value(gimli::DW_AT_name)? {
+                Some(attr) => dwarf
+                    .attr_string(unit, attr)?
+                    .to_string_lossy()
+                    .into_owned(),
+                None => String::new(),
+            };
+            let mut members = vec![];
+            let mut tree = unit.entries_tree(Some(offset))?;
+            let mut children = tree.root()?.children();
+            while let Some(child) = children.next()? {
+                let child = child.entry();
+                if child.tag() != gimli::DW_TAG_member {
+                    continue;
+                }
+                let name = match child.attr_value(gimli::DW_AT_name)? {
+                    Some(attr) => dwarf
+                        .attr_string(unit, attr)?
+                        .to_string_lossy()
+                        .into_owned(),
+                    None => continue,
+                };
+                let offset = child
+                    .attr_value(gimli::DW_AT_data_member_location)?
+                    .and_then(|v| v.udata_value())
+                    .unwrap_or(0) as u32;
+                let ty = match child.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(ty)) => resolve_type(dwarf, unit, ty, depth + 1)?,
+                    _ => VarType::Unknown(0),
+                };
+                members.push(Member { name, offset, ty });
+            }
+            VarType::Struct {
+                name,
+                size,
+                members,
+            }
+        }
+        _ => VarType::Unknown(size),
+    };
+    Ok(ty)
+}
@@ -0,0 +1,312 @@
+//! Runner of on-target test suites built with the `embedded-test` crate.
+//!
+//! The test binary talks to the host by RISC-V semihosting. After reset it reads
+//! its command line: `list` makes it report its tests in JSON by a custom
+//! semihosting operation, `run <name>` runs one test, which exits by `SYS_EXIT`.
+//! Console writes of the target are printed to stdout.
+//!
+//! The core halts on every `ebreak`, a halt outside of a semihosting call fails
+//! the test.
+use std::{
+    fmt,
+    io::Write,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use object::Object;
+use serde::Deserialize;
+
+use crate::{
+    dmi::{DebugModuleInterface, DCSR_EBREAKM},
+    operations::ProbeSession,
+    regs::{self, Dmstatus},
+};
+
+/// Section of the test functions in binaries of `embedded-test`
+const TEST_SECTION: &str = ".embedded_test";
+
+/// `slli x0, x0, 0x1f`, right before the `ebreak` of a semihosting call
+const SEMIHOSTING_ENTRY: u32 = 0x01f0_1013;
+/// `srai x0, x0, 7`, right after the `ebreak`
+const SEMIHOSTING_EXIT: u32 = 0x4070_5013;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+/// User operation of `embedded-test`, the test list in JSON
+const SYS_USER_LIST: u32 = 0x100;
+
+/// SYS_EXIT reason of a normal exit
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+const GPR_A0: u16 = 0x100a;
+const GPR_A1: u16 = 0x100b;
+
+/// Max length of a NUL terminated string from the target
+const MAX_STRING_LEN: u32 = 0x1000;
+/// Run time of the `list` command
+const LIST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A test of the suite, as reported by the target
+#[derive(Debug, Clone, Deserialize)]
+pub struct Test {
+    pub name: String,
+    #[serde(default)]
+    pub should_panic: bool,
+    #[serde(default)]
+    pub ignored: bool,
+    /// Timeout of the test, in seconds
+    #[serde(default)]
+    pub timeout: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TestList {
+    tests: Vec<Test>,
+}
+
+/// Result of a single test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "ok"),
+            Outcome::Failed => write!(f, "FAILED"),
+            Outcome::Ignored => write!(f, "ignored"),
+        }
+    }
+}
+
+/// How a program run under semihosting ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    Code(u32),
+    Timeout,
+    /// Halted outside of a semihosting call, at this pc
+    Halted(u32),
+}
+
+/// Check if an ELF file is a test binary of `embedded-test`
+pub fn is_test_binary(elf_data: &[u8]) -> Result<bool> {
+    let file = object::File::parse(elf_data)?;
+    Ok(file.section_by_name(TEST_SECTION).is_some())
+}
+
+/// Reset and ask the flashed test binary for its tests
+pub fn list_tests(sess: &mut ProbeSession) -> Result<Vec<Test>> {
+    let mut tests = None;
+    match run_program(sess, "list", LIST_TIMEOUT, &mut tests)? {
+        Exit::Code(0) => (),
+        exit => anyhow::bail!("Listing tests failed: {}", exit_reason(exit)),
+    }
+    tests.ok_or_else(|| anyhow::format_err!("the target did not report its tests"))
+}
+
+/// Reset and run a single test of the flashed test binary
+pub fn run_test(sess: &mut ProbeSession, test: &Test, timeout: Duration) -> Result<Outcome> {
+    if test.ignored {
+        return Ok(Outcome::Ignored);
+    }
+    let timeout = test
+        .timeout
+        .map(|secs| Duration::from_secs(secs as u64))
+        .unwrap_or(timeout);
+    let exit = run_program(sess, &format!("run {}", test.name), timeout, &mut None)?;
+    let passed = match exit {
+        Exit::Code(code) => (code == 0) != test.should_panic,
+        exit => {
+            log::error!("{}: {}", test.name, exit_reason(exit));
+            false
+        }
+    };
+    Ok(if passed {
+        Outcome::Passed
+    } else {
+        Outcome::Failed
+    })
+}
+
+fn exit_reason(exit: Exit) -> String {
+    match exit {
+        Exit::Code(code) => format!("exit code {}", code),
+        Exit::Timeout => "timeout".to_string(),
+        Exit::Halted(pc) => format!("halted at 0x{:08x}, not a semihosting call", pc),
+    }
+}
+
+/// Reset and run the firmware with `cmdline`, serving semihosting calls until it exits
+fn run_program(
+    sess: &mut ProbeSession,
+    cmdline: &str,
+    timeout: Duration,
+    tests: &mut Option<Vec<Test>>,
+) -> Result<Exit> {
+    log::debug!("Run with command line {:?}", cmdline);
+    sess.reset_and_halt()?;
+    let dcsr = sess.read_reg(regs::DCSR)?;
+    sess.write_reg(regs::DCSR, dcsr | DCSR_EBREAKM)?;
+    sess.ensure_mcu_resume()?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let dmstatus = sess.probe.read_dmi_reg::<Dmstatus>()?;
+        if !dmstatus.allhalted() {
+            if Instant::now() >= deadline {
+                sess.ensure_mcu_halt()?;
+                return Ok(Exit::Timeout);
+            }
+            sleep(Duration::from_millis(1));
+            continue;
+        }
+
+        let pc = sess.read_reg(regs::DPC)?;
+        let ret = sess.preserving_scratch_gprs(|sess| {
+            let is_semihosting = pc.is_multiple_of(4)
+                && sess.read_mem32(pc.wrapping_sub(4))? == SEMIHOSTING_ENTRY
+                && sess.read_mem32(pc + 4)? == SEMIHOSTING_EXIT;
+            if is_semihosting {
+                let op = sess.read_reg(GPR_A0)?;
+                let param = sess.read_reg(GPR_A1)?;
+                handle_call(sess, op, param, cmdline, tests)
+            } else {
+                Ok(Err(Exit::Halted(pc)))
+            }
+        })?;
+        let ret = match ret {
+            Ok(ret) => ret,
+            Err(exit) => return Ok(exit),
+        };
+        sess.write_reg(GPR_A0, ret)?;
+        sess.write_reg(regs::DPC, pc + 4)?;
+        sess.ensure_mcu_resume()?;
+    }
+}
+
+/// Serve a semihosting call, returns the value of a0, or how the program exited
+fn handle_call(
+    sess: &mut ProbeSession,
+    op: u32,
+    param: u32,
+    cmdline: &str,
+    tests: &mut Option<Vec<Test>>,
+) -> Result<std::result::Result<u32, Exit>> {
+    let mut stdout = std::io::stdout();
+    let ret = match op {
+        SYS_OPEN => {
+            let [name, _mode, len] = read_words(sess, param)?;
+            let name = sess.peek_memory(name, len)?;
+            if name == b":tt" {
+                1
+            } else {
+                log::warn!(
+                    "Semihosting open of {:?} is not supported",
+                    String::from_utf8_lossy(&name)
+                );
+                u32::MAX
+            }
+        }
+        SYS_WRITEC => {
+            stdout.write_all(&sess.peek_memory(param, 1)?)?;
+            stdout.flush()?;
+            0
+        }
+        SYS_WRITE0 => {
+            stdout.write_all(&read_string(sess, param)?)?;
+            stdout.flush()?;
+            0
+        }
+        SYS_WRITE => {
+            let [_handle, data, len] = read_words(sess, param)?;
+            stdout.write_all(&sess.peek_memory(data, len)?)?;
+            stdout.flush()?;
+            0
+        }
+        SYS_GET_CMDLINE => {
+            let [buf, size] = read_words(sess, param)?;
+            if cmdline.len() as u32 >= size {
+                u32::MAX
+            } else {
+                let mut data = cmdline.as_bytes().to_vec();
+                data.push(0);
+                write_bytes(sess, buf, &data)?;
+                sess.write_mem32(param + 4, cmdline.len() as u32)?;
+                0
+            }
+        }
+        SYS_EXIT => {
+            let code = if param == ADP_STOPPED_APPLICATION_EXIT {
+                0
+            } else {
+                1
+            };
+            return Ok(Err(Exit::Code(code)));
+        }
+        SYS_EXIT_EXTENDED => {
+            let [reason, subcode] = read_words(sess, param)?;
+            let code = if reason == ADP_STOPPED_APPLICATION_EXIT {
+                subcode
+            } else {
+                1
+            };
+            return Ok(Err(Exit::Code(code)));
+        }
+        SYS_USER_LIST => {
+            let [data, len] = read_words(sess, param)?;
+            let list: TestList = serde_json::from_slice(&sess.peek_memory(data, len)?)?;
+            *tests = Some(list.tests);
+            0
+        }
+        _ => {
+            log::warn!("Unsupported semihosting operation 0x{:x}", op);
+            u32::MAX
+        }
+    };
+    Ok(Ok(ret))
+}
+
+/// Read the parameter block of a semihosting call
+fn read_words<const N: usize>(sess: &mut ProbeSession, address: u32) -> Result<[u32; N]> {
+    let mut words = [0; N];
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = sess.read_mem32(address + i as u32 * 4)?;
+    }
+    Ok(words)
+}
+
+fn read_string(sess: &mut ProbeSession, address: u32) -> Result<Vec<u8>> {
+    let mut s = vec![];
+    while (s.len() as u32) < MAX_STRING_LEN {
+        let chunk = sess.peek_memory(address + s.len() as u32, 4)?;
+        match chunk.iter().position(|&b| b == 0) {
+            Some(end) => {
+                s.extend_from_slice(&chunk[..end]);
+                return Ok(s);
+            }
+            None => s.extend_from_slice(&chunk),
+        }
+    }
+    Ok(s)
+}
+
+fn write_bytes(sess: &mut ProbeSession, address: u32, data: &[u8]) -> Result<()> {
+    if address.is_multiple_of(4) {
+        sess.write_memory_by_dmi(address, data)?;
+    } else {
+        for (i, &b) in data.iter().enumerate() {
+            sess.write_mem8(address + i as u32, b)?;
+        }
+    }
+    Ok(())
+}
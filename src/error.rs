@@ -1,6 +1,8 @@
 use thiserror::Error;
 
-use crate::RiscvChip;
+pub use wlink_protocol::error::{FailureHint, ProbeFailure};
+
+use crate::{probe::FirmwareFeature, RiscvChip};
 
 /// Alias for a `Result` with the error type `wlink::Error`.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -21,24 +23,72 @@ pub enum Error {
     UnknownLinkVariant(u8),
     #[error("Unknown RISC-V Chip: 0x{0:02x}")]
     UnknownChip(u8),
+    #[error("Unknown chip: {0}")]
+    UnknownChipName(String),
     #[error("Probe is not attached to an MCU, or debug is not enabled. (hint: use wchisp to enable debug)")]
     NotAttached,
     #[error("Chip mismatch: expected {0:?}, got {1:?}")]
     ChipMismatch(RiscvChip, RiscvChip),
     #[error("WCH-Link underlying protocol error: {0:#04x} {1:#04x?}")]
     Protocol(u8, Vec<u8>),
+    #[error("WCH-Link {0}, reply {1:02x?}")]
+    ProbeFailure(ProbeFailure, Vec<u8>),
     #[error("Invalid payload length")]
     InvalidPayloadLength,
     #[error("Invalid payload")]
     InvalidPayload,
     #[error("DM Abstract comand error: {0:?}")]
     AbstractCommandError(AbstractcsCmdErr),
+    #[error("DM abstract command 0x{command:08x} failed: {err:?}, abstractcs=0x{abstractcs:08x} dmstatus=0x{dmstatus:08x}")]
+    AbstractCommandFailed {
+        command: u32,
+        err: AbstractcsCmdErr,
+        abstractcs: u32,
+        dmstatus: u32,
+    },
     #[error("DM is busy")]
     Busy,
     #[error("DMI Status Failed")]
     DmiFailed,
+    #[error("Debug module is not authenticated, the debug interface is disabled. (hint: use `wlink erase --method power-off` to erase the chip and enable debug again)")]
+    NotAuthenticated,
+    #[error("Core is unavailable, powered down or held in reset. (hint: check target power and the RST pin, or use `wlink erase --method power-off`)")]
+    HartUnavailable,
+    #[error("Fastprogram failed: {0:?}")]
+    Fastprogram(crate::commands::FastprogramStatus),
+    #[error("Flash verify failed in the pack at 0x{address:08x}: {status:?}")]
+    FastprogramVerify {
+        address: u32,
+        status: crate::commands::FastprogramStatus,
+    },
+    #[error("Flash OP upload is corrupted at offset 0x{0:x}, the loader differs in SRAM. (hint: check the USB cable, or try `--speed low`)")]
+    FlashOpCorrupted(usize),
+    #[error("Flash sectors are write protected: {}. (hint: use `wlink unprotect` first)", .0.iter().map(|addr| format!("0x{addr:08x}")).collect::<Vec<_>>().join(", "))]
+    WriteProtected(Vec<u32>),
+    #[error("Writing register {0} while the core is running may wedge the debug session (hint: halt first, or use --force)")]
+    UnsafeRegisterWrite(String),
+    #[error("{0} is not allowed in read-only mode")]
+    ReadOnly(&'static str),
+    #[error("Target was reset during the operation, watchdog enabled?")]
+    TargetReset,
+    #[error("SWD transfer failed, ACK {0:#05b}")]
+    SwdTransfer(u8),
+    #[error("{feature} requires WCH-Link firmware v{}.{} or newer, the probe has v{}.{} (hint: see `wlink probe-update`)", .required.0, .required.1, .current.0, .current.1)]
+    FirmwareTooOld {
+        feature: FirmwareFeature,
+        current: (u8, u8),
+        required: (u8, u8),
+    },
     #[error("Operation timeout")]
     Timeout,
+    #[error("Output matched {0:?}")]
+    OutputMatched(String),
+    #[error("Timeout waiting for {0:?} in output")]
+    OutputTimeout(String),
+    #[error("Probe stalled at offset 0x{received:x}, {received} of {expected} bytes arrived")]
+    PartialData { received: usize, expected: usize },
+    #[error("Permission denied to open serial port {0} (hint: {1})")]
+    SerialPermissionDenied(String, &'static str),
     #[error("Serial port error: {0}")]
     Serial(#[from] serialport::Error),
     #[error("Io error: {0}")]
@@ -47,6 +97,36 @@ pub enum Error {
     Driver,
 }
 
+impl Error {
+    /// The probe lost the chip, e.g. by a brown-out, a re-attach may bring it back
+    pub fn is_not_attached(&self) -> bool {
+        matches!(
+            self,
+            Error::NotAttached
+                | Error::ProbeFailure(
+                    ProbeFailure {
+                        hint: Some(FailureHint::AttachFailed),
+                        ..
+                    },
+                    _
+                )
+        )
+    }
+}
+
+impl From<wlink_protocol::Error> for Error {
+    fn from(e: wlink_protocol::Error) -> Self {
+        match e {
+            wlink_protocol::Error::UnknownLinkVariant(v) => Error::UnknownLinkVariant(v),
+            wlink_protocol::Error::UnknownChip(v) => Error::UnknownChip(v),
+            wlink_protocol::Error::UnknownChipName(name) => Error::UnknownChipName(name),
+            wlink_protocol::Error::Protocol(reason, resp) => Error::Protocol(reason, resp),
+            wlink_protocol::Error::InvalidPayloadLength => Error::InvalidPayloadLength,
+            wlink_protocol::Error::InvalidPayload => Error::InvalidPayload,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum AbstractcsCmdErr {
     /// Write to the command, abstractcs and abstractauto registers, or read/write to the data
@@ -67,16 +147,16 @@ pub enum AbstractcsCmdErr {
 }
 
 impl AbstractcsCmdErr {
-    pub(crate) fn try_from_cmderr(value: u8) -> Result<()> {
+    pub(crate) fn from_cmderr(value: u8) -> Option<Self> {
         match value {
-            0 => Ok(()),
-            1 => Err(Error::AbstractCommandError(AbstractcsCmdErr::Busy)),
-            2 => Err(Error::AbstractCommandError(AbstractcsCmdErr::NotSupported)),
-            3 => Err(Error::AbstractCommandError(AbstractcsCmdErr::Exception)),
-            4 => Err(Error::AbstractCommandError(AbstractcsCmdErr::HaltOrResume)),
-            5 => Err(Error::AbstractCommandError(AbstractcsCmdErr::Bus)),
-            6 => Err(Error::AbstractCommandError(AbstractcsCmdErr::Parity)),
-            7 => Err(Error::AbstractCommandError(AbstractcsCmdErr::Other)),
+            0 => None,
+            1 => Some(AbstractcsCmdErr::Busy),
+            2 => Some(AbstractcsCmdErr::NotSupported),
+            3 => Some(AbstractcsCmdErr::Exception),
+            4 => Some(AbstractcsCmdErr::HaltOrResume),
+            5 => Some(AbstractcsCmdErr::Bus),
+            6 => Some(AbstractcsCmdErr::Parity),
+            7 => Some(AbstractcsCmdErr::Other),
 
             _ => unreachable!(),
         }
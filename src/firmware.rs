@@ -1,11 +1,12 @@
 //! Firmware file formats
+use std::ops::Range;
 use std::path::Path;
 use std::str;
 
 use anyhow::Result;
 use object::{
-    elf::FileHeader32, elf::PT_LOAD, read::elf::FileHeader, read::elf::ProgramHeader, Endianness,
-    Object, ObjectSection,
+    elf::FileHeader32, elf::PF_R, elf::PF_X, elf::PT_LOAD, read::elf::FileHeader,
+    read::elf::ProgramHeader, Endianness, Object, ObjectSection, ObjectSymbol,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -13,9 +14,126 @@ pub enum FirmwareFormat {
     PlainHex,
     IntelHex,
     ELF,
+    SRecord,
     Binary,
 }
 
+/// Output format of memory dumps and conversions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DumpFormat {
+    /// Raw binary
+    #[default]
+    Bin,
+    /// Intel HEX
+    Ihex,
+    /// Motorola S-record
+    Srec,
+    /// ELF32, one loadable segment
+    Elf,
+}
+
+impl DumpFormat {
+    /// Guess from the file extension, default to binary
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let ext = path
+            .as_ref()
+            .extension()
+            .map(|s| s.to_string_lossy())
+            .unwrap_or_default()
+            .to_lowercase();
+        match &*ext {
+            "ihex" | "ihe" | "hex" => DumpFormat::Ihex,
+            "srec" | "s19" | "s28" | "s37" | "mot" => DumpFormat::Srec,
+            "elf" => DumpFormat::Elf,
+            _ => DumpFormat::Bin,
+        }
+    }
+}
+
+/// Default data bytes per S-record
+pub const SREC_DEFAULT_RECORD_LEN: usize = 32;
+/// Max data bytes per S-record, the byte count field includes 4 address bytes and the checksum
+pub const SREC_MAX_RECORD_LEN: usize = 250;
+
+/// CRC-32/ISO-HDLC, as zlib and most bootloaders
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(0xffff_ffff)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 ^= b as u32;
+            for _ in 0..8 {
+                self.0 = (self.0 >> 1) ^ (0xedb8_8320 & (self.0 & 1).wrapping_neg());
+            }
+        }
+    }
+
+    fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+/// A field of image metadata, written in little endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaField {
+    /// Chip UID of 8 bytes, from the ESIG
+    Uid,
+    /// Unix time of flashing, 4 bytes
+    Timestamp,
+    /// Version as `major << 16 | minor << 8 | patch`, 4 bytes
+    Version(u32),
+}
+
+/// Parse a version like `1.2.3` into `major << 16 | minor << 8 | patch`
+pub fn pack_version(s: &str) -> Option<u32> {
+    let mut parts = s.split('.').map(|p| p.parse::<u8>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().unwrap_or(Some(0))?;
+    let patch = parts.next().unwrap_or(Some(0))?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((major as u32) << 16 | (minor as u32) << 8 | patch as u32)
+}
+
+/// Layout fixes of images made by other tools, applied after reading the files
+#[derive(Debug, Clone, Copy)]
+pub struct ImageLayout {
+    /// Bytes to skip at the start of the image
+    pub file_offset: Option<u32>,
+    /// Pad the image to this size
+    pub pad_to: Option<u32>,
+    pub pad_byte: u8,
+}
+
+impl Default for ImageLayout {
+    fn default() -> Self {
+        Self {
+            file_offset: None,
+            pad_to: None,
+            pad_byte: 0xff,
+        }
+    }
+}
+
+impl ImageLayout {
+    pub fn apply(&self, firmware: Firmware) -> Result<Firmware> {
+        let mut firmware = firmware;
+        if let Some(offset) = self.file_offset {
+            firmware = firmware.skip(offset)?;
+        }
+        if let Some(size) = self.pad_to {
+            firmware = firmware.pad_to(size, self.pad_byte)?;
+        }
+        Ok(firmware)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Section {
     /// The start address of the segment, physical address.
@@ -39,6 +157,122 @@ pub enum Firmware {
 }
 
 impl Firmware {
+    /// Drop the first `offset` bytes, like a header before the image.
+    ///
+    /// The address of a single section is kept, the data after the offset is flashed there.
+    pub fn skip(self, offset: u32) -> Result<Self> {
+        let strip = |mut data: Vec<u8>| -> Result<Vec<u8>> {
+            if offset as usize > data.len() {
+                anyhow::bail!(
+                    "file offset 0x{:x} is beyond the image of {} bytes",
+                    offset,
+                    data.len()
+                );
+            }
+            data.drain(..offset as usize);
+            Ok(data)
+        };
+        match self {
+            Firmware::Binary(data) => Ok(Firmware::Binary(strip(data)?)),
+            Firmware::Sections(mut sections) if sections.len() == 1 => {
+                sections[0].data = strip(std::mem::take(&mut sections[0].data))?;
+                Ok(Firmware::Sections(sections))
+            }
+            Firmware::Sections(_) => {
+                anyhow::bail!("file offset is ambiguous for an image of multiple sections")
+            }
+        }
+    }
+
+    /// Pad the image to `size` bytes with `byte`, from the start of the first section
+    pub fn pad_to(self, size: u32, byte: u8) -> Result<Self> {
+        let pad = |data: &mut Vec<u8>, size: u32| -> Result<()> {
+            if data.len() > size as usize {
+                anyhow::bail!(
+                    "image of {} bytes is larger than the pad size {}",
+                    data.len(),
+                    size
+                );
+            }
+            data.resize(size as usize, byte);
+            Ok(())
+        };
+        match self {
+            Firmware::Binary(mut data) => {
+                pad(&mut data, size)?;
+                Ok(Firmware::Binary(data))
+            }
+            Firmware::Sections(mut sections) => {
+                sections.sort_by_key(|s| s.address);
+                let start = sections.first().map(|s| s.address).unwrap_or_default();
+                if let Some(last) = sections.last_mut() {
+                    let size = (start as u64 + size as u64)
+                        .checked_sub(last.address as u64)
+                        .ok_or_else(|| {
+                            anyhow::format_err!("image is larger than the pad size {}", size)
+                        })?;
+                    pad(&mut last.data, size as u32)?;
+                }
+                Ok(Firmware::Sections(sections))
+            }
+        }
+    }
+
+    /// Overwrite image bytes at `address`, `base` is the start address of a binary image
+    pub fn patch(&mut self, base: u32, address: u32, bytes: &[u8]) -> Result<()> {
+        let (start, data) = match self {
+            Firmware::Binary(data) => (base, data),
+            Firmware::Sections(sections) => sections
+                .iter_mut()
+                .find(|s| s.address <= address && address < s.end_address())
+                .map(|s| (s.address, &mut s.data))
+                .ok_or_else(|| anyhow::format_err!("0x{:08x} is outside of the image", address))?,
+        };
+        let offset = address.wrapping_sub(start) as usize;
+        match data.get_mut(offset..offset + bytes.len()) {
+            Some(dst) if address >= start => {
+                dst.copy_from_slice(bytes);
+                Ok(())
+            }
+            _ => anyhow::bail!(
+                "0x{:08x}..0x{:08x} is outside of the image",
+                address,
+                address as u64 + bytes.len() as u64
+            ),
+        }
+    }
+
+    /// Write the CRC-32 of the image at `address`, in little endian, returns the CRC.
+    ///
+    /// The CRC covers the image data in address order, without the 4 CRC bytes and gaps.
+    pub fn insert_crc32(&mut self, base: u32, address: u32) -> Result<u32> {
+        // clear first, this also checks the address
+        self.patch(base, address, &[0; 4])?;
+        let mut crc = Crc32::new();
+        let mut update = |start: u32, data: &[u8]| {
+            let crc_range = address.wrapping_sub(start) as usize..;
+            match crc_range.start.checked_add(4) {
+                Some(end) if address >= start && end <= data.len() => {
+                    crc.update(&data[..crc_range.start]);
+                    crc.update(&data[end..]);
+                }
+                _ => crc.update(data),
+            }
+        };
+        match self {
+            Firmware::Binary(data) => update(base, data),
+            Firmware::Sections(sections) => {
+                sections.sort_by_key(|s| s.address);
+                for s in sections.iter() {
+                    update(s.address, &s.data);
+                }
+            }
+        }
+        let crc = crc.finish();
+        self.patch(base, address, &crc.to_le_bytes())?;
+        Ok(crc)
+    }
+
     /// Merge sections, and fill gap with 0xff
     pub fn merge_sections(self) -> Result<Self> {
         let Firmware::Sections(mut sections) = self else {
@@ -92,9 +326,86 @@ pub fn read_firmware_from_file<P: AsRef<Path>>(path: P) -> Result<Firmware> {
             read_ihex(str::from_utf8(&raw)?).and_then(|f| f.merge_sections())
         }
         FirmwareFormat::ELF => read_elf(&raw).and_then(|f| f.merge_sections()),
+        FirmwareFormat::SRecord => {
+            read_srec(str::from_utf8(&raw)?).and_then(|f| f.merge_sections())
+        }
     }
 }
 
+/// Read firmware files given as `PATH[@ADDRESS]`, into one firmware.
+///
+/// A single file without address is read as is. Otherwise all files are combined
+/// into sections, binary files require an address.
+pub fn read_firmware_from_files(specs: &[String]) -> Result<Firmware> {
+    if let [spec] = specs {
+        if split_address(spec)?.1.is_none() {
+            return read_firmware_from_file(spec);
+        }
+    }
+
+    let mut sections = vec![];
+    for spec in specs {
+        let (path, address) = split_address(spec)?;
+        match (read_firmware_from_file(path)?, address) {
+            (Firmware::Binary(data), Some(address)) => sections.push(Section { address, data }),
+            (Firmware::Binary(_), None) => {
+                anyhow::bail!(
+                    "address is required for binary file {}, like {}@0x08000000",
+                    path,
+                    path
+                )
+            }
+            (Firmware::Sections(sects), address) => {
+                if address.is_some() {
+                    log::warn!(
+                        "Address of {} is ignored, ELF and ihex have addresses",
+                        path
+                    );
+                }
+                sections.extend(sects);
+            }
+        }
+    }
+
+    sections.sort_by_key(|s| s.address);
+    for pair in sections.windows(2) {
+        if pair[0].end_address() > pair[1].address {
+            anyhow::bail!(
+                "firmware sections overlap: 0x{:08x}..0x{:08x} and 0x{:08x}..0x{:08x}",
+                pair[0].address,
+                pair[0].end_address(),
+                pair[1].address,
+                pair[1].end_address()
+            );
+        }
+    }
+    Ok(Firmware::Sections(sections))
+}
+
+/// Split `PATH@ADDRESS`, the path is returned as is if the suffix is not an address
+pub fn split_address(spec: &str) -> Result<(&str, Option<u32>)> {
+    match spec.rsplit_once('@') {
+        Some((path, addr)) if addr.starts_with(|c: char| c.is_ascii_digit()) => {
+            let address = parse_number(addr).map_err(anyhow::Error::msg)?;
+            Ok((path, Some(address)))
+        }
+        _ => Ok((spec, None)),
+    }
+}
+
+/// Parse numbers like `0x0800_0000`, `0b1010` or `1024`
+pub fn parse_number(s: &str) -> std::result::Result<u32, String> {
+    let s = s.replace('_', "").to_lowercase();
+    let ret = if let Some(hex_str) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex_str, 16)
+    } else if let Some(bin_str) = s.strip_prefix("0b") {
+        u32::from_str_radix(bin_str, 2)
+    } else {
+        s.parse()
+    };
+    ret.map_err(|_| format!("invalid number: {s:?}"))
+}
+
 fn guess_format(path: &Path, raw: &[u8]) -> FirmwareFormat {
     let ext = path
         .extension()
@@ -104,6 +415,9 @@ fn guess_format(path: &Path, raw: &[u8]) -> FirmwareFormat {
     if ["ihex", "ihe", "h86", "hex", "a43", "a90"].contains(&&*ext) {
         return FirmwareFormat::IntelHex;
     }
+    if ["srec", "s19", "s28", "s37", "mot"].contains(&&*ext) {
+        return FirmwareFormat::SRecord;
+    }
 
     // FIXME: is this 4-byte possible to be some kind of assembly binary?
     if raw.starts_with(&[0x7f, b'E', b'L', b'F']) {
@@ -172,6 +486,58 @@ pub fn read_ihex(data: &str) -> Result<Firmware> {
     Ok(Firmware::Sections(segs))
 }
 
+/// Read Motorola S-records, data records of S1/S2/S3
+pub fn read_srec(data: &str) -> Result<Firmware> {
+    let mut sections: Vec<Section> = vec![];
+    for (lineno, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| anyhow::format_err!("S-record line {}: {}", lineno + 1, msg);
+        let (ty, rest) = match (line.as_bytes(), line.get(2..)) {
+            ([b'S', ty, ..], Some(rest)) => (*ty, rest),
+            _ => return Err(err("not a S-record")),
+        };
+        let bytes = hex::decode(rest).map_err(|_| err("invalid hex"))?;
+        if bytes.is_empty() || bytes[0] as usize != bytes.len() - 1 {
+            return Err(err("byte count mismatch"));
+        }
+        let sum = bytes[..bytes.len() - 1]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if !sum != bytes[bytes.len() - 1] {
+            return Err(err("checksum mismatch"));
+        }
+        let addr_len = match ty {
+            b'1' => 2,
+            b'2' => 3,
+            b'3' => 4,
+            // header, count and start address records
+            _ => continue,
+        };
+        let body = &bytes[1..bytes.len() - 1];
+        if body.len() < addr_len {
+            return Err(err("record too short"));
+        }
+        let address = body[..addr_len]
+            .iter()
+            .fold(0u32, |addr, b| (addr << 8) | *b as u32);
+        let value = &body[addr_len..];
+        match sections.last_mut() {
+            Some(last) if last.end_address() == address => last.data.extend_from_slice(value),
+            _ => sections.push(Section {
+                address,
+                data: value.to_vec(),
+            }),
+        }
+    }
+    if sections.is_empty() {
+        anyhow::bail!("no data records in S-record file");
+    }
+    Ok(Firmware::Sections(sections))
+}
+
 /// Simulates `objcopy -O binary`, returns loadable sections
 pub fn read_elf(elf_data: &[u8]) -> Result<Firmware> {
     let file_kind = object::FileKind::parse(elf_data)?;
@@ -254,3 +620,255 @@ pub fn read_elf(elf_data: &[u8]) -> Result<Firmware> {
     // merge_sections(sections)
     Ok(Firmware::Sections(sections))
 }
+
+/// Entry point of an ELF32 file
+pub fn read_elf_entry(elf_data: &[u8]) -> Result<u32> {
+    let elf_header = FileHeader32::<Endianness>::parse(elf_data)?;
+    Ok(elf_header.e_entry(elf_header.endian()?))
+}
+
+/// Symbols of common linker scripts, marking the stack top or the heap end
+const RAM_END_SYMBOLS: &[&str] = &[
+    "_stack_top",
+    "__stack_top",
+    "_estack",
+    "__StackTop",
+    "_eusrstack",
+    "__eheap",
+    "_heap_end",
+    "__heap_end",
+];
+
+/// End of RAM used by an ELF file, from stack and heap symbols, and sections in `ram`.
+///
+/// Returns the highest end address with the symbol or section name, None if nothing is in `ram`.
+pub fn read_elf_ram_end(elf_data: &[u8], ram: Range<u32>) -> Result<Option<(u32, String)>> {
+    let file = object::File::parse(elf_data)?;
+    // a stack top symbol may point just past the end of RAM
+    let in_ram = |addr: u32| ram.start <= addr && addr <= ram.end;
+
+    let symbols = file.symbols().filter_map(|sym| {
+        let name = sym.name().ok()?;
+        let addr = sym.address() as u32;
+        (RAM_END_SYMBOLS.contains(&name) && in_ram(addr)).then(|| (addr, name.to_string()))
+    });
+    let sections = file.sections().filter_map(|section| {
+        let start = section.address() as u32;
+        let end = start.checked_add(section.size() as u32)?;
+        (section.size() != 0 && ram.contains(&start)).then(|| {
+            let name = section.name().unwrap_or("?").to_string();
+            (end, name)
+        })
+    });
+    Ok(symbols.chain(sections).max_by_key(|(addr, _)| *addr))
+}
+
+/// Write data as Intel HEX, 16 bytes per record
+pub fn write_ihex(data: &[u8], address: u32) -> Result<String> {
+    use ihex::Record;
+
+    let mut records = vec![];
+    let mut upper = None;
+    let mut addr = address;
+    for chunk in data.chunks(16) {
+        // a record must not cross a 64K boundary
+        let room = 0x10000 - (addr & 0xffff) as usize;
+        for part in [
+            &chunk[..room.min(chunk.len())],
+            &chunk[room.min(chunk.len())..],
+        ] {
+            if part.is_empty() {
+                continue;
+            }
+            if upper != Some(addr >> 16) {
+                upper = Some(addr >> 16);
+                records.push(Record::ExtendedLinearAddress((addr >> 16) as u16));
+            }
+            records.push(Record::Data {
+                offset: addr as u16,
+                value: part.to_vec(),
+            });
+            addr += part.len() as u32;
+        }
+    }
+    records.push(Record::EndOfFile);
+    Ok(ihex::create_object_file_representation(&records)?)
+}
+
+/// Write data as a RISC-V ELF32 file, with one loadable segment and no section headers
+pub fn write_elf(data: &[u8], address: u32) -> Vec<u8> {
+    const EHDR_SIZE: u16 = 52;
+    const PHDR_SIZE: u16 = 32;
+    const EM_RISCV: u16 = 243;
+
+    let mut out = vec![];
+    // e_ident: ELFCLASS32, ELFDATA2LSB, EV_CURRENT
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0]);
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&2u16.to_le_bytes()); // e_type, ET_EXEC
+    out.extend_from_slice(&EM_RISCV.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes()); // e_version
+    out.extend_from_slice(&address.to_le_bytes()); // e_entry
+    out.extend_from_slice(&(EHDR_SIZE as u32).to_le_bytes()); // e_phoff
+    out.extend_from_slice(&0u32.to_le_bytes()); // e_shoff
+    out.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    out.extend_from_slice(&EHDR_SIZE.to_le_bytes());
+    out.extend_from_slice(&PHDR_SIZE.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes()); // e_phnum
+    out.extend_from_slice(&0u16.to_le_bytes()); // e_shentsize
+    out.extend_from_slice(&0u16.to_le_bytes()); // e_shnum
+    out.extend_from_slice(&0u16.to_le_bytes()); // e_shstrndx
+
+    let offset = (EHDR_SIZE + PHDR_SIZE) as u32;
+    out.extend_from_slice(&PT_LOAD.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes()); // p_offset
+    out.extend_from_slice(&address.to_le_bytes()); // p_vaddr
+    out.extend_from_slice(&address.to_le_bytes()); // p_paddr
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes()); // p_filesz
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes()); // p_memsz
+    out.extend_from_slice(&(PF_R | PF_X).to_le_bytes());
+    out.extend_from_slice(&4u32.to_le_bytes()); // p_align
+    out.extend_from_slice(data);
+    out
+}
+
+/// Write data as Motorola S-records.
+///
+/// Uses S1/S9, S2/S8 or S3/S7 records by the address width needed, with a S0 header
+/// and a S5/S6 record count.
+pub fn write_srec(data: &[u8], address: u32, record_len: usize) -> Result<String> {
+    if record_len == 0 || record_len > SREC_MAX_RECORD_LEN {
+        anyhow::bail!("S-record length must be 1 to {} bytes", SREC_MAX_RECORD_LEN);
+    }
+    let end = address as u64 + data.len().saturating_sub(1) as u64;
+    if end > u32::MAX as u64 {
+        anyhow::bail!("dump exceeds 32-bit address space");
+    }
+    let (data_type, term_type, addr_len) = if end <= 0xffff {
+        (1, 9, 2)
+    } else if end <= 0xff_ffff {
+        (2, 8, 3)
+    } else {
+        (3, 7, 4)
+    };
+
+    let mut out = String::new();
+    out.push_str(&srec_record(0, &[0, 0], b"wlink"));
+    let mut count = 0;
+    let mut addr = address;
+    for chunk in data.chunks(record_len) {
+        out.push_str(&srec_record(
+            data_type,
+            &addr.to_be_bytes()[4 - addr_len..],
+            chunk,
+        ));
+        addr = addr.wrapping_add(chunk.len() as u32);
+        count += 1;
+    }
+    if count <= 0xffff {
+        out.push_str(&srec_record(5, &(count as u16).to_be_bytes(), &[]));
+    } else if count <= 0xff_ffff {
+        out.push_str(&srec_record(6, &(count as u32).to_be_bytes()[1..], &[]));
+    }
+    out.push_str(&srec_record(
+        term_type,
+        &address.to_be_bytes()[4 - addr_len..],
+        &[],
+    ));
+    Ok(out)
+}
+
+fn srec_record(ty: u8, addr: &[u8], data: &[u8]) -> String {
+    let count = (addr.len() + data.len() + 1) as u8;
+    let sum = addr
+        .iter()
+        .chain(data)
+        .fold(count, |sum, b| sum.wrapping_add(*b));
+    format!(
+        "S{}{:02X}{}{}{:02X}\n",
+        ty,
+        count,
+        hex::encode_upper(addr),
+        hex::encode_upper(data),
+        !sum
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 + i / 256) as u8).collect()
+    }
+
+    fn single_section(firmware: Firmware) -> Section {
+        match firmware {
+            Firmware::Sections(mut sections) => {
+                assert_eq!(sections.len(), 1);
+                sections.pop().unwrap()
+            }
+            Firmware::Binary(_) => panic!("no addresses"),
+        }
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn insert_crc32_skips_crc_bytes() {
+        let mut image = b"1234____56789".to_vec();
+        image[4..8].fill(0xff);
+        let mut firmware = Firmware::Binary(image);
+        let crc = firmware.insert_crc32(0x0800_0000, 0x0800_0004).unwrap();
+        assert_eq!(crc, 0xcbf4_3926);
+        let Firmware::Binary(image) = firmware else {
+            unreachable!()
+        };
+        assert_eq!(&image[4..8], &0xcbf4_3926u32.to_le_bytes());
+    }
+
+    #[test]
+    fn ihex_round_trip() {
+        // unaligned start, records are split at the 64K segment boundary
+        for address in [0x0000_0100, 0x0800_fff9, 0x0801_0000] {
+            let data = data(300);
+            let section = single_section(read_ihex(&write_ihex(&data, address).unwrap()).unwrap());
+            assert_eq!(section.address, address);
+            assert_eq!(section.data, data);
+        }
+    }
+
+    #[test]
+    fn ihex_records_stay_in_segment() {
+        let hex = write_ihex(&data(32), 0x0800_fff8).unwrap();
+        for record in ihex::Reader::new(&hex) {
+            if let ihex::Record::Data { offset, value } = record.unwrap() {
+                assert!(offset as usize + value.len() <= 0x10000);
+            }
+        }
+        // 8 bytes before the boundary, then a new upper address
+        assert!(hex.contains(":020000040801F1"));
+    }
+
+    #[test]
+    fn srec_round_trip() {
+        // S1, S2 and S3 records, and a record crossing a 64K segment
+        for (address, record_len) in [
+            (0x0000_0100, 16),
+            (0x0000_fff9, 32),
+            (0x0800_fff9, 16),
+            (0x0800_fff9, 0xfa),
+        ] {
+            let data = data(300);
+            let srec = write_srec(&data, address, record_len).unwrap();
+            let section = single_section(read_srec(&srec).unwrap());
+            assert_eq!(section.address, address);
+            assert_eq!(section.data, data);
+        }
+    }
+}
@@ -1,4 +1,167 @@
 //! Flash loader program for WCH's RISC-V MCUs.
+//!
+//! The blobs are extracted from WCH-LinkUtility, the copyright belongs to WCH.
+//! External loaders can be loaded by `FlashAlgo::from_file`.
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{Error, Result, RiscvChip};
+
+/// SRAM address the probe firmware loads flash loaders to
+pub const PROBE_LOAD_ADDRESS: u32 = 0x2000_0000;
+
+/// License of the flash loader blobs
+pub const LICENSE: &str =
+    "Proprietary, (c) Nanjing Qinheng Microelectronics Co., Ltd. Extracted from WCH-LinkUtility";
+
+/// A flash loader program and its metadata
+#[derive(Debug, Clone, Copy)]
+pub struct FlashAlgo {
+    pub name: &'static str,
+    pub blob: &'static [u8],
+    /// Alternative blob, from another version of WCH-LinkUtility
+    pub alt: Option<&'static [u8]>,
+    /// SRAM address the probe firmware loads the blob to
+    pub load_address: u32,
+    /// Chip families that use this blob
+    pub families: &'static [RiscvChip],
+    /// Where the blob comes from
+    pub version: &'static str,
+    pub license: &'static str,
+    /// Fastprogram pack size the loader is built for, the chip default if None
+    pub pack_size: Option<u32>,
+}
+
+/// Metadata of an external loader, in a TOML file next to the blob.
+///
+/// ```toml
+/// name = "CH32V307-patched"
+/// load_address = 0x20000000
+/// pack_size = 4096
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FlashAlgoMeta {
+    name: Option<String>,
+    load_address: Option<u32>,
+    pack_size: Option<u32>,
+}
+
+impl FlashAlgo {
+    const fn new(name: &'static str, blob: &'static [u8], families: &'static [RiscvChip]) -> Self {
+        FlashAlgo {
+            name,
+            blob,
+            alt: None,
+            load_address: PROBE_LOAD_ADDRESS,
+            families,
+            version: "WCH-LinkUtility",
+            license: LICENSE,
+            pack_size: None,
+        }
+    }
+
+    /// The built-in flash loader of a chip, None if not available
+    pub fn for_chip(chip: RiscvChip) -> Option<Self> {
+        ALGOS
+            .iter()
+            .find(|algo| algo.families.contains(&chip))
+            .copied()
+    }
+
+    /// Load an external loader blob, with metadata from the `.toml` file of the same name if any.
+    ///
+    /// The blob is leaked, it lives until the program exits.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let blob = std::fs::read(path)?;
+        if blob.is_empty() {
+            return Err(Error::Custom(format!(
+                "empty flash loader: {}",
+                path.display()
+            )));
+        }
+
+        let meta_path = path.with_extension("toml");
+        let meta: FlashAlgoMeta = if meta_path.exists() {
+            let content = std::fs::read_to_string(&meta_path)?;
+            toml::from_str(&content).map_err(|e| {
+                Error::Custom(format!(
+                    "invalid loader metadata {}: {e}",
+                    meta_path.display()
+                ))
+            })?
+        } else {
+            FlashAlgoMeta::default()
+        };
+
+        let load_address = meta.load_address.unwrap_or(PROBE_LOAD_ADDRESS);
+        if load_address != PROBE_LOAD_ADDRESS {
+            return Err(Error::Custom(format!(
+                "loader is built for 0x{load_address:08x}, but WCH-Link loads it to 0x{PROBE_LOAD_ADDRESS:08x}"
+            )));
+        }
+        if let Some(pack_size) = meta.pack_size {
+            if pack_size == 0 || !pack_size.is_multiple_of(64) {
+                return Err(Error::Custom(format!(
+                    "invalid loader pack size: {pack_size}, must be a multiple of 64"
+                )));
+            }
+        }
+
+        let name = meta.name.unwrap_or_else(|| {
+            path.file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        });
+        Ok(FlashAlgo {
+            name: Box::leak(name.into_boxed_str()),
+            blob: Box::leak(blob.into_boxed_slice()),
+            alt: None,
+            load_address,
+            families: &[],
+            version: "external",
+            license: "Unknown, provided by user",
+            pack_size: meta.pack_size,
+        })
+    }
+
+    const fn with_alt(mut self, alt: &'static [u8]) -> Self {
+        self.alt = Some(alt);
+        self
+    }
+}
+
+/// All known flash loaders
+pub const ALGOS: &[FlashAlgo] = &[
+    FlashAlgo::new(
+        "CH32V003",
+        &CH32V003,
+        &[RiscvChip::CH32V003, RiscvChip::CH641],
+    )
+    .with_alt(&CH32V003_ALT),
+    FlashAlgo::new("CH32V103", &CH32V103, &[RiscvChip::CH32V103]),
+    FlashAlgo::new(
+        "CH32V307",
+        &CH32V307,
+        &[RiscvChip::CH32V20X, RiscvChip::CH32V30X],
+    ),
+    FlashAlgo::new("CH569", &CH569, &[RiscvChip::CH56X]),
+    FlashAlgo::new("CH573", &CH573, &[RiscvChip::CH57X]),
+    FlashAlgo::new(
+        "CH583",
+        &CH583,
+        &[RiscvChip::CH582, RiscvChip::CH59X, RiscvChip::CH585],
+    ),
+    FlashAlgo::new("CH8571", &OP8571, &[RiscvChip::CH8571]).with_alt(&OP8571_ALT),
+    FlashAlgo::new("CH643", &CH643, &[RiscvChip::CH32X035, RiscvChip::CH643]),
+    FlashAlgo::new("CH32L103", &CH32L103, &[RiscvChip::CH32L103]),
+    FlashAlgo::new("CH564", &CH564, &[RiscvChip::CH564]),
+    FlashAlgo::new("CH32V007", &CH32V007, &[RiscvChip::CH32V007]),
+    FlashAlgo::new("CH645", &CH645, &[RiscvChip::CH645]).with_alt(&CH645_ALT),
+    FlashAlgo::new("CH32V317", &CH32V317, &[RiscvChip::CH32V317]).with_alt(&CH32V317_ALT),
+];
 
 /// For both CH32V20X and CH32V30X
 // 5, 6
@@ -0,0 +1,108 @@
+//! Gang programming, flash the same firmware to all connected probes concurrently.
+
+use std::{sync::Arc, thread};
+
+use crate::{
+    commands::Speed,
+    firmware::Firmware,
+    flash_op::FlashAlgo,
+    operations::{FlashBackend, ProbeSession},
+    probe::{UsbId, WchLink},
+    AddressMap, Error, Result, RiscvChip,
+};
+
+/// Flash result of a single probe
+#[derive(Debug)]
+pub struct GangResult {
+    /// Device index, as used by `WchLink::open_nth_with_usb_id`
+    pub index: usize,
+    pub result: Result<()>,
+}
+
+/// Flash the same image to every connected WCH-Link, one thread per probe.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GangProgrammer {
+    pub chip: Option<RiscvChip>,
+    pub speed: Speed,
+    pub backend: FlashBackend,
+    /// Erase flash before flashing
+    pub erase: bool,
+    /// Reset and run after flashing
+    pub run: bool,
+    /// Detach chip after flashing
+    pub detach: bool,
+    /// Override the chip default firmware address translation
+    pub address_map: Option<AddressMap>,
+    /// Override the built-in flash loader
+    pub flash_algo: Option<FlashAlgo>,
+    pub usb_id: UsbId,
+}
+
+impl GangProgrammer {
+    pub fn new(chip: Option<RiscvChip>, speed: Speed) -> Self {
+        Self {
+            chip,
+            speed,
+            run: true,
+            detach: true,
+            ..Default::default()
+        }
+    }
+
+    /// Program all connected probes, returns per-device results, in device index order.
+    pub fn program(&self, firmware: &Firmware, address: Option<u32>) -> Result<Vec<GangResult>> {
+        let n = WchLink::count_probes(self.usb_id)?;
+        if n == 0 {
+            return Err(Error::ProbeNotFound);
+        }
+        log::info!("Gang programming {} probes", n);
+
+        let firmware = Arc::new(firmware.clone());
+        let handles: Vec<_> = (0..n)
+            .map(|index| {
+                let this = *self;
+                let firmware = firmware.clone();
+                thread::spawn(move || this.program_one(index, &firmware, address))
+            })
+            .collect();
+
+        let results = handles
+            .into_iter()
+            .enumerate()
+            .map(|(index, handle)| GangResult {
+                index,
+                result: handle
+                    .join()
+                    .unwrap_or_else(|_| Err(Error::Custom("flash thread panicked".to_string()))),
+            })
+            .collect();
+
+        Ok(results)
+    }
+
+    fn program_one(&self, index: usize, firmware: &Firmware, address: Option<u32>) -> Result<()> {
+        // USB handles are not Send, open the probe in the worker thread
+        let probe = WchLink::open_nth_with_usb_id(index, self.usb_id)?;
+        let mut sess = ProbeSession::attach(probe, self.chip, self.speed)?;
+        if let Some(map) = self.address_map {
+            sess.set_address_map(map);
+        }
+        if let Some(algo) = self.flash_algo {
+            sess.set_flash_algo(algo);
+        }
+
+        if self.erase {
+            sess.erase_flash()?;
+        }
+        sess.write_firmware(firmware, address, self.backend)?;
+        log::info!("Probe #{}: flash done", index);
+
+        if self.run {
+            sess.soft_reset()?;
+        }
+        if self.detach {
+            sess.detach_chip()?;
+        }
+        Ok(())
+    }
+}
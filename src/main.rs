@@ -1,28 +1,63 @@
-use std::{thread::sleep, time::Duration};
+use std::{path::PathBuf, sync::Arc, thread::sleep, time::Duration};
 
 use anyhow::Result;
 use wlink::{
+    cli::{
+        self, parse_byte, parse_dmi_reg, parse_duration, parse_field_update, parse_meta_spec,
+        parse_number, parse_region, parse_usb_id_part, AttachState, FlashOptions, OutputFormat,
+    },
     commands,
-    dmi::DebugModuleInterface,
-    firmware::{read_firmware_from_file, Firmware},
-    operations::ProbeSession,
-    probe::WchLink,
-    regs, RiscvChip,
+    daemon::DEFAULT_DAEMON_ADDR,
+    dap::{DapProbe, DEFAULT_SWD_CLOCK},
+    debug_adapter::DEFAULT_DAP_ADDR,
+    dmi::{DebugModuleInterface, RstMode},
+    firmware::{self, DumpFormat, ImageLayout, MetaField, SREC_DEFAULT_RECORD_LEN},
+    flash_op::FlashAlgo,
+    gang::GangProgrammer,
+    observer::Stats,
+    operations::{AttachOptions, FlashBackend, HaltPolicy, ProbeSession, RegionKind},
+    probe::{OutputWatch, UsbId, WchLink},
+    regs, AddressMap, RiscvChip,
 };
 
-use clap::{Parser, Subcommand};
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Parser, Subcommand,
+};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
 
 #[derive(clap::Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Optional device index to operate on
+    /// Optional device index to operate on, asks which one if multiple probes are connected
     #[arg(long, short = 'd', value_name = "INDEX")]
     device: Option<usize>,
 
+    /// Override probe USB VID:PID, for cloned or re-enumerated probes
+    #[arg(
+        long,
+        global = true,
+        value_name = "VID:PID",
+        env = "WLINK_USB_ID",
+        default_value = "1a86:8010"
+    )]
+    usb_id: UsbId,
+
+    /// Override the VID of --usb-id, in hex
+    #[arg(long, global = true, value_parser = parse_usb_id_part)]
+    vid: Option<u16>,
+
+    /// Override the PID of --usb-id, in hex
+    #[arg(long, global = true, value_parser = parse_usb_id_part)]
+    pid: Option<u16>,
+
     #[command(flatten)]
     verbose: Verbosity<InfoLevel>,
 
+    /// Also write logs to a file, at trace level with all probe commands
+    #[arg(long, global = true, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+
     /// Detach chip after operation
     #[arg(long, global = true, default_value = "false")]
     no_detach: bool,
@@ -35,10 +70,136 @@ struct Cli {
     #[arg(long, global = true, default_value = "high")]
     speed: crate::commands::Speed,
 
+    /// Connection speed in kHz, the nearest of 400, 4000 and 6000 is applied
+    #[arg(long, global = true, value_name = "KHZ", conflicts_with = "speed")]
+    speed_khz: Option<u32>,
+
+    /// Halt the MCU on attach, and keep it halted
+    #[arg(long, global = true, conflicts_with = "no_halt")]
+    halt: bool,
+
+    /// Keep the MCU running during inspection
+    #[arg(long, global = true)]
+    no_halt: bool,
+
+    /// USB transfer timeout, e.g. 10s, default to 5s
+    #[arg(long, global = true, value_name = "TIMEOUT", value_parser = parse_duration)]
+    usb_timeout: Option<Duration>,
+
+    /// Max retries of a busy DMI read, default to 100
+    #[arg(long, global = true, value_name = "N")]
+    dmi_retries: Option<usize>,
+
+    /// Try larger fastprogram pack sizes on the first flash write, fall back if they fail to verify
+    #[arg(long, global = true)]
+    negotiate_pack_size: bool,
+
+    /// Attach attempts at each speed, default to 3
+    #[arg(long, global = true, value_name = "N")]
+    attach_retries: Option<usize>,
+
+    /// Don't fall back to slower speeds if attach fails at the requested speed
+    #[arg(long, global = true)]
+    no_speed_fallback: bool,
+
+    /// Re-attach and retry the operation once if the chip is lost, e.g. by a brown-out
+    #[arg(long, global = true)]
+    auto_recover: bool,
+
+    /// Read the flash OP back from SRAM after uploading it, to catch corrupted uploads
+    #[arg(long, global = true)]
+    verify_flash_op: bool,
+
+    /// Let the flash OP verify each pack as it is written, without a separate readback
+    #[arg(long, global = true)]
+    verify_during_write: bool,
+
+    /// Keep the flash contents after a write in its last page, instead of erasing them
+    #[arg(long, global = true)]
+    patch_flash: bool,
+
+    /// Wait for the probe to be connected, with an optional timeout, e.g. --wait=10s
+    #[arg(
+        long,
+        global = true,
+        value_name = "TIMEOUT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "0",
+        value_parser = parse_duration
+    )]
+    wait: Option<Duration>,
+
+    /// Alternate entry point, set as pc before resuming in flash, reset and run flows.
+    /// `reset halt` only halts as before, the core runs from the entry on resume
+    #[arg(long, global = true, value_name = "ADDRESS", value_parser = parse_number)]
+    entry: Option<u32>,
+
+    /// External flash loader blob, overrides the built-in one. Metadata is read from
+    /// the `.toml` file of the same name, if any
+    #[arg(long, global = true, value_name = "LOADER")]
+    flash_op: Option<String>,
+
+    /// Record all USB transactions to a file, for `wlink replay`
+    #[arg(long, global = true, value_name = "FILE")]
+    capture_usb: Option<String>,
+
+    /// Record all USB transactions to a pcapng file, for Wireshark
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        conflicts_with = "capture_usb"
+    )]
+    usb_pcap: Option<String>,
+
+    /// Print a summary of commands, bytes transferred, retries and errors
+    #[arg(long, global = true)]
+    stats: bool,
+
+    /// Print durations and throughput of each phase, like attach, erase and program
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "text"
+    )]
+    timings: Option<OutputFormat>,
+
+    /// Ring the terminal bell, or show a desktop notification, when the command finishes
+    #[arg(long, global = true)]
+    notify: bool,
+
+    /// Reject all operations that modify the target, like flash, erase, write, reset and power
+    #[arg(long, global = true)]
+    read_only: bool,
+
+    /// Run through `wlink daemon`, which keeps the probe open and the chip attached
+    #[arg(long, global = true, value_name = "daemon")]
+    via: Option<Via>,
+
+    /// Address of `wlink daemon`, to listen on or to connect to
+    #[arg(
+        long,
+        global = true,
+        value_name = "ADDR",
+        env = "WLINK_DAEMON_ADDR",
+        default_value = DEFAULT_DAEMON_ADDR
+    )]
+    daemon_addr: String,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Via {
+    /// `wlink daemon` at --daemon-addr
+    Daemon,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
 enum EraseMode {
     /// Erase code flash by power off, the probe will power off the target chip
@@ -66,25 +227,60 @@ enum Commands {
     /// Dump memory region
     Dump {
         /// Start address
-        #[arg(value_parser = parse_number)]
-        address: u32,
+        #[arg(value_parser = parse_number, required_unless_present = "region")]
+        address: Option<u32>,
 
-        /// Length in bytes, will be rounded up to the next multiple of 4
+        /// Length in bytes, exactly the requested bytes are read.
+        /// Defaults to the end of code flash, by the flash size of the exact part
         #[arg(value_parser = parse_number)]
-        length: u32,
+        length: Option<u32>,
 
         /// Write the dumped memory region to a file
         #[arg(short = 'o', long = "out")]
         filename: Option<String>,
+
+        /// Output format, guessed from the file extension by default
+        #[arg(long, value_enum)]
+        format: Option<DumpFormat>,
+
+        /// Data bytes per S-record
+        #[arg(long, default_value_t = SREC_DEFAULT_RECORD_LEN)]
+        srec_record_len: usize,
+
+        /// Dump a whole region of the memory map instead, like the factory bootloader
+        #[arg(long, value_enum, conflicts_with_all = ["address", "length"])]
+        region: Option<RegionKind>,
+
+        /// Continue an interrupted binary dump, from this byte offset of the region,
+        /// usually the size of the partial file
+        #[arg(long, requires = "filename", value_parser = parse_number)]
+        resume_from: Option<u32>,
+    },
+    /// Print the flash, SRAM, option byte, bootloader and peripheral regions of the chip
+    MemoryMap {
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
     },
     /// Dump registers
-    Regs {},
+    Regs {
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
     /// Erase flash
     Erase {
         /// Erase mode
         #[arg(long, default_value = "default")]
         method: EraseMode,
     },
+    /// Check if a flash region is blank(erased)
+    BlankCheck {
+        /// Start address, default to code flash start
+        #[arg(value_parser = parse_number)]
+        address: Option<u32>,
+        /// Length in bytes, default to the flash size reported by the chip
+        #[arg(value_parser = parse_number)]
+        length: Option<u32>,
+    },
     /// Program the code flash
     Flash {
         /// Address in u32
@@ -102,9 +298,69 @@ enum Commands {
         /// Open serial port(print only) after reset
         #[arg(long, default_value = "false")]
         watch_serial: bool,
-        /// Path to the firmware file to flash
+        /// Continue without serial port if it cannot be opened, instead of failing
+        #[arg(long, default_value = "false", requires = "watch_serial")]
+        serial_optional: bool,
+        /// Decode the serial output as defmt log frames
+        #[arg(long, requires = "watch_serial")]
+        defmt: bool,
+        /// ELF file with the defmt table, defaults to the flashed file
+        #[arg(long, requires = "defmt")]
+        elf: Option<PathBuf>,
+        /// Exit successfully once a serial output line contains this
+        #[arg(long, requires = "watch_serial")]
+        expect: Option<String>,
+        /// Exit with code 101 once a serial output line contains this, can be repeated
+        #[arg(long, requires = "watch_serial")]
+        fail_on: Vec<String>,
+        /// Stop watching the serial port after this, e.g. 60s, exits with code 124 if --expect is not met
+        #[arg(long, requires = "watch_serial", value_parser = parse_duration)]
+        timeout: Option<Duration>,
+        /// Flash programming backend
+        #[arg(long, default_value = "flash-op")]
+        backend: FlashBackend,
+        /// Save the current flash before flashing, keyed by chip UID, for `wlink rollback`
+        #[arg(long, conflicts_with = "all")]
+        backup: bool,
+        /// Flash ELF and ihex sections at their addresses, do not remap them to code flash
+        #[arg(long)]
+        keep_addresses: bool,
+        /// Flash even if the image exceeds the flash, or targets the bootloader or addresses outside flash
+        #[arg(long)]
+        force: bool,
+        /// Skip this many bytes at the start of the image, like a header of another tool
+        #[arg(long, value_parser = parse_number, conflicts_with = "all")]
+        file_offset: Option<u32>,
+        /// Pad the image to this size, counted from its start address
+        #[arg(long, value_parser = parse_number, conflicts_with = "all")]
+        pad_to: Option<u32>,
+        /// Fill byte of --pad-to
+        #[arg(long, value_parser = parse_byte, default_value = "0xff", requires = "pad_to")]
+        pad_byte: u8,
+        /// Write metadata into the image, like 0x08000100=uid,timestamp,version:1.2.0.
+        /// A bare `version` is the wlink version
+        #[arg(long, value_name = "ADDRESS=FIELDS", value_parser = parse_meta_spec, conflicts_with = "all")]
+        insert_meta: Option<(u32, Vec<MetaField>)>,
+        /// Write the CRC-32 of the image at this address, computed without these 4 bytes
+        #[arg(long, value_name = "ADDRESS", value_parser = parse_number, conflicts_with = "all")]
+        insert_crc: Option<u32>,
+        /// Stay resident, reflash and reset whenever a firmware file changes
+        #[arg(long, conflicts_with_all = ["watch_serial", "all"])]
+        watch_file: bool,
+        /// Flash all connected probes concurrently, ignores --device
+        #[arg(long, default_value = "false")]
+        all: bool,
+        /// Firmware files to flash, binary files with an address suffix, like app.bin@0x08000000
+        #[arg(required = true, value_name = "PATH[@ADDRESS]")]
+        paths: Vec<String>,
+    },
+    /// Load an ELF file into SRAM and run it, without touching the flash
+    RunRam {
+        /// ELF file, all loadable segments must be in SRAM
         path: String,
     },
+    /// Restore the flash saved by `flash --backup`, then reset and run
+    Rollback {},
     /// Unlock flash
     Unprotect {},
     /// Protect flash
@@ -117,8 +373,11 @@ enum Commands {
         /// Value in u32
         #[arg(value_parser = parse_number)]
         value: u32,
+        /// Write debug sensitive registers, like dpc, dcsr and mstatus, even if the core is running
+        #[arg(long)]
+        force: bool,
     },
-    /// Force write a memory word
+    /// Force write a memory word, halfword or byte
     WriteMem {
         /// Address in u32
         #[arg(value_parser = parse_number)]
@@ -126,6 +385,30 @@ enum Commands {
         /// Value in u32
         #[arg(value_parser = parse_number)]
         value: u32,
+        /// Access width in bytes, for registers with byte or halfword access
+        #[arg(long, default_value = "4", value_parser = PossibleValuesParser::new(["1", "2", "4"]).map(|s| s.parse::<u8>().unwrap()))]
+        width: u8,
+    },
+    /// Fill memory with a 32-bit pattern, like clearing retention RAM
+    Fill {
+        /// Start address
+        #[arg(value_parser = parse_number)]
+        address: u32,
+        /// Length in bytes
+        #[arg(value_parser = parse_number)]
+        length: u32,
+        /// Pattern in u32, written in little endian
+        #[arg(value_parser = parse_number)]
+        pattern: u32,
+    },
+    /// Test RAM with walking ones and address-in-address patterns, destroys its content
+    Ramtest {
+        /// Start address, 4 bytes aligned
+        #[arg(value_parser = parse_number)]
+        address: u32,
+        /// Length in bytes, a multiple of 4
+        #[arg(value_parser = parse_number)]
+        length: u32,
     },
     /// Halts the MCU
     Halt {},
@@ -137,8 +420,68 @@ enum Commands {
         #[arg(default_value = "quit")]
         mode: ResetMode,
     },
+    /// Run the firmware for a limited time, then halt and dump registers.
+    /// With an ELF file, flash it and stream its output until Ctrl-C, as a Cargo runner
+    Run {
+        /// ELF file to flash and run, fails if the firmware panics
+        elf: Option<String>,
+        /// Run duration, e.g. 10s, 500ms, exits with code 124 if --expect is not met
+        #[arg(long = "for", visible_alias = "timeout", value_parser = parse_duration, required_unless_present = "elf")]
+        duration: Option<Duration>,
+        /// Exit successfully once an output line contains this
+        #[arg(long, requires = "elf")]
+        expect: Option<String>,
+        /// Exit with code 101 once an output line contains this, can be repeated, panics always fail
+        #[arg(long, requires = "elf")]
+        fail_on: Vec<String>,
+        /// Reset before running
+        #[arg(long, default_value = "false", conflicts_with = "elf")]
+        reset: bool,
+        /// Memory region to dump after halting, ADDR:LEN, can be repeated
+        #[arg(long = "mem", value_name = "ADDR:LEN", value_parser = parse_region, conflicts_with = "elf")]
+        regions: Vec<(u32, u32)>,
+    },
+    /// Run the on-target tests of an embedded-test binary, as a Cargo test runner
+    Test {
+        /// Test binary, built with the embedded-test crate
+        elf: String,
+        /// Run only tests whose names contain one of these
+        filters: Vec<String>,
+        /// Timeout of each test, unless set by the test itself
+        #[arg(long, default_value = "60s", value_parser = parse_duration)]
+        timeout: Duration,
+    },
+    /// Watch global variables while the MCU is running, decoded using DWARF info
+    WatchVar {
+        /// ELF file with debug info
+        #[arg(long)]
+        elf: String,
+        /// Variable names, comma separated
+        #[arg(value_delimiter = ',', required = true)]
+        names: Vec<String>,
+        /// Sampling interval, e.g. 500ms, 1s
+        #[arg(long, default_value = "500ms", value_parser = parse_duration)]
+        interval: Duration,
+    },
+    /// Dump trap CSRs, GPRs and backtrace of a halted or faulted MCU
+    CrashReport {
+        /// ELF file of the running firmware, to resolve function names
+        #[arg(long)]
+        elf: Option<String>,
+        /// Write the report to a file
+        #[arg(short = 'o', long = "out")]
+        filename: Option<String>,
+    },
+    /// Diagnose connection problems, prints a pass/fail checklist with hints
+    Doctor,
     /// Debug, check status
-    Status {},
+    Status {
+        /// Only show probe info, without attaching to the chip. Works in DAP mode
+        #[arg(long)]
+        probe_only: bool,
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
     /// Switch mode from RV to DAP or vice versa
     ModeSwitch {
         #[arg(long)]
@@ -147,16 +490,279 @@ enum Commands {
         dap: bool,
     },
     /// List probes
-    List {},
+    List {
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
     /// Enable or disable power output
     SetPower {
         #[command(subcommand)]
         cmd: commands::control::SetPower,
     },
+    /// Check the probe firmware for known features, and how to update it
+    ProbeUpdate {},
+    /// Control the RST pin of the probe
+    #[command(group(clap::ArgGroup::new("rst").required(true)))]
+    Rst {
+        /// RST pin state
+        #[arg(group = "rst")]
+        state: Option<commands::control::SetRSTPin>,
+        /// Hold the target in reset for N milliseconds, then release
+        #[arg(long, value_name = "N", group = "rst")]
+        assert_rst_ms: Option<u64>,
+    },
+    /// Retrieve the probe-side diagnostic log, experimental
+    ProbeLog {
+        /// Undocumented 0x0d subcommand of the firmware
+        #[arg(value_parser = parse_number)]
+        subcmd: u32,
+    },
     /// SDI virtual serial port,
     #[command(subcommand)]
     SdiPrint(SdiPrint),
-    Dev {},
+    /// Raw DMI register access, for advanced users
+    #[command(subcommand)]
+    Dmi(Dmi),
+    /// Peripheral registers by name, decoded by a CMSIS-SVD file.
+    ///
+    /// Bundled definitions: FLASH, PFIC and PWR for the chips with the FPEC flash
+    /// controller, RCC and GPIO for CH32V003, CH32V103, CH32V20x, CH32V30x and CH32L103.
+    #[command(subcommand)]
+    Peri(Peri),
+    /// Option bytes
+    #[command(subcommand)]
+    OptionBytes(OptionBytes),
+    /// PMP(Physical Memory Protection) regions, Qingke V4 only
+    #[command(subcommand)]
+    Pmp(Pmp),
+    /// SWD access in DAP mode, for the ARM core of dual-core parts
+    Swd {
+        /// MEM-AP index
+        #[arg(long, default_value = "0")]
+        ap: u8,
+        /// SWD clock in Hz
+        #[arg(long, default_value_t = DEFAULT_SWD_CLOCK)]
+        clock: u32,
+        #[command(subcommand)]
+        cmd: Swd,
+    },
+    /// Convert a firmware file between ELF, Intel HEX, S-record and binary, no probe needed
+    Convert {
+        /// Input file, binary files with an address suffix, like app.bin@0x08000000
+        #[arg(value_name = "INPUT[@ADDRESS]")]
+        input: String,
+        /// Output file
+        output: String,
+        /// Output format, guessed from the file extension by default
+        #[arg(long, value_enum)]
+        format: Option<DumpFormat>,
+        /// Start address of the output, places a binary input, or moves the image
+        #[arg(long, value_parser = parse_number)]
+        base_address: Option<u32>,
+        /// Data bytes per S-record
+        #[arg(long, default_value_t = SREC_DEFAULT_RECORD_LEN)]
+        srec_record_len: usize,
+    },
+    /// Replay a USB capture against the command layer, developer only
+    Replay {
+        /// Capture file, recorded by --capture-usb
+        file: String,
+    },
+    /// Save or restore the full chip state: flash, option bytes and protection
+    #[command(subcommand)]
+    Snapshot(Snapshot),
+    /// QE bit of the external QSPI flash of CH56X
+    #[command(subcommand)]
+    Qe(Qe),
+    /// SRAM/code flash split of CH32V20X(CH32V208) and CH32V30X
+    #[command(subcommand)]
+    Romram(RomRam),
+    /// Close the debug interface of CH56X/CH57X/CH58X/CH59X, before shipping
+    DisableDebug {
+        /// Required, the probe can no longer attach the chip afterwards
+        #[arg(long, required = true)]
+        i_understand_this_is_irreversible: bool,
+    },
+    /// Keep the chip attached and serve later `wlink --via daemon` commands, until Ctrl-C
+    Daemon {},
+    /// Attach the chip and keep it attached for the following commands, until `wlink detach`
+    Attach {},
+    /// Detach the chip kept attached by `wlink attach`
+    Detach {},
+    /// Copy firmware from one target to another, with two probes connected
+    Clone {
+        /// Source probe index
+        #[arg(long, value_name = "INDEX")]
+        from: usize,
+        /// Destination probe index
+        #[arg(long, value_name = "INDEX")]
+        to: usize,
+        /// Copy option bytes too, including write protection
+        #[arg(long)]
+        option_bytes: bool,
+    },
+    /// Hardware matrix runner
+    #[command(subcommand)]
+    Matrix(Matrix),
+    /// Interactive REPL of raw probe commands, DMI ops and memory access
+    #[command(alias = "dev")]
+    Repl {},
+    /// Serve a Debug Adapter Protocol session, for debugging from VS Code
+    DapServer {
+        /// Listen address, use `"debugServer": <port>` in the VS Code launch configuration
+        #[arg(long, value_name = "ADDR", default_value = DEFAULT_DAP_ADDR)]
+        listen: String,
+    },
+}
+
+#[derive(clap::Subcommand, PartialEq, Clone, Copy, Debug)]
+pub enum Dmi {
+    /// Read a DMI register, and decode known registers
+    Read {
+        /// Register address, or name like dmstatus
+        #[arg(value_parser = parse_dmi_reg)]
+        reg: u8,
+        /// Print only the value in hex, for scripts
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Write a DMI register
+    Write {
+        /// Register address, or name like dmcontrol
+        #[arg(value_parser = parse_dmi_reg)]
+        reg: u8,
+        /// Value in u32
+        #[arg(value_parser = parse_number)]
+        value: u32,
+    },
+}
+
+#[derive(clap::Subcommand, PartialEq, Clone, Debug)]
+pub enum Matrix {
+    /// Flash and check all targets in a plan file
+    Run {
+        /// Plan file, in TOML
+        plan: String,
+        /// Write JUnit XML report to file
+        #[arg(long)]
+        junit: Option<String>,
+        /// Run all targets concurrently, overrides the plan file
+        #[arg(long)]
+        parallel: bool,
+    },
+}
+
+#[derive(clap::Subcommand, PartialEq, Clone, Copy, Debug)]
+pub enum OptionBytes {
+    /// CH32V003 PD7/nRST pin mode
+    #[command(subcommand)]
+    RstMode(RstModeAction),
+}
+
+#[derive(clap::Subcommand, PartialEq, Clone, Debug)]
+pub enum Peri {
+    /// Read a register, like RCC.CFGR0, or all registers of a peripheral, and decode the fields
+    Read {
+        /// PERIPHERAL.REGISTER, or PERIPHERAL
+        path: String,
+        /// CMSIS-SVD file of the chip, default to the bundled definitions of the attached chip
+        #[arg(long, env = "WLINK_SVD")]
+        svd: Option<PathBuf>,
+    },
+    /// Write a register, the whole value, or fields by read-modify-write
+    Write {
+        /// PERIPHERAL.REGISTER
+        path: String,
+        /// FIELD=VALUE updates, like PLLMUL=9 SW=2
+        #[arg(value_parser = parse_field_update)]
+        fields: Vec<(String, u32)>,
+        /// Whole register value, fields are applied on top of it
+        #[arg(long, value_parser = parse_number)]
+        value: Option<u32>,
+        /// CMSIS-SVD file of the chip, default to the bundled definitions of the attached chip
+        #[arg(long, env = "WLINK_SVD")]
+        svd: Option<PathBuf>,
+    },
+}
+
+#[derive(clap::Subcommand, PartialEq, Clone, Copy, Debug)]
+pub enum Pmp {
+    /// Show all PMP regions
+    Show,
+    /// Configure a PMP region
+    Set {
+        /// Region index, 0 to 3
+        region: u8,
+        /// pmpaddr register value, byte address >> 2, NAPOT encoded in NAPOT mode
+        #[arg(long, value_parser = parse_number)]
+        addr: u32,
+        /// Config byte, L(7) A(4:3) X(2) W(1) R(0)
+        #[arg(long, value_parser = parse_number)]
+        cfg: u32,
+    },
+    /// Disable a PMP region
+    Clear {
+        /// Region index, 0 to 3
+        region: u8,
+    },
+}
+
+#[derive(clap::Subcommand, PartialEq, Clone, Debug)]
+pub enum Snapshot {
+    /// Save the chip state to a tar archive
+    Save { path: PathBuf },
+    /// Apply a saved chip state, erases the flash first
+    Restore { path: PathBuf },
+}
+
+#[derive(clap::Subcommand, PartialEq, Clone, Copy, Debug)]
+pub enum Qe {
+    /// Show whether the QE bit is set
+    Check,
+    /// Set the QE bit, enables quad SPI access
+    Enable,
+}
+
+#[derive(clap::Subcommand, PartialEq, Clone, Copy, Debug)]
+pub enum RomRam {
+    /// Show the current split
+    Get,
+    /// Set the split mode, takes effect after a power cycle
+    Set {
+        /// Split mode, 0 to 3, higher modes trade SRAM for code flash
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=3))]
+        mode: u8,
+    },
+}
+
+#[derive(clap::Subcommand, PartialEq, Clone, Copy, Debug)]
+pub enum Swd {
+    /// Read the DP IDCODE
+    Idcode,
+    /// Read memory over MEM-AP
+    Read {
+        /// Start address
+        #[arg(value_parser = parse_number)]
+        address: u32,
+        /// Length in bytes
+        #[arg(value_parser = parse_number, default_value = "4")]
+        length: u32,
+    },
+    /// Write a memory word over MEM-AP
+    Write {
+        #[arg(value_parser = parse_number)]
+        address: u32,
+        #[arg(value_parser = parse_number)]
+        value: u32,
+    },
+}
+
+#[derive(clap::Subcommand, PartialEq, Clone, Copy, Debug)]
+pub enum RstModeAction {
+    /// Show current RST pin mode
+    Get,
+    /// Set RST pin mode, takes effect after power cycle
+    Set { mode: RstMode },
 }
 
 #[derive(clap::Subcommand, PartialEq, Clone, Copy, Debug)]
@@ -177,40 +783,362 @@ fn main() -> Result<()> {
     let cli = Cli::parse();
 
     // init simplelogger
-    simplelog::TermLogger::init(
+    let mut loggers: Vec<Box<dyn simplelog::SharedLogger>> = vec![simplelog::TermLogger::new(
         cli.verbose.log_level_filter(),
         simplelog::Config::default(),
         simplelog::TerminalMode::Mixed,
         simplelog::ColorChoice::Auto,
-    )
-    .expect("initialize simple logger");
+    )];
+    if let Some(path) = &cli.log_file {
+        // full traces for bug reports, regardless of the terminal verbosity
+        loggers.push(simplelog::WriteLogger::new(
+            log::LevelFilter::Trace,
+            simplelog::Config::default(),
+            std::fs::File::create(path)?,
+        ));
+    }
+    simplelog::CombinedLogger::init(loggers).expect("initialize simple logger");
+    if cli.log_file.is_some() {
+        log::debug!(
+            "wlink v{}, args: {:?}",
+            env!("CARGO_PKG_VERSION"),
+            std::env::args().collect::<Vec<_>>()
+        );
+    }
 
-    let device_index = cli.device.unwrap_or(0);
+    if let Some(path) = &cli.capture_usb {
+        wlink::capture::capture_to(path, capture_args())?;
+        log::info!("Capturing USB transactions to {}", path);
+    }
+    if let Some(path) = &cli.usb_pcap {
+        wlink::capture::capture_to_pcapng(path)?;
+        log::info!("Capturing USB transactions to {}", path);
+    }
+
+    let notify = cli.notify;
+    let show_stats = cli.stats;
+    let timings = cli.timings;
+    let stats = (show_stats || timings.is_some()).then(|| Arc::new(Stats::default()));
+    let result = run(cli, stats.clone());
+    if let Some(stats) = stats {
+        if show_stats {
+            log::info!("Stats: {}", stats);
+        }
+        match timings {
+            Some(OutputFormat::Text) => {
+                log::info!("Timings:");
+                for phase in stats.phases() {
+                    log::info!("  {}", phase);
+                }
+            }
+            Some(OutputFormat::Json) => {
+                let phases: Vec<_> = stats.phases().iter().map(|p| p.to_json()).collect();
+                let timings = serde_json::json!({
+                    "phases": phases,
+                    "total_seconds": stats.elapsed().as_secs_f64(),
+                });
+                println!("{}", serde_json::to_string_pretty(&timings)?);
+            }
+            None => (),
+        }
+    }
+    if notify {
+        cli::notify(&result);
+    }
+    // distinct exit codes of output watching, for CI jobs
+    if let Err(e) = &result {
+        let code = match e.downcast_ref::<wlink::Error>() {
+            Some(wlink::Error::OutputMatched(_)) => Some(101),
+            Some(wlink::Error::OutputTimeout(_)) => Some(124),
+            _ => None,
+        };
+        if let Some(code) = code {
+            log::error!("{}", e);
+            std::process::exit(code);
+        }
+    }
+    result
+}
+
+/// Command line arguments, without the capture option itself
+fn capture_args() -> Vec<String> {
+    let mut args = vec![];
+    let mut it = std::env::args();
+    while let Some(arg) = it.next() {
+        if arg == "--capture-usb" {
+            it.next();
+        } else if !arg.starts_with("--capture-usb=") {
+            args.push(arg);
+        }
+    }
+    args
+}
+
+/// Probe index to operate on, asks the user if multiple probes are connected and no --device given
+fn select_device(cli: &Cli) -> Result<usize> {
+    if let Some(index) = cli.device {
+        return Ok(index);
+    }
+    // commands without a probe, or choosing probes on their own
+    if matches!(
+        cli.command,
+        None | Some(Commands::List { .. })
+            | Some(Commands::Matrix(_))
+            | Some(Commands::Clone { .. })
+            | Some(Commands::Flash { all: true, .. })
+    ) {
+        return Ok(0);
+    }
+    let probes = WchLink::probe_summaries(cli.usb_id)?;
+    if probes.len() <= 1 {
+        return Ok(0);
+    }
+    cli::choose_probe(&probes)
+}
+
+/// Resume the session left by `wlink attach`, None if the chip has to be attached again
+fn resume_session(
+    device_index: usize,
+    usb_id: UsbId,
+    expected_chip: Option<RiscvChip>,
+    state: &AttachState,
+    options: AttachOptions,
+    stats: Option<Arc<Stats>>,
+) -> Result<Option<ProbeSession>> {
+    let chip = state.chip()?;
+    if expected_chip.is_some_and(|expected| expected != chip) {
+        log::warn!("Attached chip is {:?}, attach again", chip);
+        return Ok(None);
+    }
+    let mut probe = WchLink::open_nth_with_usb_id(device_index, usb_id)?;
+    if let Some(stats) = stats {
+        probe.set_observer(stats);
+    }
+    match ProbeSession::resume_with_options(probe, chip, state.chip_id, state.speed()?, options) {
+        Ok(sess) => {
+            log::info!("Resumed attached chip: {:?}", chip);
+            Ok(Some(sess))
+        }
+        Err(e) => {
+            log::warn!("Attached session is lost ({}), attach again", e);
+            Ok(None)
+        }
+    }
+}
+
+fn run(mut cli: Cli, stats: Option<Arc<Stats>>) -> Result<()> {
     let mut will_detach = !cli.no_detach;
 
+    if let Some(Commands::Replay { file }) = &cli.command {
+        let header = wlink::capture::replay_from(file)?;
+        log::info!("Replay: {}", header.args.join(" "));
+        let mut cli = Cli::try_parse_from(&header.args)?;
+        // the replayed session has a single probe
+        cli.device.get_or_insert(0);
+        return run(cli, stats);
+    }
+    if let Some(Commands::Convert {
+        input,
+        output,
+        format,
+        base_address,
+        srec_record_len,
+    }) = &cli.command
+    {
+        return cli::convert(input, output, *format, *base_address, *srec_record_len);
+    }
+
+    if let Some(vid) = cli.vid {
+        cli.usb_id.vid = vid;
+    }
+    if let Some(pid) = cli.pid {
+        cli.usb_id.pid = pid;
+    }
+    wlink::usb_device::set_alternate_ids(cli::load_alternate_ids()?);
+
+    if let Some(khz) = cli.speed_khz {
+        cli.speed = commands::Speed::nearest_khz(khz);
+        if cli.speed.khz() != khz {
+            log::info!(
+                "Speed {}kHz is not supported, using {}kHz",
+                khz,
+                cli.speed.khz()
+            );
+        }
+    }
+
+    if let Some(timeout) = cli.wait {
+        if cli.command.is_some() {
+            let device_index = cli.device.unwrap_or(0);
+            log::info!("Waiting for WCH-Link #{}...", device_index);
+            let timeout = (!timeout.is_zero()).then_some(timeout);
+            WchLink::wait_for_probe(device_index, cli.usb_id, timeout)?;
+        }
+    }
+    let daemon_session = match cli.via {
+        Some(Via::Daemon) => Some(wlink::daemon::connect(&cli.daemon_addr, cli.usb_id)?),
+        None => None,
+    };
+    // the daemon serves a single probe
+    let device_index = if daemon_session.is_some() {
+        0
+    } else {
+        select_device(&cli)?
+    };
+
+    if cli.read_only {
+        // these commands bypass the probe session
+        let op = match cli.command {
+            Some(Commands::Erase { method }) if method != EraseMode::Default => Some("Flash erase"),
+            Some(Commands::Flash { all: true, .. }) => Some("Flash write"),
+            Some(Commands::SetPower { .. }) => Some("Power control"),
+            Some(Commands::Rst { .. }) => Some("RST pin control"),
+            Some(Commands::ModeSwitch { .. }) => Some("Mode switch"),
+            Some(Commands::Matrix(_)) => Some("Flash write"),
+            Some(Commands::Clone { .. }) => Some("Flash write"),
+            Some(Commands::Swd {
+                cmd: Swd::Write { .. },
+                ..
+            }) => Some("SWD memory write"),
+            _ => None,
+        };
+        if let Some(op) = op {
+            return Err(wlink::Error::ReadOnly(op).into());
+        }
+    }
+
+    let flash_algo = cli
+        .flash_op
+        .as_deref()
+        .map(FlashAlgo::from_file)
+        .transpose()?;
+    if let Some(algo) = &flash_algo {
+        log::info!(
+            "Using external flash loader {}, {} bytes",
+            algo.name,
+            algo.blob.len()
+        );
+    }
+
     match cli.command {
         None => {
-            WchLink::list_probes()?;
+            WchLink::list_probes_with_usb_id(cli.usb_id)?;
 
             println!("No command given, use --help for help.");
             println!("hint: use `wlink status` to get started.");
         }
         Some(Commands::ModeSwitch { rv, dap }) => {
-            WchLink::list_probes()?;
+            WchLink::list_probes_with_usb_id(cli.usb_id)?;
             log::warn!("This is an experimental feature, better use the WCH-LinkUtility!");
             if !(rv ^ dap) {
                 println!("Please choose one mode to switch, either --rv or --dap");
             } else if dap {
-                WchLink::switch_from_rv_to_dap(device_index)?;
+                WchLink::switch_from_rv_to_dap(device_index, cli.usb_id)?;
             } else {
                 WchLink::switch_from_dap_to_rv(device_index)?;
             }
         }
-        Some(Commands::List {}) => {
-            WchLink::list_probes()?;
+        Some(Commands::List { output }) => {
+            cli::list_probes(cli.usb_id, output)?;
         }
         Some(Commands::SetPower { cmd }) => {
-            WchLink::set_power_output_enabled(device_index, cmd)?;
+            WchLink::set_power_output_enabled(device_index, cli.usb_id, cmd)?;
+        }
+        Some(Commands::ProbeUpdate {}) => {
+            let probe = WchLink::open_nth_with_usb_id(device_index, cli.usb_id)?;
+            cli::probe_update(&probe)?;
+        }
+        Some(Commands::Doctor) => {
+            cli::doctor(device_index, cli.usb_id, cli.chip, cli.speed)?;
+        }
+        Some(Commands::Rst {
+            state,
+            assert_rst_ms,
+        }) => match (state, assert_rst_ms) {
+            (Some(pin), _) => WchLink::set_rst_pin(device_index, cli.usb_id, pin)?,
+            (None, Some(ms)) => {
+                WchLink::assert_rst_pin(device_index, cli.usb_id, Duration::from_millis(ms))?
+            }
+            (None, None) => unreachable!(),
+        },
+        Some(Commands::Status {
+            probe_only: true, ..
+        }) => match WchLink::open_nth_with_usb_id(device_index, cli.usb_id) {
+            Ok(probe) => println!("{} (RV mode)", probe.info),
+            Err(wlink::Error::ProbeModeNotSupported) => {
+                let mut dap = DapProbe::open_nth(device_index)?;
+                println!("{} (DAP mode)", dap.probe_info()?);
+            }
+            Err(e) => return Err(e.into()),
+        },
+        Some(Commands::Swd { ap, clock, cmd }) => {
+            let mut dap = DapProbe::open_nth(device_index)?;
+            let idcode = dap.connect_swd(clock)?;
+            let ret = match cmd {
+                Swd::Idcode => {
+                    println!("IDCODE: 0x{:08x}", idcode);
+                    Ok(())
+                }
+                Swd::Read { address, length } => dap
+                    .init_mem_ap(ap)
+                    .and_then(|_| dap.read_memory(ap, address, length))
+                    .map(|data| cli::print_hexdump(&data, address)),
+                Swd::Write { address, value } => dap
+                    .init_mem_ap(ap)
+                    .and_then(|_| dap.write_mem32(ap, address, value)),
+            };
+            dap.disconnect()?;
+            ret?;
+        }
+        Some(Commands::Clone {
+            from,
+            to,
+            option_bytes,
+        }) => {
+            if from == to {
+                anyhow::bail!("Source and destination must be different probes");
+            }
+            let attach = |index| -> Result<ProbeSession> {
+                let probe = WchLink::open_nth_with_usb_id(index, cli.usb_id)?;
+                Ok(ProbeSession::attach(probe, cli.chip, cli.speed)?)
+            };
+            let mut src = attach(from)?;
+            let mut dst = attach(to)?;
+            cli::clone(&mut src, &mut dst, option_bytes)?;
+            src.detach_chip()?;
+            if will_detach {
+                dst.soft_reset()?;
+                dst.detach_chip()?;
+            }
+            log::info!("Clone done");
+        }
+        Some(Commands::Matrix(Matrix::Run {
+            plan: plan_path,
+            junit,
+            parallel,
+        })) => {
+            let mut plan = wlink::matrix::Plan::from_file(&plan_path)?;
+            plan.parallel |= parallel;
+            let base_dir = std::path::Path::new(&plan_path)
+                .parent()
+                .unwrap_or(std::path::Path::new("."));
+
+            let results = plan.run(base_dir, cli.usb_id);
+            if let Some(fname) = junit {
+                std::fs::write(&fname, wlink::matrix::junit_xml(&plan_path, &results))?;
+                log::info!("JUnit report written to file {}", &fname);
+            }
+            let failed = results.iter().filter(|r| r.result.is_err()).count();
+            if failed > 0 {
+                anyhow::bail!("{} of {} targets failed", failed, results.len());
+            }
+            log::info!("All {} targets passed", results.len());
+        }
+        Some(Commands::ProbeLog { subcmd }) => {
+            log::warn!("This is an experimental feature, the log format is undocumented");
+            let mut probe = WchLink::open_nth_with_usb_id(device_index, cli.usb_id)?;
+            let log = probe.probe_log(subcmd as u8)?;
+            cli::print_hexdump(&log, 0);
         }
 
         Some(Commands::Erase { method }) if method != EraseMode::Default => {
@@ -220,7 +1148,7 @@ fn main() -> Result<()> {
                 "--chip required to do a special erase".into(),
             ))?;
 
-            let mut probe = WchLink::open_nth(device_index)?;
+            let mut probe = WchLink::open_nth_with_usb_id(device_index, cli.usb_id)?;
             log::info!("Erase chip by {:?}", method);
             match method {
                 EraseMode::PowerOff => {
@@ -233,58 +1161,184 @@ fn main() -> Result<()> {
                 _ => unreachable!(),
             }
         }
+        Some(Commands::Flash {
+            address,
+            erase,
+            no_run,
+            paths,
+            backend,
+            keep_addresses,
+            all: true,
+            ..
+        }) => {
+            let mut gang = GangProgrammer::new(cli.chip, cli.speed);
+            gang.backend = backend;
+            gang.erase = erase;
+            gang.run = !no_run;
+            gang.detach = will_detach;
+            gang.usb_id = cli.usb_id;
+            gang.flash_algo = flash_algo;
+            if keep_addresses {
+                gang.address_map = Some(AddressMap::Identity);
+            }
+
+            cli::gang_flash(&gang, &paths, address)?;
+        }
         Some(command) => {
-            let probe = WchLink::open_nth(device_index)?;
-            let mut sess = ProbeSession::attach(probe, cli.chip, cli.speed)?;
+            let options = AttachOptions {
+                halt: if cli.halt {
+                    HaltPolicy::Halt
+                } else if cli.no_halt {
+                    HaltPolicy::Run
+                } else {
+                    HaltPolicy::Unchanged
+                },
+                read_only: cli.read_only,
+                usb_timeout: cli.usb_timeout,
+                dmi_retries: cli.dmi_retries,
+                negotiate_pack_size: cli.negotiate_pack_size,
+                attach_retries: cli.attach_retries,
+                no_speed_fallback: cli.no_speed_fallback,
+                auto_recover: cli.auto_recover,
+                verify_flash_op: cli.verify_flash_op,
+                verify_during_write: cli.verify_during_write,
+                patch_flash: cli.patch_flash,
+            };
+            let attached = match &daemon_session {
+                Some(state) => Some(state.clone()),
+                None => cli::load_attach_state(device_index)?,
+            };
+            let resumed = match &attached {
+                Some(state) => resume_session(
+                    device_index,
+                    cli.usb_id,
+                    cli.chip,
+                    state,
+                    options,
+                    stats.clone(),
+                )?,
+                None => None,
+            };
+            let is_resumed = resumed.is_some();
+            let mut sess = match resumed {
+                Some(sess) => sess,
+                None => {
+                    let start = std::time::Instant::now();
+                    let mut probe = WchLink::open_nth_with_usb_id(device_index, cli.usb_id)?;
+                    if let Some(stats) = stats {
+                        stats.record_phase("open", start.elapsed(), None);
+                        probe.set_observer(stats);
+                    }
+                    // speed of `wlink attach` is kept until `wlink detach`
+                    let speed = match &attached {
+                        Some(state) => state.speed()?,
+                        None => cli.speed,
+                    };
+                    ProbeSession::attach_with_options(probe, cli.chip, speed, options)?
+                }
+            };
+            if attached.is_some() {
+                will_detach = false; // kept attached until `wlink detach` or the daemon stops
+                if !is_resumed && daemon_session.is_none() {
+                    cli::save_attach_state(device_index, &AttachState::new(&sess))?;
+                }
+            }
+            if let Some(algo) = flash_algo {
+                sess.set_flash_algo(algo);
+            }
+            if options.halt == HaltPolicy::Halt {
+                will_detach = false; // detach will resume the MCU
+            }
 
             match command {
-                Commands::Dev {} => {
-                    // dev only
+                Commands::Repl {} => {
+                    cli::repl(&mut sess)?;
                 }
                 Commands::Dump {
                     address,
                     length,
                     filename,
+                    format,
+                    srec_record_len,
+                    region,
+                    resume_from,
                 } => {
-                    log::info!(
-                        "Read memory from 0x{:08x} to 0x{:08x}",
+                    let (address, length) = match region {
+                        Some(kind) => {
+                            let region = sess.memory_region(kind)?;
+                            let size = region.size.ok_or_else(|| {
+                                anyhow::format_err!("size of {} is unknown", region.name)
+                            })?;
+                            (region.start, Some(size))
+                        }
+                        None => (address.expect("required without region"), length),
+                    };
+                    cli::dump(
+                        &mut sess,
                         address,
-                        address + length
-                    );
-
-                    let out = sess.read_memory(address, length)?;
-
-                    if let Some(fname) = filename {
-                        std::fs::write(&fname, &out)?;
-                        log::info!("{} bytes written to file {}", length, &fname);
-                    } else {
-                        println!(
-                            "{}",
-                            nu_pretty_hex::config_hex(
-                                &out,
-                                nu_pretty_hex::HexConfig {
-                                    title: true,
-                                    ascii: true,
-                                    address_offset: address as _,
-                                    ..Default::default()
-                                },
-                            )
-                        );
+                        length,
+                        filename.as_deref(),
+                        format,
+                        srec_record_len,
+                        resume_from,
+                    )?;
+                }
+                Commands::MemoryMap { output } => {
+                    let map = sess.memory_map()?;
+                    match output {
+                        OutputFormat::Text => println!("{}", map),
+                        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&map)?),
                     }
                 }
-                Commands::Regs {} => {
-                    log::info!("Dump GPRs");
-                    sess.dump_regs()?;
-                    sess.dump_pmp_csrs()?;
+                Commands::Regs { output } => {
+                    let regs = sess.register_file()?;
+                    match output {
+                        OutputFormat::Text => {
+                            log::info!("Dump GPRs");
+                            println!("{}", regs);
+                            sess.dump_pmp_csrs()?;
+                        }
+                        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&regs)?),
+                    }
                 }
-                Commands::WriteReg { reg, value } => {
+                Commands::WriteReg { reg, value, force } => {
                     let regno = reg as u16;
+                    sess.check_reg_write(regno, force)?;
                     log::info!("Set reg 0x{:04x} to 0x{:08x}", regno, value);
                     sess.write_reg(regno, value)?;
                 }
-                Commands::WriteMem { address, value } => {
-                    log::info!("Write memory 0x{:08x} to 0x{:08x}", value, address);
-                    sess.write_mem32(address, value)?;
+                Commands::WriteMem {
+                    address,
+                    value,
+                    width,
+                } => {
+                    if !address.is_multiple_of(width as u32) {
+                        anyhow::bail!("Address 0x{:08x} is not {}-byte aligned", address, width);
+                    }
+                    if width < 4 && value >> (width * 8) != 0 {
+                        anyhow::bail!("Value 0x{:x} does not fit in {} bytes", value, width);
+                    }
+                    log::info!(
+                        "Write memory 0x{:0w$x} to 0x{:08x}",
+                        value,
+                        address,
+                        w = width as usize * 2
+                    );
+                    match width {
+                        1 => sess.write_mem8(address, value as u8)?,
+                        2 => sess.write_mem16(address, value as u16)?,
+                        _ => sess.write_mem32(address, value)?,
+                    }
+                }
+                Commands::Fill {
+                    address,
+                    length,
+                    pattern,
+                } => {
+                    cli::fill(&mut sess, address, length, pattern)?;
+                }
+                Commands::Ramtest { address, length } => {
+                    cli::ramtest(&mut sess, address, length)?;
                 }
                 Commands::Halt {} => {
                     log::info!("Halt MCU");
@@ -313,68 +1367,143 @@ fn main() -> Result<()> {
                     }
                     log::info!("Erase done");
                 }
+                Commands::BlankCheck { address, length } => {
+                    match cli::blank_check(&mut sess, address, length)? {
+                        None => log::info!("Flash region is blank"),
+                        Some(addr) => {
+                            if will_detach {
+                                sess.detach_chip()?;
+                            }
+                            anyhow::bail!("Flash region is not blank at 0x{:08x}", addr);
+                        }
+                    }
+                }
                 Commands::Flash {
                     address,
                     erase,
                     no_run,
-                    path,
+                    paths,
                     enable_sdi_print,
                     watch_serial,
+                    serial_optional,
+                    defmt,
+                    elf,
+                    expect,
+                    fail_on,
+                    timeout,
+                    backend,
+                    backup,
+                    keep_addresses,
+                    force,
+                    watch_file,
+                    file_offset,
+                    pad_to,
+                    pad_byte,
+                    insert_meta,
+                    insert_crc,
+                    ..
                 } => {
-                    sess.dump_info()?;
-
-                    if erase {
-                        log::info!("Erase Flash");
-                        sess.erase_flash()?;
+                    let defmt = match (defmt, elf) {
+                        (false, _) => None,
+                        (true, Some(elf)) => Some(elf),
+                        // the flashed file, without its `@ADDRESS`
+                        (true, None) => Some(PathBuf::from(firmware::split_address(&paths[0])?.0)),
+                    };
+                    let opts = FlashOptions {
+                        address,
+                        erase,
+                        run: !no_run,
+                        enable_sdi_print,
+                        watch_serial,
+                        serial_optional,
+                        output_watch: OutputWatch {
+                            expect,
+                            fail_on,
+                            timeout,
+                        },
+                        defmt,
+                        backend,
+                        entry: cli.entry,
+                        backup,
+                        keep_addresses,
+                        force,
+                        layout: ImageLayout {
+                            file_offset,
+                            pad_to,
+                            pad_byte,
+                        },
+                        insert_meta,
+                        insert_crc,
+                    };
+                    cli::flash(&mut sess, &paths, &opts)?;
+                    if watch_file {
+                        cli::flash_on_change(&mut sess, &paths, &opts)?;
                     }
-
-                    let firmware = read_firmware_from_file(path)?;
-
-                    match firmware {
-                        Firmware::Binary(data) => {
-                            let start_address =
-                                address.unwrap_or_else(|| sess.chip_family.code_flash_start());
-                            log::info!("Flashing {} bytes to 0x{:08x}", data.len(), start_address);
-                            sess.write_flash(&data, start_address)?;
-                        }
-                        Firmware::Sections(sections) => {
-                            // Flash section by section
-                            if address.is_some() {
-                                log::warn!("--address is ignored when flashing ELF or ihex");
-                            }
-                            for section in sections {
-                                let start_address =
-                                    sess.chip_family.fix_code_flash_start(section.address);
-                                log::info!(
-                                    "Flashing {} bytes to 0x{:08x}",
-                                    section.data.len(),
-                                    start_address
-                                );
-                                sess.write_flash(&section.data, start_address)?;
-                            }
-                        }
+                    if !no_run && enable_sdi_print {
+                        will_detach = false;
                     }
-
-                    log::info!("Flash done");
-
-                    sleep(Duration::from_millis(500));
-
-                    if !no_run {
-                        log::info!("Now reset...");
-                        sess.soft_reset()?;
-                        if enable_sdi_print {
-                            sess.set_sdi_print_enabled(true)?;
-
-                            will_detach = false;
-                            log::info!("Now connect to the WCH-Link serial port to read SDI print");
-                        }
-                        if watch_serial {
-                            wlink::probe::watch_serial()?;
-                        } else {
-                            sleep(Duration::from_millis(500));
+                }
+                Commands::RunRam { path } => {
+                    cli::run_ram(&mut sess, &path)?;
+                }
+                Commands::Snapshot(Snapshot::Save { path }) => {
+                    let meta = wlink::snapshot::save(&mut sess, &path)?;
+                    log::info!("Snapshot of {} saved to {}", meta.chip, path.display());
+                }
+                Commands::Snapshot(Snapshot::Restore { path }) => {
+                    wlink::snapshot::restore(&mut sess, &path)?;
+                    log::info!("Snapshot restored");
+                    sess.soft_reset()?;
+                }
+                Commands::Qe(Qe::Check) => {
+                    let qe = sess.qe_bit()?;
+                    println!("QE bit: {}", if qe { "set" } else { "clear" });
+                }
+                Commands::Qe(Qe::Enable) => {
+                    if sess.qe_bit()? {
+                        log::info!("QE bit is already set");
+                    } else {
+                        sess.enable_qe_bit()?;
+                        let qe = sess.qe_bit()?;
+                        println!("QE bit: {}", if qe { "set" } else { "clear" });
+                        if !qe {
+                            anyhow::bail!("QE bit is still clear, check the QSPI flash connection");
                         }
                     }
                 }
+                Commands::Romram(RomRam::Get) => {
+                    cli::rom_ram_split(&mut sess, None)?;
+                }
+                Commands::Romram(RomRam::Set { mode }) => {
+                    cli::rom_ram_split(&mut sess, Some(mode))?;
+                }
+                Commands::DisableDebug { .. } => {
+                    cli::disable_debug(&mut sess)?;
+                }
+                Commands::Daemon {} => {
+                    if daemon_session.is_some() {
+                        anyhow::bail!("`wlink daemon` can't run --via daemon");
+                    }
+                    wlink::daemon::serve(&mut sess, &cli.daemon_addr)?;
+                }
+                Commands::DapServer { listen } => {
+                    wlink::debug_adapter::serve(&mut sess, &listen)?;
+                }
+                Commands::Attach {} => {
+                    cli::save_attach_state(device_index, &AttachState::new(&sess))?;
+                    log::info!("Chip kept attached until `wlink detach`");
+                    will_detach = false;
+                }
+                Commands::Detach {} => {
+                    sess.detach_chip()?;
+                    cli::clear_attach_state(device_index)?;
+                    log::info!("Chip detached");
+                    will_detach = false;
+                }
+                Commands::Rollback {} => {
+                    cli::rollback(&mut sess)?;
+                    sess.soft_reset()?;
+                }
                 Commands::Unprotect {} => {
                     log::info!("Unprotect Flash");
                     sess.unprotect_flash()?;
@@ -384,16 +1513,27 @@ fn main() -> Result<()> {
                     sess.protect_flash()?;
                 }
                 Commands::Reset { mode } => {
+                    sess.ensure_writable("Reset")?;
                     log::info!("Reset {:?}", mode);
                     match mode {
-                        ResetMode::Quit => {
-                            sess.probe.send_command(commands::Reset::Soft)?;
-                        }
+                        ResetMode::Quit => match cli.entry {
+                            Some(entry) => sess.reset_and_run_at(entry)?,
+                            None => {
+                                sess.probe.send_command(commands::Reset::Soft)?;
+                            }
+                        },
                         ResetMode::Run => {
+                            if let Some(entry) = cli.entry {
+                                sess.set_entry(entry)?;
+                            }
                             sess.ensure_mcu_resume()?;
                         }
                         ResetMode::Halt => {
                             sess.ensure_mcu_halt()?;
+                            // halt only, no reset, the core runs from the entry on resume
+                            if let Some(entry) = cli.entry {
+                                sess.set_entry(entry)?;
+                            }
 
                             will_detach = false; // detach will resume the MCU
                         }
@@ -405,10 +1545,65 @@ fn main() -> Result<()> {
                     }
                     sleep(Duration::from_millis(300));
                 }
-                Commands::Status {} => {
-                    sess.dump_info()?;
-                    sess.dump_core_csrs()?;
-                    sess.dump_dmi()?;
+                Commands::Run {
+                    elf: Some(elf),
+                    duration,
+                    expect,
+                    fail_on,
+                    ..
+                } => {
+                    let opts = FlashOptions {
+                        entry: cli.entry,
+                        output_watch: OutputWatch {
+                            expect,
+                            fail_on,
+                            timeout: duration,
+                        },
+                        ..Default::default()
+                    };
+                    cli::run_elf(&mut sess, &elf, &opts)?;
+                }
+                Commands::Run {
+                    elf: None,
+                    duration,
+                    reset,
+                    regions,
+                    ..
+                } => {
+                    let duration = duration.expect("required without elf");
+                    cli::run(&mut sess, duration, reset, cli.entry, &regions)?;
+                    will_detach = false; // detach will resume the MCU
+                }
+                Commands::Test {
+                    elf,
+                    filters,
+                    timeout,
+                } => {
+                    let opts = FlashOptions::default();
+                    cli::test(&mut sess, &elf, &filters, &opts, timeout)?;
+                }
+                Commands::WatchVar {
+                    elf,
+                    names,
+                    interval,
+                } => {
+                    cli::watch_var(&mut sess, &elf, &names, interval)?;
+                }
+                Commands::CrashReport { elf, filename } => {
+                    let report = cli::crash_report(&mut sess, elf.as_deref())?;
+                    will_detach = false; // keep the faulted state for further inspection
+
+                    if let Some(fname) = filename {
+                        std::fs::write(&fname, &report)?;
+                        log::info!("Crash report written to file {}", &fname);
+                    } else {
+                        print!("{}", report);
+                    }
+                }
+                Commands::Status { output, .. } => {
+                    // before chip_status, which halts the MCU
+                    cli::report_power_state(&mut sess)?;
+                    cli::status(&mut sess, output)?;
                 }
                 Commands::SdiPrint(v) => match v {
                     // By enabling SDI print and modifying the _write function called by printf in the mcu code,
@@ -427,8 +1622,74 @@ fn main() -> Result<()> {
                         sess.set_sdi_print_enabled(false)?;
                     }
                 },
+                Commands::Dmi(v) => match v {
+                    Dmi::Read { reg, raw } => cli::dmi_read(&mut sess, reg, raw)?,
+                    Dmi::Write { reg, value } => {
+                        cli::dmi_write(&mut sess, reg, value)?;
+                        will_detach = false; // keep the DM state as written
+                    }
+                },
+                Commands::Peri(v) => match v {
+                    Peri::Read { path, svd } => {
+                        let device = cli::load_svd(svd.as_deref(), sess.chip_family)?;
+                        cli::peri_read(&mut sess, &device, &path)?;
+                    }
+                    Peri::Write {
+                        path,
+                        fields,
+                        value,
+                        svd,
+                    } => {
+                        let device = cli::load_svd(svd.as_deref(), sess.chip_family)?;
+                        cli::peri_write(&mut sess, &device, &path, value, &fields)?;
+                    }
+                },
+                Commands::Pmp(v) => match v {
+                    Pmp::Show => cli::pmp_show(&mut sess)?,
+                    Pmp::Set { region, addr, cfg } => {
+                        let cfg = u8::try_from(cfg)
+                            .map_err(|_| anyhow::format_err!("PMP config must be a byte"))?;
+                        log::info!(
+                            "Set PMP region {} to pmpaddr=0x{:08x} cfg=0x{:02x}({})",
+                            region,
+                            addr,
+                            cfg,
+                            regs::decode_pmp_cfg(cfg)
+                        );
+                        sess.write_pmp(region, addr, cfg)?;
+                    }
+                    Pmp::Clear { region } => {
+                        log::info!("Clear PMP region {}", region);
+                        sess.write_pmp(region, 0, 0)?;
+                    }
+                },
+                Commands::OptionBytes(OptionBytes::RstMode(action)) => match action {
+                    RstModeAction::Get => {
+                        let mode = sess.rst_mode()?;
+                        println!("RST pin mode: {:?}", mode);
+                    }
+                    RstModeAction::Set { mode } => {
+                        if mode == RstMode::Gpio {
+                            log::warn!("PD7 will be a GPIO, the chip can no longer be reset by the RST pin");
+                            log::warn!("To revert, attach by SWIO and run `wlink option-bytes rst-mode set reset`");
+                        } else {
+                            log::warn!(
+                                "PD7 will be the RST pin, it can no longer be used as a GPIO"
+                            );
+                        }
+                        sess.set_rst_mode(mode)?;
+                        log::info!(
+                            "RST pin mode set to {:?}, power cycle the chip to take effect",
+                            mode
+                        );
+                    }
+                },
                 _ => unreachable!("unimplemented command"),
             }
+            if options.halt == HaltPolicy::Run {
+                // some operations, like chip_status, halt the MCU
+                sess.apply_halt_policy()?;
+            }
             if will_detach {
                 sess.detach_chip()?;
             }
@@ -437,17 +1698,3 @@ fn main() -> Result<()> {
 
     Ok(())
 }
-
-pub fn parse_number(s: &str) -> std::result::Result<u32, String> {
-    let s = s.replace('_', "").to_lowercase();
-    if let Some(hex_str) = s.strip_prefix("0x") {
-        Ok(
-            u32::from_str_radix(hex_str, 16)
-                .unwrap_or_else(|_| panic!("error while parsing {s:?}")),
-        )
-    } else if let Some(bin_str) = s.strip_prefix("0b") {
-        Ok(u32::from_str_radix(bin_str, 2).unwrap_or_else(|_| panic!("error while parsing {s:?}")))
-    } else {
-        Ok(s.parse().expect("must be a number"))
-    }
-}
@@ -0,0 +1,246 @@
+//! Hardware matrix runner, flash and check a list of boards, report in JUnit XML.
+//!
+//! Plan file format:
+//!
+//! ```toml
+//! parallel = false
+//!
+//! [[target]]
+//! name = "ch32v003-evt"
+//! serial = "0123456789AB"    # probe USB serial number, or `device = 0`
+//! chip = "CH32V003"
+//! firmware = "firmware/blinky.elf"
+//! expect = ["Hello", "PASS"] # expected serial output after reset, optional
+//! timeout = "10s"
+//! ```
+use std::{
+    fmt::Write as _,
+    io::Read,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{
+    cli::parse_duration,
+    firmware::read_firmware_from_file,
+    operations::{FlashBackend, ProbeSession},
+    probe::{self, UsbId, WchLink},
+    usb_device, RiscvChip,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Plan {
+    /// Run all targets concurrently
+    #[serde(default)]
+    pub parallel: bool,
+    #[serde(rename = "target", default)]
+    pub targets: Vec<Target>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Target {
+    pub name: String,
+    /// Probe USB serial number
+    pub serial: Option<String>,
+    /// Probe index, used when serial is not given
+    pub device: Option<usize>,
+    pub chip: Option<String>,
+    /// Firmware path, relative to the plan file
+    pub firmware: PathBuf,
+    /// Strings expected in the serial output after reset
+    #[serde(default)]
+    pub expect: Vec<String>,
+    /// Timeout of serial expectations, like "10s"
+    pub timeout: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct TargetResult {
+    pub name: String,
+    pub elapsed: Duration,
+    pub result: Result<()>,
+}
+
+impl Plan {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let plan = toml::from_str(&content)?;
+        Ok(plan)
+    }
+
+    /// Run all targets, firmware paths are relative to `base_dir`
+    pub fn run(&self, base_dir: &Path, usb_id: UsbId) -> Vec<TargetResult> {
+        if self.parallel {
+            let handles: Vec<_> = self
+                .targets
+                .iter()
+                .cloned()
+                .map(|target| {
+                    let base_dir = base_dir.to_path_buf();
+                    thread::spawn(move || target.run(&base_dir, usb_id))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .zip(&self.targets)
+                .map(|(handle, target)| {
+                    handle.join().unwrap_or_else(|_| TargetResult {
+                        name: target.name.clone(),
+                        elapsed: Duration::ZERO,
+                        result: Err(anyhow::format_err!("runner thread panicked")),
+                    })
+                })
+                .collect()
+        } else {
+            self.targets
+                .iter()
+                .map(|target| target.run(base_dir, usb_id))
+                .collect()
+        }
+    }
+}
+
+impl Target {
+    pub fn run(&self, base_dir: &Path, usb_id: UsbId) -> TargetResult {
+        log::info!("[{}] start", self.name);
+        let start = Instant::now();
+        let result = self.run_inner(base_dir, usb_id);
+        match &result {
+            Ok(()) => log::info!("[{}] passed", self.name),
+            Err(e) => log::error!("[{}] failed: {}", self.name, e),
+        }
+        TargetResult {
+            name: self.name.clone(),
+            elapsed: start.elapsed(),
+            result,
+        }
+    }
+
+    fn run_inner(&self, base_dir: &Path, usb_id: UsbId) -> Result<()> {
+        let chip = self
+            .chip
+            .as_deref()
+            .map(|s| s.parse::<RiscvChip>())
+            .transpose()?;
+        let timeout = match &self.timeout {
+            Some(s) => parse_duration(s).map_err(anyhow::Error::msg)?,
+            None => DEFAULT_TIMEOUT,
+        };
+        let nth = match &self.serial {
+            Some(sn) => usb_device::find_device_by_serial(usb_id.vid, usb_id.pid, sn)?
+                .ok_or_else(|| anyhow::format_err!("probe with serial {:?} not found", sn))?,
+            None => self.device.unwrap_or(0),
+        };
+
+        let firmware = read_firmware_from_file(base_dir.join(&self.firmware))?;
+
+        let probe = WchLink::open_nth_with_usb_id(nth, usb_id)?;
+        let mut sess = ProbeSession::attach(probe, chip, Default::default())?;
+        sess.write_firmware(&firmware, None, FlashBackend::default())?;
+
+        // open serial port before reset, not to miss any output.
+        // The port of this probe, by the serial read on open if not given in the plan
+        let port = if self.expect.is_empty() {
+            None
+        } else {
+            let serial = self
+                .serial
+                .clone()
+                .or_else(|| sess.probe.info.serial.clone());
+            if serial.is_none() {
+                log::warn!(
+                    "[{}] probe has no serial number, using the first serial port",
+                    self.name
+                );
+            }
+            Some(probe::open_serial_port(usb_id, serial.as_deref())?)
+        };
+        sess.soft_reset()?;
+        sess.detach_chip()?;
+
+        if let Some(port) = port {
+            expect_serial(port, &self.expect, timeout)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read serial port until all expected strings are seen
+fn expect_serial(
+    mut port: Box<dyn serialport::SerialPort>,
+    expected: &[String],
+    timeout: Duration,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut output = String::new();
+    let mut buf = [0u8; 1024];
+    while start.elapsed() < timeout {
+        match port.read(&mut buf) {
+            Ok(n) => output.push_str(&String::from_utf8_lossy(&buf[..n])),
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => (),
+            Err(e) => return Err(e.into()),
+        }
+        if expected.iter().all(|s| output.contains(s.as_str())) {
+            return Ok(());
+        }
+    }
+    let missing: Vec<_> = expected
+        .iter()
+        .filter(|s| !output.contains(s.as_str()))
+        .collect();
+    anyhow::bail!("serial output timeout, missing {:?}", missing)
+}
+
+/// Render results as a JUnit XML report
+pub fn junit_xml(suite: &str, results: &[TargetResult]) -> String {
+    let failures = results.iter().filter(|r| r.result.is_err()).count();
+    let total: Duration = results.iter().map(|r| r.elapsed).sum();
+
+    let mut xml = String::new();
+    let _ = writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    let _ = writeln!(
+        xml,
+        r#"<testsuite name="{}" tests="{}" failures="{}" time="{:.3}">"#,
+        escape(suite),
+        results.len(),
+        failures,
+        total.as_secs_f64()
+    );
+    for r in results {
+        let _ = write!(
+            xml,
+            r#"  <testcase name="{}" time="{:.3}""#,
+            escape(&r.name),
+            r.elapsed.as_secs_f64()
+        );
+        match &r.result {
+            Ok(()) => {
+                let _ = writeln!(xml, "/>");
+            }
+            Err(e) => {
+                let _ = writeln!(xml, ">");
+                let _ = writeln!(
+                    xml,
+                    r#"    <failure message="{}"/>"#,
+                    escape(&format!("{:#}", e))
+                );
+                let _ = writeln!(xml, "  </testcase>");
+            }
+        }
+    }
+    let _ = writeln!(xml, "</testsuite>");
+    xml
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
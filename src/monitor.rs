@@ -0,0 +1,87 @@
+//! `monitor` commands, for probe operations GDB has no command for.
+//!
+//! GDB sends `monitor <command>` as a `qRcmd,<hex>` packet, [`handle_qrcmd`] runs it
+//! for a GDB server built on wlink. The output is sent back hex encoded in an `O`
+//! packet, followed by `OK`. The commands are also available in `wlink repl` as
+//! `monitor <command>`.
+use crate::{commands::Speed, operations::ProbeSession, Error, Result};
+
+pub const HELP: &str = "\
+monitor reset [halt|run]       reset the chip, halt at the reset vector with `halt`
+monitor erase                  erase the code flash
+monitor sdi enable|disable     enable or disable SDI print
+monitor speed low|medium|high  set the SWD clock, or a frequency in kHz
+monitor help                   show this help";
+
+/// Run a monitor command, returns the text to print on the GDB console
+pub fn execute(sess: &mut ProbeSession, line: &str) -> Result<String> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.as_slice() {
+        ["reset"] | ["reset", "run"] => {
+            sess.soft_reset()?;
+            Ok("Reset\n".to_string())
+        }
+        ["reset", "halt"] => {
+            sess.ensure_writable("Reset")?;
+            sess.reset_and_halt()?;
+            Ok("Reset, halted\n".to_string())
+        }
+        ["erase"] => {
+            sess.erase_flash()?;
+            Ok("Flash erased\n".to_string())
+        }
+        ["sdi", state @ ("enable" | "disable")] => {
+            let enable = *state == "enable";
+            sess.set_sdi_print_enabled(enable)?;
+            Ok(format!("SDI print {}d\n", state))
+        }
+        ["speed", speed] => {
+            let speed = parse_speed(speed)?;
+            sess.set_speed(speed)?;
+            Ok(format!("Speed {:?}, {} kHz\n", speed, speed.khz()))
+        }
+        [] | ["help"] => Ok(format!("{}\n", HELP)),
+        _ => Err(Error::Custom(format!(
+            "unknown monitor command {:?}, try `monitor help`",
+            line.trim()
+        ))),
+    }
+}
+
+fn parse_speed(s: &str) -> Result<Speed> {
+    match s {
+        "low" => Ok(Speed::Low),
+        "medium" => Ok(Speed::Medium),
+        "high" => Ok(Speed::High),
+        _ => s
+            .trim_end_matches("kHz")
+            .parse::<u32>()
+            .map(Speed::nearest_khz)
+            .map_err(|_| Error::Custom(format!("invalid speed {:?}", s))),
+    }
+}
+
+/// Reply packets to a `qRcmd,<hex>` packet, None if it is another packet.
+///
+/// Packets are payloads, without the `$` and `#checksum` framing. A command that
+/// fails replies its error in an `O` packet and `E02`, a command that is not hex
+/// encoded UTF-8 replies `E01`.
+pub fn handle_qrcmd(sess: &mut ProbeSession, packet: &str) -> Option<Vec<String>> {
+    let payload = packet.strip_prefix("qRcmd,")?;
+    let Some(line) = hex::decode(payload)
+        .ok()
+        .and_then(|command| String::from_utf8(command).ok())
+    else {
+        return Some(vec!["E01".to_string()]);
+    };
+    let replies = match execute(sess, &line) {
+        Ok(output) if output.is_empty() => vec!["OK".to_string()],
+        Ok(output) => vec![output_packet(&output), "OK".to_string()],
+        Err(e) => vec![output_packet(&format!("{}\n", e)), "E02".to_string()],
+    };
+    Some(replies)
+}
+
+fn output_packet(text: &str) -> String {
+    format!("O{}", hex::encode(text))
+}
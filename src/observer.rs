@@ -0,0 +1,167 @@
+//! Session observers, structured events for telemetry of tools embedding wlink
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::Error;
+
+/// A structured event of a probe session
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// A command was sent to the probe
+    CommandSent { id: u8, payload_len: usize },
+    /// Bytes read from the data endpoint
+    DataRead { bytes: usize },
+    /// Bytes written to the data endpoint
+    DataWritten { bytes: usize },
+    /// An operation is retried, like a busy DMI read
+    Retry { op: &'static str },
+    /// An operation failed
+    Error { op: &'static str, error: &'a Error },
+    /// A phase of an operation finished, like attach, erase or program
+    Phase {
+        name: &'static str,
+        elapsed: Duration,
+        /// Bytes transferred in the phase, for throughput
+        bytes: Option<u64>,
+    },
+}
+
+/// Observer of session events, set by `WchLink::set_observer`
+pub trait SessionObserver: fmt::Debug + Send + Sync {
+    fn on_event(&self, event: &Event<'_>);
+}
+
+/// Duration of a phase, by `Event::Phase`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhaseTiming {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    pub bytes: Option<u64>,
+}
+
+impl PhaseTiming {
+    /// Bytes per second, None for phases without data
+    pub fn throughput(&self) -> Option<f64> {
+        let secs = self.elapsed.as_secs_f64();
+        self.bytes
+            .filter(|_| secs > 0.0)
+            .map(|bytes| bytes as f64 / secs)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "phase": self.name,
+            "seconds": self.elapsed.as_secs_f64(),
+            "bytes": self.bytes,
+            "bytes_per_second": self.throughput(),
+        })
+    }
+}
+
+impl fmt::Display for PhaseTiming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:<14} {:>8.3}s", self.name, self.elapsed.as_secs_f64())?;
+        if let (Some(bytes), Some(rate)) = (self.bytes, self.throughput()) {
+            write!(f, "  {} bytes, {:.1} KiB/s", bytes, rate / 1024.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Default observer, counts events for a summary, and keeps phase timings
+#[derive(Debug)]
+pub struct Stats {
+    start: Instant,
+    commands: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    retries: AtomicU64,
+    errors: AtomicU64,
+    phases: Mutex<Vec<PhaseTiming>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            commands: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            phases: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl Stats {
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+    pub fn commands(&self) -> u64 {
+        self.commands.load(Ordering::Relaxed)
+    }
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+    /// Phase timings, in order of completion
+    pub fn phases(&self) -> Vec<PhaseTiming> {
+        self.phases.lock().unwrap().clone()
+    }
+    /// Record a phase timed outside of the probe, like opening it
+    pub fn record_phase(&self, name: &'static str, elapsed: Duration, bytes: Option<u64>) {
+        self.phases.lock().unwrap().push(PhaseTiming {
+            name,
+            elapsed,
+            bytes,
+        });
+    }
+}
+
+impl SessionObserver for Stats {
+    fn on_event(&self, event: &Event<'_>) {
+        let (counter, n) = match event {
+            Event::CommandSent { .. } => (&self.commands, 1),
+            Event::DataRead { bytes } => (&self.bytes_read, *bytes as u64),
+            Event::DataWritten { bytes } => (&self.bytes_written, *bytes as u64),
+            Event::Retry { .. } => (&self.retries, 1),
+            Event::Error { .. } => (&self.errors, 1),
+            &Event::Phase {
+                name,
+                elapsed,
+                bytes,
+            } => return self.record_phase(name, elapsed, bytes),
+        };
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} commands, {} bytes read, {} bytes written, {} retries, {} errors in {:.3}s",
+            self.commands(),
+            self.bytes_read(),
+            self.bytes_written(),
+            self.retries(),
+            self.errors(),
+            self.elapsed().as_secs_f64()
+        )
+    }
+}
@@ -1,25 +1,283 @@
 //! Predefined operations for WCH-Link
 
-use indicatif::ProgressBar;
-use std::{thread::sleep, time::Duration};
+use serde::Serialize;
+use std::{
+    fmt,
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 use crate::{
+    chips::{self, ChipMemory},
     commands::{self, Speed},
-    probe::WchLink,
-    Error, Result, RiscvChip,
+    dmi::{DebugModuleInterface, MAX_RESET_RECOVERIES},
+    firmware::Firmware,
+    flash_op::FlashAlgo,
+    observer::Event,
+    probe::{FirmwareFeature, WchLink},
+    progress::ProgressBar,
+    regs, AddressMap, Error, Result, RiscvChip,
 };
 
+/// Max bytes of one read memory transfer, a target reset loses at most one chunk
+const READ_MEMORY_CHUNK_SIZE: u32 = 0x1000;
+
+/// Attach attempts at each speed
+const ATTACH_RETRIES: usize = 3;
+
+/// Max resends of a fastprogram pack the flash was busy for
+const MAX_PACK_RETRIES: usize = 3;
+
+/// Max extra reads of the ROM/RAM split, until two reads agree
+const MAX_ROM_RAM_SPLIT_READS: usize = 3;
+
+/// How the code flash is programmed
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum FlashBackend {
+    /// Upload the vendor flash_op blob, then fastprogram
+    #[default]
+    FlashOp,
+    /// Drive the FLASH controller registers by DMI, page by page
+    Dmi,
+}
+
+/// What to do with the target core when attaching
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum HaltPolicy {
+    /// Leave the core as it is
+    #[default]
+    Unchanged,
+    /// Halt the core after attaching
+    Halt,
+    /// Keep the core running, resume it if halted
+    Run,
+}
+
+/// Options of attaching a chip
+#[derive(Debug, Copy, Clone, Default)]
+pub struct AttachOptions {
+    pub halt: HaltPolicy,
+    /// Reject all operations that modify the target
+    pub read_only: bool,
+    /// Override the USB transfer timeout
+    pub usb_timeout: Option<Duration>,
+    /// Override the max retries of a busy DMI read
+    pub dmi_retries: Option<usize>,
+    /// Try larger pack sizes on the first flash write, keep the largest that verifies
+    pub negotiate_pack_size: bool,
+    /// Override the attach attempts at each speed
+    pub attach_retries: Option<usize>,
+    /// Fail if the chip can't be attached at the requested speed, instead of trying slower ones
+    pub no_speed_fallback: bool,
+    /// Re-attach and retry once if the chip is lost mid-operation, e.g. by a brown-out
+    pub auto_recover: bool,
+    /// Read the flash OP back after uploading it
+    pub verify_flash_op: bool,
+    /// Program by WriteFlashAndVerify, the flash OP verifies each pack as it is written
+    pub verify_during_write: bool,
+    /// Keep the flash contents after a write in its last page, instead of erasing them
+    pub patch_flash: bool,
+}
+
+/// Pack sizes of fastprogram
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PackSizes {
+    /// Bytes programmed per status report of the flash OP
+    pub write_pack_size: u32,
+    /// Bytes per USB transfer on the data endpoint
+    pub data_packet_size: usize,
+}
+
+/// Chip identity and protection state, by `ProbeSession::chip_status`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChipStatus {
+    pub chip_family: RiscvChip,
+    pub chip_id: u32,
+    /// None if the chip can't be queried
+    pub esig: Option<commands::ESignature>,
+    /// Memory of the exact part in the default split, None if not in the chip DB
+    pub memory: Option<ChipMemory>,
+    /// None if the chip can't be queried
+    pub read_protected: Option<bool>,
+    /// SRAM/code flash split mode, for chips supporting it
+    pub rom_ram_split: Option<u8>,
+}
+
+impl fmt::Display for ChipStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Chip: {:?} (0x{:08x})", self.chip_family, self.chip_id)?;
+        if let Some(esig) = &self.esig {
+            write!(f, "\nChip ESIG: {}", esig)?;
+        }
+        if let Some(memory) = &self.memory {
+            write!(
+                f,
+                "\nChip memory: {}KB flash, {}KB SRAM",
+                memory.flash_kb, memory.sram_kb
+            )?;
+        }
+        if let Some(protected) = self.read_protected {
+            write!(f, "\nFlash protected: {}", protected)?;
+        }
+        if let Some(mode) = self.rom_ram_split {
+            write!(
+                f,
+                "\nROM/RAM split: {}",
+                rom_ram_split_label(self.chip_family, mode)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Kind of a memory region, also the `--region` names of `dump`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum RegionKind {
+    CodeFlash,
+    /// System flash with the factory bootloader
+    Bootloader,
+    Esig,
+    OptionBytes,
+    Sram,
+    #[cfg_attr(feature = "cli", value(skip))]
+    Peripherals,
+    #[cfg_attr(feature = "cli", value(skip))]
+    CorePrivate,
+}
+
+/// A region of the chip address space
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemoryRegion {
+    pub kind: RegionKind,
+    pub name: &'static str,
+    pub start: u32,
+    /// Size in bytes, None if unknown for the exact part
+    pub size: Option<u32>,
+}
+
+/// Address space of the attached chip, by `ProbeSession::memory_map`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemoryMap {
+    pub chip_family: RiscvChip,
+    /// SRAM/code flash split mode, for chips supporting it
+    pub rom_ram_split: Option<u8>,
+    pub regions: Vec<MemoryRegion>,
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Memory map of {:?}", self.chip_family)?;
+        if let Some(mode) = self.rom_ram_split {
+            write!(
+                f,
+                ", ROM/RAM split {}",
+                rom_ram_split_label(self.chip_family, mode)
+            )?;
+        }
+        for region in &self.regions {
+            write!(f, "\n{:<24} 0x{:08x}", region.name, region.start)?;
+            match region.size {
+                Some(size) => {
+                    let end = region.start as u64 + size as u64 - 1;
+                    write!(f, " - 0x{:08x}  ", end)?;
+                    if size >= 1024 && size.is_multiple_of(1024) {
+                        write!(f, "{}KB", size / 1024)?;
+                    } else {
+                        write!(f, "{}B", size)?;
+                    }
+                }
+                None => write!(f, " - unknown size")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Human readable ROM/RAM split mode, with the sizes if known
+pub fn rom_ram_split_label(chip: RiscvChip, mode: u8) -> String {
+    match chip.rom_ram_split_sizes(mode) {
+        Some((flash_kb, sram_kb)) => {
+            format!("mode {}, {}KB flash + {}KB SRAM", mode, flash_kb, sram_kb)
+        }
+        None => format!("mode {}, sizes unknown for {:?}", mode, chip),
+    }
+}
+
+/// Warn if the chip ID belongs to another family than the attached one, flashing
+/// with the flash_op of a wrong family may corrupt the flash
+fn check_chip_id(family: RiscvChip, chip_id: u32) {
+    let Some(id_family) = chips::chip_id_to_family(chip_id) else {
+        return;
+    };
+    if chips::is_family_compatible(family, id_family) {
+        return;
+    }
+    let name = chips::chip_id_to_chip_name(chip_id)
+        .map(|name| format!(" ({})", name))
+        .unwrap_or_default();
+    log::warn!(
+        "Chip ID 0x{:08x} is a {:?}{}, but the chip is attached as {:?}!",
+        chip_id,
+        id_family,
+        name,
+        family
+    );
+    log::warn!("Flashing as the wrong chip family may corrupt the flash, check --chip");
+}
+
+/// SRAM of all supported chips is mapped here
+const SRAM_START: u32 = 0x2000_0000;
+/// Peripherals of all supported chips, up to the core private region
+const PERIPHERAL_REGION: std::ops::Range<u32> = 0x4000_0000..0x6000_0000;
+/// PFIC and SysTick of QingKe cores
+const CORE_PRIVATE_REGION: std::ops::Range<u32> = 0xE000_0000..0xE010_0000;
+/// ESIG of CH32 parts, flash size and UID
+const ESIG_REGION: std::ops::Range<u32> = 0x1FFF_F7E0..0x1FFF_F800;
+
+/// System flash of CH32 parts, with the factory bootloader
+const SYSTEM_FLASH_REGION: std::ops::Range<u32> = 0x1FFF_0000..0x2000_0000;
+
+/// Max data endpoint transfer tried by pack size negotiation
+const MAX_DATA_PACKET_SIZE: usize = 512;
+
 /// A running probe session, flash, erase, inspect, etc.
 pub struct ProbeSession {
     pub probe: WchLink,
     pub chip_family: RiscvChip,
+    /// Chip ID reported on attach, 0 if not available
+    pub chip_id: u32,
     pub speed: Speed,
+    pub options: AttachOptions,
+    flash_algo: Option<FlashAlgo>,
+    address_map: Option<AddressMap>,
+    /// Pack sizes that worked in this session
+    pack_sizes: Option<PackSizes>,
 }
 
 impl ProbeSession {
     /// Attach probe to target chip, start a probe session
     pub fn attach(probe: WchLink, expected_chip: Option<RiscvChip>, speed: Speed) -> Result<Self> {
+        Self::attach_with_options(probe, expected_chip, speed, AttachOptions::default())
+    }
+
+    /// Attach probe to target chip, with halt policy
+    pub fn attach_with_options(
+        probe: WchLink,
+        expected_chip: Option<RiscvChip>,
+        speed: Speed,
+        options: AttachOptions,
+    ) -> Result<Self> {
+        let start = Instant::now();
         let mut probe = probe;
+        if let Some(timeout) = options.usb_timeout {
+            probe.set_usb_timeout(timeout);
+        }
+        if let Some(retries) = options.dmi_retries {
+            probe.set_dmi_retries(retries);
+        }
 
         let chip = expected_chip.unwrap_or(RiscvChip::CH32V103);
 
@@ -30,9 +288,29 @@ impl ProbeSession {
             return Err(Error::UnsupportedChip(chip));
         }
 
-        let mut chip_info = None;
+        let mut speeds = vec![speed];
+        if !options.no_speed_fallback {
+            speeds.extend(std::iter::successors(speed.slower(), |s| s.slower()));
+        }
+        let retries = options.attach_retries.unwrap_or(ATTACH_RETRIES).max(1);
 
-        for _ in 0..3 {
+        let mut chip_info = None;
+        let mut attempts = speeds
+            .iter()
+            .flat_map(|&speed| std::iter::repeat_n(speed, retries));
+        let mut speed = speed;
+        while chip_info.is_none() {
+            let Some(next) = attempts.next() else {
+                break;
+            };
+            if next != speed {
+                log::warn!(
+                    "Attach failed at {}kHz, falling back to {}kHz",
+                    speed.khz(),
+                    next.khz()
+                );
+                speed = next;
+            }
             probe.send_command(commands::SetSpeed {
                 riscvchip: chip as u8,
                 speed,
@@ -68,7 +346,8 @@ impl ProbeSession {
         }
 
         let chip_info = chip_info.ok_or(Error::NotAttached)?;
-        chip_info.chip_family.do_post_init(&mut probe)?;
+        check_chip_id(chip_info.chip_family, chip_info.chip_id);
+        probe.do_post_init(chip_info.chip_family)?;
 
         //let ret = self.send_command(control::CheckQE)?;
         //log::info!("Check QE: {:?}", ret);
@@ -76,11 +355,175 @@ impl ProbeSession {
         //let flash_addr = chip_info.chip_family.code_flash_start();
         //let page_size = chip_info.chip_family.data_packet_size();
 
-        Ok(ProbeSession {
+        let mut sess = ProbeSession {
             probe,
             chip_family: chip_info.chip_family,
+            chip_id: chip_info.chip_id,
             speed,
-        })
+            options,
+            flash_algo: None,
+            address_map: None,
+            pack_sizes: None,
+        };
+        sess.apply_halt_policy()?;
+        sess.probe.emit(Event::Phase {
+            name: "attach",
+            elapsed: start.elapsed(),
+            bytes: None,
+        });
+
+        Ok(sess)
+    }
+
+    /// Resume a session left attached by an earlier run, without the attach handshake
+    ///
+    /// The chip is not re-detected, the link is checked by a DMSTATUS read instead.
+    pub fn resume_with_options(
+        probe: WchLink,
+        chip_family: RiscvChip,
+        chip_id: u32,
+        speed: Speed,
+        options: AttachOptions,
+    ) -> Result<Self> {
+        let mut probe = probe;
+        if let Some(timeout) = options.usb_timeout {
+            probe.set_usb_timeout(timeout);
+        }
+        if let Some(retries) = options.dmi_retries {
+            probe.set_dmi_retries(retries);
+        }
+
+        let dmstatus = probe.read_dmi_reg::<regs::Dmstatus>()?;
+        log::debug!("Resume session, {:?}", dmstatus);
+        if dmstatus.version() == 0 || u32::from(dmstatus) == 0xffff_ffff {
+            return Err(Error::NotAttached);
+        }
+
+        let mut sess = ProbeSession {
+            probe,
+            chip_family,
+            chip_id,
+            speed,
+            options,
+            flash_algo: None,
+            address_map: None,
+            pack_sizes: None,
+        };
+        sess.apply_halt_policy()?;
+
+        Ok(sess)
+    }
+
+    /// The flash loader in use, the chip default if not overridden
+    pub fn flash_algo(&self) -> Result<FlashAlgo> {
+        self.flash_algo
+            .or_else(|| FlashAlgo::for_chip(self.chip_family))
+            .ok_or(Error::UnsupportedChip(self.chip_family))
+    }
+
+    /// Override the flash loader, e.g. to use an alternative blob
+    pub fn set_flash_algo(&mut self, algo: FlashAlgo) {
+        self.flash_algo = Some(algo);
+    }
+
+    /// The firmware address translation in use, the chip default if not overridden
+    pub fn address_map(&self) -> AddressMap {
+        self.address_map
+            .unwrap_or_else(|| self.chip_family.address_map())
+    }
+
+    /// Override the firmware address translation, e.g. `AddressMap::Identity` to keep addresses
+    pub fn set_address_map(&mut self, map: AddressMap) {
+        self.address_map = Some(map);
+    }
+
+    /// Run a phase of an operation, its duration is reported to the session observer
+    pub fn timed<T, F>(&mut self, name: &'static str, bytes: Option<u64>, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        let start = Instant::now();
+        let ret = f(self)?;
+        self.probe.emit(Event::Phase {
+            name,
+            elapsed: start.elapsed(),
+            bytes,
+        });
+        Ok(ret)
+    }
+
+    /// Re-attach a chip lost by the probe, e.g. after a brown-out of the target
+    ///
+    /// Runs the attach handshake again at the session speed. The chip restarted, so
+    /// the halt policy is applied again.
+    pub fn recover(&mut self) -> Result<()> {
+        log::warn!("Chip is not attached, re-attaching");
+        self.probe.send_command(commands::SetSpeed {
+            riscvchip: self.chip_family as u8,
+            speed: self.speed,
+        })?;
+        let resp = self
+            .probe
+            .send_command(commands::control::AttachChip)
+            .map_err(|_| Error::NotAttached)?;
+        if resp.chip_family != self.chip_family {
+            return Err(Error::ChipMismatch(self.chip_family, resp.chip_family));
+        }
+        log::info!("Re-attached chip: {}", resp);
+        self.probe.do_post_init(self.chip_family)?;
+        self.apply_halt_policy()
+    }
+
+    /// Change the SWD clock of the attached chip
+    pub fn set_speed(&mut self, speed: Speed) -> Result<()> {
+        self.probe.send_command(commands::SetSpeed {
+            riscvchip: self.chip_family as u8,
+            speed,
+        })?;
+        self.speed = speed;
+        Ok(())
+    }
+
+    /// Run an operation, with `auto_recover` it is retried once after re-attaching a lost chip.
+    ///
+    /// Flash erase and write, memory reads, resets, flash protection and register
+    /// access are retried, a retried register read returns the value after the restart.
+    pub fn with_recovery<T, F>(&mut self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut Self) -> Result<T>,
+    {
+        match f(self) {
+            Err(e) if self.options.auto_recover && e.is_not_attached() => {
+                log::warn!("{}", e);
+                self.recover()?;
+                f(self)
+            }
+            ret => ret,
+        }
+    }
+
+    /// Returns error if the session is read-only
+    pub fn ensure_writable(&self, op: &'static str) -> Result<()> {
+        if self.options.read_only {
+            return Err(Error::ReadOnly(op));
+        }
+        Ok(())
+    }
+
+    /// Halt or resume the core according to the attach options
+    pub fn apply_halt_policy(&mut self) -> Result<()> {
+        match self.options.halt {
+            HaltPolicy::Unchanged => (),
+            HaltPolicy::Halt => {
+                log::debug!("Halt on attach");
+                self.ensure_mcu_halt()?;
+            }
+            HaltPolicy::Run => {
+                log::debug!("Leave running on attach");
+                self.ensure_mcu_resume()?;
+            }
+        }
+        Ok(())
     }
 
     pub fn detach_chip(&mut self) -> Result<()> {
@@ -97,39 +540,211 @@ impl ProbeSession {
     }
 
     // NOTE: this halts the MCU
-    pub fn dump_info(&mut self) -> Result<()> {
-        if self.chip_family.support_query_info() {
-            let esig = if self.probe.info.version() >= (2, 9) {
-                self.probe.send_command(commands::GetChipInfo::V2)?
-            } else {
-                self.probe.send_command(commands::GetChipInfo::V1)?
-            };
-            log::info!("Chip ESIG: {esig}");
+    pub fn chip_status(&mut self) -> Result<ChipStatus> {
+        let (esig, read_protected) = if self.chip_family.support_query_info() {
+            (Some(self.esig()?), Some(self.is_read_protected()?))
+        } else {
+            (None, None)
+        };
+        let rom_ram_split = if self.chip_family.support_ram_rom_mode() {
+            Some(self.rom_ram_split()?)
+        } else {
+            None
+        };
+        Ok(ChipStatus {
+            chip_family: self.chip_family,
+            chip_id: self.chip_id,
+            esig,
+            memory: self.chip_memory(),
+            read_protected,
+            rom_ram_split,
+        })
+    }
 
-            let flash_protected = self
-                .probe
-                .send_command(commands::ConfigChip::CheckReadProtect)?;
-            let protected = flash_protected == commands::ConfigChip::FLAG_READ_PROTECTED;
-            log::info!("Flash protected: {}", protected);
-            if protected {
-                log::warn!("Flash is protected, debug access is not available");
-            }
+    /// Regions of the chip address space, flash and SRAM sizes follow the ROM/RAM split
+    pub fn memory_map(&mut self) -> Result<MemoryMap> {
+        let rom_ram_split = if self.chip_family.support_ram_rom_mode() {
+            Some(self.rom_ram_split()?)
+        } else {
+            None
+        };
+        let split_sizes = rom_ram_split.and_then(|mode| self.split_sizes(mode));
+        let (flash_kb, sram_kb) = match split_sizes {
+            Some((flash_kb, sram_kb)) => (flash_kb, Some(sram_kb)),
+            None => (self.flash_size_kb()? as u32, self.sram_size_kb()?),
+        };
+
+        let mut regions = vec![MemoryRegion {
+            kind: RegionKind::CodeFlash,
+            name: "Code flash",
+            start: self.chip_family.code_flash_start(),
+            size: Some(flash_kb * 1024),
+        }];
+        if let Some((start, size)) = self.chip_family.system_flash_region() {
+            regions.push(MemoryRegion {
+                kind: RegionKind::Bootloader,
+                name: "System flash(bootloader)",
+                start,
+                size: Some(size),
+            });
         }
-        if self.chip_family.support_ram_rom_mode() {
-            let sram_code_mode = self
+        if self.chip_family.support_dmi_flash() {
+            regions.push(MemoryRegion {
+                kind: RegionKind::Esig,
+                name: "ESIG",
+                start: ESIG_REGION.start,
+                size: Some(ESIG_REGION.end - ESIG_REGION.start),
+            });
+            regions.push(MemoryRegion {
+                kind: RegionKind::OptionBytes,
+                name: "Option bytes",
+                start: crate::dmi::OPTION_BYTES_ADDR,
+                size: Some(crate::dmi::OPTION_BYTES_LEN * 2),
+            });
+        }
+        regions.push(MemoryRegion {
+            kind: RegionKind::Sram,
+            name: "SRAM",
+            start: SRAM_START,
+            size: sram_kb.map(|kb| kb * 1024),
+        });
+        regions.push(MemoryRegion {
+            kind: RegionKind::Peripherals,
+            name: "Peripherals",
+            start: PERIPHERAL_REGION.start,
+            size: Some(PERIPHERAL_REGION.end - PERIPHERAL_REGION.start),
+        });
+        regions.push(MemoryRegion {
+            kind: RegionKind::CorePrivate,
+            name: "Core private(PFIC)",
+            start: CORE_PRIVATE_REGION.start,
+            size: Some(CORE_PRIVATE_REGION.end - CORE_PRIVATE_REGION.start),
+        });
+        Ok(MemoryMap {
+            chip_family: self.chip_family,
+            rom_ram_split,
+            regions,
+        })
+    }
+
+    /// A region of the memory map, fails if the chip has no such region
+    pub fn memory_region(&mut self, kind: RegionKind) -> Result<MemoryRegion> {
+        self.memory_map()?
+            .regions
+            .into_iter()
+            .find(|region| region.kind == kind)
+            .ok_or_else(|| {
+                Error::Custom(format!("{:?} has no {:?} region", self.chip_family, kind))
+            })
+    }
+
+    /// Query the SRAM/code flash split mode, 0 to 3.
+    ///
+    /// Sends the 0x11 0x0d query first as WCH-LinkUtility does, and reads the split
+    /// until two reads agree, the first read may be stale on CH32V30x.
+    pub fn rom_ram_split(&mut self) -> Result<u8> {
+        match self.probe.send_command(commands::QueryPreRomRamSplit) {
+            Ok(info) => log::debug!("Pre ROM/RAM split query: {}", hex::encode(info.payload())),
+            Err(e) => log::debug!("Pre ROM/RAM split query failed: {}", e),
+        }
+
+        let mut last = self
+            .probe
+            .send_command(commands::control::GetChipRomRamSplit)?;
+        for _ in 0..MAX_ROM_RAM_SPLIT_READS {
+            let split = self
                 .probe
                 .send_command(commands::control::GetChipRomRamSplit)?;
-            log::debug!("SRAM CODE split mode: {}", sram_code_mode);
+            if split == last {
+                return Ok(split);
+            }
+            log::debug!(
+                "ROM/RAM split changed from {} to {}, read again",
+                last,
+                split
+            );
+            last = split;
         }
-        /*
-        if detailed {
+        log::warn!("ROM/RAM split is not stable, using {}", last);
+        Ok(last)
+    }
 
+    /// Close the debug interface, the chip can no longer be attached by the probe
+    pub fn disable_debug(&mut self) -> Result<()> {
+        self.ensure_writable("Disable debug")?;
+        if !self.chip_family.support_disable_debug() {
+            return Err(Error::Custom(format!(
+                "Disabling debug is not supported by {:?}",
+                self.chip_family
+            )));
         }
-        */
+        self.probe.send_command(commands::DisableDebug)?;
         Ok(())
     }
 
+    /// QE bit of the external QSPI flash, CH56X only
+    pub fn qe_bit(&mut self) -> Result<bool> {
+        self.ensure_qe_bit_supported()?;
+        let qe = self.probe.send_command(commands::control::CheckQE)?;
+        log::debug!("Check QE: {}", qe);
+        Ok(qe != 0)
+    }
+
+    /// Set the QE bit of the external QSPI flash, for quad SPI access
+    pub fn enable_qe_bit(&mut self) -> Result<()> {
+        self.ensure_writable("QE bit write")?;
+        self.ensure_qe_bit_supported()?;
+        self.probe.send_command(commands::control::EnableQE)?;
+        Ok(())
+    }
+
+    fn ensure_qe_bit_supported(&self) -> Result<()> {
+        if !self.chip_family.support_qe_bit() {
+            return Err(Error::Custom(format!(
+                "QE bit is not supported by {:?}",
+                self.chip_family
+            )));
+        }
+        Ok(())
+    }
+
+    /// Set the SRAM/code flash split mode, 0 to 3, takes effect after a power cycle
+    pub fn set_rom_ram_split(&mut self, mode: u8) -> Result<()> {
+        self.ensure_writable("ROM/RAM split")?;
+        if !self.chip_family.support_ram_rom_mode() {
+            return Err(Error::Custom(format!(
+                "ROM/RAM split is not supported by {:?}",
+                self.chip_family
+            )));
+        }
+        if mode > 3 {
+            return Err(Error::Custom(format!(
+                "ROM/RAM split mode must be 0 to 3, got {}",
+                mode
+            )));
+        }
+        self.probe
+            .send_command(commands::control::SetChipRomRamSplit(mode))?;
+        Ok(())
+    }
+
+    /// Check code flash read protection, false if the chip can't be queried
+    pub fn is_read_protected(&mut self) -> Result<bool> {
+        if !self.chip_family.support_query_info() {
+            return Ok(false);
+        }
+        let flash_protected = self
+            .probe
+            .send_command(commands::ConfigChip::CheckReadProtect)?;
+        Ok(flash_protected == commands::ConfigChip::FLAG_READ_PROTECTED)
+    }
+
     pub fn unprotect_flash(&mut self) -> Result<()> {
+        self.ensure_writable("Flash unprotect")?;
+        self.with_recovery(Self::unprotect_flash_once)
+    }
+
+    fn unprotect_flash_once(&mut self) -> Result<()> {
         // HACK: requires a fresh attach
         self.reattach_chip()?;
 
@@ -176,6 +791,11 @@ impl ProbeSession {
     }
 
     pub fn protect_flash(&mut self) -> Result<()> {
+        self.ensure_writable("Flash protect")?;
+        self.with_recovery(Self::protect_flash_once)
+    }
+
+    fn protect_flash_once(&mut self) -> Result<()> {
         // HACK: requires a fresh attach
         self.reattach_chip()?;
 
@@ -201,10 +821,15 @@ impl ProbeSession {
         Ok(())
     }
 
-    /// Clear cmderror
-
     /// Erases flash and re-attach
     pub fn erase_flash(&mut self) -> Result<()> {
+        self.ensure_writable("Flash erase")?;
+        self.timed("erase", None, |sess| {
+            sess.with_recovery(Self::erase_flash_once)
+        })
+    }
+
+    fn erase_flash_once(&mut self) -> Result<()> {
         if self.chip_family.support_flash_protect() {
             let ret = self
                 .probe
@@ -218,21 +843,172 @@ impl ProbeSession {
                 log::warn!("Unknown flash protect status: {}", ret);
             }
         }
+        let flash_start = self.chip_family.code_flash_start();
+        // WRPR covers 32 sectors, but only up to the end of the code flash
+        let mut length = 32 * self.chip_family.write_protect_sector_size();
+        if let Ok(flash_kb) = self.flash_size_kb() {
+            length = length.min(flash_kb as u32 * 1024);
+        }
+        self.check_write_protect(flash_start, length)?;
+
         self.probe.send_command(commands::Program::EraseFlash)?;
         self.probe.send_command(commands::control::AttachChip)?;
 
         Ok(())
     }
 
+    /// Pack sizes for fastprogram, negotiated ones if any, otherwise of the loader or chip family
+    pub fn pack_sizes(&mut self) -> Result<PackSizes> {
+        if let Some(sizes) = self.pack_sizes {
+            return Ok(sizes);
+        }
+        let algo = self.flash_algo()?;
+        Ok(PackSizes {
+            write_pack_size: algo
+                .pack_size
+                .unwrap_or_else(|| self.chip_family.write_pack_size()),
+            data_packet_size: self.chip_family.data_packet_size(),
+        })
+    }
+
+    /// Find the largest pack sizes that work, by writing and verifying the head of `data`.
+    ///
+    /// Falls back to the default sizes, the result is kept for the session.
+    fn negotiate_pack_sizes(&mut self, data: &[u8], address: u32) -> Result<PackSizes> {
+        let default = self.pack_sizes()?;
+        let candidates = [
+            PackSizes {
+                write_pack_size: default.write_pack_size * 2,
+                data_packet_size: (default.data_packet_size * 2).min(MAX_DATA_PACKET_SIZE),
+            },
+            PackSizes {
+                write_pack_size: default.write_pack_size * 2,
+                data_packet_size: default.data_packet_size,
+            },
+        ];
+        for sizes in candidates {
+            let len = data.len().min(sizes.write_pack_size as usize * 2);
+            let head = &data[..len];
+            let ret = self.write_flash_packs(head, address, sizes).and_then(|_| {
+                self.ensure_mcu_halt()?;
+                self.read_memory(address, len as u32)
+            });
+            match ret {
+                Ok(readback) if readback[..len] == *head => {
+                    log::info!("Negotiated pack sizes: {:?}", sizes);
+                    self.pack_sizes = Some(sizes);
+                    return Ok(sizes);
+                }
+                Ok(_) => log::debug!("Pack sizes {:?} failed to verify", sizes),
+                Err(e) => log::debug!("Pack sizes {:?} failed: {}", sizes, e),
+            }
+            self.reattach_chip()?;
+        }
+        log::info!("Using default pack sizes: {:?}", default);
+        self.pack_sizes = Some(default);
+        Ok(default)
+    }
+
     // wlink_write
     pub fn write_flash(&mut self, data: &[u8], address: u32) -> Result<()> {
-        let chip_family = self.chip_family;
-        let write_pack_size = chip_family.write_pack_size();
-        let data_packet_size = chip_family.data_packet_size();
+        self.ensure_writable("Flash write")?;
+        self.with_recovery(|sess| sess.write_flash_once(data, address))
+    }
 
-        if chip_family.support_flash_protect() {
+    fn write_flash_once(&mut self, data: &[u8], address: u32) -> Result<()> {
+        if self.chip_family.support_flash_protect() {
             self.unprotect_flash()?;
         }
+        // sectors still protected after unprotect would be skipped by the flash OP
+        self.check_write_protect(address, data.len() as u32)?;
+        let merged = self.merge_with_flash_pages(data, address)?;
+        let (address, data) = match &merged {
+            Some((address, data)) => (*address, data.as_slice()),
+            None => (address, data),
+        };
+
+        // an external loader has a fixed buffer, its pack size is not negotiable
+        let negotiate = self.options.negotiate_pack_size
+            && self.pack_sizes.is_none()
+            && self.flash_algo()?.pack_size.is_none();
+        let sizes = if negotiate {
+            self.negotiate_pack_sizes(data, address)?
+        } else {
+            self.pack_sizes()?
+        };
+        self.write_flash_packs(data, address, sizes)
+    }
+
+    /// Widen an unaligned write to whole flash pages, None if it is aligned.
+    ///
+    /// Pages are erased as a whole. The current contents before an unaligned start are
+    /// read and merged, so a single struct can be patched in place. The tail is padded
+    /// with the erased value, or merged too with `patch_flash`.
+    pub(crate) fn merge_with_flash_pages(
+        &mut self,
+        data: &[u8],
+        address: u32,
+    ) -> Result<Option<(u32, Vec<u8>)>> {
+        let page_size = self.chip_family.fast_page_size();
+        let (end, aligned_end) = u32::try_from(data.len())
+            .ok()
+            .and_then(|len| address.checked_add(len))
+            .and_then(|end| Some((end, end.checked_next_multiple_of(page_size)?)))
+            .ok_or_else(|| {
+                Error::Custom(format!(
+                    "{} bytes at 0x{:08x} wrap past the end of memory",
+                    data.len(),
+                    address
+                ))
+            })?;
+        let start = address - address % page_size;
+        if start == address && aligned_end == end {
+            return Ok(None);
+        }
+        let merge_head = start < address;
+        let merge_tail = end < aligned_end && self.options.patch_flash;
+
+        let mut merged = Vec::with_capacity((aligned_end - start) as usize);
+        if merge_head {
+            log::debug!(
+                "Unaligned flash write, merging with the current flash from 0x{:08x}",
+                start
+            );
+            let first = self.read_flash_page(start, page_size)?;
+            merged.extend_from_slice(&first[..(address - start) as usize]);
+        }
+        merged.extend_from_slice(data);
+        if merge_tail {
+            let last_page = aligned_end - page_size;
+            let last = self.read_flash_page(last_page, page_size)?;
+            merged.extend_from_slice(&last[(end - last_page) as usize..page_size as usize]);
+        } else {
+            merged.resize((aligned_end - start) as usize, ERASED_BYTE);
+        }
+        Ok(Some((start, merged)))
+    }
+
+    /// Read a flash page to merge with a write, fails if the chip does not return
+    /// the real contents
+    fn read_flash_page(&mut self, address: u32, page_size: u32) -> Result<Vec<u8>> {
+        self.ensure_mcu_halt()?;
+        let page = self.read_memory(address, page_size)?;
+        if page.chunks(4).any(|word| word == UNREADABLE_FLASH_WORD) {
+            return Err(Error::Custom(format!(
+                "flash at 0x{:08x} reads as {:02x?}, the contents can't be kept, write whole {}-byte pages",
+                address, UNREADABLE_FLASH_WORD, page_size
+            )));
+        }
+        Ok(page)
+    }
+
+    /// Upload the flash OP, then fastprogram `data` in packs
+    fn write_flash_packs(&mut self, data: &[u8], address: u32, sizes: PackSizes) -> Result<()> {
+        let algo = self.flash_algo()?;
+        let PackSizes {
+            write_pack_size,
+            data_packet_size,
+        } = sizes;
 
         let data = data.to_vec();
 
@@ -254,12 +1030,21 @@ impl ProbeSession {
         })?;
 
         // if self.chip.as_ref().unwrap().chip_family == RiscvChip::CH32V103 {}
+        let start = Instant::now();
         self.probe.send_command(commands::Program::WriteFlashOP)?;
         // wlink_ramcodewrite
-        let flash_op = self.chip_family.get_flash_op();
-        self.probe.write_data(flash_op, data_packet_size)?;
+        log::debug!(
+            "Flash OP: {} ({} bytes, {})",
+            algo.name,
+            algo.blob.len(),
+            algo.version
+        );
+        self.probe.write_data(algo.blob, data_packet_size)?;
 
         log::debug!("Flash OP written");
+        if self.options.verify_flash_op {
+            self.verify_flash_op(&algo)?;
+        }
 
         let n = self
             .probe
@@ -269,27 +1054,56 @@ impl ProbeSession {
                 "Unknown07AfterFlashOPWritten failed".to_string(),
             ));
         }
+        self.probe.emit(Event::Phase {
+            name: "flash-op upload",
+            elapsed: start.elapsed(),
+            bytes: Some(algo.blob.len() as u64),
+        });
 
         // wlink_fastprogram
+        let start = Instant::now();
         let bar = ProgressBar::new(data.len() as _);
 
-        self.probe.send_command(commands::Program::WriteFlash)?;
-        for chunk in data.chunks(write_pack_size as usize) {
+        let verify = self.options.verify_during_write;
+        self.probe.send_command(if verify {
+            commands::Program::WriteFlashAndVerify
+        } else {
+            commands::Program::WriteFlash
+        })?;
+        for (i, chunk) in data.chunks(write_pack_size as usize).enumerate() {
             self.probe
                 .write_data_with_progress(chunk, data_packet_size, &|nbytes| {
                     bar.inc(nbytes as _);
                 })?;
-            let rxbuf = self.probe.read_data(4)?;
-            // 41 01 01 04
-            if rxbuf[3] != 0x04 {
-                return Err(Error::Custom(format!(
-                    // 0x05, 0x18, 0xff
-                    "Error while fastprogram: {:02x?}",
-                    rxbuf
-                )));
+            let mut retries = 0;
+            loop {
+                // 41 01 01 04
+                let rxbuf = self.probe.read_data(4)?;
+                let status = commands::FastprogramStatus::from_raw(&rxbuf)?;
+                match status {
+                    commands::FastprogramStatus::Done => break,
+                    _ if status.is_retryable() && retries < MAX_PACK_RETRIES => {
+                        log::warn!("Fastprogram pack failed: {:?}, resending", status);
+                        self.probe.emit(Event::Retry { op: "fastprogram" });
+                        retries += 1;
+                        self.probe.write_data(chunk, data_packet_size)?;
+                    }
+                    _ if verify => {
+                        return Err(Error::FastprogramVerify {
+                            address: address + i as u32 * write_pack_size,
+                            status,
+                        })
+                    }
+                    _ => return Err(Error::Fastprogram(status)),
+                }
             }
         }
         bar.finish();
+        self.probe.emit(Event::Phase {
+            name: "program",
+            elapsed: start.elapsed(),
+            bytes: Some(data.len() as u64),
+        });
 
         log::debug!("Fastprogram done");
 
@@ -299,36 +1113,389 @@ impl ProbeSession {
         Ok(())
     }
 
+    /// Read the uploaded flash OP back from SRAM, a corrupted loader fails programming
+    /// in obscure ways later.
+    ///
+    /// Runs before the probe starts the flash OP, the GPRs used for the readback are restored.
+    fn verify_flash_op(&mut self, algo: &FlashAlgo) -> Result<()> {
+        self.ensure_mcu_halt()?;
+        let len = (algo.blob.len() as u32).next_multiple_of(4);
+        let readback =
+            self.preserving_scratch_gprs(|sess| sess.read_memory_by_dmi(algo.load_address, len))?;
+        match algo.blob.iter().zip(&readback).position(|(a, b)| a != b) {
+            Some(offset) => Err(Error::FlashOpCorrupted(offset)),
+            None => {
+                log::debug!("Flash OP verified");
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write_flash_with_backend(
+        &mut self,
+        data: &[u8],
+        address: u32,
+        backend: FlashBackend,
+    ) -> Result<()> {
+        match backend {
+            // the flash OP erases the pages as it programs them
+            FlashBackend::FlashOp => self.write_flash(data, address),
+            FlashBackend::Dmi => {
+                // the DMI backend does not unprotect, protected pages fail to erase
+                self.check_write_protect(address, data.len() as u32)?;
+                let erase = !self.is_region_blank(address, data.len() as u32)?;
+                self.timed("program", Some(data.len() as u64), |sess| {
+                    sess.write_flash_by_dmi(data, address, erase)
+                })
+            }
+        }
+    }
+
+    /// Check where a firmware would be flashed, returns the problems found.
+    ///
+    /// Catches images larger than the flash, or targeting the bootloader or
+    /// addresses outside the code flash, like firmware linked for another chip.
+    pub fn check_firmware(
+        &mut self,
+        firmware: &Firmware,
+        address: Option<u32>,
+    ) -> Result<Vec<String>> {
+        let flash_start = self.chip_family.code_flash_start();
+        let regions: Vec<(u32, u32)> = match firmware {
+            Firmware::Binary(data) => vec![(address.unwrap_or(flash_start), data.len() as u32)],
+            Firmware::Sections(sections) => {
+                let map = self.address_map();
+                sections
+                    .iter()
+                    .map(|s| (map.translate(s.address), s.data.len() as u32))
+                    .collect()
+            }
+        };
+        let flash_kb = match self.flash_size_kb() {
+            Ok(kb) => kb as u32,
+            Err(e) => {
+                log::debug!("Flash size unknown, skip image checks: {}", e);
+                return Ok(vec![]);
+            }
+        };
+        let flash = flash_start..flash_start + flash_kb * 1024;
+
+        let mut problems = vec![];
+        for (start, len) in regions {
+            let end = start.saturating_add(len);
+            if flash_start != 0 && SYSTEM_FLASH_REGION.contains(&start) {
+                problems.push(format!(
+                    "0x{:08x}..0x{:08x} overlaps the system flash with the bootloader",
+                    start, end
+                ));
+            } else if !flash.contains(&start) {
+                problems.push(format!(
+                    "0x{:08x}..0x{:08x} is outside the code flash 0x{:08x}..0x{:08x}",
+                    start, end, flash.start, flash.end
+                ));
+            } else if end > flash.end {
+                problems.push(format!(
+                    "0x{:08x}..0x{:08x} exceeds the {}KB code flash, ending at 0x{:08x}",
+                    start, end, flash_kb, flash.end
+                ));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Write a firmware image, section by section.
+    ///
+    /// `address` is only used for binary firmware, defaults to code flash start.
+    pub fn write_firmware(
+        &mut self,
+        firmware: &Firmware,
+        address: Option<u32>,
+        backend: FlashBackend,
+    ) -> Result<()> {
+        match firmware {
+            Firmware::Binary(data) => {
+                let start_address = address.unwrap_or_else(|| self.chip_family.code_flash_start());
+                log::info!("Flashing {} bytes to 0x{:08x}", data.len(), start_address);
+                self.write_flash_with_backend(data, start_address, backend)?;
+            }
+            Firmware::Sections(sections) => {
+                // Flash section by section
+                if address.is_some() {
+                    log::warn!("--address is ignored when flashing ELF or ihex");
+                }
+                let map = self.address_map();
+                for section in sections {
+                    let start_address = map.translate(section.address);
+                    if start_address != section.address {
+                        log::warn!(
+                            "Section at 0x{:08x} remapped to 0x{:08x}, use --keep-addresses to flash as-is",
+                            section.address,
+                            start_address
+                        );
+                    }
+                    log::info!(
+                        "Flashing {} bytes to 0x{:08x}",
+                        section.data.len(),
+                        start_address
+                    );
+                    self.write_flash_with_backend(&section.data, start_address, backend)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn soft_reset(&mut self) -> Result<()> {
-        self.probe.send_command(commands::Reset::Soft)?; // quit reset
+        self.ensure_writable("Reset")?;
+
+        self.timed("reset", None, |sess| {
+            sess.with_recovery(|sess| {
+                sess.probe.send_command(commands::Reset::Soft)?; // quit reset
+                Ok(())
+            })
+        })
+    }
+
+    /// Let the MCU run for a bounded time, then halt it.
+    ///
+    /// With `entry`, the MCU runs from the entry point instead of the current pc.
+    pub fn run_for(&mut self, duration: Duration, reset: bool, entry: Option<u32>) -> Result<()> {
+        if let Some(entry) = entry {
+            if reset {
+                self.reset_and_run_at(entry)?;
+            } else {
+                self.set_entry(entry)?;
+                self.ensure_mcu_resume()?;
+            }
+        } else if reset {
+            self.soft_reset()?;
+        } else {
+            self.ensure_mcu_resume()?;
+        }
+        log::info!("Running for {:?}", duration);
+        sleep(duration);
+        self.ensure_mcu_halt()?;
+        log::info!("MCU halted");
         Ok(())
     }
 
-    /// Read a continuous memory region, require MCU to be halted
+    /// Read a continuous memory region, require MCU to be halted.
+    ///
+    /// Read in chunks, a chunk is read again if the target resets in between.
+    /// Address and length need no alignment, exactly the requested bytes are returned.
     pub fn read_memory(&mut self, address: u32, length: u32) -> Result<Vec<u8>> {
-        let mut length = length;
-        if length % 4 != 0 {
-            length = (length / 4 + 1) * 4;
+        self.with_recovery(|sess| {
+            let mut mem = Vec::with_capacity(length as usize + 3);
+            sess.read_memory_chunked(address, length, |_, chunk| {
+                mem.extend_from_slice(chunk);
+                Ok(())
+            })?;
+            Ok(mem)
+        })
+    }
+
+    /// Read a continuous memory region in bounded chunks, passing each chunk and its
+    /// address to `on_chunk`, for large regions and progress
+    pub fn read_memory_chunked(
+        &mut self,
+        address: u32,
+        length: u32,
+        mut on_chunk: impl FnMut(u32, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        // the probe reads whole words, read around the region and trim the chunks
+        let (end, aligned_end) = address
+            .checked_add(length)
+            .and_then(|end| Some((end, end.checked_next_multiple_of(4)?)))
+            .ok_or_else(|| {
+                Error::Custom(format!(
+                    "{} bytes at 0x{:08x} wrap past the end of memory",
+                    length, address
+                ))
+            })?;
+        let start = address & !0b11;
+        let length = aligned_end - start;
+
+        // stale reset flag, from attaching or earlier operations
+        self.check_target_reset()?;
+
+        let mut offset = 0;
+        let mut recoveries = 0;
+        let mut rerequested = false;
+        while offset < length {
+            let chunk_len = (length - offset).min(READ_MEMORY_CHUNK_SIZE);
+            self.probe.send_command(commands::SetReadMemoryRegion {
+                start_addr: start + offset,
+                len: chunk_len,
+            })?;
+            self.probe.send_command(commands::Program::ReadMemory)?;
+            let mut chunk = self.probe.read_data_partial(chunk_len as usize)?;
+            if chunk.len() < chunk_len as usize {
+                // re-request the rest once, from the last complete word
+                if rerequested {
+                    return Err(Error::PartialData {
+                        received: chunk.len(),
+                        expected: chunk_len as usize,
+                    });
+                }
+                rerequested = true;
+                chunk.truncate(chunk.len() / 4 * 4);
+                log::warn!(
+                    "Partial read, re-requesting from 0x{:08x}",
+                    start + offset + chunk.len() as u32
+                );
+            }
+
+            if self.check_target_reset()? {
+                if recoveries >= MAX_RESET_RECOVERIES {
+                    return Err(Error::TargetReset);
+                }
+                recoveries += 1;
+                log::warn!("Resume reading from 0x{:08x}", start + offset);
+                self.ensure_mcu_halt()?;
+                continue;
+            }
+
+            // Fix endian
+            for word in chunk.chunks_exact_mut(4) {
+                word.reverse();
+            }
+            if offset == 0 && chunk.starts_with(&[0xA9, 0xBD, 0xF9, 0xF3]) {
+                log::warn!("A9 BD F9 F3 sequence detected!");
+                log::warn!("If the chip is just put into debug mode, you should flash the new firmware to the chip first");
+                log::warn!("Or else this indicates a reading to invalid location");
+            }
+            let chunk_address = start + offset;
+            let head = address.saturating_sub(chunk_address) as usize;
+            let tail = (end - chunk_address).min(chunk.len() as u32) as usize;
+            if head < tail {
+                on_chunk(chunk_address + head as u32, &chunk[head..tail])?;
+            }
+            offset += chunk.len() as u32;
         }
-        self.probe.send_command(commands::SetReadMemoryRegion {
-            start_addr: address,
-            len: length,
-        })?;
-        self.probe.send_command(commands::Program::ReadMemory)?;
+        Ok(())
+    }
 
-        let mut mem = self.probe.read_data(length as usize)?;
-        // Fix endian
-        for chunk in mem.chunks_exact_mut(4) {
-            chunk.reverse();
+    /// Check if a flash region is blank, returns the first non-blank address.
+    ///
+    /// Reading stops at the first chunk with data, so programmed flash is found fast.
+    pub fn blank_check(&mut self, address: u32, length: u32) -> Result<Option<u32>> {
+        let mut offset = 0;
+        while offset < length {
+            let len = (length - offset).min(READ_MEMORY_CHUNK_SIZE);
+            let mem = self.read_memory(address + offset, len)?;
+            if let Some(i) = mem.chunks(4).position(|word| !is_blank(word)) {
+                return Ok(Some(address + offset + i as u32 * 4));
+            }
+            offset += len;
+        }
+        Ok(None)
+    }
+
+    /// Blank check before an erase, a blank region needs none
+    pub fn is_region_blank(&mut self, address: u32, length: u32) -> Result<bool> {
+        self.ensure_mcu_halt()?;
+        let blank = self.blank_check(address, length)?.is_none();
+        if blank {
+            log::debug!(
+                "Flash 0x{:08x}..0x{:08x} is blank, skip erase",
+                address,
+                address + length
+            );
         }
+        Ok(blank)
+    }
 
-        if mem.starts_with(&[0xA9, 0xBD, 0xF9, 0xF3]) {
-            log::warn!("A9 BD F9 F3 sequence detected!");
-            log::warn!("If the chip is just put into debug mode, you should flash the new firmware to the chip first");
-            log::warn!("Or else this indicates a reading to invalid location");
+    /// Electronic signature, flash size and UID
+    pub fn esig(&mut self) -> Result<commands::ESignature> {
+        if !self.chip_family.support_query_info() {
+            return Err(Error::Custom(format!(
+                "ESIG is not available for {:?}",
+                self.chip_family
+            )));
+        }
+        if self.probe.info.supports(FirmwareFeature::EsigV2) {
+            self.probe.send_command(commands::GetChipInfo::V2)
+        } else {
+            self.probe.send_command(commands::GetChipInfo::V1)
         }
+    }
 
-        Ok(mem)
+    /// Memory size of the exact part, from the chip DB
+    pub fn chip_memory(&self) -> Option<ChipMemory> {
+        chips::chip_id_to_memory(self.chip_id)
+    }
+
+    /// Flash and SRAM sizes in KB of a ROM/RAM split mode.
+    /// Split sizes only apply to the parts with the larger shared memory.
+    fn split_sizes(&self, mode: u8) -> Option<(u32, u32)> {
+        self.chip_family
+            .rom_ram_split_sizes(mode)
+            .filter(|(flash_kb, sram_kb)| {
+                self.chip_memory()
+                    .is_some_and(|m| m.flash_kb + m.sram_kb == flash_kb + sram_kb)
+            })
+    }
+
+    /// Flash and SRAM sizes in KB of the active ROM/RAM split, None if the chip has none
+    fn active_split_sizes(&mut self) -> Option<(u32, u32)> {
+        if !self.chip_family.support_ram_rom_mode() {
+            return None;
+        }
+        match self.rom_ram_split() {
+            Ok(mode) => self.split_sizes(mode),
+            Err(e) => {
+                log::debug!("ROM/RAM split unknown: {}", e);
+                None
+            }
+        }
+    }
+
+    /// SRAM size in KB of the exact part, None if unknown.
+    ///
+    /// On chips with a configurable ROM/RAM split, the active split decides, or the
+    /// rest of the shared memory after the flash size reported by ESIG.
+    pub fn sram_size_kb(&mut self) -> Result<Option<u32>> {
+        let Some(memory) = self.chip_memory() else {
+            return Ok(None);
+        };
+        if let Some((_, sram_kb)) = self.active_split_sizes() {
+            return Ok(Some(sram_kb));
+        }
+        if self.chip_family.support_ram_rom_mode() {
+            if let Ok(esig) = self.esig() {
+                let total = memory.flash_kb + memory.sram_kb;
+                let flash_kb = esig.flash_size_kb as u32;
+                if flash_kb != memory.flash_kb && flash_kb < total {
+                    return Ok(Some(total - flash_kb));
+                }
+            }
+        }
+        Ok(Some(memory.sram_kb))
+    }
+
+    /// Code flash size in KB, as reported by ESIG.
+    ///
+    /// ESIG follows the active ROM/RAM split, the chip DB only has the default split,
+    /// so it is a fallback when ESIG is not available.
+    pub fn flash_size_kb(&mut self) -> Result<u16> {
+        let esig_err = match self.esig() {
+            Ok(esig) => return Ok(esig.flash_size_kb),
+            Err(e) => e,
+        };
+        let flash_kb = match self.active_split_sizes() {
+            Some((flash_kb, _)) => flash_kb,
+            None => match self.chip_memory() {
+                Some(memory) => memory.flash_kb,
+                None => return Err(esig_err),
+            },
+        };
+        log::debug!(
+            "ESIG not available ({}), flash size of chip 0x{:08x}: {}KB",
+            esig_err,
+            self.chip_id,
+            flash_kb
+        );
+        Ok(flash_kb as u16)
     }
 
     pub fn set_sdi_print_enabled(&mut self, enable: bool) -> Result<()> {
@@ -342,6 +1509,7 @@ impl ProbeSession {
                 "Chip doesn't support SDI print functionality".to_string(),
             ));
         }
+        self.probe.require_firmware(FirmwareFeature::SdiPrint)?;
 
         self.probe
             .send_command(commands::control::SetSdiPrintEnabled(enable))?;
@@ -391,6 +1559,30 @@ impl ProbeSession {
     }
 }
 
+/// Padding of partially written flash pages
+const ERASED_BYTE: u8 = 0xff;
+
+/// CH5xx read erased or protected flash as `a9 bd f9 f3`, not the real contents
+const UNREADABLE_FLASH_WORD: [u8; 4] = [0xa9, 0xbd, 0xf9, 0xf3];
+
+/// Erased flash reads as 0xFF, or 0xE339E339 on CH32V20x/CH32V30x
+pub fn is_blank(data: &[u8]) -> bool {
+    data.chunks(4).all(|word| {
+        word.iter().all(|&b| b == 0xff)
+            || word == [0xe3, 0x39, 0xe3, 0x39]
+            || word == [0x39, 0xe3, 0x39, 0xe3]
+    })
+}
+
+/// Drop trailing blank words of a flash image
+pub(crate) fn trim_blank_tail(image: &mut Vec<u8>) {
+    let used = image
+        .chunks(4)
+        .rposition(|word| !is_blank(word))
+        .map_or(0, |i| (i + 1) * 4);
+    image.truncate(used);
+}
+
 /*
 
     // NOTE: this halts the MCU, so it's not suitable except for dumping info
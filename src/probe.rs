@@ -1,9 +1,19 @@
 //! The probe - WCH-Link
 
 use crate::commands::{self, RawCommand, Response};
-use crate::{commands::control::ProbeInfo, usb_device::USBDeviceBackend};
-use crate::{usb_device, Error, Result, RiscvChip};
-use std::fmt;
+use crate::{
+    commands::control::{AttachChipResponse, ProbeInfo},
+    usb_device::USBDeviceBackend,
+};
+use crate::{
+    error::ProbeFailure,
+    observer::{Event, SessionObserver},
+    usb_device, Error, Result, RiscvChip,
+};
+use serde::Serialize;
+use std::{fmt, sync::Arc, thread, time::Duration};
+
+pub use wlink_protocol::probe::{FirmwareFeature, WchLinkVariant};
 
 pub const VENDOR_ID: u16 = 0x1a86;
 pub const PRODUCT_ID: u16 = 0x8010;
@@ -19,84 +29,170 @@ pub const PRODUCT_ID_DAP: u16 = 0x8012;
 
 pub const ENDPOINT_OUT_DAP: u8 = 0x02;
 
-/// All WCH-Link probe variants, see-also: <http://www.wch-ic.com/products/WCH-Link.html>
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
-#[repr(u8)]
-pub enum WchLinkVariant {
-    /// WCH-Link-CH549, does not support CH32V00X
-    Ch549 = 1,
-    /// WCH-LinkE-CH32V305
-    #[default]
-    ECh32v305 = 2,
-    /// WCH-LinkS-CH32V203
-    SCh32v203 = 3,
-    /// WCH-LinkW-CH32V208
-    WCh32v208 = 5,
+/// USB mode of a probe, by VID:PID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeMode {
+    /// RISC-V debug, the native WCH-Link protocol
+    Rv,
+    /// CMSIS-DAP, for ARM chips
+    Dap,
 }
 
-impl WchLinkVariant {
-    pub fn try_from_u8(value: u8) -> Result<Self> {
-        match value {
-            1 => Ok(Self::Ch549),
-            2 | 0x12 => Ok(Self::ECh32v305),
-            3 => Ok(Self::SCh32v203),
-            5 | 0x85 => Ok(Self::WCh32v208),
-            _ => Err(Error::UnknownLinkVariant(value)),
-        }
-    }
+/// A connected probe, listed by `WchLink::enumerate`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProbeListing {
+    /// Index among probes of the same mode, as used by `--device`
+    pub index: usize,
+    pub mode: ProbeMode,
+    pub bus: u8,
+    pub address: u8,
+    pub serial: Option<String>,
+    pub variant: Option<String>,
+    /// Firmware version, like "2.10"
+    pub firmware: Option<String>,
+}
 
-    /// CH549 variant does not support mode switch. re-program is needed.
-    pub fn support_switch_mode(&self) -> bool {
-        !matches!(self, WchLinkVariant::Ch549)
+impl fmt::Display for ProbeListing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = "unknown";
+        write!(
+            f,
+            "#{} {} mode, Bus {:03} Device {:03}, serial {}, {}, firmware {}",
+            self.index,
+            match self.mode {
+                ProbeMode::Rv => "RV",
+                ProbeMode::Dap => "DAP",
+            },
+            self.bus,
+            self.address,
+            self.serial.as_deref().unwrap_or(unknown),
+            self.variant.as_deref().unwrap_or(unknown),
+            self.firmware
+                .as_deref()
+                .map_or(unknown.to_string(), |v| format!("v{}", v)),
+        )
     }
+}
 
-    /// Only W, E mode support this, power functions
-    pub fn support_power_funcs(&self) -> bool {
-        matches!(self, WchLinkVariant::WCh32v208 | WchLinkVariant::ECh32v305)
-    }
+/// Default max retries of a busy DMI read
+pub const DEFAULT_DMI_RETRIES: usize = 100;
 
-    /// Only E mode support SDR print functionality
-    pub fn support_sdi_print(&self) -> bool {
-        matches!(self, WchLinkVariant::ECh32v305)
+/// USB VID:PID of a probe, for clones or re-enumerated probes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct UsbId {
+    pub vid: u16,
+    pub pid: u16,
+}
+
+impl UsbId {
+    /// WCH-Link in RV mode
+    pub const RV: UsbId = UsbId {
+        vid: VENDOR_ID,
+        pid: PRODUCT_ID,
+    };
+    /// WCH-Link in DAP mode
+    pub const DAP: UsbId = UsbId {
+        vid: VENDOR_ID_DAP,
+        pid: PRODUCT_ID_DAP,
+    };
+}
+
+impl Default for UsbId {
+    fn default() -> Self {
+        Self::RV
     }
+}
 
-    /// Better use E variant, the Old CH549-based variant does not support all chips
-    pub fn support_chip(&self, chip: RiscvChip) -> bool {
-        match self {
-            WchLinkVariant::Ch549 => !matches!(
-                chip,
-                RiscvChip::CH32V003 | RiscvChip::CH32X035 | RiscvChip::CH643
-            ),
-            WchLinkVariant::WCh32v208 => !matches!(
-                chip,
-                RiscvChip::CH56X | RiscvChip::CH57X | RiscvChip::CH582 | RiscvChip::CH59X
-            ),
-            _ => true,
-        }
+impl std::str::FromStr for UsbId {
+    type Err = String;
+
+    /// Parse `1a86:8010` style id
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (vid, pid) = s
+            .split_once(':')
+            .ok_or_else(|| format!("invalid USB id {s:?}, expected VID:PID"))?;
+        let vid = u16::from_str_radix(vid, 16).map_err(|_| format!("invalid VID {vid:?}"))?;
+        let pid = u16::from_str_radix(pid, 16).map_err(|_| format!("invalid PID {pid:?}"))?;
+        Ok(UsbId { vid, pid })
     }
 }
 
-impl fmt::Display for WchLinkVariant {
+impl fmt::Display for UsbId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            WchLinkVariant::Ch549 => write!(f, "WCH-Link-CH549"),
-            WchLinkVariant::ECh32v305 => write!(f, "WCH-LinkE-CH32V305"),
-            WchLinkVariant::SCh32v203 => write!(f, "WCH-LinkS-CH32V203"),
-            WchLinkVariant::WCh32v208 => write!(f, "WCH-LinkW-CH32V208"),
-        }
+        write!(f, "{:04x}:{:04x}", self.vid, self.pid)
     }
 }
 
+/// Max retries of zero-length or short reads from the probe
+const MAX_SHORT_READS: usize = 3;
+
+/// The probe streams a data transfer without gaps, a longer pause is a stall
+const DATA_STALL_TIMEOUT: Duration = Duration::from_millis(500);
+
 /// Abstraction of WchLink probe interface
 #[derive(Debug)]
 pub struct WchLink {
     pub(crate) device: Box<dyn USBDeviceBackend>,
     pub info: ProbeInfo,
+    usb_timeout: Duration,
+    /// Max retries of a busy DMI read
+    pub(crate) dmi_retries: usize,
+    observer: Option<Arc<dyn SessionObserver>>,
+    /// VID:PID the probe was opened with, to find its serial port
+    usb_id: UsbId,
 }
 
 impl WchLink {
+    /// Fails with the minimum firmware version if the probe firmware lacks a feature
+    pub fn require_firmware(&self, feature: FirmwareFeature) -> Result<()> {
+        if self.info.supports(feature) {
+            return Ok(());
+        }
+        Err(Error::FirmwareTooOld {
+            feature,
+            current: self.info.version(),
+            required: feature.min_version(),
+        })
+    }
+
+    /// Device-specific post init logic
+    pub fn do_post_init(&mut self, chip: RiscvChip) -> Result<()> {
+        match chip {
+            RiscvChip::CH32V103 => {
+                // 81 0d 01 03
+                // 81 0d 01 10
+                let _ = self.send_command(commands::RawCommand::<0x0d>(vec![0x03]))?;
+                // let _ = self.send_command(commands::RawCommand::<0x0d>(vec![0x10]))?;
+            }
+            RiscvChip::CH32V30X | RiscvChip::CH8571 | RiscvChip::CH32V003 => {
+                // 81 0d 01 03
+                // let _ = self.send_command(commands::RawCommand::<0x0d>(vec![0x03]))?;
+            }
+            RiscvChip::CH57X | RiscvChip::CH582 => {
+                log::warn!("The debug interface has been opened, there is a risk of code leakage.");
+                log::warn!("Please ensure that the debug interface has been closed before leaving factory!");
+            }
+            RiscvChip::CH56X => {
+                log::warn!("The debug interface has been opened, there is a risk of code leakage.");
+                log::warn!("Please ensure that the debug interface has been closed before leaving factory!");
+                // 81 0d 01 04
+                // should test return value
+                let resp = self.send_command(commands::RawCommand::<0x0d>(vec![0x04]))?;
+                log::debug!("TODO, handle CH56X resp {:?}", resp);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
     pub fn open_nth(nth: usize) -> Result<Self> {
-        let device = match crate::usb_device::open_nth(VENDOR_ID, PRODUCT_ID, nth) {
+        Self::open_nth_with_usb_id(nth, UsbId::RV)
+    }
+
+    /// Open the nth probe with the given VID:PID
+    pub fn open_nth_with_usb_id(nth: usize, usb_id: UsbId) -> Result<Self> {
+        let device = match crate::usb_device::open_nth(usb_id.vid, usb_id.pid, nth) {
             Ok(dev) => dev,
             Err(e) => {
                 // Detect if it is in DAP mode
@@ -107,11 +203,23 @@ impl WchLink {
                 }
             }
         };
+        let mut this = Self::from_device(device)?;
+        this.usb_id = usb_id;
+        Ok(this)
+    }
+
+    /// Wrap an opened USB device, e.g. `capture::ReplayDevice` for tests, and query the probe info
+    pub fn from_device(device: Box<dyn USBDeviceBackend>) -> Result<Self> {
         let mut this = WchLink {
             device,
             info: Default::default(),
+            usb_timeout: usb_device::DEFAULT_TIMEOUT,
+            dmi_retries: DEFAULT_DMI_RETRIES,
+            observer: None,
+            usb_id: UsbId::RV,
         };
-        let info = this.send_command(commands::control::GetProbeInfo)?;
+        let mut info = this.send_command(commands::control::GetProbeInfo)?;
+        info.serial = this.device.serial_number();
         this.info = info;
 
         log::info!("Connected to {}", this.info);
@@ -119,29 +227,174 @@ impl WchLink {
         Ok(this)
     }
 
+    /// Open the serial port of this probe, by its VID:PID and serial number
+    pub fn open_serial_port(&self) -> Result<Box<dyn serialport::SerialPort>> {
+        open_serial_port(self.usb_id, self.info.serial.as_deref())
+    }
+
+    pub fn usb_timeout(&self) -> Duration {
+        self.usb_timeout
+    }
+
+    /// Timeout of USB transfers, default to 5s
+    pub fn set_usb_timeout(&mut self, timeout: Duration) {
+        self.usb_timeout = timeout;
+        self.device.set_timeout(timeout);
+    }
+
+    pub fn dmi_retries(&self) -> usize {
+        self.dmi_retries
+    }
+
+    /// Max retries of a busy DMI read, default to 100
+    pub fn set_dmi_retries(&mut self, retries: usize) {
+        self.dmi_retries = retries;
+    }
+
+    /// Set an observer of session events, e.g. `observer::Stats`
+    pub fn set_observer(&mut self, observer: Arc<dyn SessionObserver>) {
+        self.observer = Some(observer);
+    }
+
+    /// Switch the 3.3V or 5V output, WCH-LinkE and WCH-LinkW only
+    pub fn set_power_output(&mut self, cmd: commands::control::SetPower) -> Result<()> {
+        if !self.info.variant.support_power_funcs() {
+            return Err(Error::Custom(
+                "Probe doesn't support power control".to_string(),
+            ));
+        }
+        self.send_command(cmd)?;
+        Ok(())
+    }
+
+    /// Send a single attach request, returns the chip if it answers.
+    ///
+    /// The chip is not halted, and is detached afterwards.
+    pub fn detect_target(&mut self, chip: Option<RiscvChip>) -> Result<Option<AttachChipResponse>> {
+        let chip = chip.unwrap_or(RiscvChip::CH32V103);
+        self.send_command(commands::SetSpeed {
+            riscvchip: chip as u8,
+            speed: Default::default(),
+        })?;
+        match self.send_command(commands::control::AttachChip) {
+            Ok(resp) => {
+                self.send_command(commands::control::OptEnd)?;
+                Ok(Some(resp))
+            }
+            Err(Error::Protocol(..) | Error::ProbeFailure(..)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(crate) fn emit(&self, event: Event<'_>) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
+    }
+
+    /// Block until the nth probe is connected, in RV or DAP mode
+    pub fn wait_for_probe(nth: usize, usb_id: UsbId, timeout: Option<Duration>) -> Result<()> {
+        const WAIT_SLICE: Duration = Duration::from_millis(500);
+
+        let start = std::time::Instant::now();
+        loop {
+            // DAP mode probes also count, so that a proper error is reported later
+            if usb_device::list_devices(VENDOR_ID_DAP, PRODUCT_ID_DAP)?.len() > nth {
+                return Ok(());
+            }
+            let slice = match timeout {
+                Some(timeout) => timeout.saturating_sub(start.elapsed()).min(WAIT_SLICE),
+                None => WAIT_SLICE,
+            };
+            match usb_device::wait_for_device(usb_id.vid, usb_id.pid, nth, Some(slice)) {
+                Ok(()) => return Ok(()),
+                Err(Error::Timeout) if timeout.is_none_or(|t| start.elapsed() < t) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     pub fn probe_info(&mut self) -> Result<ProbeInfo> {
-        let info = self.send_command(commands::control::GetProbeInfo)?;
+        let mut info = self.send_command(commands::control::GetProbeInfo)?;
+        info.serial = self.device.serial_number();
         log::info!("{}", info);
-        self.info = info;
+        self.info = info.clone();
         Ok(info)
     }
 
     pub fn list_probes() -> Result<()> {
-        let devs = usb_device::list_devices(VENDOR_ID, PRODUCT_ID)?;
-        for dev in devs {
-            println!("{} (RV mode)", dev)
-        }
-        let devs = usb_device::list_devices(VENDOR_ID_DAP, PRODUCT_ID_DAP)?;
-        for dev in devs {
-            println!("{} (DAP mode)", dev)
+        Self::list_probes_with_usb_id(UsbId::RV)
+    }
+
+    pub fn list_probes_with_usb_id(usb_id: UsbId) -> Result<()> {
+        for probe in Self::enumerate(usb_id)? {
+            println!("{}", probe);
         }
         Ok(())
     }
 
+    /// All connected probes, in RV mode by `usb_id`, then in DAP mode.
+    ///
+    /// Each probe is opened to query variant and firmware, they are None if it can't be opened.
+    pub fn enumerate(usb_id: UsbId) -> Result<Vec<ProbeListing>> {
+        let mut probes = vec![];
+        let locations = usb_device::list_device_locations(usb_id.vid, usb_id.pid)?;
+        for (index, loc) in locations.into_iter().enumerate() {
+            let info = Self::open_nth_with_usb_id(index, usb_id)
+                .map(|probe| probe.info)
+                .map_err(|e| log::debug!("Failed to open probe #{}: {}", index, e))
+                .ok();
+            probes.push(ProbeListing {
+                index,
+                mode: ProbeMode::Rv,
+                bus: loc.bus,
+                address: loc.address,
+                serial: loc.serial,
+                variant: info.as_ref().map(|info| info.variant.to_string()),
+                firmware: info.map(|info| format!("{}.{}", info.major_version, info.minor_version)),
+            });
+        }
+
+        let locations = usb_device::list_device_locations(VENDOR_ID_DAP, PRODUCT_ID_DAP)?;
+        for (index, loc) in locations.into_iter().enumerate() {
+            let info = crate::dap::DapProbe::open_nth(index)
+                .and_then(|mut dap| dap.probe_info())
+                .map_err(|e| log::debug!("Failed to query DAP info: {}", e))
+                .ok();
+            probes.push(ProbeListing {
+                index,
+                mode: ProbeMode::Dap,
+                bus: loc.bus,
+                address: loc.address,
+                serial: loc
+                    .serial
+                    .or_else(|| info.as_ref().and_then(|info| info.serial.clone())),
+                variant: info.as_ref().and_then(|info| info.product.clone()),
+                firmware: info.and_then(|info| info.firmware_version),
+            });
+        }
+        Ok(probes)
+    }
+
+    /// Number of connected probes in RV mode
+    pub fn count_probes(usb_id: UsbId) -> Result<usize> {
+        Ok(usb_device::list_devices(usb_id.vid, usb_id.pid)?.len())
+    }
+
+    /// One line summary of each connected probe in RV mode, with serial number, variant and firmware
+    pub fn probe_summaries(usb_id: UsbId) -> Result<Vec<String>> {
+        let summaries = Self::enumerate(usb_id)?
+            .into_iter()
+            .filter(|probe| probe.mode == ProbeMode::Rv)
+            .map(|probe| probe.to_string())
+            .collect();
+        Ok(summaries)
+    }
+
     /// Switch from DAP mode to RV mode
     // ref: https://github.com/cjacker/wchlinke-mode-switch/blob/main/main.c
-    pub fn switch_from_rv_to_dap(nth: usize) -> Result<()> {
-        let mut probe = Self::open_nth(nth)?;
+    pub fn switch_from_rv_to_dap(nth: usize, usb_id: UsbId) -> Result<()> {
+        let mut probe = Self::open_nth_with_usb_id(nth, usb_id)?;
 
         if probe.info.variant.support_switch_mode() {
             log::info!("Switch mode for WCH-LinkRV");
@@ -173,27 +426,76 @@ impl WchLink {
         Ok(())
     }
 
-    pub fn set_power_output_enabled(nth: usize, cmd: commands::control::SetPower) -> Result<()> {
-        let mut probe = Self::open_nth(nth)?;
+    pub fn set_power_output_enabled(
+        nth: usize,
+        usb_id: UsbId,
+        cmd: commands::control::SetPower,
+    ) -> Result<()> {
+        let mut probe = Self::open_nth_with_usb_id(nth, usb_id)?;
+        probe.set_power_output(cmd)?;
+
+        match cmd {
+            commands::control::SetPower::Enable3v3 => log::info!("Enable 3.3V Output"),
+            commands::control::SetPower::Disable3v3 => log::info!("Disable 3.3V Output"),
+            commands::control::SetPower::Enable5v => log::info!("Enable 5V Output"),
+            commands::control::SetPower::Disable5v => log::info!("Disable 5V Output"),
+        }
+
+        Ok(())
+    }
+
+    /// Drive the RST pin of the probe
+    pub fn set_rst_pin(nth: usize, usb_id: UsbId, pin: commands::control::SetRSTPin) -> Result<()> {
+        let mut probe = Self::open_nth_with_usb_id(nth, usb_id)?;
 
         if !probe.info.variant.support_power_funcs() {
             return Err(Error::Custom(
-                "Probe doesn't support power control".to_string(),
+                "Probe doesn't support RST pin control".to_string(),
             ));
         }
 
-        probe.send_command(cmd)?;
+        probe.send_command(pin)?;
+        log::info!("Set RST pin {:?}", pin);
 
-        match cmd {
-            commands::control::SetPower::Enable3v3 => log::info!("Enable 3.3V Output"),
-            commands::control::SetPower::Disable3v3 => log::info!("Disable 3.3V Output"),
-            commands::control::SetPower::Enable5v => log::info!("Enable 5V Output"),
-            commands::control::SetPower::Disable5v => log::info!("Disable 5V Output"),
+        Ok(())
+    }
+
+    /// Hold the target in reset for a while, then release the RST pin
+    pub fn assert_rst_pin(nth: usize, usb_id: UsbId, duration: Duration) -> Result<()> {
+        let mut probe = Self::open_nth_with_usb_id(nth, usb_id)?;
+
+        if !probe.info.variant.support_power_funcs() {
+            return Err(Error::Custom(
+                "Probe doesn't support RST pin control".to_string(),
+            ));
         }
 
+        log::info!("Assert RST pin for {:?}", duration);
+        probe.send_command(commands::control::SetRSTPin::Low)?;
+        thread::sleep(duration);
+        probe.send_command(commands::control::SetRSTPin::Floating)?;
+
         Ok(())
     }
 
+    /// Experimental, retrieve the probe-side diagnostic log
+    pub fn probe_log(&mut self, subcmd: u8) -> Result<Vec<u8>> {
+        if self.info.variant != WchLinkVariant::ECh32v305 {
+            return Err(Error::Custom(
+                "Only WCH-LinkE supports diagnostic log".to_string(),
+            ));
+        }
+        match self.send_command(commands::control::GetProbeLog(subcmd)) {
+            Err(Error::Protocol(_, resp) | Error::ProbeFailure(_, resp)) => {
+                Err(Error::Custom(format!(
+                    "Probe log is not supported by this firmware, reply: {:02x?}",
+                    resp
+                )))
+            }
+            ret => ret,
+        }
+    }
+
     fn write_raw_cmd(&mut self, buf: &[u8]) -> Result<()> {
         log::trace!("send {} {}", hex::encode(&buf[..3]), hex::encode(&buf[3..]));
         self.device.write_endpoint(ENDPOINT_OUT, buf)?;
@@ -202,7 +504,23 @@ impl WchLink {
 
     fn read_raw_cmd_resp(&mut self) -> Result<Vec<u8>> {
         let mut buf = [0u8; 64];
-        let bytes_read = self.device.read_endpoint(ENDPOINT_IN, &mut buf)?;
+        let mut retries = 0;
+        let bytes_read = loop {
+            let bytes_read = self.device.read_endpoint(ENDPOINT_IN, &mut buf)?;
+            // a response has at least 3 bytes of header
+            if bytes_read >= 3 {
+                break bytes_read;
+            }
+            if retries >= MAX_SHORT_READS {
+                log::warn!("short response: {}", hex::encode(&buf[..bytes_read]));
+                return Err(Error::InvalidPayloadLength);
+            }
+            log::debug!("short response of {} bytes, retrying", bytes_read);
+            self.emit(Event::Retry {
+                op: "read response",
+            });
+            retries += 1;
+        };
 
         let resp = buf[..bytes_read].to_vec();
         log::trace!(
@@ -213,36 +531,107 @@ impl WchLink {
         Ok(resp)
     }
 
+    /// Send a raw command frame, like `81 0d 01 02`, returns the raw response frame
+    pub fn send_raw_command(&mut self, frame: &[u8]) -> Result<Vec<u8>> {
+        if frame.len() < 3 || frame.len() > 64 {
+            return Err(Error::InvalidPayloadLength);
+        }
+        self.write_raw_cmd(frame)?;
+        self.read_raw_cmd_resp()
+    }
+
     pub fn send_command<C: crate::commands::Command>(&mut self, cmd: C) -> Result<C::Response> {
         log::trace!("send command: {:?}", cmd);
         let raw = cmd.to_raw();
-        self.write_raw_cmd(&raw)?;
-        let resp = self.read_raw_cmd_resp()?;
-
-        C::Response::from_raw(&resp)
+        let ret = self.write_raw_cmd(&raw).and_then(|_| {
+            self.emit(Event::CommandSent {
+                id: C::COMMAND_ID,
+                payload_len: raw.len() - 3,
+            });
+            let resp = self.read_raw_cmd_resp()?;
+            C::Response::from_raw(&resp).map_err(|e| match e {
+                wlink_protocol::Error::Protocol(reason, resp) => {
+                    match ProbeFailure::decode(&raw, reason) {
+                        Some(failure) => Error::ProbeFailure(failure, resp),
+                        None => Error::Protocol(reason, resp),
+                    }
+                }
+                e => e.into(),
+            })
+        });
+        if let Err(error) = &ret {
+            self.emit(Event::Error {
+                op: "command",
+                error,
+            });
+        }
+        ret
     }
 
     pub(crate) fn read_data(&mut self, n: usize) -> Result<Vec<u8>> {
+        let buf = self.read_data_partial(n)?;
+        if buf.len() != n {
+            return Err(Error::PartialData {
+                received: buf.len(),
+                expected: n,
+            });
+        }
+        Ok(buf)
+    }
+
+    /// Read up to `n` bytes from the data endpoint, returns early if the probe stalls.
+    ///
+    /// Once data starts to arrive, a gap longer than `DATA_STALL_TIMEOUT` is a stall,
+    /// instead of waiting for the full USB timeout.
+    pub(crate) fn read_data_partial(&mut self, n: usize) -> Result<Vec<u8>> {
         let mut buf = Vec::with_capacity(n);
-        let mut bytes_read = 0;
-        while bytes_read < n {
+        let mut retries = 0;
+        let mut stalled = false;
+        while buf.len() < n {
             let mut chunk = vec![0u8; 64];
-            let chunk_read = self.device.read_endpoint(DATA_ENDPOINT_IN, &mut chunk)?;
+            let chunk_read = match self.device.read_endpoint(DATA_ENDPOINT_IN, &mut chunk) {
+                Ok(len) => len,
+                Err(Error::Rusb(rusb::Error::Timeout)) => {
+                    stalled = true;
+                    break;
+                }
+                Err(e) => {
+                    self.device.set_timeout(self.usb_timeout);
+                    return Err(e);
+                }
+            };
+            if chunk_read == 0 {
+                if retries >= MAX_SHORT_READS {
+                    stalled = true;
+                    break;
+                }
+                self.emit(Event::Retry { op: "read data" });
+                retries += 1;
+                continue;
+            }
+            if buf.is_empty() {
+                self.device
+                    .set_timeout(self.usb_timeout.min(DATA_STALL_TIMEOUT));
+            }
             buf.extend_from_slice(&chunk[..chunk_read]);
-            bytes_read += chunk_read;
-        }
-        if bytes_read != n {
-            return Err(crate::Error::InvalidPayloadLength);
         }
-        log::trace!("read data ep {} bytes", bytes_read);
-        if bytes_read <= 10 {
-            log::trace!("recv data {}", hex::encode(&buf[..bytes_read]));
+        self.device.set_timeout(self.usb_timeout);
+        buf.truncate(n);
+
+        self.emit(Event::DataRead { bytes: buf.len() });
+        log::trace!("read data ep {} bytes", buf.len());
+        if buf.len() <= 10 {
+            log::trace!("recv data {}", hex::encode(&buf));
         }
-        if bytes_read != n {
-            log::warn!("read data ep {} bytes", bytes_read);
-            return Err(Error::InvalidPayloadLength);
+        if stalled {
+            log::warn!(
+                "Probe stalled at offset 0x{:x}, {} of {} bytes arrived",
+                buf.len(),
+                buf.len(),
+                n
+            );
         }
-        Ok(buf[..n].to_vec())
+        Ok(buf)
     }
 
     pub(crate) fn write_data(&mut self, buf: &[u8], packet_len: usize) -> Result<()> {
@@ -264,20 +653,36 @@ impl WchLink {
             log::trace!("write data ep {} bytes", chunk.len());
             self.device.write_endpoint(DATA_ENDPOINT_OUT, &chunk)?;
         }
+        self.emit(Event::DataWritten { bytes: buf.len() });
         log::trace!("write data ep total {} bytes", buf.len());
         Ok(())
     }
 }
 
-/// Helper for SDI print
-pub fn watch_serial() -> Result<()> {
+fn serial_permission_hint() -> &'static str {
+    if cfg!(target_os = "linux") {
+        "add your user to the dialout(or uucp) group: `sudo usermod -aG dialout $USER`, then re-login"
+    } else if cfg!(target_os = "windows") {
+        "the port might be opened by another program"
+    } else {
+        "check the permission of the device node"
+    }
+}
+
+/// Open the serial port of a WCH-Link with the VID:PID, the first one or the one with the USB serial number
+pub fn open_serial_port(
+    usb_id: UsbId,
+    serial_number: Option<&str>,
+) -> Result<Box<dyn serialport::SerialPort>> {
     use serialport::SerialPortType;
 
     let port_info = serialport::available_ports()?
         .into_iter()
         .find(|port| {
             if let SerialPortType::UsbPort(info) = &port.port_type {
-                info.vid == VENDOR_ID && info.pid == PRODUCT_ID
+                info.vid == usb_id.vid
+                    && info.pid == usb_id.pid
+                    && serial_number.is_none_or(|sn| info.serial_number.as_deref() == Some(sn))
             } else {
                 false
             }
@@ -285,37 +690,146 @@ pub fn watch_serial() -> Result<()> {
         .ok_or_else(|| Error::Custom("No serial port found".to_string()))?;
     log::debug!("Opening serial port: {:?}", port_info.port_name);
 
-    let mut port = serialport::new(&port_info.port_name, 115200)
+    let port = serialport::new(&port_info.port_name, 115200)
         .timeout(std::time::Duration::from_millis(1000))
-        .open()?;
+        .open()
+        .map_err(|e| match e.kind() {
+            serialport::ErrorKind::Io(std::io::ErrorKind::PermissionDenied) => {
+                Error::SerialPermissionDenied(port_info.port_name.clone(), serial_permission_hint())
+            }
+            _ => e.into(),
+        })?;
+    Ok(port)
+}
 
-    log::trace!("Serial port opened: {:?}", port);
+/// Stop conditions of serial watching, for hardware-in-the-loop CI
+#[derive(Debug, Clone, Default)]
+pub struct OutputWatch {
+    /// Succeed once a line contains this
+    pub expect: Option<String>,
+    /// Fail once a line contains any of these
+    pub fail_on: Vec<String>,
+    /// Stop watching after this, a failure if `expect` is set
+    pub timeout: Option<Duration>,
+}
+
+impl OutputWatch {
+    /// Check an output line, returns the outcome if watching should stop
+    pub fn check_line(&self, line: &str) -> Option<Result<()>> {
+        if let Some(pattern) = self.fail_on.iter().find(|p| line.contains(p.as_str())) {
+            return Some(Err(Error::OutputMatched(pattern.clone())));
+        }
+        if self
+            .expect
+            .as_ref()
+            .is_some_and(|p| line.contains(p.as_str()))
+        {
+            return Some(Ok(()));
+        }
+        None
+    }
 
+    /// Outcome when the timeout is up
+    pub fn timed_out(&self) -> Result<()> {
+        match &self.expect {
+            Some(pattern) => Err(Error::OutputTimeout(pattern.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Helper for SDI print, returns only if `watch` stops it
+pub fn watch_serial(port: Box<dyn serialport::SerialPort>, watch: &OutputWatch) -> Result<()> {
     let mut endl = true;
+    let mut line = String::new();
+    watch_serial_with(port, watch, |data| {
+        let s = String::from_utf8_lossy(data);
+        for c in s.chars() {
+            if c == '\r' || c == '\n' {
+                if let Some(ret) = watch.check_line(&line) {
+                    if !endl {
+                        println!();
+                    }
+                    return Some(ret);
+                }
+                line.clear();
+                if endl {
+                    // continous line break
+                    println!("{}:", chrono::Local::now());
+                } else {
+                    endl = true;
+                    println!()
+                }
+            } else if endl {
+                print!(
+                    "{}: {}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                    c
+                );
+                line.push(c);
+                endl = false;
+            } else {
+                print!("{}", c);
+                line.push(c);
+            }
+        }
+        None
+    })
+}
+
+/// SDI print of firmware using defmt, decoded with the table of its ELF file
+pub fn watch_serial_defmt(
+    port: Box<dyn serialport::SerialPort>,
+    table: &crate::defmt::Table,
+    watch: &OutputWatch,
+) -> Result<()> {
+    let mut decoder = crate::defmt::Decoder::new(table);
+    watch_serial_with(port, watch, |data| {
+        for msg in decoder.feed(data) {
+            let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+            match msg {
+                Ok(msg) => {
+                    let level = msg.level.map(|l| l.as_str().to_uppercase());
+                    let prefix = [msg.timestamp, level]
+                        .into_iter()
+                        .flatten()
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    if prefix.is_empty() {
+                        println!("{}: {}", now, msg.text);
+                    } else {
+                        println!("{}: {} {}", now, prefix, msg.text);
+                    }
+                    if let Some(ret) = watch.check_line(&msg.text) {
+                        return Some(ret);
+                    }
+                }
+                Err(e) => log::warn!("defmt decoding failed: {}", e),
+            }
+        }
+        None
+    })
+}
+
+/// Read the serial port of the probe, passing received bytes to `f`,
+/// until `f` returns an outcome or the timeout of `watch` is up
+fn watch_serial_with(
+    mut port: Box<dyn serialport::SerialPort>,
+    watch: &OutputWatch,
+    mut f: impl FnMut(&[u8]) -> Option<Result<()>>,
+) -> Result<()> {
+    log::trace!("Serial port opened: {:?}", port);
+
+    let deadline = watch.timeout.map(|t| std::time::Instant::now() + t);
     loop {
+        if deadline.is_some_and(|t| std::time::Instant::now() >= t) {
+            return watch.timed_out();
+        }
         let mut buf = [0u8; 1024];
         match port.read(&mut buf) {
             Ok(n) => {
-                let s = String::from_utf8_lossy(&buf[..n]);
-                for c in s.chars() {
-                    if c == '\r' || c == '\n' {
-                        if endl {
-                            // continous line break
-                            println!("{}:", chrono::Local::now());
-                        } else {
-                            endl = true;
-                            println!()
-                        }
-                    } else if endl {
-                        print!(
-                            "{}: {}",
-                            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                            c
-                        );
-                        endl = false;
-                    } else {
-                        print!("{}", c);
-                    }
+                if let Some(ret) = f(&buf[..n]) {
+                    return ret;
                 }
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => (),
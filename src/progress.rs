@@ -0,0 +1,25 @@
+//! Progress bars of long operations, shown by the command line tool only.
+#[cfg(feature = "cli")]
+pub(crate) use indicatif::ProgressBar;
+
+/// Position tracking only, without the `cli` feature
+#[cfg(not(feature = "cli"))]
+#[derive(Debug)]
+pub(crate) struct ProgressBar {
+    position: std::cell::Cell<u64>,
+}
+
+#[cfg(not(feature = "cli"))]
+impl ProgressBar {
+    pub fn new(_len: u64) -> Self {
+        Self {
+            position: std::cell::Cell::new(0),
+        }
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.position.set(self.position.get() + delta);
+    }
+
+    pub fn finish(&self) {}
+}
@@ -0,0 +1,174 @@
+//! Chip state snapshots, to reproduce a device state on another bench.
+//!
+//! A snapshot is a tar archive of:
+//!
+//! - `meta.json`, chip metadata and protection state
+//! - `flash.bin`, code flash without the blank tail, absent if read protected
+//! - `option-bytes.bin`, for chips with DMI flash access
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::operations::{trim_blank_tail, ProbeSession};
+
+const META: &str = "meta.json";
+const FLASH: &str = "flash.bin";
+const OPTION_BYTES: &str = "option-bytes.bin";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Meta {
+    /// wlink version of the snapshot
+    pub wlink: String,
+    pub chip: String,
+    pub chip_id: u32,
+    /// None if the chip has no ESIG
+    pub uid: Option<[u32; 2]>,
+    pub flash_address: u32,
+    pub flash_size: u32,
+    pub read_protected: bool,
+}
+
+/// Save flash, option bytes and protection state of the attached chip
+pub fn save<P: AsRef<Path>>(sess: &mut ProbeSession, path: P) -> Result<Meta> {
+    let flash_address = sess.chip_family.code_flash_start();
+    let flash_size = sess.flash_size_kb()? as u32 * 1024;
+    let meta = Meta {
+        wlink: env!("CARGO_PKG_VERSION").to_string(),
+        chip: format!("{:?}", sess.chip_family),
+        chip_id: sess.chip_id,
+        uid: sess.esig().ok().map(|esig| esig.uid),
+        flash_address,
+        flash_size,
+        read_protected: sess.is_read_protected()?,
+    };
+
+    sess.ensure_mcu_halt()?;
+    let flash = if meta.read_protected {
+        log::warn!("Flash is read protected, only the protection state is saved");
+        None
+    } else {
+        log::info!(
+            "Read flash 0x{:08x} to 0x{:08x}",
+            flash_address,
+            flash_address + flash_size
+        );
+        let mut image = sess.read_memory(flash_address, flash_size)?;
+        trim_blank_tail(&mut image);
+        Some(image)
+    };
+    let option_bytes = if sess.chip_family.support_dmi_flash() {
+        Some(sess.read_option_bytes()?)
+    } else {
+        None
+    };
+
+    let mut tar = tar::Builder::new(File::create(path)?);
+    let mut append = |name: &str, data: &[u8]| -> Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(chrono::Utc::now().timestamp() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, name, data)?;
+        Ok(())
+    };
+    append(META, &serde_json::to_vec_pretty(&meta)?)?;
+    if let Some(flash) = &flash {
+        append(FLASH, flash)?;
+    }
+    if let Some(obs) = &option_bytes {
+        append(OPTION_BYTES, obs)?;
+    }
+    tar.into_inner()?;
+    Ok(meta)
+}
+
+/// Apply a snapshot to the attached chip: flash, option bytes, then read protection
+pub fn restore<P: AsRef<Path>>(sess: &mut ProbeSession, path: P) -> Result<Meta> {
+    let mut meta = None;
+    let mut flash = None;
+    let mut option_bytes = None;
+    let mut archive = tar::Archive::new(File::open(path)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut data = vec![];
+        entry.read_to_end(&mut data)?;
+        match name.as_str() {
+            META => meta = Some(serde_json::from_slice::<Meta>(&data)?),
+            FLASH => flash = Some(data),
+            OPTION_BYTES => option_bytes = Some(data),
+            _ => log::warn!("Unknown snapshot entry {}, ignored", name),
+        }
+    }
+    let meta = meta.ok_or_else(|| anyhow::format_err!("not a snapshot, {} missing", META))?;
+
+    let chip = format!("{:?}", sess.chip_family);
+    if meta.chip != chip {
+        anyhow::bail!(
+            "Chip mismatch: snapshot of {}, attached {}",
+            meta.chip,
+            chip
+        );
+    }
+    if meta.chip_id != sess.chip_id {
+        log::warn!(
+            "Chip ID mismatch: snapshot of 0x{:08x}, attached 0x{:08x}",
+            meta.chip_id,
+            sess.chip_id
+        );
+    }
+    if let (Some(uid), Ok(esig)) = (meta.uid, sess.esig()) {
+        if uid != esig.uid {
+            log::info!("Restoring a snapshot of another chip");
+        }
+    }
+
+    if sess.is_read_protected()? {
+        log::info!("Unprotect flash, this erases the flash");
+        sess.unprotect_flash()?;
+    }
+    log::info!("Erase flash");
+    sess.erase_flash()?;
+    match &flash {
+        Some(image) if !image.is_empty() => {
+            log::info!(
+                "Flashing {} bytes to 0x{:08x}",
+                image.len(),
+                meta.flash_address
+            );
+            sess.write_flash(image, meta.flash_address)?;
+
+            sess.ensure_mcu_halt()?;
+            let readback = sess.timed("verify", Some(image.len() as u64), |sess| {
+                sess.read_memory(meta.flash_address, image.len() as u32)
+            })?;
+            if let Some(pos) = image.iter().zip(&readback).position(|(a, b)| a != b) {
+                anyhow::bail!("Verify failed at 0x{:08x}", meta.flash_address + pos as u32);
+            }
+            log::info!("Verify OK");
+        }
+        Some(_) => log::info!("Snapshot flash is blank"),
+        None => log::warn!("Snapshot has no flash contents, the flash is left blank"),
+    }
+
+    if let Some(obs) = option_bytes {
+        if sess.chip_family.support_dmi_flash() {
+            let obs = obs
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow::format_err!("invalid option bytes of {} bytes", obs.len()))?;
+            log::info!("Write option bytes");
+            sess.write_option_bytes(&obs)?;
+            log::info!("Option bytes take effect after a power cycle");
+        } else {
+            log::warn!("Option bytes write is not supported for {}, skipped", chip);
+        }
+    }
+    if meta.read_protected {
+        log::info!("Protect flash");
+        sess.protect_flash()?;
+    }
+    Ok(meta)
+}
@@ -0,0 +1,466 @@
+//! Peripheral registers from CMSIS-SVD files, for `wlink peri`.
+//!
+//! Only what register access needs is loaded: peripherals, with `derivedFrom`,
+//! their registers and fields. Clusters and `dim` arrays are not supported.
+//!
+//! With the `bundled-svd` feature, definitions of the FLASH, PFIC and PWR registers
+//! are embedded for the chips with the FPEC flash controller, and RCC and GPIO for
+//! the CH32V003, CH32V103, CH32V20x, CH32V30x and CH32L103 families.
+use std::path::Path;
+
+use anyhow::Result;
+use roxmltree::Node;
+
+use crate::RiscvChip;
+
+#[cfg(feature = "bundled-svd")]
+const FPEC_SVD: &str = include_str!("../svd/wch-fpec.svd");
+#[cfg(feature = "bundled-svd")]
+const RCC_GPIO_SVD: &str = include_str!("../svd/wch-rcc-gpio.svd");
+
+/// GPIO ports of the families with bundled RCC and GPIO definitions
+#[cfg(feature = "bundled-svd")]
+fn gpio_ports(chip: RiscvChip) -> &'static [&'static str] {
+    match chip {
+        RiscvChip::CH32V003 => &["GPIOA", "GPIOC", "GPIOD"],
+        RiscvChip::CH32V103 | RiscvChip::CH32V20X | RiscvChip::CH32L103 => {
+            &["GPIOA", "GPIOB", "GPIOC", "GPIOD"]
+        }
+        RiscvChip::CH32V30X | RiscvChip::CH32V317 => &["GPIOA", "GPIOB", "GPIOC", "GPIOD", "GPIOE"],
+        _ => &[],
+    }
+}
+
+/// Register definitions bundled for the chip family, if any
+#[cfg(feature = "bundled-svd")]
+pub fn bundled(chip: RiscvChip) -> Result<Option<Device>> {
+    if !chip.support_dmi_flash() {
+        return Ok(None);
+    }
+    let mut device = Device::parse(FPEC_SVD)?;
+    device.name = format!("{:?}", chip);
+
+    let ports = gpio_ports(chip);
+    if !ports.is_empty() {
+        let rcc_gpio = Device::parse(RCC_GPIO_SVD)?;
+        for mut peripheral in rcc_gpio.peripherals {
+            if peripheral.name.starts_with("GPIO") && !ports.contains(&peripheral.name.as_str()) {
+                continue;
+            }
+            // CH32V003 ports have 8 pins
+            if chip == RiscvChip::CH32V003 {
+                peripheral.registers.retain(|r| r.name != "CFGHR");
+            }
+            device.peripherals.push(peripheral);
+        }
+    }
+    Ok(Some(device))
+}
+
+#[cfg(not(feature = "bundled-svd"))]
+pub fn bundled(_chip: RiscvChip) -> Result<Option<Device>> {
+    Ok(None)
+}
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub name: String,
+    pub peripherals: Vec<Peripheral>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Peripheral {
+    pub name: String,
+    pub base_address: u32,
+    pub registers: Vec<Register>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Register {
+    pub name: String,
+    pub description: String,
+    pub offset: u32,
+    /// Size in bits, 8, 16 or 32
+    pub size: u32,
+    pub reset_value: Option<u32>,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    pub description: String,
+    pub offset: u32,
+    pub width: u32,
+}
+
+impl Field {
+    pub fn mask(&self) -> u32 {
+        let bits = if self.width >= 32 {
+            u32::MAX
+        } else {
+            (1 << self.width) - 1
+        };
+        bits.checked_shl(self.offset).unwrap_or(0)
+    }
+
+    /// Value of the field in a register value
+    pub fn extract(&self, value: u32) -> u32 {
+        (value & self.mask()).checked_shr(self.offset).unwrap_or(0)
+    }
+
+    /// Bit range, like `[7:4]`, or `[3]` for a single bit
+    pub fn bit_range(&self) -> String {
+        if self.width == 1 {
+            format!("[{}]", self.offset)
+        } else {
+            format!("[{}:{}]", self.offset + self.width - 1, self.offset)
+        }
+    }
+}
+
+impl Register {
+    /// Apply `FIELD=value` updates to a register value
+    pub fn update(&self, mut value: u32, updates: &[(String, u32)]) -> Result<u32> {
+        for (name, field_value) in updates {
+            let field = self
+                .fields
+                .iter()
+                .find(|f| f.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| anyhow::format_err!("no field {} in {}", name, self.name))?;
+            if field.width < 32 && *field_value >> field.width != 0 {
+                anyhow::bail!(
+                    "value 0x{:x} does not fit {}.{}, {} bits",
+                    field_value,
+                    self.name,
+                    field.name,
+                    field.width
+                );
+            }
+            let bits = field_value.checked_shl(field.offset).unwrap_or(0);
+            value = (value & !field.mask()) | (bits & field.mask());
+        }
+        Ok(value)
+    }
+}
+
+impl Device {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(xml: &str) -> Result<Self> {
+        let doc = roxmltree::Document::parse(xml)?;
+        let root = doc.root_element();
+        if !root.has_tag_name("device") {
+            anyhow::bail!(
+                "not a SVD file, root element is <{}>",
+                root.tag_name().name()
+            );
+        }
+        let default_size = child_number(root, "size")?.unwrap_or(32);
+
+        let nodes: Vec<Node> = child(root, "peripherals")
+            .map(|n| {
+                n.children()
+                    .filter(|n| n.has_tag_name("peripheral"))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut peripherals: Vec<Peripheral> = vec![];
+        for node in &nodes {
+            let size = child_number(*node, "size")?.unwrap_or(default_size);
+            let mut registers = vec![];
+            if let Some(regs) = child(*node, "registers") {
+                for reg in regs.children().filter(|n| n.has_tag_name("register")) {
+                    registers.push(parse_register(reg, size)?);
+                }
+            }
+            peripherals.push(Peripheral {
+                name: child_text(*node, "name").unwrap_or_default(),
+                base_address: child_number(*node, "baseAddress")?.unwrap_or(0),
+                registers,
+            });
+        }
+        // derived peripherals take the registers of their base if they have none
+        for (i, node) in nodes.iter().enumerate() {
+            let Some(base) = node.attribute("derivedFrom") else {
+                continue;
+            };
+            if !peripherals[i].registers.is_empty() {
+                continue;
+            }
+            let registers = peripherals
+                .iter()
+                .find(|p| p.name == base)
+                .map(|p| p.registers.clone())
+                .ok_or_else(|| {
+                    anyhow::format_err!("{} derives from unknown {}", peripherals[i].name, base)
+                })?;
+            peripherals[i].registers = registers;
+        }
+
+        Ok(Self {
+            name: child_text(root, "name").unwrap_or_default(),
+            peripherals,
+        })
+    }
+
+    /// Find a peripheral, and a register with `PERIPHERAL.REGISTER`, case insensitive
+    pub fn find(&self, path: &str) -> Result<(&Peripheral, Option<&Register>)> {
+        let (peripheral, register) = match path.split_once('.') {
+            Some((p, r)) => (p, Some(r)),
+            None => (path, None),
+        };
+        let peripheral = self
+            .peripherals
+            .iter()
+            .find(|p| p.name.eq_ignore_ascii_case(peripheral))
+            .ok_or_else(|| anyhow::format_err!("no peripheral {} in {}", peripheral, self.name))?;
+        let Some(register) = register else {
+            return Ok((peripheral, None));
+        };
+        let register = peripheral
+            .registers
+            .iter()
+            .find(|r| r.name.eq_ignore_ascii_case(register))
+            .ok_or_else(|| {
+                anyhow::format_err!("no register {} in {}", register, peripheral.name)
+            })?;
+        Ok((peripheral, Some(register)))
+    }
+}
+
+fn parse_register(node: Node, default_size: u32) -> Result<Register> {
+    let name = child_text(node, "name").unwrap_or_default();
+    let size = child_number(node, "size")?.unwrap_or(default_size);
+    if !matches!(size, 8 | 16 | 32) {
+        anyhow::bail!("register {} of {} bits is not supported", name, size);
+    }
+    let mut fields = vec![];
+    if let Some(nodes) = child(node, "fields") {
+        for field in nodes.children().filter(|n| n.has_tag_name("field")) {
+            let field_name = child_text(field, "name").unwrap_or_default();
+            let (offset, width) = field_bits(field)?;
+            if width == 0 || offset.checked_add(width).is_none_or(|end| end > size) {
+                anyhow::bail!(
+                    "field {}.{} at bit {}, {} bits, does not fit the {} bit register",
+                    name,
+                    field_name,
+                    offset,
+                    width,
+                    size
+                );
+            }
+            fields.push(Field {
+                name: field_name,
+                description: description(field),
+                offset,
+                width,
+            });
+        }
+    }
+    fields.sort_by_key(|f| f.offset);
+    Ok(Register {
+        description: description(node),
+        offset: child_number(node, "addressOffset")?.unwrap_or(0),
+        size,
+        reset_value: child_number(node, "resetValue")?,
+        fields,
+        name,
+    })
+}
+
+/// Bit offset and width, from any of the 3 forms of SVD
+fn field_bits(node: Node) -> Result<(u32, u32)> {
+    if let (Some(offset), Some(width)) = (
+        child_number(node, "bitOffset")?,
+        child_number(node, "bitWidth")?,
+    ) {
+        return Ok((offset, width));
+    }
+    let name = child_text(node, "name").unwrap_or_default();
+    if let (Some(lsb), Some(msb)) = (child_number(node, "lsb")?, child_number(node, "msb")?) {
+        return lsb_msb_bits(&name, lsb, msb);
+    }
+    if let Some(range) = child_text(node, "bitRange") {
+        let bits = range
+            .trim_matches(|c| c == '[' || c == ']')
+            .split_once(':')
+            .and_then(|(msb, lsb)| Some((msb.parse::<u32>().ok()?, lsb.parse::<u32>().ok()?)));
+        if let Some((msb, lsb)) = bits {
+            return lsb_msb_bits(&name, lsb, msb);
+        }
+    }
+    anyhow::bail!("field {} has no bit range", name)
+}
+
+fn lsb_msb_bits(name: &str, lsb: u32, msb: u32) -> Result<(u32, u32)> {
+    match msb.checked_sub(lsb) {
+        Some(width) => Ok((lsb, width + 1)),
+        None => anyhow::bail!("field {} has msb {} below lsb {}", name, msb, lsb),
+    }
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn child_text(node: Node, name: &str) -> Option<String> {
+    child(node, name)
+        .and_then(|n| n.text())
+        .map(|s| s.trim().to_string())
+}
+
+/// Single line description, SVD descriptions are often wrapped
+fn description(node: Node) -> String {
+    child_text(node, "description")
+        .map(|s| s.split_whitespace().collect::<Vec<_>>().join(" "))
+        .unwrap_or_default()
+}
+
+fn child_number(node: Node, name: &str) -> Result<Option<u32>> {
+    child_text(node, name)
+        .map(|s| parse_svd_number(&s))
+        .transpose()
+}
+
+/// SVD integers: decimal, `0x` hex, or `#` binary
+fn parse_svd_number(s: &str) -> Result<u32> {
+    let n = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(bin) = s.strip_prefix('#') {
+        u32::from_str_radix(bin, 2)
+    } else {
+        s.parse()
+    };
+    n.map_err(|_| anyhow::format_err!("invalid number {:?} in SVD", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(fields: &str) -> Result<Device> {
+        Device::parse(&format!(
+            "<device><name>TEST</name><peripherals><peripheral>
+                <name>GPIOA</name><baseAddress>0x40010800</baseAddress>
+                <registers><register>
+                    <name>CFGLR</name><addressOffset>0x0</addressOffset><size>32</size>
+                    <fields>{}</fields>
+                </register></registers>
+            </peripheral></peripherals></device>",
+            fields
+        ))
+    }
+
+    fn register(fields: &str) -> Register {
+        let device = device(fields).unwrap();
+        device.find("gpioa.cfglr").unwrap().1.unwrap().clone()
+    }
+
+    #[cfg(feature = "bundled-svd")]
+    #[test]
+    fn bundled_svd_parses() {
+        let device = bundled(RiscvChip::CH32V30X).unwrap().unwrap();
+        assert!(device.find("FLASH.STATR").unwrap().1.is_some());
+        assert!(device.find("RCC.APB2PCENR").unwrap().1.is_some());
+        let (gpioe, cfghr) = device.find("GPIOE.CFGHR").unwrap();
+        assert_eq!(gpioe.base_address, 0x4001_1800);
+        assert_eq!(cfghr.unwrap().fields.len(), 16);
+
+        let device = bundled(RiscvChip::CH32V003).unwrap().unwrap();
+        assert!(device.find("GPIOD.CFGLR").unwrap().1.is_some());
+        assert!(device.find("GPIOB").is_err());
+        assert!(device.find("GPIOD.CFGHR").is_err());
+
+        // FPEC only
+        let device = bundled(RiscvChip::CH32X035).unwrap().unwrap();
+        assert!(device.find("PWR").is_ok());
+        assert!(device.find("RCC").is_err());
+        assert!(bundled(RiscvChip::CH582).unwrap().is_none());
+    }
+
+    #[test]
+    fn bit_range_forms() {
+        let reg = register(
+            "<field><name>A</name><bitOffset>4</bitOffset><bitWidth>2</bitWidth></field>
+             <field><name>B</name><lsb>8</lsb><msb>11</msb></field>
+             <field><name>C</name><bitRange>[31:28]</bitRange></field>
+             <field><name>D</name><bitRange>[0:0]</bitRange></field>",
+        );
+        let bits: Vec<_> = reg
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.offset, f.width))
+            .collect();
+        assert_eq!(bits, [("D", 0, 1), ("A", 4, 2), ("B", 8, 4), ("C", 28, 4)]);
+        assert_eq!(reg.fields[0].bit_range(), "[0]");
+        assert_eq!(reg.fields[3].bit_range(), "[31:28]");
+    }
+
+    #[test]
+    fn invalid_bit_ranges() {
+        // msb below lsb
+        assert!(device("<field><name>A</name><lsb>4</lsb><msb>3</msb></field>").is_err());
+        assert!(device("<field><name>A</name><bitRange>[3:4]</bitRange></field>").is_err());
+        // past the register size
+        assert!(device(
+            "<field><name>A</name><bitOffset>32</bitOffset><bitWidth>1</bitWidth></field>"
+        )
+        .is_err());
+        assert!(device("<field><name>A</name><bitRange>[32:31]</bitRange></field>").is_err());
+        assert!(device(
+            "<field><name>A</name><bitOffset>4294967295</bitOffset><bitWidth>2</bitWidth></field>"
+        )
+        .is_err());
+        assert!(device(
+            "<field><name>A</name><bitOffset>0</bitOffset><bitWidth>0</bitWidth></field>"
+        )
+        .is_err());
+        assert!(device("<field><name>A</name></field>").is_err());
+    }
+
+    #[test]
+    fn field_extract() {
+        let reg = register(
+            "<field><name>MODE</name><bitRange>[5:4]</bitRange></field>
+             <field><name>ALL</name><bitOffset>0</bitOffset><bitWidth>32</bitWidth></field>
+             <field><name>TOP</name><bitOffset>31</bitOffset><bitWidth>1</bitWidth></field>",
+        );
+        let field = |name: &str| reg.fields.iter().find(|f| f.name == name).unwrap();
+        assert_eq!(field("MODE").mask(), 0x30);
+        assert_eq!(field("MODE").extract(0xffff_ffef), 0b10);
+        assert_eq!(field("ALL").mask(), u32::MAX);
+        assert_eq!(field("ALL").extract(0x1234_5678), 0x1234_5678);
+        assert_eq!(field("TOP").extract(0x8000_0000), 1);
+
+        // out of range offsets of hand built fields don't overflow
+        let field = Field {
+            name: "X".to_string(),
+            description: String::new(),
+            offset: 32,
+            width: 4,
+        };
+        assert_eq!(field.mask(), 0);
+        assert_eq!(field.extract(u32::MAX), 0);
+    }
+
+    #[test]
+    fn register_update() {
+        let reg = register(
+            "<field><name>MODE0</name><bitRange>[1:0]</bitRange></field>
+             <field><name>CNF0</name><bitRange>[3:2]</bitRange></field>
+             <field><name>MODE7</name><bitRange>[29:28]</bitRange></field>",
+        );
+        let updates = [("mode0".to_string(), 0b11), ("CNF0".to_string(), 0b00)];
+        assert_eq!(reg.update(0x4444_4444, &updates).unwrap(), 0x4444_4443);
+        assert_eq!(
+            reg.update(0, &[("MODE7".to_string(), 0b10)]).unwrap(),
+            0x2000_0000
+        );
+        // too wide for the field
+        assert!(reg.update(0, &[("MODE0".to_string(), 0b100)]).is_err());
+        assert!(reg.update(0, &[("NOPE".to_string(), 0)]).is_err());
+    }
+}
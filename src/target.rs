@@ -0,0 +1,163 @@
+//! The target chip as seen by command handlers.
+//!
+//! `ProbeSession` is the target behind a real probe. Command handlers take
+//! `&mut dyn Target`, so they also run against other implementations, like the
+//! in-memory chip of the integration tests.
+use crate::{
+    commands::ESignature,
+    firmware::Firmware,
+    operations::{ChipStatus, FlashBackend, ProbeSession},
+    AddressMap, Result, RiscvChip,
+};
+
+/// Memory, flash and run control of an attached chip
+pub trait Target {
+    fn chip_family(&self) -> RiscvChip;
+
+    /// Chip identification, memory and protection status
+    fn chip_status(&mut self) -> Result<ChipStatus>;
+
+    fn esig(&mut self) -> Result<ESignature>;
+
+    /// Code flash size in KB
+    fn flash_size_kb(&mut self) -> Result<u16>;
+
+    /// SRAM size in KB, None if unknown
+    fn sram_size_kb(&mut self) -> Result<Option<u32>>;
+
+    /// Override the firmware address translation
+    fn set_address_map(&mut self, map: AddressMap);
+
+    /// Check where a firmware would be flashed, returns the problems found
+    fn check_firmware(&mut self, firmware: &Firmware, address: Option<u32>) -> Result<Vec<String>>;
+
+    /// Write a firmware image, `address` is only used for binary firmware
+    fn write_firmware(
+        &mut self,
+        firmware: &Firmware,
+        address: Option<u32>,
+        backend: FlashBackend,
+    ) -> Result<()>;
+
+    fn write_flash(&mut self, data: &[u8], address: u32) -> Result<()>;
+
+    /// Erase the whole code flash
+    fn erase_flash(&mut self) -> Result<()>;
+
+    /// Check if a flash region is blank, returns the first non-blank address
+    fn blank_check(&mut self, address: u32, length: u32) -> Result<Option<u32>>;
+
+    fn is_region_blank(&mut self, address: u32, length: u32) -> Result<bool>;
+
+    /// Read memory, the core must be halted
+    fn read_memory(&mut self, address: u32, length: u32) -> Result<Vec<u8>>;
+
+    /// Read memory in chunks, passing each chunk and its address to `on_chunk`
+    fn read_memory_chunked(
+        &mut self,
+        address: u32,
+        length: u32,
+        on_chunk: &mut dyn FnMut(u32, &[u8]) -> Result<()>,
+    ) -> Result<()>;
+
+    fn halt(&mut self) -> Result<()>;
+
+    /// Reset the chip and run
+    fn soft_reset(&mut self) -> Result<()>;
+
+    /// Reset, then run from `entry` instead of the reset vector
+    fn reset_and_run_at(&mut self, entry: u32) -> Result<()>;
+
+    fn set_sdi_print_enabled(&mut self, enable: bool) -> Result<()>;
+
+    /// Serial port of the probe, for SDI print or UART output
+    fn open_serial_port(&self) -> Result<Box<dyn serialport::SerialPort>>;
+}
+
+impl Target for ProbeSession {
+    fn chip_family(&self) -> RiscvChip {
+        self.chip_family
+    }
+
+    fn chip_status(&mut self) -> Result<ChipStatus> {
+        ProbeSession::chip_status(self)
+    }
+
+    fn esig(&mut self) -> Result<ESignature> {
+        ProbeSession::esig(self)
+    }
+
+    fn flash_size_kb(&mut self) -> Result<u16> {
+        ProbeSession::flash_size_kb(self)
+    }
+
+    fn sram_size_kb(&mut self) -> Result<Option<u32>> {
+        ProbeSession::sram_size_kb(self)
+    }
+
+    fn set_address_map(&mut self, map: AddressMap) {
+        ProbeSession::set_address_map(self, map)
+    }
+
+    fn check_firmware(&mut self, firmware: &Firmware, address: Option<u32>) -> Result<Vec<String>> {
+        ProbeSession::check_firmware(self, firmware, address)
+    }
+
+    fn write_firmware(
+        &mut self,
+        firmware: &Firmware,
+        address: Option<u32>,
+        backend: FlashBackend,
+    ) -> Result<()> {
+        ProbeSession::write_firmware(self, firmware, address, backend)
+    }
+
+    fn write_flash(&mut self, data: &[u8], address: u32) -> Result<()> {
+        ProbeSession::write_flash(self, data, address)
+    }
+
+    fn erase_flash(&mut self) -> Result<()> {
+        ProbeSession::erase_flash(self)
+    }
+
+    fn blank_check(&mut self, address: u32, length: u32) -> Result<Option<u32>> {
+        ProbeSession::blank_check(self, address, length)
+    }
+
+    fn is_region_blank(&mut self, address: u32, length: u32) -> Result<bool> {
+        ProbeSession::is_region_blank(self, address, length)
+    }
+
+    fn read_memory(&mut self, address: u32, length: u32) -> Result<Vec<u8>> {
+        ProbeSession::read_memory(self, address, length)
+    }
+
+    fn read_memory_chunked(
+        &mut self,
+        address: u32,
+        length: u32,
+        on_chunk: &mut dyn FnMut(u32, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        ProbeSession::read_memory_chunked(self, address, length, on_chunk)
+    }
+
+    fn halt(&mut self) -> Result<()> {
+        self.ensure_mcu_halt()
+    }
+
+    fn soft_reset(&mut self) -> Result<()> {
+        ProbeSession::soft_reset(self)
+    }
+
+    fn reset_and_run_at(&mut self, entry: u32) -> Result<()> {
+        ProbeSession::reset_and_run_at(self, entry)
+    }
+
+    fn set_sdi_print_enabled(&mut self, enable: bool) -> Result<()> {
+        ProbeSession::set_sdi_print_enabled(self, enable)
+    }
+
+    fn open_serial_port(&self) -> Result<Box<dyn serialport::SerialPort>> {
+        self.probe.open_serial_port()
+    }
+}
@@ -1,14 +1,44 @@
 //! USB Device abstraction - The USB Device of WCH-Link.
 
-use crate::Result;
+use crate::{probe::UsbId, Result};
 use std::{
     fmt::{Debug, Display},
-    time::Duration,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
 };
 
+/// Default timeout of USB transfers
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// VID:PIDs of clones and re-flashed probes, matched along with the WCH-Link RV mode id
+static ALTERNATE_IDS: Mutex<Vec<UsbId>> = Mutex::new(Vec::new());
+
+/// Also match these VID:PIDs where WCH-Link in RV mode is looked up.
+///
+/// Devices of all matching ids are indexed together, in bus order.
+pub fn set_alternate_ids(ids: Vec<UsbId>) {
+    *ALTERNATE_IDS.lock().unwrap() = ids;
+}
+
+/// Whether a device is a match of a `vid:pid` lookup
+fn id_matches(vid: u16, pid: u16, desc: &rusb::DeviceDescriptor) -> bool {
+    let id = UsbId {
+        vid: desc.vendor_id(),
+        pid: desc.product_id(),
+    };
+    (id.vid == vid && id.pid == pid)
+        || (UsbId { vid, pid } == UsbId::RV && ALTERNATE_IDS.lock().unwrap().contains(&id))
+}
+
 pub trait USBDeviceBackend: Debug {
     fn set_timeout(&mut self, _timeout: Duration) {}
 
+    /// USB serial number of the device, if read on open
+    fn serial_number(&self) -> Option<String> {
+        None
+    }
+
     fn read_endpoint(&mut self, ep: u8, buf: &mut [u8]) -> Result<usize>;
 
     fn open_nth(vid: u16, pid: u16, nth: usize) -> Result<Box<dyn USBDeviceBackend>>
@@ -19,15 +49,21 @@ pub trait USBDeviceBackend: Debug {
 }
 
 pub fn open_nth(vid: u16, pid: u16, nth: usize) -> Result<Box<dyn USBDeviceBackend>> {
-    #[cfg(all(target_os = "windows", target_arch = "x86"))]
-    {
-        ch375_driver::CH375USBDevice::open_nth(vid, pid, nth)
-            .or_else(|_| libusb::LibUSBDevice::open_nth(vid, pid, nth))
-    }
-    #[cfg(not(all(target_os = "windows", target_arch = "x86")))]
-    {
-        libusb::LibUSBDevice::open_nth(vid, pid, nth)
-    }
+    crate::capture::hook_open(|| {
+        #[cfg(feature = "cli")]
+        if let Some(device) = crate::daemon::open_remote(vid, pid)? {
+            return Ok(device);
+        }
+        #[cfg(all(target_os = "windows", target_arch = "x86"))]
+        {
+            ch375_driver::CH375USBDevice::open_nth(vid, pid, nth)
+                .or_else(|_| libusb::LibUSBDevice::open_nth(vid, pid, nth))
+        }
+        #[cfg(not(all(target_os = "windows", target_arch = "x86")))]
+        {
+            libusb::LibUSBDevice::open_nth(vid, pid, nth)
+        }
+    })
 }
 
 pub fn list_devices(vid: u16, pid: u16) -> Result<Vec<String>> {
@@ -50,6 +86,67 @@ pub fn list_devices(vid: u16, pid: u16) -> Result<Vec<String>> {
     Ok(ret)
 }
 
+/// Find the device index by USB serial number, libusb only
+pub fn find_device_by_serial(vid: u16, pid: u16, serial: &str) -> Result<Option<usize>> {
+    libusb::find_libusb_device_by_serial(vid, pid, serial)
+}
+
+/// USB serial numbers of devices, by index, libusb only
+pub fn list_serial_numbers(vid: u16, pid: u16) -> Result<Vec<Option<String>>> {
+    libusb::list_libusb_serial_numbers(vid, pid)
+}
+
+/// Bus location and serial number of a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceLocation {
+    pub bus: u8,
+    pub address: u8,
+    /// None if the serial number can't be read, e.g. no permission
+    pub serial: Option<String>,
+}
+
+/// Bus locations of devices, by index, libusb only
+pub fn list_device_locations(vid: u16, pid: u16) -> Result<Vec<DeviceLocation>> {
+    libusb::list_libusb_device_locations(vid, pid)
+}
+
+/// Block until the nth device appears on the bus, returns `Error::Timeout` on timeout.
+///
+/// Uses libusb hotplug events when supported, polling otherwise.
+pub fn wait_for_device(vid: u16, pid: u16, nth: usize, timeout: Option<Duration>) -> Result<()> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    use rusb::UsbContext;
+
+    let start = Instant::now();
+    let context = rusb::Context::new()?;
+    let registration = if rusb::has_hotplug() {
+        let mut builder = rusb::HotplugBuilder::new();
+        // alternate ids can't be filtered by one registration
+        if ALTERNATE_IDS.lock().unwrap().is_empty() {
+            builder.vendor_id(vid).product_id(pid);
+        }
+        Some(builder.register(&context, Box::new(libusb::HotplugLogger))?)
+    } else {
+        log::debug!("libusb hotplug is not supported, polling");
+        None
+    };
+
+    loop {
+        if list_devices(vid, pid)?.len() > nth {
+            return Ok(());
+        }
+        if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            return Err(crate::Error::Timeout);
+        }
+        if registration.is_some() {
+            context.handle_events(Some(POLL_INTERVAL))?;
+        } else {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
 pub mod libusb {
     use std::fmt;
 
@@ -64,7 +161,7 @@ pub mod libusb {
 
         for device in devices.iter() {
             let device_desc = device.device_descriptor()?;
-            if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
+            if id_matches(vid, pid, &device_desc) {
                 result.push(format!(
                     "<WCH-Link#{} libusb device> Bus {:03} Device {:03} ID {:04x}:{:04x}({})",
                     idx,
@@ -80,9 +177,80 @@ pub mod libusb {
         Ok(result)
     }
 
+    /// Hotplug callback, only used to wake up `wait_for_device`
+    pub(crate) struct HotplugLogger;
+
+    impl rusb::Hotplug<rusb::Context> for HotplugLogger {
+        fn device_arrived(&mut self, device: rusb::Device<rusb::Context>) {
+            log::debug!("Device arrived: {:?}", device);
+        }
+
+        fn device_left(&mut self, device: rusb::Device<rusb::Context>) {
+            log::debug!("Device left: {:?}", device);
+        }
+    }
+
+    pub fn find_libusb_device_by_serial(vid: u16, pid: u16, serial: &str) -> Result<Option<usize>> {
+        let context = rusb::Context::new()?;
+        let devices = context.devices()?;
+        let mut idx = 0;
+
+        for device in devices.iter() {
+            let device_desc = device.device_descriptor()?;
+            if id_matches(vid, pid, &device_desc) {
+                let sn = device
+                    .open()
+                    .and_then(|handle| handle.read_serial_number_string_ascii(&device_desc));
+                match sn {
+                    Ok(sn) if sn == serial => return Ok(Some(idx)),
+                    Ok(_) => (),
+                    Err(e) => log::debug!("Failed to read serial number: {}", e),
+                }
+                idx += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn list_libusb_serial_numbers(vid: u16, pid: u16) -> Result<Vec<Option<String>>> {
+        Ok(list_libusb_device_locations(vid, pid)?
+            .into_iter()
+            .map(|loc| loc.serial)
+            .collect())
+    }
+
+    pub fn list_libusb_device_locations(vid: u16, pid: u16) -> Result<Vec<DeviceLocation>> {
+        let context = rusb::Context::new()?;
+        let devices = context.devices()?;
+        let mut result = vec![];
+
+        for device in devices.iter() {
+            let device_desc = device.device_descriptor()?;
+            if id_matches(vid, pid, &device_desc) {
+                let sn = device
+                    .open()
+                    .and_then(|handle| handle.read_serial_number_string_ascii(&device_desc));
+                let serial = match sn {
+                    Ok(sn) => Some(sn),
+                    Err(e) => {
+                        log::debug!("Failed to read serial number: {}", e);
+                        None
+                    }
+                };
+                result.push(DeviceLocation {
+                    bus: device.bus_number(),
+                    address: device.address(),
+                    serial,
+                });
+            }
+        }
+        Ok(result)
+    }
+
     pub struct LibUSBDevice {
         handle: DeviceHandle<rusb::Context>,
         timeout: Duration,
+        serial_number: String,
     }
 
     impl fmt::Debug for LibUSBDevice {
@@ -99,13 +267,17 @@ pub mod libusb {
             self.timeout = timeout;
         }
 
+        fn serial_number(&self) -> Option<String> {
+            Some(self.serial_number.clone())
+        }
+
         fn open_nth(vid: u16, pid: u16, nth: usize) -> Result<Box<dyn USBDeviceBackend>> {
             let context = rusb::Context::new()?;
             let devices = context.devices()?;
             let mut result = vec![];
             for device in devices.iter() {
                 let device_desc = device.device_descriptor()?;
-                if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
+                if id_matches(vid, pid, &device_desc) {
                     result.push(device);
                 }
             }
@@ -125,7 +297,8 @@ pub mod libusb {
 
             Ok(Box::new(LibUSBDevice {
                 handle,
-                timeout: Duration::from_millis(5000),
+                timeout: DEFAULT_TIMEOUT,
+                serial_number,
             }))
         }
 
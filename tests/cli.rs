@@ -0,0 +1,265 @@
+//! Command handlers against an in-memory chip
+#![cfg(feature = "cli")]
+use std::path::PathBuf;
+
+use wlink::{
+    cli::{self, FlashOptions},
+    commands::ESignature,
+    firmware::{self, DumpFormat, Firmware},
+    operations::{ChipStatus, FlashBackend},
+    target::Target,
+    AddressMap, Result, RiscvChip,
+};
+
+const FLASH_START: u32 = 0x0800_0000;
+const FLASH_KB: u16 = 16;
+
+/// A chip of plain memory, flash is blank with 0xff
+struct MemTarget {
+    flash: Vec<u8>,
+    erases: usize,
+}
+
+impl MemTarget {
+    fn blank() -> Self {
+        Self {
+            flash: vec![0xff; FLASH_KB as usize * 1024],
+            erases: 0,
+        }
+    }
+
+    fn offset(&self, address: u32, length: u32) -> Result<std::ops::Range<usize>> {
+        let start = address.wrapping_sub(FLASH_START) as usize;
+        let end = start + length as usize;
+        if end > self.flash.len() {
+            return Err(wlink::Error::Custom(format!(
+                "0x{:08x}+0x{:x} is outside the flash",
+                address, length
+            )));
+        }
+        Ok(start..end)
+    }
+}
+
+impl Target for MemTarget {
+    fn chip_family(&self) -> RiscvChip {
+        RiscvChip::CH32V30X
+    }
+
+    fn chip_status(&mut self) -> Result<ChipStatus> {
+        Ok(ChipStatus {
+            chip_family: RiscvChip::CH32V30X,
+            chip_id: 0,
+            esig: None,
+            memory: None,
+            read_protected: Some(false),
+            rom_ram_split: None,
+        })
+    }
+
+    fn esig(&mut self) -> Result<ESignature> {
+        Ok(ESignature {
+            flash_size_kb: FLASH_KB,
+            uid: [0x1234_5678, 0x9abc_def0],
+        })
+    }
+
+    fn flash_size_kb(&mut self) -> Result<u16> {
+        Ok(FLASH_KB)
+    }
+
+    fn sram_size_kb(&mut self) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    fn set_address_map(&mut self, _map: AddressMap) {}
+
+    fn check_firmware(
+        &mut self,
+        _firmware: &Firmware,
+        _address: Option<u32>,
+    ) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    fn write_firmware(
+        &mut self,
+        firmware: &Firmware,
+        address: Option<u32>,
+        _backend: FlashBackend,
+    ) -> Result<()> {
+        match firmware {
+            Firmware::Binary(data) => self.write_flash(data, address.unwrap_or(FLASH_START)),
+            Firmware::Sections(sections) => sections
+                .iter()
+                .try_for_each(|s| self.write_flash(&s.data, s.address)),
+        }
+    }
+
+    fn write_flash(&mut self, data: &[u8], address: u32) -> Result<()> {
+        let range = self.offset(address, data.len() as u32)?;
+        self.flash[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn erase_flash(&mut self) -> Result<()> {
+        self.flash.fill(0xff);
+        self.erases += 1;
+        Ok(())
+    }
+
+    fn blank_check(&mut self, address: u32, length: u32) -> Result<Option<u32>> {
+        let range = self.offset(address, length)?;
+        Ok(self.flash[range]
+            .iter()
+            .position(|&b| b != 0xff)
+            .map(|i| address + i as u32))
+    }
+
+    fn is_region_blank(&mut self, address: u32, length: u32) -> Result<bool> {
+        Ok(self.blank_check(address, length)?.is_none())
+    }
+
+    fn read_memory(&mut self, address: u32, length: u32) -> Result<Vec<u8>> {
+        let range = self.offset(address, length)?;
+        Ok(self.flash[range].to_vec())
+    }
+
+    fn read_memory_chunked(
+        &mut self,
+        address: u32,
+        length: u32,
+        on_chunk: &mut dyn FnMut(u32, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let range = self.offset(address, length)?;
+        let data = self.flash[range].to_vec();
+        for (i, chunk) in data.chunks(0x1000).enumerate() {
+            on_chunk(address + i as u32 * 0x1000, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn halt(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn soft_reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn reset_and_run_at(&mut self, _entry: u32) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_sdi_print_enabled(&mut self, _enable: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn open_serial_port(&self) -> Result<Box<dyn serialport::SerialPort>> {
+        Err(wlink::Error::Custom("no serial port".to_string()))
+    }
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("wlink-test-{}-{}", std::process::id(), name))
+}
+
+fn image(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+fn flash_image(target: &mut MemTarget, data: &[u8], erase: bool) {
+    let path = temp_path(&format!("image-{}.bin", data.len()));
+    std::fs::write(&path, data).unwrap();
+    let opts = FlashOptions {
+        address: Some(FLASH_START),
+        erase,
+        ..Default::default()
+    };
+    cli::flash(target, &[path.display().to_string()], &opts).unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn flash_verify_roundtrip() {
+    let mut target = MemTarget::blank();
+    let data = image(5000);
+    flash_image(&mut target, &data, false);
+
+    let out = temp_path("roundtrip.bin");
+    let fname = out.display().to_string();
+    cli::dump(
+        &mut target,
+        FLASH_START,
+        Some(data.len() as u32),
+        Some(&fname),
+        None,
+        16,
+        None,
+    )
+    .unwrap();
+    assert_eq!(std::fs::read(&out).unwrap(), data);
+    std::fs::remove_file(out).unwrap();
+}
+
+#[test]
+fn dump_formats_read_back() {
+    let mut target = MemTarget::blank();
+    let data = image(300);
+    flash_image(&mut target, &data, false);
+
+    for (ext, format) in [("hex", DumpFormat::Ihex), ("srec", DumpFormat::Srec)] {
+        let out = temp_path(&format!("dump.{}", ext));
+        let fname = out.display().to_string();
+        cli::dump(
+            &mut target,
+            FLASH_START + 4,
+            Some(data.len() as u32 - 4),
+            Some(&fname),
+            None,
+            16,
+            None,
+        )
+        .unwrap();
+        assert_eq!(DumpFormat::from_path(&out), format);
+
+        let Firmware::Sections(sections) = firmware::read_firmware_from_file(&out).unwrap() else {
+            panic!("{} has no addresses", fname);
+        };
+        std::fs::remove_file(out).unwrap();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].address, FLASH_START + 4);
+        assert_eq!(sections[0].data, data[4..]);
+    }
+}
+
+#[test]
+fn dump_length_required_outside_flash() {
+    let mut target = MemTarget::blank();
+    assert!(cli::dump(&mut target, 0x2000_0000, None, None, None, 16, None).is_err());
+}
+
+#[test]
+fn dump_rejects_wrapping_range() {
+    let mut target = MemTarget::blank();
+    let err = cli::dump(&mut target, 0xffff_ff00, Some(0x200), None, None, 16, None).unwrap_err();
+    assert!(err.to_string().contains("wrap"), "{}", err);
+}
+
+#[test]
+fn blank_check_rejects_wrapping_range() {
+    let mut target = MemTarget::blank();
+    assert!(cli::blank_check(&mut target, Some(0xffff_ff00), Some(0x200)).is_err());
+}
+
+#[test]
+fn erase_skipped_on_blank_flash() {
+    let mut target = MemTarget::blank();
+    flash_image(&mut target, &image(64), true);
+    assert_eq!(target.erases, 0);
+
+    flash_image(&mut target, &image(32), true);
+    assert_eq!(target.erases, 1);
+    // the tail of the first image is erased
+    assert_eq!(target.flash[32..64], [0xff; 32]);
+}
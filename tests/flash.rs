@@ -0,0 +1,85 @@
+//! Flash writes on a simulated probe and chip, no hardware needed
+mod sim;
+
+use sim::{image, session, Chip, FLASH_START};
+use wlink::{commands::FastprogramStatus, operations::FlashBackend, Error};
+
+#[test]
+fn write_protected_chip_is_unprotected_and_flashed() {
+    let mut chip = Chip::new();
+    // first two sectors protected
+    chip.wpr = 0xffff_fffc;
+    let (mut sess, chip) = session(chip);
+
+    let data = image(4096);
+    sess.write_flash_with_backend(&data, FLASH_START, FlashBackend::FlashOp)
+        .unwrap();
+
+    let chip = chip.lock().unwrap();
+    assert_eq!(chip.wpr, 0xffff_ffff);
+    assert_eq!(chip.flash[..data.len()], data);
+}
+
+#[test]
+fn dmi_backend_fails_early_on_write_protected_sectors() {
+    let mut chip = Chip::new();
+    chip.wpr = 0xffff_fffc;
+    let (mut sess, chip) = session(chip);
+
+    let data = image(3 * 4096);
+    let err = sess
+        .write_flash_with_backend(&data, FLASH_START, FlashBackend::Dmi)
+        .unwrap_err();
+    assert!(
+        matches!(&err, Error::WriteProtected(sectors) if *sectors == [FLASH_START, FLASH_START + 4096]),
+        "{}",
+        err
+    );
+    assert!(chip.lock().unwrap().flash.iter().all(|&b| b == 0xff));
+}
+
+fn write_with_pack_statuses(statuses: &[u8]) -> (wlink::Result<()>, Vec<u8>) {
+    let mut chip = Chip::new();
+    chip.pack_statuses = statuses.iter().copied().collect();
+    let (mut sess, chip) = session(chip);
+
+    let data = image(2 * 4096);
+    let ret = sess.write_flash_with_backend(&data, FLASH_START, FlashBackend::FlashOp);
+    let flash = chip.lock().unwrap().flash[..data.len()].to_vec();
+    (ret, flash)
+}
+
+#[test]
+fn busy_pack_is_resent() {
+    // 41 01 01 05 on the first pack, twice
+    let (ret, flash) = write_with_pack_statuses(&[0x05, 0x05]);
+    ret.unwrap();
+    assert_eq!(flash, image(2 * 4096));
+}
+
+#[test]
+fn busy_pack_gives_up_after_retries() {
+    let (ret, _) = write_with_pack_statuses(&[0x05; 4]);
+    assert!(matches!(
+        ret,
+        Err(Error::Fastprogram(FastprogramStatus::WriteFailed))
+    ));
+}
+
+#[test]
+fn fatal_pack_statuses_are_not_resent() {
+    for (frame, status) in [
+        (0x18, FastprogramStatus::ProgramError),
+        (0xff, FastprogramStatus::NoResponse),
+    ] {
+        // the second pack fails, the next status would be done
+        let (ret, flash) = write_with_pack_statuses(&[0x04, frame]);
+        assert!(
+            matches!(ret, Err(Error::Fastprogram(s)) if s == status),
+            "{:?}",
+            ret
+        );
+        assert_eq!(flash[..4096], image(4096)[..]);
+        assert!(flash[4096..].iter().all(|&b| b == 0xff));
+    }
+}
@@ -0,0 +1,47 @@
+//! gdb `qRcmd` packets on a simulated probe and chip
+mod sim;
+
+use sim::{session, Chip};
+use wlink::monitor::handle_qrcmd;
+
+fn qrcmd(command: &str) -> String {
+    format!("qRcmd,{}", hex::encode(command))
+}
+
+fn output(reply: &str) -> String {
+    let text = hex::decode(reply.strip_prefix('O').expect("O packet")).unwrap();
+    String::from_utf8(text).unwrap()
+}
+
+#[test]
+fn output_then_ok() {
+    let (mut sess, _) = session(Chip::new());
+
+    let replies = handle_qrcmd(&mut sess, &qrcmd("speed low")).unwrap();
+    assert_eq!(replies.len(), 2);
+    assert!(output(&replies[0]).starts_with("Speed Low"));
+    assert_eq!(replies[1], "OK");
+
+    let replies = handle_qrcmd(&mut sess, &qrcmd("help")).unwrap();
+    assert!(output(&replies[0]).contains("monitor erase"));
+    assert_eq!(replies[1], "OK");
+}
+
+#[test]
+fn failed_command_replies_error() {
+    let (mut sess, _) = session(Chip::new());
+
+    let replies = handle_qrcmd(&mut sess, &qrcmd("frobnicate")).unwrap();
+    assert!(output(&replies[0]).contains("unknown monitor command"));
+    assert_eq!(replies[1], "E02");
+}
+
+#[test]
+fn malformed_and_other_packets() {
+    let (mut sess, _) = session(Chip::new());
+
+    assert_eq!(handle_qrcmd(&mut sess, "qRcmd,zz").unwrap(), ["E01"]);
+    // "\xff", not UTF-8
+    assert_eq!(handle_qrcmd(&mut sess, "qRcmd,ff").unwrap(), ["E01"]);
+    assert_eq!(handle_qrcmd(&mut sess, "qSupported"), None);
+}
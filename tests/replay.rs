@@ -0,0 +1,52 @@
+//! Sessions on a `WchLink` replaying captured USB frames, no probe needed
+use wlink::{
+    capture::{self, ReplayDevice},
+    commands::Speed,
+    operations::{AttachOptions, ProbeSession},
+    probe::WchLink,
+    RiscvChip,
+};
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn resume_session(device: ReplayDevice) -> ProbeSession {
+    let probe = WchLink::from_device(Box::new(device)).unwrap();
+    ProbeSession::resume_with_options(
+        probe,
+        RiscvChip::CH32V30X,
+        0,
+        Speed::High,
+        AttachOptions::default(),
+    )
+    .unwrap()
+}
+
+#[test]
+fn probe_info_from_capture() {
+    let (_, transactions) = capture::load(fixture("read-memory.jsonl")).unwrap();
+    let probe = WchLink::from_device(Box::new(ReplayDevice::new(transactions))).unwrap();
+    assert_eq!(probe.info.version(), (2, 9));
+}
+
+#[test]
+fn read_memory_from_capture() {
+    let (header, transactions) = capture::load(fixture("read-memory.jsonl")).unwrap();
+    assert_eq!(header.args[1], "dump");
+    let device = ReplayDevice::new(transactions).strict();
+    let remaining = device.remaining();
+
+    let mut sess = resume_session(device);
+    // words arrive big-endian from the probe
+    let mem = sess.read_memory(0x0800_0000, 8).unwrap();
+    assert_eq!(mem, [0x00, 0x10, 0x00, 0x20, 0x01, 0x01, 0x00, 0x08]);
+    assert!(remaining.lock().unwrap().is_empty());
+}
+
+#[test]
+fn replay_rejects_diverging_commands() {
+    let (_, transactions) = capture::load(fixture("read-memory.jsonl")).unwrap();
+    let mut sess = resume_session(ReplayDevice::new(transactions).strict());
+    assert!(sess.read_memory(0x0800_0100, 8).is_err());
+}
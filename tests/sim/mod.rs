@@ -0,0 +1,253 @@
+//! A simulated WCH-LinkE and CH32V30x, no hardware needed
+#![allow(dead_code)]
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use wlink::{
+    commands::Speed,
+    operations::{AttachOptions, ProbeSession},
+    probe::WchLink,
+    usb_device::USBDeviceBackend,
+    Result, RiscvChip,
+};
+
+pub const FLASH_START: u32 = 0x0800_0000;
+pub const FLASH_LEN: usize = 64 * 1024;
+pub const FLASH_WPR: u32 = 0x4002_2020;
+
+/// What the probe does with the data endpoint
+#[derive(Debug, Default, PartialEq)]
+pub enum DataMode {
+    #[default]
+    Idle,
+    FlashOp,
+    Program,
+}
+
+/// A CH32V30x behind a WCH-LinkE, as far as flash writes need
+#[derive(Debug)]
+pub struct Chip {
+    pub flash: Vec<u8>,
+    /// FLASH_WPR, a cleared bit protects a sector
+    pub wpr: u32,
+    pub halted: bool,
+    pub data0: u32,
+    pub progbuf: [u32; 2],
+    pub gprs: [u32; 32],
+    pub csrs: BTreeMap<u16, u32>,
+    pub mode: DataMode,
+    /// Flash address of the next pack
+    pub cursor: u32,
+    /// Data of the pack in flight
+    pub pack: Vec<u8>,
+    /// Status of each pack, `41 01 01 04` once these run out
+    pub pack_statuses: VecDeque<u8>,
+    pub cmd_resp: VecDeque<Vec<u8>>,
+}
+
+impl Chip {
+    pub fn new() -> Self {
+        Self {
+            flash: vec![0xff; FLASH_LEN],
+            wpr: 0xffff_ffff,
+            halted: true,
+            data0: 0,
+            progbuf: [0; 2],
+            gprs: [0; 32],
+            csrs: BTreeMap::new(),
+            mode: DataMode::Idle,
+            cursor: 0,
+            pack: vec![],
+            pack_statuses: VecDeque::new(),
+            cmd_resp: VecDeque::new(),
+        }
+    }
+
+    fn read_mem32(&self, address: u32) -> u32 {
+        if address == FLASH_WPR {
+            return self.wpr;
+        }
+        let offset = address.wrapping_sub(FLASH_START) as usize;
+        match self.flash.get(offset..offset + 4) {
+            Some(word) => u32::from_le_bytes(word.try_into().unwrap()),
+            None => 0,
+        }
+    }
+
+    fn abstract_command(&mut self, command: u32) {
+        let regno = command as u16;
+        if command & (1 << 17) != 0 {
+            let write = command & (1 << 16) != 0;
+            match regno {
+                0x1000..=0x101f => {
+                    let gpr = &mut self.gprs[(regno - 0x1000) as usize];
+                    if write {
+                        *gpr = self.data0;
+                    } else {
+                        self.data0 = *gpr;
+                    }
+                }
+                _ if write => {
+                    self.csrs.insert(regno, self.data0);
+                }
+                _ => self.data0 = self.csrs.get(&regno).copied().unwrap_or(0),
+            }
+        }
+        if command & (1 << 18) != 0 {
+            match self.progbuf[0] {
+                // lw x6,0(x5)
+                0x0002a303 => self.gprs[6] = self.read_mem32(self.gprs[5]),
+                // sw x7,0(x5), only the WPR is writable
+                0x0072a023 if self.gprs[5] == FLASH_WPR => self.wpr = self.gprs[7],
+                _ => (),
+            }
+        }
+    }
+
+    fn dmi_op(&mut self, addr: u8, data: u32, op: u8) -> u32 {
+        match (addr, op) {
+            (0x04, 1) => self.data0,
+            (0x04, 2) => {
+                self.data0 = data;
+                0
+            }
+            (0x10, 2) => {
+                if data & (1 << 31) != 0 {
+                    self.halted = true;
+                }
+                if data & (1 << 30) != 0 {
+                    self.halted = false;
+                }
+                0
+            }
+            // version 2, authenticated, halted or running
+            (0x11, 1) => 0x82 | if self.halted { 0x300 } else { 0xc00 },
+            (0x17, 2) => {
+                self.abstract_command(data);
+                0
+            }
+            (0x20, 2) => {
+                self.progbuf[0] = data;
+                0
+            }
+            (0x21, 2) => {
+                self.progbuf[1] = data;
+                0
+            }
+            _ => 0,
+        }
+    }
+
+    fn command(&mut self, frame: &[u8]) -> Vec<u8> {
+        let (cmd, payload) = (frame[1], &frame[3..]);
+        match (cmd, payload.first().copied()) {
+            // probe info, v2.9 of a WCH-LinkE
+            (0x0d, Some(0x01)) => vec![0x02, 0x09, 0x12, 0x00],
+            // attach, CH32V307
+            (0x0d, Some(0x02)) => vec![0x06, 0x30, 0x70, 0x05, 0x18],
+            (0x0d, _) => vec![],
+            (0x01, _) => {
+                self.cursor = u32::from_be_bytes(payload[..4].try_into().unwrap());
+                vec![]
+            }
+            (0x02, Some(0x05)) => {
+                self.mode = DataMode::FlashOp;
+                vec![0x00]
+            }
+            (0x02, Some(0x07)) => vec![0x07],
+            (0x02, Some(0x02 | 0x04)) => {
+                self.mode = DataMode::Program;
+                vec![0x00]
+            }
+            (0x02, _) => {
+                self.mode = DataMode::Idle;
+                vec![0x00]
+            }
+            // read protect, not protected
+            (0x06, Some(0x01)) => vec![0x02],
+            // UnprotectEx, clears WRP
+            (0x06, Some(0x02)) if payload.len() > 1 => {
+                self.wpr = 0xffff_ffff;
+                vec![0x00]
+            }
+            // write protect
+            (0x06, Some(0x04)) => vec![if self.wpr == 0xffff_ffff { 0x00 } else { 0x11 }],
+            (0x06, _) => vec![0x00],
+            (0x08, _) => {
+                let data = u32::from_be_bytes(payload[1..5].try_into().unwrap());
+                let value = self.dmi_op(payload[0], data, payload[5]);
+                let mut resp = vec![payload[0]];
+                resp.extend_from_slice(&value.to_be_bytes());
+                resp.push(0x00);
+                resp
+            }
+            (0x0c, _) => vec![0x01],
+            _ => vec![0x00],
+        }
+    }
+
+    /// Status of the pack in flight, written to flash if the status is done
+    fn pack_status(&mut self) -> Vec<u8> {
+        let status = self.pack_statuses.pop_front().unwrap_or(0x04);
+        let pack = std::mem::take(&mut self.pack);
+        if status == 0x04 {
+            let offset = self.cursor.wrapping_sub(FLASH_START) as usize;
+            let len = pack.len().min(FLASH_LEN - offset);
+            self.flash[offset..offset + len].copy_from_slice(&pack[..len]);
+            self.cursor += len as u32;
+        }
+        vec![0x41, 0x01, 0x01, status]
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimDevice(pub Arc<Mutex<Chip>>);
+
+impl USBDeviceBackend for SimDevice {
+    fn read_endpoint(&mut self, ep: u8, buf: &mut [u8]) -> Result<usize> {
+        let mut chip = self.0.lock().unwrap();
+        let resp = match ep {
+            0x81 => chip.cmd_resp.pop_front().expect("no command pending"),
+            _ => chip.pack_status(),
+        };
+        buf[..resp.len()].copy_from_slice(&resp);
+        Ok(resp.len())
+    }
+
+    fn open_nth(_vid: u16, _pid: u16, _nth: usize) -> Result<Box<dyn USBDeviceBackend>> {
+        unreachable!("created by the tests")
+    }
+
+    fn write_endpoint(&mut self, ep: u8, buf: &[u8]) -> Result<()> {
+        let mut chip = self.0.lock().unwrap();
+        if ep == 0x01 {
+            let payload = chip.command(buf);
+            let mut resp = vec![0x82, buf[1], payload.len() as u8];
+            resp.extend_from_slice(&payload);
+            chip.cmd_resp.push_back(resp);
+        } else if chip.mode == DataMode::Program {
+            chip.pack.extend_from_slice(buf);
+        }
+        Ok(())
+    }
+}
+
+pub fn session(chip: Chip) -> (ProbeSession, Arc<Mutex<Chip>>) {
+    let chip = Arc::new(Mutex::new(chip));
+    let probe = WchLink::from_device(Box::new(SimDevice(chip.clone()))).unwrap();
+    let sess = ProbeSession::resume_with_options(
+        probe,
+        RiscvChip::CH32V30X,
+        0x3070_0518,
+        Speed::High,
+        AttachOptions::default(),
+    )
+    .unwrap();
+    (sess, chip)
+}
+
+pub fn image(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 3) as u8).collect()
+}